@@ -0,0 +1,322 @@
+use docopt::Docopt;
+use hbb_common::{
+    env_logger::{init_from_env, Env, DEFAULT_FILTER_ENV},
+    log,
+    message_proto::VideoFrame,
+    protobuf::Message as _,
+    ResultType,
+};
+use scrap::{
+    aom::{AomEncoder, AomEncoderConfig},
+    codec::{EncoderApi, EncoderCfg, Quality},
+    convert_to_yuv,
+    synthetic::{SyntheticCapturer, SyntheticConfig},
+    EncodeInput, EncodeYuvFormat, Frame, TraitCapturer, VpxEncoder, VpxEncoderConfig,
+    VpxVideoCodecId,
+};
+use std::time::{Duration, Instant};
+
+// Capture -> convert -> encode throughput with the synthetic display, no display server needed.
+//
+// cargo run --package scrap --example pipeline_benchmark --release
+// cargo run --package scrap --example pipeline_benchmark --release --features hwcodec -- --size=3840x2160
+
+const USAGE: &'static str = "
+Capture pipeline benchmark.
+
+Usage:
+  pipeline_benchmark [--content=CONTENT] [--size=SIZE] [--count=COUNT] [--codec=CODEC]
+  pipeline_benchmark (-h | --help)
+
+Options:
+  -h --help             Show this screen.
+  --content=CONTENT     Synthetic content, boxes, text or png:<dir> [default: boxes].
+  --size=SIZE           Frame size [default: 1920x1080].
+  --count=COUNT         Frame count of each run [default: 120].
+  --codec=CODEC         Only run the codec, vp8, vp9, av1, h264 or h265.
+";
+
+#[derive(Debug, serde::Deserialize)]
+struct Args {
+    flag_content: String,
+    flag_size: String,
+    flag_count: usize,
+    flag_codec: Option<String>,
+}
+
+const QUALITIES: [Quality; 3] = [Quality::Best, Quality::Balanced, Quality::Low];
+
+struct Report {
+    name: String,
+    frames: usize,
+    total: Duration,
+    convert: Vec<Duration>,
+    encode: Vec<Duration>,
+    bytes: usize,
+}
+
+impl Report {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            frames: 0,
+            total: Duration::ZERO,
+            convert: vec![],
+            encode: vec![],
+            bytes: 0,
+        }
+    }
+
+    fn print_header() {
+        println!(
+            "{:<28} {:>8} {:>10} {:>10} {:>10} {:>10} {:>10} {:>12}",
+            "run", "fps", "convert", "enc p50", "enc p90", "enc p99", "enc max", "bytes/frame"
+        );
+    }
+
+    fn print(&self) {
+        let fps = if self.total.is_zero() {
+            0.
+        } else {
+            self.frames as f64 / self.total.as_secs_f64()
+        };
+        let mut encode = self.encode.clone();
+        encode.sort();
+        println!(
+            "{:<28} {:>8.1} {:>10} {:>10} {:>10} {:>10} {:>10} {:>12}",
+            self.name,
+            fps,
+            ms(average(&self.convert)),
+            ms(percentile(&encode, 50)),
+            ms(percentile(&encode, 90)),
+            ms(percentile(&encode, 99)),
+            ms(encode.last().cloned().unwrap_or_default()),
+            self.bytes / self.frames.max(1),
+        );
+    }
+}
+
+fn ms(d: Duration) -> String {
+    format!("{:.2}ms", d.as_secs_f64() * 1000.)
+}
+
+fn average(v: &[Duration]) -> Duration {
+    if v.is_empty() {
+        return Duration::ZERO;
+    }
+    v.iter().sum::<Duration>() / v.len() as u32
+}
+
+// Nearest rank of the sorted durations.
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p * sorted.len() + 99) / 100;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn encoded_size(vf: &VideoFrame) -> usize {
+    vf.compute_size() as _
+}
+
+fn main() {
+    init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let mut config =
+        SyntheticConfig::parse(&format!("{};size={}", args.flag_content, args.flag_size))
+            .unwrap_or_else(|e| {
+                eprintln!("{e}");
+                std::process::exit(1);
+            });
+    // Render as fast as possible.
+    config.fps = 1_000_000;
+    let (width, height) = (config.width, config.height);
+    let count = args.flag_count.max(1);
+    let only = args.flag_codec.map(|c| c.to_lowercase());
+    let enabled = |codec: &str| only.as_deref().map(|c| c == codec).unwrap_or(true);
+
+    println!(
+        "pipeline benchmark {}x{} {}, {} frames",
+        width, height, args.flag_content, count
+    );
+    Report::print_header();
+
+    // The same yuv layouts as the encoders.
+    for i444 in [false, true] {
+        let cfg = vpx_cfg(
+            VpxVideoCodecId::VP9,
+            width,
+            height,
+            Quality::default().ratio(),
+        );
+        let yuvfmt = match VpxEncoder::new(cfg, i444) {
+            Ok(encoder) => encoder.yuvfmt(),
+            Err(e) => {
+                log::error!("Failed to create vp9 encoder: {}", e);
+                continue;
+            }
+        };
+        let mut report = Report::new(format!("convert {:?}", yuvfmt.pixfmt));
+        run(&config, count, &mut report, yuvfmt, |_| Ok(0));
+        report.print();
+    }
+
+    for quality in QUALITIES {
+        let ratio = quality.ratio();
+        for (name, codec) in [("vp8", VpxVideoCodecId::VP8), ("vp9", VpxVideoCodecId::VP9)] {
+            if !enabled(name) {
+                continue;
+            }
+            let i444s: &[bool] = if codec == VpxVideoCodecId::VP8 {
+                &[false]
+            } else {
+                &[false, true]
+            };
+            for &i444 in i444s {
+                let cfg = vpx_cfg(codec, width, height, ratio);
+                bench_encoder::<VpxEncoder>(&config, count, name, quality, cfg, i444);
+            }
+        }
+        if enabled("av1") {
+            for i444 in [false, true] {
+                let cfg = EncoderCfg::AOM(AomEncoderConfig {
+                    width: width as _,
+                    height: height as _,
+                    quality: ratio,
+                    keyframe_interval: None,
+                });
+                bench_encoder::<AomEncoder>(&config, count, "av1", quality, cfg, i444);
+            }
+        }
+        #[cfg(feature = "hwcodec")]
+        hw::bench(&config, count, quality, &enabled);
+    }
+}
+
+fn vpx_cfg(codec: VpxVideoCodecId, width: usize, height: usize, ratio: f32) -> EncoderCfg {
+    EncoderCfg::VPX(VpxEncoderConfig {
+        width: width as _,
+        height: height as _,
+        quality: ratio,
+        codec,
+        keyframe_interval: None,
+    })
+}
+
+fn bench_encoder<E: EncoderApi>(
+    config: &SyntheticConfig,
+    count: usize,
+    name: &str,
+    quality: Quality,
+    cfg: EncoderCfg,
+    i444: bool,
+) {
+    let mut encoder = match E::new(cfg, i444) {
+        Ok(e) => e,
+        Err(e) => {
+            log::error!("Failed to create {} encoder: {}", name, e);
+            return;
+        }
+    };
+    let yuvfmt = encoder.yuvfmt();
+    let mut report = Report::new(format!("{} {:?} {:?}", name, yuvfmt.pixfmt, quality));
+    let start = Instant::now();
+    run(config, count, &mut report, yuvfmt, |yuv| {
+        let ms = start.elapsed().as_millis() as i64;
+        match encoder.encode_to_message(EncodeInput::YUV(yuv), ms) {
+            Ok(vf) => Ok(encoded_size(&vf)),
+            // Encoders may buffer frames, the output is counted when flushed.
+            Err(e) if e.to_string().contains("no valid frame") => Ok(0),
+            Err(e) => Err(e),
+        }
+    });
+    report.print();
+}
+
+// Capture, convert and encode `count` frames.
+fn run(
+    config: &SyntheticConfig,
+    count: usize,
+    report: &mut Report,
+    yuvfmt: EncodeYuvFormat,
+    mut encode: impl FnMut(&[u8]) -> ResultType<usize>,
+) {
+    let mut capturer = match SyntheticCapturer::new(config.clone()) {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("Failed to create synthetic capturer: {}", e);
+            return;
+        }
+    };
+    let mut yuv = Vec::new();
+    let mut mid_data = Vec::new();
+    let start = Instant::now();
+    for _ in 0..count {
+        let frame = match capturer.frame(Duration::from_millis(30)) {
+            Ok(frame) => frame,
+            Err(e) => {
+                log::error!("Failed to capture: {}", e);
+                return;
+            }
+        };
+        let Frame::PixelBuffer(pixelbuffer) = frame else {
+            log::error!("Unexpected frame type");
+            return;
+        };
+        let t = Instant::now();
+        if let Err(e) = convert_to_yuv(&pixelbuffer, yuvfmt.clone(), &mut yuv, &mut mid_data) {
+            log::error!("Failed to convert: {}", e);
+            return;
+        }
+        report.convert.push(t.elapsed());
+        let t = Instant::now();
+        match encode(&yuv) {
+            Ok(size) => report.bytes += size,
+            Err(e) => {
+                log::error!("Failed to encode: {}", e);
+                return;
+            }
+        }
+        report.encode.push(t.elapsed());
+        report.frames += 1;
+    }
+    report.total = start.elapsed();
+}
+
+#[cfg(feature = "hwcodec")]
+mod hw {
+    use scrap::{
+        hwcodec::{HwRamEncoder, HwRamEncoderConfig},
+        CodecFormat,
+    };
+
+    use super::*;
+
+    pub fn bench(
+        config: &SyntheticConfig,
+        count: usize,
+        quality: Quality,
+        enabled: &dyn Fn(&str) -> bool,
+    ) {
+        for (name, format) in [("h264", CodecFormat::H264), ("h265", CodecFormat::H265)] {
+            if !enabled(name) {
+                continue;
+            }
+            let Some(info) = HwRamEncoder::try_get(format) else {
+                continue;
+            };
+            let cfg = EncoderCfg::HWRAM(HwRamEncoderConfig {
+                name: info.name.clone(),
+                mc_name: None,
+                width: config.width,
+                height: config.height,
+                quality: quality.ratio(),
+                keyframe_interval: None,
+            });
+            bench_encoder::<HwRamEncoder>(config, count, &info.name, quality, cfg, false);
+        }
+    }
+}
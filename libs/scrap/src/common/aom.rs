@@ -55,6 +55,7 @@ pub struct AomEncoder {
     height: usize,
     i444: bool,
     yuvfmt: EncodeYuvFormat,
+    keyframe: bool,
 }
 
 // https://webrtc.googlesource.com/src/+/refs/heads/main/modules/video_coding/codecs/av1/libaom_av1_encoder.cc
@@ -226,6 +227,7 @@ impl EncoderApi for AomEncoder {
                     height: config.height as _,
                     i444,
                     yuvfmt: Self::get_yuvfmt(config.width, config.height, i444),
+                    keyframe: false,
                 })
             }
             _ => Err(anyhow!("encoder type mismatch")),
@@ -284,6 +286,11 @@ impl EncoderApi for AomEncoder {
     }
 
     fn disable(&self) {}
+
+    fn request_keyframe(&mut self) -> bool {
+        self.keyframe = true;
+        true
+    }
 }
 
 impl AomEncoder {
@@ -309,12 +316,17 @@ impl AomEncoder {
         ));
         let pts = webrtc::kTimeBaseDen / 1000 * ms;
         let duration = webrtc::kTimeBaseDen / 1000;
+        let flags = if std::mem::take(&mut self.keyframe) {
+            AOM_EFLAG_FORCE_KF
+        } else {
+            0
+        };
         call_aom!(aom_codec_encode(
             &mut self.ctx,
             &image,
             pts as _,
             duration as _, // Duration
-            0,             // Flags
+            flags as _,
         ));
 
         Ok(EncodeFrames {
@@ -1,4 +1,5 @@
 use std::{
+    collections::{HashMap, HashSet},
     io,
     sync::{Arc, Mutex},
 };
@@ -7,11 +8,19 @@ use std::{
 use nokhwa::{
     pixel_format::RgbAFormat,
     query,
-    utils::{ApiBackend, CameraIndex, RequestedFormat, RequestedFormatType},
+    utils::{
+        frame_formats, ApiBackend, CameraControl as NokhwaControl, CameraFormat as NokhwaFormat,
+        CameraIndex, ControlValueDescription, ControlValueSetter, FrameFormat, KnownCameraControl,
+        RequestedFormat, RequestedFormatType, Resolution as NokhwaResolution,
+    },
     Camera,
 };
 
-use hbb_common::message_proto::{DisplayInfo, Resolution};
+use hbb_common::{
+    log,
+    message_proto::{DisplayInfo, Resolution},
+};
+use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "vram")]
 use crate::AdapterDevice;
@@ -22,8 +31,87 @@ use crate::{Frame, PixelBuffer, Pixfmt, TraitCapturer};
 pub const PRIMARY_CAMERA_IDX: usize = 0;
 lazy_static::lazy_static! {
     static ref SYNC_CAMERA_DISPLAYS: Arc<Mutex<Vec<DisplayInfo>>> = Arc::new(Mutex::new(Vec::new()));
+    // Formats requested by the controller, applied when the camera is created.
+    static ref CAMERA_FORMATS: Mutex<HashMap<usize, CameraFormat>> = Default::default();
+    // Cameras whose format is changed, the video service needs to recreate the capturer.
+    static ref FORMAT_CHANGED: Mutex<HashSet<usize>> = Default::default();
+    // Controls to be applied by the running capturer.
+    static ref PENDING_CONTROLS: Mutex<HashMap<usize, Vec<CameraControl>>> = Default::default();
+    // Capabilities of the cameras being captured, they can't be reopened for querying.
+    static ref CAPTURING_CAPABILITIES: Mutex<HashMap<usize, CameraCapabilities>> = Default::default();
+    // Incremented when the capabilities of a camera being captured are updated.
+    static ref CAPABILITIES_SERIALS: Mutex<HashMap<usize, u64>> = Default::default();
+}
+
+// The formats and controls of a camera, exchanged in the extension messages of the view camera
+// session.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraFormat {
+    pub width: i32,
+    pub height: i32,
+    pub frame_rate: u32,
+    pub fourcc: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraControl {
+    pub name: String,
+    pub value: i64,
+    pub min: i64,
+    pub max: i64,
+    pub step: i64,
+    pub default_value: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraCapabilities {
+    pub display: i32,
+    pub formats: Vec<CameraFormat>,
+    pub current: Option<CameraFormat>,
+    pub controls: Vec<CameraControl>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraSettings {
+    pub display: i32,
+    pub format: Option<CameraFormat>,
+    pub controls: Vec<CameraControl>,
+}
+
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+fn update_capturing_capabilities(display: usize, caps: CameraCapabilities) {
+    CAPTURING_CAPABILITIES.lock().unwrap().insert(display, caps);
+    *CAPABILITIES_SERIALS
+        .lock()
+        .unwrap()
+        .entry(display)
+        .or_default() += 1;
 }
 
+// The controls exposed to the controller, named as V4L2 does.
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+const CAMERA_CONTROLS: [(KnownCameraControl, &str); 15] = [
+    (KnownCameraControl::Brightness, "brightness"),
+    (KnownCameraControl::Contrast, "contrast"),
+    (KnownCameraControl::Hue, "hue"),
+    (KnownCameraControl::Saturation, "saturation"),
+    (KnownCameraControl::Sharpness, "sharpness"),
+    (KnownCameraControl::Gamma, "gamma"),
+    (KnownCameraControl::WhiteBalance, "white_balance"),
+    (KnownCameraControl::BacklightComp, "backlight_compensation"),
+    (KnownCameraControl::Gain, "gain"),
+    (KnownCameraControl::Pan, "pan"),
+    (KnownCameraControl::Tilt, "tilt"),
+    (KnownCameraControl::Zoom, "zoom"),
+    (KnownCameraControl::Exposure, "exposure"),
+    (KnownCameraControl::Iris, "iris"),
+    (KnownCameraControl::Focus, "focus"),
+];
+
 #[cfg(not(any(target_os = "windows", target_os = "linux")))]
 const CAMERA_NOT_SUPPORTED: &str = "This platform doesn't support camera yet";
 
@@ -60,7 +148,7 @@ impl Cameras {
                                 camera_index = CameraIndex::Index(0);
                             }
                         }
-                        let camera = Self::create_camera(&camera_index)?;
+                        let camera = Self::create_camera(&camera_index, PRIMARY_CAMERA_IDX)?;
                         let resolution = camera.resolution();
                         let (width, height) = (resolution.width() as i32, resolution.height() as i32);
                         camera_displays.push(DisplayInfo {
@@ -81,8 +169,8 @@ impl Cameras {
                         });
                     } else {
                         let mut x = 0;
-                        for info in &cameras {
-                            let camera = Self::create_camera(info.index())?;
+                        for (idx, info) in cameras.iter().enumerate() {
+                            let camera = Self::create_camera(info.index(), idx)?;
                             let resolution = camera.resolution();
                             let (width, height) = (resolution.width() as i32, resolution.height() as i32);
                             camera_displays.push(DisplayInfo {
@@ -120,8 +208,10 @@ impl Cameras {
         }
     }
 
-    fn create_camera(index: &CameraIndex) -> ResultType<Camera> {
-        let format_type = if cfg!(target_os = "linux") {
+    fn create_camera(index: &CameraIndex, display: usize) -> ResultType<Camera> {
+        let format_type = if let Some(format) = CAMERA_FORMATS.lock().unwrap().get(&display) {
+            RequestedFormatType::Closest(to_nokhwa_format(format))
+        } else if cfg!(target_os = "linux") {
             RequestedFormatType::None
         } else {
             RequestedFormatType::AbsoluteHighestResolution
@@ -137,8 +227,7 @@ impl Cameras {
     }
 
     pub fn get_camera_resolution(index: usize) -> ResultType<Resolution> {
-        let index = CameraIndex::Index(index as u32);
-        let camera = Self::create_camera(&index)?;
+        let camera = Self::create_camera(&CameraIndex::Index(index as u32), index)?;
         let resolution = camera.resolution();
         Ok(Resolution {
             width: resolution.width() as i32,
@@ -154,6 +243,165 @@ impl Cameras {
     pub fn get_capturer(current: usize) -> ResultType<Box<dyn TraitCapturer>> {
         Ok(Box::new(CameraCapturer::new(current)?))
     }
+
+    pub fn capabilities(display: usize) -> ResultType<CameraCapabilities> {
+        if let Some(caps) = CAPTURING_CAPABILITIES.lock().unwrap().get(&display) {
+            return Ok(caps.clone());
+        }
+        let mut camera = Self::create_camera(&CameraIndex::Index(display as u32), display)?;
+        Ok(Self::query_capabilities(&mut camera, display))
+    }
+
+    fn query_capabilities(camera: &mut Camera, display: usize) -> CameraCapabilities {
+        let formats = match camera.compatible_camera_formats() {
+            Ok(formats) => formats.iter().map(to_camera_format).collect(),
+            Err(e) => {
+                log::warn!("Failed to get formats of camera {}: {}", display, e);
+                vec![]
+            }
+        };
+        let controls = match camera.camera_controls() {
+            Ok(controls) => controls.iter().filter_map(to_camera_control).collect(),
+            Err(e) => {
+                log::warn!("Failed to get controls of camera {}: {}", display, e);
+                vec![]
+            }
+        };
+        CameraCapabilities {
+            display: display as _,
+            formats,
+            current: Some(to_camera_format(&camera.camera_format())),
+            controls,
+        }
+    }
+
+    // The format takes effect after the capturer is recreated, see `take_format_changed`.
+    // The controls are applied by the running capturer, or on creating the capturer.
+    // Returns true if the capturer has something to apply, the capabilities are updated then,
+    // see `capabilities_serial`.
+    pub fn apply_settings(settings: &CameraSettings) -> ResultType<bool> {
+        let display = settings.display as usize;
+        let mut changed = false;
+        if let Some(format) = settings.format.as_ref() {
+            if format.width <= 0 || format.height <= 0 {
+                bail!("Invalid camera format {}x{}", format.width, format.height);
+            }
+            let mut formats = CAMERA_FORMATS.lock().unwrap();
+            if formats.get(&display) != Some(format) {
+                changed = true;
+                formats.insert(display, format.clone());
+                if let Some(d) = SYNC_CAMERA_DISPLAYS.lock().unwrap().get_mut(display) {
+                    d.width = format.width;
+                    d.height = format.height;
+                    d.original_resolution = Some(Resolution {
+                        width: format.width,
+                        height: format.height,
+                        ..Default::default()
+                    })
+                    .into();
+                }
+                FORMAT_CHANGED.lock().unwrap().insert(display);
+            }
+        }
+        if let Some(c) = settings
+            .controls
+            .iter()
+            .find(|c| known_control(&c.name).is_none())
+        {
+            bail!("Unknown camera control {}", c.name);
+        }
+        if !settings.controls.is_empty() {
+            changed = true;
+            PENDING_CONTROLS
+                .lock()
+                .unwrap()
+                .entry(display)
+                .or_default()
+                .extend(settings.controls.iter().cloned());
+        }
+        Ok(changed)
+    }
+
+    pub fn take_format_changed(display: usize) -> bool {
+        FORMAT_CHANGED.lock().unwrap().remove(&display)
+    }
+
+    pub fn capabilities_serial(display: usize) -> u64 {
+        CAPABILITIES_SERIALS
+            .lock()
+            .unwrap()
+            .get(&display)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+fn known_control(name: &str) -> Option<KnownCameraControl> {
+    CAMERA_CONTROLS
+        .iter()
+        .find(|(_, n)| *n == name)
+        .map(|(c, _)| *c)
+}
+
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+fn to_camera_format(format: &NokhwaFormat) -> CameraFormat {
+    CameraFormat {
+        width: format.width() as _,
+        height: format.height() as _,
+        frame_rate: format.frame_rate(),
+        fourcc: format.format().to_string(),
+    }
+}
+
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+fn to_nokhwa_format(format: &CameraFormat) -> NokhwaFormat {
+    let frame_format = frame_formats()
+        .iter()
+        .find(|f| f.to_string() == format.fourcc)
+        .copied()
+        .unwrap_or(FrameFormat::MJPEG);
+    NokhwaFormat::new(
+        NokhwaResolution::new(format.width as _, format.height as _),
+        frame_format,
+        format.frame_rate,
+    )
+}
+
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+fn to_camera_control(control: &NokhwaControl) -> Option<CameraControl> {
+    let (_, name) = CAMERA_CONTROLS
+        .iter()
+        .find(|(c, _)| *c == control.control())?;
+    let mut c = CameraControl {
+        name: name.to_string(),
+        ..Default::default()
+    };
+    match control.description() {
+        ControlValueDescription::IntegerRange {
+            min,
+            max,
+            value,
+            step,
+            default,
+        } => {
+            (c.min, c.max, c.value, c.step, c.default_value) =
+                (*min, *max, *value, *step, *default);
+        }
+        ControlValueDescription::Integer {
+            value,
+            default,
+            step,
+        } => {
+            (c.value, c.step, c.default_value) = (*value, *step, *default);
+        }
+        ControlValueDescription::Boolean { value, default } => {
+            (c.min, c.max, c.step) = (0, 1, 1);
+            (c.value, c.default_value) = (*value as _, *default as _);
+        }
+        _ => return None,
+    }
+    Some(c)
 }
 
 #[cfg(not(any(target_os = "windows", target_os = "linux")))]
@@ -177,11 +425,28 @@ impl Cameras {
     pub fn get_capturer(current: usize) -> ResultType<Box<dyn TraitCapturer>> {
         bail!(CAMERA_NOT_SUPPORTED);
     }
+
+    pub fn capabilities(display: usize) -> ResultType<CameraCapabilities> {
+        bail!(CAMERA_NOT_SUPPORTED);
+    }
+
+    pub fn apply_settings(settings: &CameraSettings) -> ResultType<bool> {
+        bail!(CAMERA_NOT_SUPPORTED);
+    }
+
+    pub fn take_format_changed(display: usize) -> bool {
+        false
+    }
+
+    pub fn capabilities_serial(display: usize) -> u64 {
+        0
+    }
 }
 
 #[cfg(any(target_os = "windows", target_os = "linux"))]
 pub struct CameraCapturer {
     camera: Camera,
+    display: usize,
     data: Vec<u8>,
     last_data: Vec<u8>, // for faster compare and copy
 }
@@ -193,12 +458,55 @@ impl CameraCapturer {
     #[cfg(any(target_os = "windows", target_os = "linux"))]
     fn new(current: usize) -> ResultType<Self> {
         let index = CameraIndex::Index(current as u32);
-        let camera = Cameras::create_camera(&index)?;
-        Ok(CameraCapturer {
+        let camera = Cameras::create_camera(&index, current)?;
+        let mut capturer = CameraCapturer {
             camera,
+            display: current,
             data: Vec::new(),
             last_data: Vec::new(),
-        })
+        };
+        // The capabilities are queried with the pending controls applied.
+        capturer.apply_pending_controls();
+        let caps = Cameras::query_capabilities(&mut capturer.camera, current);
+        update_capturing_capabilities(current, caps);
+        Ok(capturer)
+    }
+
+    #[cfg(any(target_os = "windows", target_os = "linux"))]
+    fn apply_pending_controls(&mut self) {
+        let Some(controls) = PENDING_CONTROLS.lock().unwrap().remove(&self.display) else {
+            return;
+        };
+        let current = self.camera.camera_controls().unwrap_or_default();
+        for c in controls {
+            let Some(id) = known_control(&c.name) else {
+                continue;
+            };
+            let is_bool = current.iter().any(|x| {
+                x.control() == id
+                    && matches!(x.description(), ControlValueDescription::Boolean { .. })
+            });
+            let value = if is_bool {
+                ControlValueSetter::Boolean(c.value != 0)
+            } else {
+                ControlValueSetter::Integer(c.value)
+            };
+            match self.camera.set_camera_control(id, value) {
+                Ok(()) => log::info!("Set camera control {} to {}", c.name, c.value),
+                Err(e) => log::error!("Failed to set camera control {}: {}", c.name, e),
+            }
+        }
+        if let Ok(controls) = self.camera.camera_controls() {
+            let caps = CAPTURING_CAPABILITIES
+                .lock()
+                .unwrap()
+                .get(&self.display)
+                .cloned();
+            if let Some(mut caps) = caps {
+                caps.controls = controls.iter().filter_map(to_camera_control).collect();
+                update_capturing_capabilities(self.display, caps);
+            }
+        }
     }
 
     #[cfg(not(any(target_os = "windows", target_os = "linux")))]
@@ -207,9 +515,17 @@ impl CameraCapturer {
     }
 }
 
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+impl Drop for CameraCapturer {
+    fn drop(&mut self) {
+        CAPTURING_CAPABILITIES.lock().unwrap().remove(&self.display);
+    }
+}
+
 impl TraitCapturer for CameraCapturer {
     #[cfg(any(target_os = "windows", target_os = "linux"))]
     fn frame<'a>(&'a mut self, _timeout: std::time::Duration) -> std::io::Result<Frame<'a>> {
+        self.apply_pending_controls();
         // TODO: move this check outside `frame`.
         if !self.camera.is_stream_open() {
             if let Err(e) = self.camera.open_stream() {
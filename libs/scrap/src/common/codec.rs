@@ -73,6 +73,14 @@ pub trait EncoderApi {
     fn is_hardware(&self) -> bool;
 
     fn disable(&self);
+
+    // Changed tiles of the next frame, encoders supporting ROI give them a better quality.
+    fn set_dirty_tiles(&mut self, _tiles: &crate::dirty::TileMap) {}
+
+    // Make the next frame a key frame, false if not supported.
+    fn request_keyframe(&mut self) -> bool {
+        false
+    }
 }
 
 pub struct Encoder {
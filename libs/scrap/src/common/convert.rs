@@ -58,14 +58,41 @@ pub fn convert_to_yuv(
         "unsupported pixfmt conversion: {src_pixfmt:?} -> {:?}",
         dst_fmt.pixfmt
     );
+    let dst_stride_y = dst_fmt.stride[0];
+    let dst_len = match dst_fmt.pixfmt {
+        // waste some memory to ensure memory safety
+        crate::Pixfmt::I420 => dst_fmt.h * dst_stride_y * 2,
+        crate::Pixfmt::NV12 => {
+            align(dst_fmt.h) * (align(dst_stride_y) + align(dst_fmt.stride[1] / 2))
+        }
+        crate::Pixfmt::I444 => {
+            align(dst_fmt.h)
+                * (align(dst_stride_y) + align(dst_fmt.stride[1]) + align(dst_fmt.stride[2]))
+        }
+        _ => bail!(unsupported),
+    };
+    dst.resize(dst_len, 0);
+    let is_rgb = matches!(
+        src_pixfmt,
+        crate::Pixfmt::BGRA | crate::Pixfmt::RGBA | crate::Pixfmt::RGB565LE
+    );
+    if is_rgb && crate::rgb2yuv::is_preferred(src_pixfmt, src_stride[0]) {
+        return crate::rgb2yuv::convert(
+            src,
+            src_stride[0],
+            src_pixfmt,
+            src_width,
+            src_height,
+            &dst_fmt,
+            dst,
+        );
+    }
 
     match (src_pixfmt, dst_fmt.pixfmt) {
         (crate::Pixfmt::BGRA, crate::Pixfmt::I420)
         | (crate::Pixfmt::RGBA, crate::Pixfmt::I420)
         | (crate::Pixfmt::RGB565LE, crate::Pixfmt::I420) => {
-            let dst_stride_y = dst_fmt.stride[0];
             let dst_stride_uv = dst_fmt.stride[1];
-            dst.resize(dst_fmt.h * dst_stride_y * 2, 0); // waste some memory to ensure memory safety
             let dst_y = dst.as_mut_ptr();
             let dst_u = dst[dst_fmt.u..].as_mut_ptr();
             let dst_v = dst[dst_fmt.v..].as_mut_ptr();
@@ -91,12 +118,7 @@ pub fn convert_to_yuv(
         (crate::Pixfmt::BGRA, crate::Pixfmt::NV12)
         | (crate::Pixfmt::RGBA, crate::Pixfmt::NV12)
         | (crate::Pixfmt::RGB565LE, crate::Pixfmt::NV12) => {
-            let dst_stride_y = dst_fmt.stride[0];
             let dst_stride_uv = dst_fmt.stride[1];
-            dst.resize(
-                align(dst_fmt.h) * (align(dst_stride_y) + align(dst_stride_uv / 2)),
-                0,
-            );
             let dst_y = dst.as_mut_ptr();
             let dst_uv = dst[dst_fmt.u..].as_mut_ptr();
             let (input, input_stride) = match src_pixfmt {
@@ -137,14 +159,8 @@ pub fn convert_to_yuv(
         (crate::Pixfmt::BGRA, crate::Pixfmt::I444)
         | (crate::Pixfmt::RGBA, crate::Pixfmt::I444)
         | (crate::Pixfmt::RGB565LE, crate::Pixfmt::I444) => {
-            let dst_stride_y = dst_fmt.stride[0];
             let dst_stride_u = dst_fmt.stride[1];
             let dst_stride_v = dst_fmt.stride[2];
-            dst.resize(
-                align(dst_fmt.h)
-                    * (align(dst_stride_y) + align(dst_stride_u) + align(dst_stride_v)),
-                0,
-            );
             let dst_y = dst.as_mut_ptr();
             let dst_u = dst[dst_fmt.u..].as_mut_ptr();
             let dst_v = dst[dst_fmt.v..].as_mut_ptr();
@@ -0,0 +1,260 @@
+// Changed regions of captured frames.
+//
+// Capture backends either report dirty rects themselves (DXGI) or find the changed tiles
+// while comparing the frame with the previous one, see `would_block_if_equal_tiles`.
+// Frames of the other backends are fully dirty. The tile map is used to:
+//   skip encoding if nothing changed
+//   give the changed blocks a lower quantizer (ROI)
+//   report the changed area ratio to the quality control
+
+pub const TILE_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DirtyRect {
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+}
+
+impl DirtyRect {
+    pub fn new(x: usize, y: usize, w: usize, h: usize) -> Self {
+        Self { x, y, w, h }
+    }
+
+    pub fn clip(&self, width: usize, height: usize) -> Option<Self> {
+        if self.x >= width || self.y >= height || self.w == 0 || self.h == 0 {
+            return None;
+        }
+        Some(Self {
+            x: self.x,
+            y: self.y,
+            w: self.w.min(width - self.x),
+            h: self.h.min(height - self.y),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TileMap {
+    pub width: usize,
+    pub height: usize,
+    pub tile: usize,
+    pub cols: usize,
+    pub rows: usize,
+    tiles: Vec<bool>,
+}
+
+impl TileMap {
+    pub fn new(width: usize, height: usize, tile: usize) -> Self {
+        let tile = tile.max(1);
+        let cols = (width + tile - 1) / tile;
+        let rows = (height + tile - 1) / tile;
+        Self {
+            width,
+            height,
+            tile,
+            cols,
+            rows,
+            tiles: vec![false; cols * rows],
+        }
+    }
+
+    pub fn full(width: usize, height: usize, tile: usize) -> Self {
+        let mut map = Self::new(width, height, tile);
+        map.tiles.iter_mut().for_each(|t| *t = true);
+        map
+    }
+
+    #[inline]
+    pub fn is_dirty(&self, col: usize, row: usize) -> bool {
+        self.tiles[row * self.cols + col]
+    }
+
+    #[inline]
+    pub fn set_dirty(&mut self, col: usize, row: usize) {
+        self.tiles[row * self.cols + col] = true;
+    }
+
+    pub fn mark(&mut self, rect: &DirtyRect) {
+        let Some(rect) = rect.clip(self.width, self.height) else {
+            return;
+        };
+        let (c0, r0) = (rect.x / self.tile, rect.y / self.tile);
+        let c1 = (rect.x + rect.w + self.tile - 1) / self.tile;
+        let r1 = (rect.y + rect.h + self.tile - 1) / self.tile;
+        for row in r0..r1 {
+            for col in c0..c1 {
+                self.set_dirty(col, row);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.tiles.iter().any(|t| *t)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.tiles.iter().all(|t| *t)
+    }
+
+    // Pixel rect of a tile, the last column and row may be smaller.
+    pub fn tile_rect(&self, col: usize, row: usize) -> DirtyRect {
+        let (x, y) = (col * self.tile, row * self.tile);
+        DirtyRect {
+            x,
+            y,
+            w: self.tile.min(self.width - x),
+            h: self.tile.min(self.height - y),
+        }
+    }
+
+    /// Ratio of the changed area to the frame area, in [0, 1].
+    pub fn changed_ratio(&self) -> f32 {
+        let total = self.width * self.height;
+        if total == 0 {
+            return 0.0;
+        }
+        let mut changed = 0;
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                if self.is_dirty(col, row) {
+                    let r = self.tile_rect(col, row);
+                    changed += r.w * r.h;
+                }
+            }
+        }
+        changed as f32 / total as f32
+    }
+
+    /// Changed tiles as rects, horizontal runs are merged.
+    pub fn rects(&self) -> Vec<DirtyRect> {
+        let mut rects = vec![];
+        for row in 0..self.rows {
+            let mut col = 0;
+            while col < self.cols {
+                if !self.is_dirty(col, row) {
+                    col += 1;
+                    continue;
+                }
+                let start = col;
+                while col < self.cols && self.is_dirty(col, row) {
+                    col += 1;
+                }
+                let first = self.tile_rect(start, row);
+                let last = self.tile_rect(col - 1, row);
+                rects.push(DirtyRect {
+                    x: first.x,
+                    y: first.y,
+                    w: last.x + last.w - first.x,
+                    h: first.h,
+                });
+            }
+        }
+        rects
+    }
+
+    /// Block map for an encoder, one byte per `block` pixels, 1 if the block is changed.
+    pub fn block_map(&self, block: usize) -> (Vec<u8>, usize, usize) {
+        let block = block.max(1);
+        let cols = (self.width + block - 1) / block;
+        let rows = (self.height + block - 1) / block;
+        let mut map = vec![0u8; cols * rows];
+        for row in 0..rows {
+            let tile_row = (row * block / self.tile).min(self.rows.saturating_sub(1));
+            for col in 0..cols {
+                let tile_col = (col * block / self.tile).min(self.cols.saturating_sub(1));
+                if self.is_dirty(tile_col, tile_row) {
+                    map[row * cols + col] = 1;
+                }
+            }
+        }
+        (map, cols, rows)
+    }
+}
+
+/// `would_block_if_equal` which also finds the changed tiles, the frame is compared once.
+///
+/// `old` is the previous frame of the capturer, the changed tiles are copied into it.
+/// `bpp` is bytes per pixel. A frame with another size is fully dirty.
+pub fn would_block_if_equal_tiles(
+    old: &mut Vec<u8>,
+    b: &[u8],
+    stride: usize,
+    width: usize,
+    height: usize,
+    bpp: usize,
+) -> std::io::Result<TileMap> {
+    if old.len() != b.len() {
+        old.resize(b.len(), 0);
+        old.copy_from_slice(b);
+        return Ok(TileMap::full(width, height, TILE_SIZE));
+    }
+    let mut map = TileMap::new(width, height, TILE_SIZE);
+    for row in 0..map.rows {
+        for col in 0..map.cols {
+            let r = map.tile_rect(col, row);
+            let (start, len) = (r.x * bpp, r.w * bpp);
+            let mut changed = false;
+            for y in r.y..r.y + r.h {
+                let offset = y * stride + start;
+                if offset + len > b.len() {
+                    break;
+                }
+                if changed {
+                    old[offset..offset + len].copy_from_slice(&b[offset..offset + len]);
+                } else if b[offset..offset + len] != old[offset..offset + len] {
+                    changed = true;
+                    // Copy the rest of the tile too, rows above were equal.
+                    old[offset..offset + len].copy_from_slice(&b[offset..offset + len]);
+                }
+            }
+            if changed {
+                map.set_dirty(col, row);
+            }
+        }
+    }
+    if map.is_empty() {
+        return Err(std::io::ErrorKind::WouldBlock.into());
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(w: usize, h: usize, fill: u8) -> Vec<u8> {
+        vec![fill; w * h * 4]
+    }
+
+    #[test]
+    fn test_changed_tiles() {
+        let (w, h) = (160, 80);
+        let mut saved = vec![];
+        let mut data = frame(w, h, 0);
+        let map = would_block_if_equal_tiles(&mut saved, &data, w * 4, w, h, 4).unwrap();
+        assert!(map.is_full());
+        assert!((map.changed_ratio() - 1.0).abs() < f32::EPSILON);
+        assert!(would_block_if_equal_tiles(&mut saved, &data, w * 4, w, h, 4).is_err());
+
+        // pixel (70, 65) => tile (1, 1)
+        data[(65 * w + 70) * 4] = 255;
+        let map = would_block_if_equal_tiles(&mut saved, &data, w * 4, w, h, 4).unwrap();
+        assert!(map.is_dirty(1, 1));
+        assert_eq!(map.rects(), vec![DirtyRect::new(64, 64, 64, 16)]);
+        assert!((map.changed_ratio() - 64.0 * 16.0 / (w * h) as f32).abs() < f32::EPSILON);
+        assert!(would_block_if_equal_tiles(&mut saved, &data, w * 4, w, h, 4).is_err());
+    }
+
+    #[test]
+    fn test_block_map() {
+        let mut map = TileMap::new(40, 20, 16);
+        map.mark(&DirtyRect::new(33, 0, 1, 1));
+        let (blocks, cols, rows) = map.block_map(8);
+        assert_eq!((cols, rows), (5, 3));
+        assert_eq!(blocks.iter().filter(|b| **b == 1).count(), 1 * 2);
+        assert_eq!(blocks[4], 1);
+        assert_eq!(blocks[cols + 4], 1);
+    }
+}
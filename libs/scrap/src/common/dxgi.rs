@@ -1,6 +1,6 @@
 #[cfg(feature = "vram")]
 use crate::AdapterDevice;
-use crate::{common::TraitCapturer, dxgi, Frame, Pixfmt};
+use crate::{common::TraitCapturer, dirty::TileMap, dxgi, Frame, Pixfmt};
 use std::{
     io::{
         self,
@@ -74,6 +74,7 @@ pub struct PixelBuffer<'a> {
     width: usize,
     height: usize,
     stride: Vec<usize>,
+    dirty_tiles: Option<&'a TileMap>,
 }
 
 impl<'a> PixelBuffer<'a> {
@@ -87,6 +88,7 @@ impl<'a> PixelBuffer<'a> {
             width,
             height,
             stride,
+            dirty_tiles: None,
         }
     }
 
@@ -94,6 +96,11 @@ impl<'a> PixelBuffer<'a> {
     pub fn with_BGRA(data: &'a [u8], width: usize, height: usize) -> Self {
         Self::new(data, Pixfmt::BGRA, width, height)
     }
+
+    pub fn with_dirty_tiles(mut self, dirty_tiles: Option<&'a TileMap>) -> Self {
+        self.dirty_tiles = dirty_tiles;
+        self
+    }
 }
 
 impl<'a> crate::TraitPixelBuffer for PixelBuffer<'a> {
@@ -116,6 +123,10 @@ impl<'a> crate::TraitPixelBuffer for PixelBuffer<'a> {
     fn pixfmt(&self) -> Pixfmt {
         self.pixfmt
     }
+
+    fn dirty_tiles(&self) -> Option<&TileMap> {
+        self.dirty_tiles
+    }
 }
 
 pub struct Display(dxgi::Display);
@@ -74,6 +74,33 @@ impl Display {
         })
     }
 
+    // Top level windows as displays, X11 only. Wayland windows are selected in the portal.
+    pub fn all_windows() -> io::Result<Vec<Display>> {
+        Ok(if super::is_x11() {
+            x11::Display::all_windows()?
+                .drain(..)
+                .map(|x| Display::X11(x))
+                .collect()
+        } else {
+            vec![]
+        })
+    }
+
+    pub fn is_window(&self) -> bool {
+        match self {
+            Display::X11(d) => d.is_window(),
+            Display::WAYLAND(_) => false,
+        }
+    }
+
+    // Crop to a region of the display at capture time, X11 only.
+    pub fn crop(&mut self, x: i32, y: i32, w: usize, h: usize) -> bool {
+        match self {
+            Display::X11(d) => d.crop(x, y, w, h),
+            Display::WAYLAND(_) => false,
+        }
+    }
+
     pub fn width(&self) -> usize {
         match self {
             Display::X11(d) => d.width(),
@@ -51,7 +51,15 @@ pub const HW_STRIDE_ALIGN: usize = 0; // recommended by av_frame_get_buffer
 pub mod aom;
 #[cfg(not(any(target_os = "ios")))]
 pub mod camera;
+pub mod dirty;
 pub mod record;
+pub mod record_crypt;
+pub mod rgb2yuv;
+#[cfg(not(any(target_os = "ios")))]
+pub mod synthetic;
+pub mod tilecodec;
+#[cfg(target_os = "linux")]
+pub mod v4l2loopback;
 mod vpx;
 
 #[repr(usize)]
@@ -170,6 +178,11 @@ pub trait TraitPixelBuffer {
     fn stride(&self) -> Vec<usize>;
 
     fn pixfmt(&self) -> Pixfmt;
+
+    // Changed tiles found by the capture backend, none if the whole frame is treated as changed.
+    fn dirty_tiles(&self) -> Option<&dirty::TileMap> {
+        None
+    }
 }
 
 #[cfg(not(any(target_os = "ios")))]
@@ -259,6 +272,10 @@ pub struct EncodeYuvFormat {
     pub v: usize,
 }
 
+// Share single application windows as additional displays.
+#[cfg(x11)]
+pub const OPTION_WINDOW_CAPTURE: &str = "enable-window-capture";
+
 #[cfg(x11)]
 #[inline]
 pub fn is_x11() -> bool {
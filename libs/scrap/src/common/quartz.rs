@@ -1,4 +1,7 @@
-use crate::{quartz, Frame, Pixfmt};
+use crate::{
+    dirty::{self, TileMap},
+    quartz, Frame, Pixfmt,
+};
 use std::marker::PhantomData;
 use std::sync::{Arc, Mutex, TryLockError};
 use std::{io, mem};
@@ -7,6 +10,7 @@ pub struct Capturer {
     inner: quartz::Capturer,
     frame: Arc<Mutex<Option<quartz::Frame>>>,
     saved_raw_data: Vec<u8>, // for faster compare and copy
+    tiles: Option<TileMap>,
 }
 
 impl Capturer {
@@ -32,6 +36,7 @@ impl Capturer {
             inner,
             frame,
             saved_raw_data: Vec::new(),
+            tiles: None,
         })
     }
 
@@ -53,13 +58,21 @@ impl crate::TraitCapturer for Capturer {
 
                 match frame {
                     Some(mut frame) => {
-                        crate::would_block_if_equal(&mut self.saved_raw_data, frame.inner())?;
+                        self.tiles = Some(dirty::would_block_if_equal_tiles(
+                            &mut self.saved_raw_data,
+                            frame.inner(),
+                            frame.inner_stride(),
+                            self.width(),
+                            self.height(),
+                            4,
+                        )?);
                         frame.surface_to_bgra(self.height());
                         Ok(Frame::PixelBuffer(PixelBuffer {
                             frame,
                             data: PhantomData,
                             width: self.width(),
                             height: self.height(),
+                            dirty_tiles: self.tiles.as_ref(),
                         }))
                     }
 
@@ -79,6 +92,7 @@ pub struct PixelBuffer<'a> {
     data: PhantomData<&'a [u8]>,
     width: usize,
     height: usize,
+    dirty_tiles: Option<&'a TileMap>,
 }
 
 impl<'a> crate::TraitPixelBuffer for PixelBuffer<'a> {
@@ -103,6 +117,10 @@ impl<'a> crate::TraitPixelBuffer for PixelBuffer<'a> {
     fn pixfmt(&self) -> Pixfmt {
         Pixfmt::BGRA
     }
+
+    fn dirty_tiles(&self) -> Option<&TileMap> {
+        self.dirty_tiles
+    }
 }
 
 pub struct Display(quartz::Display);
@@ -1,4 +1,7 @@
-use crate::CodecFormat;
+use crate::{
+    record_crypt::{self, EncryptWriter},
+    CodecFormat,
+};
 #[cfg(feature = "hwcodec")]
 use hbb_common::anyhow::anyhow;
 use hbb_common::{
@@ -10,7 +13,7 @@ use hbb_common::{
 use hwcodec::mux::{MuxContext, Muxer};
 use std::{
     fs::{File, OpenOptions},
-    io,
+    io::{self, Seek, SeekFrom, Write},
     ops::{Deref, DerefMut},
     path::PathBuf,
     sync::mpsc::Sender,
@@ -28,6 +31,14 @@ pub struct RecorderContext {
     pub display_idx: usize,
     pub camera: bool,
     pub tx: Option<Sender<RecordState>>,
+    /// Base64 public key of the operator, recordings are encrypted at rest if set.
+    pub encrypt_key: Option<String>,
+}
+
+impl RecorderContext {
+    fn encrypt(&self) -> bool {
+        self.encrypt_key.is_some()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -65,6 +76,9 @@ impl RecorderContext2 {
             .join(file)
             .to_string_lossy()
             .to_string();
+        if ctx.encrypt() {
+            self.filename = record_crypt::encrypted_path(&self.filename);
+        }
         Ok(())
     }
 }
@@ -111,6 +125,10 @@ impl DerefMut for Recorder {
 
 impl Recorder {
     pub fn new(ctx: RecorderContext) -> ResultType<Self> {
+        if let Some(key) = &ctx.encrypt_key {
+            // Refuse to record rather than silently writing plaintext.
+            record_crypt::decode_public_key(key)?;
+        }
         Ok(Self {
             inner: None,
             ctx,
@@ -165,7 +183,10 @@ impl Recorder {
             };
             // pts is None when new inner is created
             self.pts = None;
-            self.send_state(RecordState::NewFile(ctx2.filename.clone()));
+            // Encrypted recordings are announced once the ciphertext is ready.
+            if !self.ctx.encrypt() {
+                self.send_state(RecordState::NewFile(ctx2.filename.clone()));
+            }
         }
         Ok(())
     }
@@ -232,7 +253,9 @@ impl Recorder {
             }
             _ => bail!("unsupported frame type"),
         }
-        self.send_state(RecordState::NewFrame);
+        if !self.ctx.encrypt() {
+            self.send_state(RecordState::NewFrame);
+        }
         Ok(())
     }
 
@@ -270,9 +293,57 @@ impl Recorder {
     }
 }
 
+fn finish(ctx: &RecorderContext, filename: &str, removed: bool) {
+    let send = |state| {
+        ctx.tx.as_ref().map(|tx| tx.send(state));
+    };
+    if ctx.encrypt() {
+        // The muxer patches the beginning of the file when it finishes, so an encrypted
+        // recording is uploaded as a whole once it is complete.
+        if !removed {
+            send(RecordState::NewFile(filename.to_owned()));
+            send(RecordState::WriteTail);
+        }
+    } else if removed {
+        send(RecordState::RemoveFile);
+    } else {
+        send(RecordState::WriteTail);
+    }
+}
+
+enum RecordFile {
+    Plain(File),
+    Encrypted(EncryptWriter<File>),
+}
+
+impl Write for RecordFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            RecordFile::Plain(f) => f.write(buf),
+            RecordFile::Encrypted(f) => f.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            RecordFile::Plain(f) => f.flush(),
+            RecordFile::Encrypted(f) => f.flush(),
+        }
+    }
+}
+
+impl Seek for RecordFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            RecordFile::Plain(f) => f.seek(pos),
+            RecordFile::Encrypted(f) => f.seek(pos),
+        }
+    }
+}
+
 struct WebmRecorder {
     vt: VideoTrack,
-    webm: Option<Segment<Writer<File>>>,
+    webm: Option<Segment<Writer<RecordFile>>>,
     ctx: RecorderContext,
     ctx2: RecorderContext2,
     key: bool,
@@ -282,15 +353,24 @@ struct WebmRecorder {
 
 impl RecorderApi for WebmRecorder {
     fn new(ctx: RecorderContext, ctx2: RecorderContext2) -> ResultType<Self> {
-        let out = match {
-            OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(&ctx2.filename)
-        } {
-            Ok(file) => file,
-            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => File::create(&ctx2.filename)?,
-            Err(e) => return Err(e.into()),
+        let out = if let Some(key) = &ctx.encrypt_key {
+            let pk = record_crypt::decode_public_key(key)?;
+            RecordFile::Encrypted(EncryptWriter::create(&ctx2.filename, &pk)?)
+        } else {
+            RecordFile::Plain(
+                match {
+                    OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(&ctx2.filename)
+                } {
+                    Ok(file) => file,
+                    Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                        File::create(&ctx2.filename)?
+                    }
+                    Err(e) => return Err(e.into()),
+                },
+            )
         };
         let mut webm = match mux::Segment::new(mux::Writer::new(out)) {
             Some(v) => v,
@@ -347,12 +427,11 @@ impl RecorderApi for WebmRecorder {
 impl Drop for WebmRecorder {
     fn drop(&mut self) {
         let _ = std::mem::replace(&mut self.webm, None).map_or(false, |webm| webm.finalize(None));
-        let mut state = RecordState::WriteTail;
-        if !self.written || self.start.elapsed().as_secs() < MIN_SECS {
+        let remove = !self.written || self.start.elapsed().as_secs() < MIN_SECS;
+        if remove {
             std::fs::remove_file(&self.ctx2.filename).ok();
-            state = RecordState::RemoveFile;
         }
-        self.ctx.tx.as_ref().map(|tx| tx.send(state));
+        finish(&self.ctx, &self.ctx2.filename, remove);
     }
 }
 
@@ -369,6 +448,10 @@ struct HwRecorder {
 #[cfg(feature = "hwcodec")]
 impl RecorderApi for HwRecorder {
     fn new(ctx: RecorderContext, ctx2: RecorderContext2) -> ResultType<Self> {
+        if ctx.encrypt() {
+            // The hardware muxer writes the file itself, it cannot be encrypted as it is written.
+            bail!("encrypted recording is not supported with {}", ctx2.format);
+        }
         let muxer = Muxer::new(MuxContext {
             filename: ctx2.filename.clone(),
             width: ctx2.width,
@@ -411,13 +494,12 @@ impl RecorderApi for HwRecorder {
 impl Drop for HwRecorder {
     fn drop(&mut self) {
         self.muxer.as_mut().map(|m| m.write_tail().ok());
-        let mut state = RecordState::WriteTail;
-        if !self.written || self.start.elapsed().as_secs() < MIN_SECS {
-            // The process cannot access the file because it is being used by another process
-            self.muxer = None;
+        let remove = !self.written || self.start.elapsed().as_secs() < MIN_SECS;
+        // The process cannot access the file because it is being used by another process
+        self.muxer = None;
+        if remove {
             std::fs::remove_file(&self.ctx2.filename).ok();
-            state = RecordState::RemoveFile;
         }
-        self.ctx.tx.as_ref().map(|tx| tx.send(state));
+        finish(&self.ctx, &self.ctx2.filename, remove);
     }
 }
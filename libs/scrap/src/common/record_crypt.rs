@@ -0,0 +1,443 @@
+//! Encryption at rest for recordings.
+//!
+//! Every recording gets its own random secretbox key. The key is sealed with
+//! the operator's public key, so only the holder of the matching secret key can
+//! read the file. The recording is encrypted as it is written, in blocks of a
+//! fixed size, so no plaintext reaches the disk and the muxer can still seek
+//! back to patch what it has written. File layout:
+//!
+//! ```text
+//! MAGIC | u32 sealed key len | sealed key | { nonce | secretbox(u64 index | u32 len | block) }*
+//!     | nonce | secretbox(u64 block count | u32 TRAILER | u64 total len)
+//! ```
+//!
+//! A block is padded to `BLOCK_SIZE`, `len` is the part of it which is used. The trailer
+//! is written when the recording is finished, a file without it was cut off.
+
+use hbb_common::{
+    bail, log,
+    sodiumoxide::{
+        base64,
+        crypto::{box_, sealedbox, secretbox},
+    },
+    ResultType,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+const MAGIC: &[u8; 8] = b"RDREC\x00\x00\x01";
+const BLOCK_SIZE: usize = 64 * 1024;
+const BLOCK_HEADER_SIZE: usize = 8 + 4;
+const RECORD_SIZE: usize =
+    secretbox::NONCEBYTES + secretbox::MACBYTES + BLOCK_HEADER_SIZE + BLOCK_SIZE;
+// The `len` of the trailer record, which holds the total length instead of a block.
+const TRAILER: u32 = u32::MAX;
+pub const ENCRYPTED_EXT: &str = "enc";
+
+pub fn decode_public_key(key: &str) -> ResultType<box_::PublicKey> {
+    let Ok(bytes) = base64::decode(key.trim(), base64::Variant::Original) else {
+        bail!("invalid base64 public key");
+    };
+    let Some(pk) = box_::PublicKey::from_slice(&bytes) else {
+        bail!("invalid public key length");
+    };
+    Ok(pk)
+}
+
+pub fn decode_secret_key(key: &str) -> ResultType<box_::SecretKey> {
+    let Ok(bytes) = base64::decode(key.trim(), base64::Variant::Original) else {
+        bail!("invalid base64 secret key");
+    };
+    let Some(sk) = box_::SecretKey::from_slice(&bytes) else {
+        bail!("invalid secret key length");
+    };
+    Ok(sk)
+}
+
+/// Returns `(public_key, secret_key)` in base64, for `--gen-record-keypair`.
+pub fn gen_keypair() -> (String, String) {
+    let (pk, sk) = box_::gen_keypair();
+    (
+        base64::encode(pk, base64::Variant::Original),
+        base64::encode(sk, base64::Variant::Original),
+    )
+}
+
+pub fn encrypted_path(path: &str) -> String {
+    format!("{}.{}", path, ENCRYPTED_EXT)
+}
+
+/// Encrypts everything written to it, seeking is supported within what has been written.
+///
+/// One block is kept in memory, it is encrypted when the writer moves to another block,
+/// on flush and on drop. The trailer is written on drop.
+pub struct EncryptWriter<F: Read + Write + Seek> {
+    inner: F,
+    key: secretbox::Key,
+    header_len: u64,
+    block: Vec<u8>,
+    index: u64,
+    dirty: bool,
+    pos: u64,
+    len: u64,
+}
+
+impl EncryptWriter<File> {
+    pub fn create(path: &str, pk: &box_::PublicKey) -> ResultType<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Self::new(file, pk)
+    }
+}
+
+impl<F: Read + Write + Seek> EncryptWriter<F> {
+    pub fn new(mut inner: F, pk: &box_::PublicKey) -> ResultType<Self> {
+        let key = secretbox::gen_key();
+        let sealed = sealedbox::seal(key.as_ref(), pk);
+        inner.write_all(MAGIC)?;
+        inner.write_all(&(sealed.len() as u32).to_le_bytes())?;
+        inner.write_all(&sealed)?;
+        Ok(Self {
+            inner,
+            key,
+            header_len: (MAGIC.len() + 4 + sealed.len()) as _,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            index: 0,
+            dirty: false,
+            pos: 0,
+            len: 0,
+        })
+    }
+
+    fn record_offset(&self, index: u64) -> u64 {
+        self.header_len + index * RECORD_SIZE as u64
+    }
+
+    fn write_record(&mut self, index: u64, len: u32, data: &[u8]) -> io::Result<()> {
+        let mut plain = Vec::with_capacity(BLOCK_HEADER_SIZE + BLOCK_SIZE);
+        plain.extend_from_slice(&index.to_le_bytes());
+        plain.extend_from_slice(&len.to_le_bytes());
+        plain.extend_from_slice(data);
+        plain.resize(BLOCK_HEADER_SIZE + BLOCK_SIZE, 0);
+        // A rewritten block gets a new nonce.
+        let nonce = secretbox::gen_nonce();
+        let sealed = secretbox::seal(&plain, &nonce, &self.key);
+        self.inner
+            .seek(SeekFrom::Start(self.record_offset(index)))?;
+        self.inner.write_all(nonce.as_ref())?;
+        self.inner.write_all(&sealed)
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let block = std::mem::take(&mut self.block);
+        let res = self.write_record(self.index, block.len() as _, &block);
+        self.block = block;
+        res?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    // After the last block, which is the only record it can be at, so an older trailer is
+    // never left in the file.
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush_block()?;
+        let blocks = self.len.div_ceil(BLOCK_SIZE as u64);
+        self.write_record(blocks, TRAILER, &self.len.to_le_bytes())?;
+        self.inner.flush()
+    }
+
+    fn load_block(&mut self, index: u64) -> io::Result<()> {
+        if index == self.index {
+            return Ok(());
+        }
+        self.flush_block()?;
+        self.block.clear();
+        self.index = index;
+        if index * (BLOCK_SIZE as u64) < self.len {
+            self.inner
+                .seek(SeekFrom::Start(self.record_offset(index)))?;
+            let mut record = vec![0u8; RECORD_SIZE];
+            self.inner.read_exact(&mut record)?;
+            match open_record(&record, index, &self.key) {
+                Ok(Record::Block(block)) => self.block = block,
+                Ok(Record::Trailer(_)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected trailer in encrypted recording",
+                    ))
+                }
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<F: Read + Write + Seek> Write for EncryptWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.load_block(self.pos / BLOCK_SIZE as u64)?;
+        let offset = (self.pos % BLOCK_SIZE as u64) as usize;
+        let n = buf.len().min(BLOCK_SIZE - offset);
+        if self.block.len() < offset + n {
+            self.block.resize(offset + n, 0);
+        }
+        self.block[offset..offset + n].copy_from_slice(&buf[..n]);
+        self.dirty = true;
+        self.pos += n as u64;
+        self.len = self.len.max(self.pos);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_block()?;
+        self.inner.flush()
+    }
+}
+
+impl<F: Read + Write + Seek> Seek for EncryptWriter<F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.len.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        match pos {
+            // Seeking beyond the end would leave blocks which were never written.
+            Some(p) if p <= self.len => {
+                self.pos = p;
+                Ok(p)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek in encrypted recording",
+            )),
+        }
+    }
+}
+
+impl<F: Read + Write + Seek> Drop for EncryptWriter<F> {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            log::error!("failed to write encrypted recording: {}", e);
+        }
+    }
+}
+
+enum Record {
+    Block(Vec<u8>),
+    /// The total length of the recording.
+    Trailer(u64),
+}
+
+fn open_record(record: &[u8], index: u64, key: &secretbox::Key) -> ResultType<Record> {
+    let (nonce, sealed) = record.split_at(secretbox::NONCEBYTES);
+    let Some(nonce) = secretbox::Nonce::from_slice(nonce) else {
+        bail!("invalid nonce");
+    };
+    let Ok(plain) = secretbox::open(sealed, &nonce, key) else {
+        bail!("corrupted recording");
+    };
+    let mut stored = [0u8; 8];
+    stored.copy_from_slice(&plain[..8]);
+    if u64::from_le_bytes(stored) != index {
+        bail!("recording block {} out of order", index);
+    }
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&plain[8..BLOCK_HEADER_SIZE]);
+    let len = u32::from_le_bytes(len);
+    if len == TRAILER {
+        let mut total = [0u8; 8];
+        total.copy_from_slice(&plain[BLOCK_HEADER_SIZE..BLOCK_HEADER_SIZE + 8]);
+        return Ok(Record::Trailer(u64::from_le_bytes(total)));
+    }
+    let len = len as usize;
+    if len > BLOCK_SIZE {
+        bail!("invalid block length {}", len);
+    }
+    Ok(Record::Block(
+        plain[BLOCK_HEADER_SIZE..BLOCK_HEADER_SIZE + len].to_vec(),
+    ))
+}
+
+/// Fails if the recording was cut off, unless `allow_truncated`, then the blocks before
+/// the cut are written.
+pub fn decrypt<R: Read, W: Write>(
+    mut input: R,
+    output: &mut W,
+    pk: &box_::PublicKey,
+    sk: &box_::SecretKey,
+    allow_truncated: bool,
+) -> ResultType<()> {
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("not an encrypted recording");
+    }
+    let mut len = [0u8; 4];
+    input.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len != secretbox::KEYBYTES + sealedbox::SEALBYTES {
+        bail!("invalid sealed key length {}", len);
+    }
+    let mut sealed = vec![0u8; len];
+    input.read_exact(&mut sealed)?;
+    let Ok(key) = sealedbox::open(&sealed, pk, sk) else {
+        bail!("failed to open recording key, wrong key pair?");
+    };
+    let Some(key) = secretbox::Key::from_slice(&key) else {
+        bail!("invalid recording key");
+    };
+    let mut record = vec![0u8; RECORD_SIZE];
+    let mut index = 0;
+    let mut total = 0;
+    loop {
+        // No trailer: the recorder was killed, or the end of the file was removed.
+        if read_full(&mut input, &mut record)? < RECORD_SIZE {
+            if !allow_truncated {
+                bail!("recording truncated at block {}", index);
+            }
+            log::warn!("recording truncated at block {}", index);
+            break;
+        }
+        match open_record(&record, index, &key)? {
+            Record::Block(block) => {
+                total += block.len() as u64;
+                output.write_all(&block)?;
+            }
+            Record::Trailer(len) => {
+                if len != total {
+                    bail!("recording length {} instead of {}", total, len);
+                }
+                if read_full(&mut input, &mut record[..1])? != 0 {
+                    bail!("data after the end of the recording");
+                }
+                break;
+            }
+        }
+        index += 1;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+pub fn decrypt_file(
+    path: &str,
+    out: &str,
+    sk: &box_::SecretKey,
+    allow_truncated: bool,
+) -> ResultType<()> {
+    if Path::new(out).exists() {
+        bail!("{} already exists", out);
+    }
+    let pk = sk.public_key();
+    let input = BufReader::new(File::open(path)?);
+    let mut output = BufWriter::new(File::create(out)?);
+    let res = decrypt(input, &mut output, &pk, sk, allow_truncated);
+    if res.is_err() {
+        drop(output);
+        std::fs::remove_file(out).ok();
+    }
+    res
+}
+
+fn read_full<R: Read>(input: &mut R, buf: &mut [u8]) -> ResultType<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match input.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn encrypt(plain: &[u8], pk: &box_::PublicKey) -> Vec<u8> {
+        let mut cipher = Cursor::new(Vec::new());
+        let mut writer = EncryptWriter::new(&mut cipher, pk).unwrap();
+        writer.write_all(plain).unwrap();
+        drop(writer);
+        cipher.into_inner()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let (pk, sk) = box_::gen_keypair();
+        for len in [0, 1, BLOCK_SIZE, BLOCK_SIZE * 2 + 7] {
+            let plain: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let cipher = encrypt(&plain, &pk);
+            let mut out = Vec::new();
+            decrypt(&cipher[..], &mut out, &pk, &sk, false).unwrap();
+            assert_eq!(plain, out);
+        }
+    }
+
+    #[test]
+    fn test_seek() {
+        let (pk, sk) = box_::gen_keypair();
+        let mut plain: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| (i % 251) as u8).collect();
+        let mut cipher = Cursor::new(Vec::new());
+        let mut writer = EncryptWriter::new(&mut cipher, &pk).unwrap();
+        writer.write_all(&plain).unwrap();
+        // Patch a header like the muxer does on finalize, across a block boundary.
+        writer.seek(SeekFrom::Start(BLOCK_SIZE as u64 - 2)).unwrap();
+        writer.write_all(&[0xff; 4]).unwrap();
+        writer.seek(SeekFrom::End(0)).unwrap();
+        writer.write_all(&[1, 2, 3]).unwrap();
+        assert!(writer.seek(SeekFrom::Current(1)).is_err());
+        drop(writer);
+        plain[BLOCK_SIZE - 2..BLOCK_SIZE + 2].copy_from_slice(&[0xff; 4]);
+        plain.extend_from_slice(&[1, 2, 3]);
+        let mut out = Vec::new();
+        decrypt(&cipher.into_inner()[..], &mut out, &pk, &sk, false).unwrap();
+        assert_eq!(plain, out);
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let (pk, _) = box_::gen_keypair();
+        let (pk2, sk2) = box_::gen_keypair();
+        let cipher = encrypt(b"secret screen", &pk);
+        let mut out = Vec::new();
+        assert!(decrypt(&cipher[..], &mut out, &pk2, &sk2, false).is_err());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let (pk, sk) = box_::gen_keypair();
+        let plain: Vec<u8> = (0..BLOCK_SIZE * 2 + 7).map(|i| (i % 251) as u8).collect();
+        let cipher = encrypt(&plain, &pk);
+        // Three blocks and the trailer.
+        let header_len = cipher.len() - 4 * RECORD_SIZE;
+        for (blocks, len) in [(3, plain.len()), (2, BLOCK_SIZE * 2), (0, 0)] {
+            let cut = &cipher[..header_len + blocks * RECORD_SIZE];
+            let mut out = Vec::new();
+            assert!(decrypt(cut, &mut out, &pk, &sk, false).is_err());
+            let mut out = Vec::new();
+            decrypt(cut, &mut out, &pk, &sk, true).unwrap();
+            assert_eq!(out, plain[..len]);
+        }
+        // Killed while writing a block.
+        let cut = &cipher[..header_len + RECORD_SIZE + 10];
+        assert!(decrypt(cut, &mut Vec::new(), &pk, &sk, false).is_err());
+    }
+}
@@ -0,0 +1,634 @@
+// Pure-Rust conversion of BGRA/RGBA/RGB565LE to I420/NV12/I444.
+//
+// BT.601 limited range with the fixed point coefficients of libyuv:
+//   Y = (66 * R + 129 * G + 25 * B + 0x1080) >> 8
+//   U = (112 * B - 74 * G - 38 * R + 0x8080) >> 8
+//   V = (112 * R - 94 * G - 18 * B + 0x8080) >> 8
+// The chroma of I420 and NV12 is computed from the rounded average of 2x2 pixels.
+//
+// The scalar rows are the reference, the AVX2, SSE4.1 and NEON rows are selected at runtime and
+// give the same result. Used instead of libyuv with the feature `rust-yuv`, or for strides that
+// are not a multiple of the pixel size.
+
+use hbb_common::{bail, ResultType};
+
+use crate::{EncodeYuvFormat, Pixfmt};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    Bgra,
+    Rgba,
+}
+
+impl Order {
+    // Byte index of (r, b) in a pixel, g is always 1.
+    #[inline]
+    fn rb(self) -> (usize, usize) {
+        match self {
+            Order::Bgra => (2, 0),
+            Order::Rgba => (0, 2),
+        }
+    }
+}
+
+type YRow = fn(&[u8], Order, &mut [u8]);
+type UvRow = fn(&[u8], Order, &mut [u8], &mut [u8]);
+
+#[derive(Clone, Copy)]
+pub struct Kernels {
+    pub name: &'static str,
+    y_row: YRow,
+    uv_row: UvRow,
+}
+
+pub const SCALAR: Kernels = Kernels {
+    name: "scalar",
+    y_row: scalar::y_row,
+    uv_row: scalar::uv_row,
+};
+
+lazy_static::lazy_static! {
+    static ref KERNELS: Kernels = detect();
+}
+
+fn detect() -> Kernels {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return x86::AVX2;
+        }
+        if is_x86_feature_detected!("sse4.1") {
+            return x86::SSE41;
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return neon::NEON;
+        }
+    }
+    SCALAR
+}
+
+/// The fastest kernels of the cpu.
+pub fn kernels() -> Kernels {
+    *KERNELS
+}
+
+/// Whether to convert with this module instead of libyuv.
+pub fn is_preferred(src_pixfmt: Pixfmt, src_stride: usize) -> bool {
+    cfg!(feature = "rust-yuv") || src_stride % src_pixfmt.bytes_per_pixel() != 0
+}
+
+mod scalar {
+    use super::Order;
+
+    #[inline]
+    pub fn y(r: u8, g: u8, b: u8) -> u8 {
+        ((66 * r as u32 + 129 * g as u32 + 25 * b as u32 + 0x1080) >> 8) as u8
+    }
+
+    #[inline]
+    pub fn u(r: u8, g: u8, b: u8) -> u8 {
+        ((112 * b as i32 - 74 * g as i32 - 38 * r as i32 + 0x8080) >> 8) as u8
+    }
+
+    #[inline]
+    pub fn v(r: u8, g: u8, b: u8) -> u8 {
+        ((112 * r as i32 - 94 * g as i32 - 18 * b as i32 + 0x8080) >> 8) as u8
+    }
+
+    pub fn y_row(src: &[u8], order: Order, dst: &mut [u8]) {
+        let (ri, bi) = order.rb();
+        for (d, p) in dst.iter_mut().zip(src.chunks_exact(4)) {
+            *d = y(p[ri], p[1], p[bi]);
+        }
+    }
+
+    pub fn uv_row(src: &[u8], order: Order, dst_u: &mut [u8], dst_v: &mut [u8]) {
+        let (ri, bi) = order.rb();
+        for ((u_, v_), p) in dst_u
+            .iter_mut()
+            .zip(dst_v.iter_mut())
+            .zip(src.chunks_exact(4))
+        {
+            *u_ = u(p[ri], p[1], p[bi]);
+            *v_ = v(p[ri], p[1], p[bi]);
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    use super::{scalar, Kernels, Order};
+
+    pub const AVX2: Kernels = Kernels {
+        name: "avx2",
+        y_row: |src, order, dst| unsafe { y_row_avx2(src, order, dst) },
+        uv_row: |src, order, u, v| unsafe { uv_row_avx2(src, order, u, v) },
+    };
+
+    pub const SSE41: Kernels = Kernels {
+        name: "sse4.1",
+        y_row: |src, order, dst| unsafe { y_row_sse41(src, order, dst) },
+        uv_row: |src, order, u, v| unsafe { uv_row_sse41(src, order, u, v) },
+    };
+
+    // Shift counts of (r, b) in the little endian u32 of a pixel.
+    #[inline]
+    fn shifts(order: Order) -> (i32, i32) {
+        let (r, b) = order.rb();
+        (r as i32 * 8, b as i32 * 8)
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn channels_avx2(p: __m256i, order: Order) -> (__m256i, __m256i, __m256i) {
+        let (rs, bs) = shifts(order);
+        let mask = _mm256_set1_epi32(0xff);
+        let r = _mm256_and_si256(_mm256_srl_epi32(p, _mm_cvtsi32_si128(rs)), mask);
+        let g = _mm256_and_si256(_mm256_srli_epi32::<8>(p), mask);
+        let b = _mm256_and_si256(_mm256_srl_epi32(p, _mm_cvtsi32_si128(bs)), mask);
+        (r, g, b)
+    }
+
+    // 8 i32 values in 0..=255 to 8 bytes.
+    #[target_feature(enable = "avx2")]
+    unsafe fn store8_avx2(v: __m256i, dst: &mut [u8]) {
+        let t = _mm256_packus_epi32(v, v);
+        let t = _mm256_packus_epi16(t, t);
+        let lo = _mm256_extract_epi32::<0>(t) as u32;
+        let hi = _mm256_extract_epi32::<4>(t) as u32;
+        dst[..4].copy_from_slice(&lo.to_le_bytes());
+        dst[4..8].copy_from_slice(&hi.to_le_bytes());
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn dot_avx2(
+        r: __m256i,
+        g: __m256i,
+        b: __m256i,
+        k: (i32, i32, i32),
+        bias: i32,
+    ) -> __m256i {
+        let s = _mm256_add_epi32(
+            _mm256_mullo_epi32(r, _mm256_set1_epi32(k.0)),
+            _mm256_mullo_epi32(g, _mm256_set1_epi32(k.1)),
+        );
+        let s = _mm256_add_epi32(s, _mm256_mullo_epi32(b, _mm256_set1_epi32(k.2)));
+        _mm256_srai_epi32::<8>(_mm256_add_epi32(s, _mm256_set1_epi32(bias)))
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn y_row_avx2(src: &[u8], order: Order, dst: &mut [u8]) {
+        let n = dst.len().min(src.len() / 4) / 8 * 8;
+        for i in (0..n).step_by(8) {
+            let p = _mm256_loadu_si256(src[i * 4..i * 4 + 32].as_ptr() as *const __m256i);
+            let (r, g, b) = channels_avx2(p, order);
+            store8_avx2(dot_avx2(r, g, b, (66, 129, 25), 0x1080), &mut dst[i..]);
+        }
+        scalar::y_row(&src[n * 4..], order, &mut dst[n..]);
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn uv_row_avx2(src: &[u8], order: Order, dst_u: &mut [u8], dst_v: &mut [u8]) {
+        let n = dst_u.len().min(dst_v.len()).min(src.len() / 4) / 8 * 8;
+        for i in (0..n).step_by(8) {
+            let p = _mm256_loadu_si256(src[i * 4..i * 4 + 32].as_ptr() as *const __m256i);
+            let (r, g, b) = channels_avx2(p, order);
+            store8_avx2(dot_avx2(r, g, b, (-38, -74, 112), 0x8080), &mut dst_u[i..]);
+            store8_avx2(dot_avx2(r, g, b, (112, -94, -18), 0x8080), &mut dst_v[i..]);
+        }
+        scalar::uv_row(&src[n * 4..], order, &mut dst_u[n..], &mut dst_v[n..]);
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn channels_sse41(p: __m128i, order: Order) -> (__m128i, __m128i, __m128i) {
+        let (rs, bs) = shifts(order);
+        let mask = _mm_set1_epi32(0xff);
+        let r = _mm_and_si128(_mm_srl_epi32(p, _mm_cvtsi32_si128(rs)), mask);
+        let g = _mm_and_si128(_mm_srli_epi32::<8>(p), mask);
+        let b = _mm_and_si128(_mm_srl_epi32(p, _mm_cvtsi32_si128(bs)), mask);
+        (r, g, b)
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn store4_sse41(v: __m128i, dst: &mut [u8]) {
+        let t = _mm_packus_epi32(v, v);
+        let t = _mm_packus_epi16(t, t);
+        dst[..4].copy_from_slice(&(_mm_cvtsi128_si32(t) as u32).to_le_bytes());
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn dot_sse41(
+        r: __m128i,
+        g: __m128i,
+        b: __m128i,
+        k: (i32, i32, i32),
+        bias: i32,
+    ) -> __m128i {
+        let s = _mm_add_epi32(
+            _mm_mullo_epi32(r, _mm_set1_epi32(k.0)),
+            _mm_mullo_epi32(g, _mm_set1_epi32(k.1)),
+        );
+        let s = _mm_add_epi32(s, _mm_mullo_epi32(b, _mm_set1_epi32(k.2)));
+        _mm_srai_epi32::<8>(_mm_add_epi32(s, _mm_set1_epi32(bias)))
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn y_row_sse41(src: &[u8], order: Order, dst: &mut [u8]) {
+        let n = dst.len().min(src.len() / 4) / 4 * 4;
+        for i in (0..n).step_by(4) {
+            let p = _mm_loadu_si128(src[i * 4..i * 4 + 16].as_ptr() as *const __m128i);
+            let (r, g, b) = channels_sse41(p, order);
+            store4_sse41(dot_sse41(r, g, b, (66, 129, 25), 0x1080), &mut dst[i..]);
+        }
+        scalar::y_row(&src[n * 4..], order, &mut dst[n..]);
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn uv_row_sse41(src: &[u8], order: Order, dst_u: &mut [u8], dst_v: &mut [u8]) {
+        let n = dst_u.len().min(dst_v.len()).min(src.len() / 4) / 4 * 4;
+        for i in (0..n).step_by(4) {
+            let p = _mm_loadu_si128(src[i * 4..i * 4 + 16].as_ptr() as *const __m128i);
+            let (r, g, b) = channels_sse41(p, order);
+            store4_sse41(dot_sse41(r, g, b, (-38, -74, 112), 0x8080), &mut dst_u[i..]);
+            store4_sse41(dot_sse41(r, g, b, (112, -94, -18), 0x8080), &mut dst_v[i..]);
+        }
+        scalar::uv_row(&src[n * 4..], order, &mut dst_u[n..], &mut dst_v[n..]);
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    use super::{scalar, Kernels, Order};
+
+    pub const NEON: Kernels = Kernels {
+        name: "neon",
+        y_row: |src, order, dst| unsafe { y_row_neon(src, order, dst) },
+        uv_row: |src, order, u, v| unsafe { uv_row_neon(src, order, u, v) },
+    };
+
+    #[target_feature(enable = "neon")]
+    unsafe fn load8(src: &[u8], order: Order) -> (uint8x8_t, uint8x8_t, uint8x8_t) {
+        let p = vld4_u8(src[..32].as_ptr());
+        match order {
+            Order::Bgra => (p.2, p.1, p.0),
+            Order::Rgba => (p.0, p.1, p.2),
+        }
+    }
+
+    // All the sums stay in 0..=65535, the u16 arithmetic is exact.
+    #[target_feature(enable = "neon")]
+    unsafe fn y_row_neon(src: &[u8], order: Order, dst: &mut [u8]) {
+        let n = dst.len().min(src.len() / 4) / 8 * 8;
+        for i in (0..n).step_by(8) {
+            let (r, g, b) = load8(&src[i * 4..], order);
+            let mut y = vmull_u8(r, vdup_n_u8(66));
+            y = vmlal_u8(y, g, vdup_n_u8(129));
+            y = vmlal_u8(y, b, vdup_n_u8(25));
+            y = vaddq_u16(y, vdupq_n_u16(0x1080));
+            vst1_u8(dst[i..i + 8].as_mut_ptr(), vshrn_n_u16::<8>(y));
+        }
+        scalar::y_row(&src[n * 4..], order, &mut dst[n..]);
+    }
+
+    #[target_feature(enable = "neon")]
+    unsafe fn uv_row_neon(src: &[u8], order: Order, dst_u: &mut [u8], dst_v: &mut [u8]) {
+        let n = dst_u.len().min(dst_v.len()).min(src.len() / 4) / 8 * 8;
+        let bias = vdupq_n_u16(0x8080);
+        for i in (0..n).step_by(8) {
+            let (r, g, b) = load8(&src[i * 4..], order);
+            let mut u = vmlal_u8(bias, b, vdup_n_u8(112));
+            u = vmlsl_u8(u, g, vdup_n_u8(74));
+            u = vmlsl_u8(u, r, vdup_n_u8(38));
+            vst1_u8(dst_u[i..i + 8].as_mut_ptr(), vshrn_n_u16::<8>(u));
+            let mut v = vmlal_u8(bias, r, vdup_n_u8(112));
+            v = vmlsl_u8(v, g, vdup_n_u8(94));
+            v = vmlsl_u8(v, b, vdup_n_u8(18));
+            vst1_u8(dst_v[i..i + 8].as_mut_ptr(), vshrn_n_u16::<8>(v));
+        }
+        scalar::uv_row(&src[n * 4..], order, &mut dst_u[n..], &mut dst_v[n..]);
+    }
+}
+
+// Row `y` of the source as 4 bytes per pixel, RGB565LE is expanded to BGRA.
+fn source_row<'a>(
+    src: &'a [u8],
+    stride: usize,
+    pixfmt: Pixfmt,
+    w: usize,
+    y: usize,
+    buf: &'a mut Vec<u8>,
+) -> &'a [u8] {
+    let start = y * stride;
+    match pixfmt {
+        Pixfmt::RGB565LE => {
+            buf.resize(w * 4, 0);
+            for (d, s) in buf
+                .chunks_exact_mut(4)
+                .zip(src[start..start + w * 2].chunks_exact(2))
+            {
+                let p = u16::from_le_bytes([s[0], s[1]]);
+                let b = (p & 0x1f) as u8;
+                let g = ((p >> 5) & 0x3f) as u8;
+                let r = (p >> 11) as u8;
+                d[0] = b << 3 | b >> 2;
+                d[1] = g << 2 | g >> 4;
+                d[2] = r << 3 | r >> 2;
+                d[3] = 0xff;
+            }
+            buf
+        }
+        _ => &src[start..start + w * 4],
+    }
+}
+
+// The rounded average of 2x2 pixels, the last column and row are repeated if odd.
+fn average_2x2(row0: &[u8], row1: &[u8], w: usize, dst: &mut Vec<u8>) {
+    let cw = (w + 1) / 2;
+    dst.resize(cw * 4, 0);
+    for x in 0..cw {
+        let x0 = x * 2 * 4;
+        let x1 = (x * 2 + 1).min(w - 1) * 4;
+        for c in 0..4 {
+            let sum = row0[x0 + c] as u16
+                + row0[x1 + c] as u16
+                + row1[x0 + c] as u16
+                + row1[x1 + c] as u16;
+            dst[x * 4 + c] = ((sum + 2) >> 2) as u8;
+        }
+    }
+}
+
+fn check_len(dst: &[u8], offset: usize, stride: usize, rows: usize, len: usize) -> ResultType<()> {
+    if rows > 0 && offset + stride * (rows - 1) + len > dst.len() {
+        bail!(
+            "dst too small: {} < {}",
+            dst.len(),
+            offset + stride * (rows - 1) + len
+        );
+    }
+    Ok(())
+}
+
+// `a` and `b` rows at the offsets, `a` must be before `b`.
+fn split_rows(dst: &mut [u8], a: usize, b: usize, len: usize) -> (&mut [u8], &mut [u8]) {
+    let (head, tail) = dst.split_at_mut(b);
+    (&mut head[a..a + len], &mut tail[..len])
+}
+
+/// Convert to `dst_fmt`, `dst` must be large enough for the planes of `dst_fmt`.
+pub fn convert_with(
+    kernels: Kernels,
+    src: &[u8],
+    src_stride: usize,
+    src_pixfmt: Pixfmt,
+    w: usize,
+    h: usize,
+    dst_fmt: &EncodeYuvFormat,
+    dst: &mut [u8],
+) -> ResultType<()> {
+    let order = match src_pixfmt {
+        Pixfmt::BGRA | Pixfmt::RGB565LE => Order::Bgra,
+        Pixfmt::RGBA => Order::Rgba,
+        _ => bail!("unsupported src pixfmt {:?}", src_pixfmt),
+    };
+    if w == 0 || h == 0 {
+        return Ok(());
+    }
+    let bpp = src_pixfmt.bytes_per_pixel();
+    if src_stride < w * bpp || src.len() < src_stride * (h - 1) + w * bpp {
+        bail!(
+            "src too small: {}, stride {}, {}x{}",
+            src.len(),
+            src_stride,
+            w,
+            h
+        );
+    }
+    if dst_fmt.stride.len() < 2 || dst_fmt.stride[0] < w {
+        bail!("invalid dst stride {:?}", dst_fmt.stride);
+    }
+    let stride_y = dst_fmt.stride[0];
+    check_len(dst, 0, stride_y, h, w)?;
+    let (mut buf0, mut buf1, mut avg) = (Vec::new(), Vec::new(), Vec::new());
+
+    match dst_fmt.pixfmt {
+        Pixfmt::I444 => {
+            let stride_u = dst_fmt.stride[1];
+            let stride_v = *dst_fmt.stride.get(2).unwrap_or(&stride_u);
+            if dst_fmt.u + stride_u * (h - 1) + w > dst_fmt.v || stride_u < w || stride_v < w {
+                bail!("invalid dst planes");
+            }
+            check_len(dst, dst_fmt.v, stride_v, h, w)?;
+            for y in 0..h {
+                let row = source_row(src, src_stride, src_pixfmt, w, y, &mut buf0);
+                (kernels.y_row)(row, order, &mut dst[y * stride_y..y * stride_y + w]);
+                let (u, v) = split_rows(dst, dst_fmt.u + y * stride_u, dst_fmt.v + y * stride_v, w);
+                (kernels.uv_row)(row, order, u, v);
+            }
+        }
+        Pixfmt::I420 | Pixfmt::NV12 => {
+            let (cw, ch) = ((w + 1) / 2, (h + 1) / 2);
+            let stride_uv = dst_fmt.stride[1];
+            let nv12 = dst_fmt.pixfmt == Pixfmt::NV12;
+            let (mut tmp_u, mut tmp_v) = (vec![0u8; cw], vec![0u8; cw]);
+            if nv12 {
+                if stride_uv < cw * 2 || dst_fmt.u < stride_y * (h - 1) + w {
+                    bail!("invalid dst planes");
+                }
+                check_len(dst, dst_fmt.u, stride_uv, ch, cw * 2)?;
+            } else {
+                if stride_uv < cw || dst_fmt.u + stride_uv * (ch - 1) + cw > dst_fmt.v {
+                    bail!("invalid dst planes");
+                }
+                check_len(dst, dst_fmt.v, stride_uv, ch, cw)?;
+            }
+            for cy in 0..ch {
+                let (y0, y1) = (cy * 2, (cy * 2 + 1).min(h - 1));
+                let row0 = source_row(src, src_stride, src_pixfmt, w, y0, &mut buf0);
+                let row1 = source_row(src, src_stride, src_pixfmt, w, y1, &mut buf1);
+                (kernels.y_row)(row0, order, &mut dst[y0 * stride_y..y0 * stride_y + w]);
+                if y1 != y0 {
+                    (kernels.y_row)(row1, order, &mut dst[y1 * stride_y..y1 * stride_y + w]);
+                }
+                average_2x2(row0, row1, w, &mut avg);
+                if nv12 {
+                    (kernels.uv_row)(&avg, order, &mut tmp_u, &mut tmp_v);
+                    let start = dst_fmt.u + cy * stride_uv;
+                    for (i, d) in dst[start..start + cw * 2].chunks_exact_mut(2).enumerate() {
+                        d[0] = tmp_u[i];
+                        d[1] = tmp_v[i];
+                    }
+                } else {
+                    let (u, v) = split_rows(
+                        dst,
+                        dst_fmt.u + cy * stride_uv,
+                        dst_fmt.v + cy * stride_uv,
+                        cw,
+                    );
+                    (kernels.uv_row)(&avg, order, u, v);
+                }
+            }
+        }
+        _ => bail!("unsupported dst pixfmt {:?}", dst_fmt.pixfmt),
+    }
+    Ok(())
+}
+
+/// Convert with the fastest kernels of the cpu.
+pub fn convert(
+    src: &[u8],
+    src_stride: usize,
+    src_pixfmt: Pixfmt,
+    w: usize,
+    h: usize,
+    dst_fmt: &EncodeYuvFormat,
+    dst: &mut [u8],
+) -> ResultType<()> {
+    convert_with(kernels(), src, src_stride, src_pixfmt, w, h, dst_fmt, dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift, the tests must be reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn range(&mut self, lo: usize, hi: usize) -> usize {
+            lo + (self.next() % (hi - lo + 1) as u64) as usize
+        }
+
+        fn bytes(&mut self, n: usize) -> Vec<u8> {
+            (0..n).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    fn yuv_format(pixfmt: Pixfmt, w: usize, h: usize, pad: usize) -> EncodeYuvFormat {
+        let (cw, ch) = ((w + 1) / 2, (h + 1) / 2);
+        let stride_y = w + pad;
+        let (stride, u, v) = match pixfmt {
+            Pixfmt::I444 => (vec![stride_y; 3], stride_y * h, stride_y * h * 2),
+            Pixfmt::NV12 => {
+                let s = cw * 2 + pad;
+                (vec![stride_y, s], stride_y * h, stride_y * h)
+            }
+            _ => {
+                let s = cw + pad;
+                (vec![stride_y, s, s], stride_y * h, stride_y * h + s * ch)
+            }
+        };
+        EncodeYuvFormat {
+            pixfmt,
+            w,
+            h,
+            stride,
+            u,
+            v,
+        }
+    }
+
+    fn all_kernels() -> Vec<Kernels> {
+        #[allow(unused_mut)]
+        let mut v = vec![SCALAR];
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if is_x86_feature_detected!("avx2") {
+                v.push(x86::AVX2);
+            }
+            if is_x86_feature_detected!("sse4.1") {
+                v.push(x86::SSE41);
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            if std::arch::is_aarch64_feature_detected!("neon") {
+                v.push(neon::NEON);
+            }
+        }
+        v
+    }
+
+    #[test]
+    fn test_reference_values() {
+        assert_eq!(scalar::y(255, 255, 255), 235);
+        assert_eq!(scalar::y(0, 0, 0), 16);
+        assert_eq!(
+            (scalar::u(255, 255, 255), scalar::v(255, 255, 255)),
+            (128, 128)
+        );
+        assert_eq!((scalar::u(0, 0, 255), scalar::v(255, 0, 0)), (240, 240));
+        let fmt = yuv_format(Pixfmt::I420, 2, 2, 0);
+        let mut dst = vec![0; 6];
+        // 2x2 red BGRA
+        let src = [0, 0, 255, 255].repeat(4);
+        convert_with(SCALAR, &src, 8, Pixfmt::BGRA, 2, 2, &fmt, &mut dst).unwrap();
+        assert_eq!(dst, vec![82, 82, 82, 82, 90, 240]);
+    }
+
+    // Every kernel gives the same result as the scalar reference, for random sizes, strides
+    // and pixels of every source and destination pixfmt.
+    #[test]
+    fn test_kernels_match_scalar() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let kernels = all_kernels();
+        for _ in 0..200 {
+            let src_pixfmt = [Pixfmt::BGRA, Pixfmt::RGBA, Pixfmt::RGB565LE][rng.range(0, 2)];
+            let dst_pixfmt = [Pixfmt::I420, Pixfmt::NV12, Pixfmt::I444][rng.range(0, 2)];
+            let (w, h) = (rng.range(1, 67), rng.range(1, 9));
+            let bpp = src_pixfmt.bytes_per_pixel();
+            // Odd strides included.
+            let src_stride = w * bpp + rng.range(0, 7);
+            let src = rng.bytes(src_stride * h);
+            let fmt = yuv_format(dst_pixfmt, w, h, rng.range(0, 5));
+            let len = fmt.v + fmt.stride.last().unwrap() * h + w * 2;
+            let mut expected = vec![0; len];
+            convert_with(
+                SCALAR,
+                &src,
+                src_stride,
+                src_pixfmt,
+                w,
+                h,
+                &fmt,
+                &mut expected,
+            )
+            .unwrap();
+            for k in &kernels {
+                let mut dst = vec![0; len];
+                convert_with(*k, &src, src_stride, src_pixfmt, w, h, &fmt, &mut dst).unwrap();
+                assert_eq!(
+                    dst, expected,
+                    "{} {:?} -> {:?} {}x{} stride {}",
+                    k.name, src_pixfmt, dst_pixfmt, w, h, src_stride
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        let fmt = yuv_format(Pixfmt::I420, 4, 4, 0);
+        let mut dst = vec![0; 8];
+        assert!(convert(&[0; 64], 16, Pixfmt::BGRA, 4, 4, &fmt, &mut dst).is_err());
+        let mut dst = vec![0; 24];
+        assert!(convert(&[0; 60], 16, Pixfmt::BGRA, 4, 4, &fmt, &mut dst).is_err());
+        assert!(convert(&[0; 64], 16, Pixfmt::I420, 4, 4, &fmt, &mut dst).is_err());
+    }
+}
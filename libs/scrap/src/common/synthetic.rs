@@ -0,0 +1,430 @@
+// Synthetic capturer, a virtual display with scripted content.
+//
+// It needs no display server, GPU or device, so video_service, the encoders, QoS and the
+// client decode paths can be tested on headless CI.
+//
+// Enabled by the env `RUSTDESK_SYNTHETIC_DISPLAY` or the option `synthetic-display`, not on
+// macOS:
+//   <content>[;size=<w>x<h>][;fps=<fps>][;pixfmt=bgra|rgba]
+// content:
+//   boxes         moving boxes on a static background
+//   text          scrolling text-like lines
+//   png:<dir>     replay the png files of the dir in name order, looped
+
+use std::{
+    io,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use hbb_common::{
+    config::Config,
+    message_proto::{DisplayInfo, Resolution},
+};
+
+use crate::common::{bail, ResultType};
+#[cfg(feature = "vram")]
+use crate::AdapterDevice;
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+use crate::PixelBuffer;
+use crate::{Frame, Pixfmt, TraitCapturer};
+
+pub const ENV_SYNTHETIC_DISPLAY: &str = "RUSTDESK_SYNTHETIC_DISPLAY";
+pub const OPTION_SYNTHETIC_DISPLAY: &str = "synthetic-display";
+pub const SYNTHETIC_DISPLAY_NAME: &str = "Synthetic";
+
+const DEFAULT_WIDTH: usize = 1280;
+const DEFAULT_HEIGHT: usize = 720;
+const DEFAULT_FPS: u32 = 30;
+const BOX_COUNT: usize = 6;
+const BOX_SIZE: usize = 96;
+const GLYPH_W: usize = 8;
+const GLYPH_H: usize = 16;
+const SCROLL_SPEED: usize = 2; // pixels per frame
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Content {
+    Boxes,
+    Text,
+    Png(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticConfig {
+    pub content: Content,
+    pub width: usize,
+    pub height: usize,
+    pub fps: u32,
+    pub pixfmt: Pixfmt,
+}
+
+impl SyntheticConfig {
+    pub fn parse(spec: &str) -> ResultType<Self> {
+        let mut parts = spec.split(';').map(|s| s.trim());
+        let content = match parts.next().unwrap_or_default() {
+            "boxes" => Content::Boxes,
+            "text" => Content::Text,
+            s if s.starts_with("png:") => Content::Png(PathBuf::from(&s[4..])),
+            s => bail!("unknown synthetic content: {s}"),
+        };
+        let mut config = Self {
+            content,
+            width: DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+            fps: DEFAULT_FPS,
+            pixfmt: Pixfmt::BGRA,
+        };
+        for part in parts.filter(|p| !p.is_empty()) {
+            let Some((key, value)) = part.split_once('=') else {
+                bail!("invalid synthetic display option: {part}");
+            };
+            match key {
+                "size" => {
+                    let Some((w, h)) = value.split_once('x') else {
+                        bail!("invalid synthetic display size: {value}");
+                    };
+                    config.width = w.parse()?;
+                    config.height = h.parse()?;
+                }
+                "fps" => config.fps = value.parse()?,
+                "pixfmt" => {
+                    config.pixfmt = match value {
+                        "bgra" => Pixfmt::BGRA,
+                        "rgba" => Pixfmt::RGBA,
+                        _ => bail!("unsupported synthetic pixfmt: {value}"),
+                    }
+                }
+                _ => bail!("unknown synthetic display option: {key}"),
+            }
+        }
+        // Encoders require even sizes.
+        config.width &= !1;
+        config.height &= !1;
+        if config.width == 0 || config.height == 0 || config.fps == 0 {
+            bail!("invalid synthetic display: {spec}");
+        }
+        Ok(config)
+    }
+}
+
+// The synthetic display config if enabled, the env takes precedence over the option.
+pub fn config() -> Option<SyntheticConfig> {
+    if cfg!(target_os = "macos") {
+        return None;
+    }
+    let spec = std::env::var(ENV_SYNTHETIC_DISPLAY)
+        .ok()
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| Config::get_option(OPTION_SYNTHETIC_DISPLAY));
+    if spec.is_empty() {
+        return None;
+    }
+    match SyntheticConfig::parse(&spec) {
+        Ok(config) => Some(config),
+        Err(e) => {
+            hbb_common::log::error!("invalid synthetic display '{spec}': {e}");
+            None
+        }
+    }
+}
+
+pub fn display_info(config: &SyntheticConfig) -> DisplayInfo {
+    let (width, height) = (config.width as i32, config.height as i32);
+    DisplayInfo {
+        x: 0,
+        y: 0,
+        name: SYNTHETIC_DISPLAY_NAME.to_owned(),
+        width,
+        height,
+        online: true,
+        cursor_embedded: false,
+        scale: 1.0,
+        original_resolution: Some(Resolution {
+            width,
+            height,
+            ..Default::default()
+        })
+        .into(),
+        ..Default::default()
+    }
+}
+
+pub struct SyntheticCapturer {
+    config: SyntheticConfig,
+    pngs: Vec<Vec<u8>>, // decoded png frames
+    data: Vec<u8>,
+    index: usize,
+    last: Option<Instant>,
+}
+
+impl SyntheticCapturer {
+    pub fn new(config: SyntheticConfig) -> ResultType<Self> {
+        let pngs = match &config.content {
+            Content::Png(dir) => load_pngs(dir, config.width, config.height)?,
+            _ => vec![],
+        };
+        Ok(Self {
+            config,
+            pngs,
+            data: Vec::new(),
+            index: 0,
+            last: None,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.config.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.config.height
+    }
+
+    // Render the next frame into `data`, the content only depends on the frame index.
+    pub fn render(&mut self) {
+        let (w, h) = (self.config.width, self.config.height);
+        self.data.resize(w * h * 4, 0);
+        match &self.config.content {
+            Content::Boxes => render_boxes(&mut self.data, w, h, self.index),
+            Content::Text => render_text(&mut self.data, w, h, self.index),
+            Content::Png(_) => {
+                if !self.pngs.is_empty() {
+                    let png = &self.pngs[self.index % self.pngs.len()];
+                    self.data.copy_from_slice(png);
+                }
+            }
+        }
+        if self.config.pixfmt == Pixfmt::RGBA {
+            self.data.chunks_exact_mut(4).for_each(|p| p.swap(0, 2));
+        }
+        self.index += 1;
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn wait_next(&mut self, timeout: Duration) -> io::Result<()> {
+        let spf = Duration::from_secs_f32(1.0 / self.config.fps as f32);
+        if let Some(last) = self.last {
+            let elapsed = last.elapsed();
+            if elapsed < spf {
+                let wait = spf - elapsed;
+                if wait > timeout {
+                    std::thread::sleep(timeout);
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                std::thread::sleep(wait);
+            }
+        }
+        self.last = Some(Instant::now());
+        Ok(())
+    }
+}
+
+impl TraitCapturer for SyntheticCapturer {
+    fn frame<'a>(&'a mut self, timeout: Duration) -> io::Result<Frame<'a>> {
+        self.wait_next(timeout)?;
+        self.render();
+        cfg_if::cfg_if! {
+            if #[cfg(any(target_os = "windows", target_os = "linux"))] {
+                Ok(Frame::PixelBuffer(PixelBuffer::new(
+                    &self.data,
+                    self.config.pixfmt,
+                    self.config.width,
+                    self.config.height,
+                )))
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Synthetic display is not supported on this platform yet",
+                ))
+            }
+        }
+    }
+
+    #[cfg(windows)]
+    fn is_gdi(&self) -> bool {
+        false
+    }
+
+    #[cfg(windows)]
+    fn set_gdi(&mut self) -> bool {
+        false
+    }
+
+    #[cfg(feature = "vram")]
+    fn device(&self) -> AdapterDevice {
+        AdapterDevice::default()
+    }
+
+    #[cfg(feature = "vram")]
+    fn set_output_texture(&mut self, _texture: bool) {}
+}
+
+#[inline]
+fn put(data: &mut [u8], w: usize, x: usize, y: usize, bgra: [u8; 4]) {
+    let offset = (y * w + x) * 4;
+    data[offset..offset + 4].copy_from_slice(&bgra);
+}
+
+fn render_boxes(data: &mut [u8], w: usize, h: usize, index: usize) {
+    for y in 0..h {
+        for x in 0..w {
+            put(
+                data,
+                w,
+                x,
+                y,
+                [(x * 255 / w) as u8, (y * 255 / h) as u8, 64, 255],
+            );
+        }
+    }
+    let size = BOX_SIZE.min(w / 2).min(h / 2).max(1);
+    for i in 0..BOX_COUNT {
+        // Bounce between the edges with a different speed per box.
+        let bounce = |pos: usize, range: usize| {
+            if range == 0 {
+                return 0;
+            }
+            let p = pos % (2 * range);
+            if p < range {
+                p
+            } else {
+                2 * range - p
+            }
+        };
+        let bx = bounce(index * (3 + i * 2) + i * 131, w - size);
+        let by = bounce(index * (2 + i) + i * 71, h - size);
+        let color = [
+            (i * 40) as u8,
+            (255 - i * 30) as u8,
+            (i * 90 % 256) as u8,
+            255,
+        ];
+        for y in by..by + size {
+            for x in bx..bx + size {
+                put(data, w, x, y, color);
+            }
+        }
+    }
+}
+
+fn render_text(data: &mut [u8], w: usize, h: usize, index: usize) {
+    const BACKGROUND: [u8; 4] = [30, 30, 30, 255];
+    const FOREGROUND: [u8; 4] = [220, 220, 220, 255];
+    let scroll = index * SCROLL_SPEED;
+    for y in 0..h {
+        let line = (y + scroll) / GLYPH_H;
+        let gy = (y + scroll) % GLYPH_H;
+        // Lines of different lengths with some indent, like source code.
+        let indent = (line * 7 % 5) * 4;
+        let len = 20 + line * 37 % 60;
+        for x in 0..w {
+            let col = x / GLYPH_W;
+            let gx = x % GLYPH_W;
+            let ink = col >= indent
+                && col < indent + len
+                && gy >= 3
+                && gy < GLYPH_H - 3
+                && gx < GLYPH_W - 2
+                && glyph_bit(line * 131 + col, gx, gy - 3);
+            put(data, w, x, y, if ink { FOREGROUND } else { BACKGROUND });
+        }
+    }
+}
+
+// A pseudo glyph of 6x10 pixels, a few characters are spaces.
+fn glyph_bit(seed: usize, gx: usize, gy: usize) -> bool {
+    let hash = (seed as u64 ^ 0x9e37_79b9_7f4a_7c15).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let hash = hash ^ (hash >> 31);
+    if hash % 7 == 0 {
+        return false;
+    }
+    let bit = (gy / 2) * 3 + gx / 2; // 3x5 cells
+    (hash >> (bit + 3)) & 1 == 1
+}
+
+fn load_pngs(dir: &PathBuf, width: usize, height: usize) -> ResultType<Vec<Vec<u8>>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.extension()
+                .map_or(false, |e| e.eq_ignore_ascii_case("png"))
+        })
+        .collect();
+    paths.sort();
+    if paths.is_empty() {
+        bail!("no png files in {}", dir.display());
+    }
+    paths.iter().map(|p| load_png(p, width, height)).collect()
+}
+
+// Decode a png to BGRA of the display size, cropped or padded at the right and bottom.
+fn load_png(path: &PathBuf, width: usize, height: usize) -> ResultType<Vec<u8>> {
+    let decoder = png::Decoder::new(std::fs::File::open(path)?);
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    let channels = match (info.color_type, info.bit_depth) {
+        (png::ColorType::Rgba, png::BitDepth::Eight) => 4,
+        (png::ColorType::Rgb, png::BitDepth::Eight) => 3,
+        (color_type, bit_depth) => {
+            bail!(
+                "unsupported png {}: {color_type:?} {bit_depth:?}",
+                path.display()
+            )
+        }
+    };
+    let (pw, ph) = (info.width as usize, info.height as usize);
+    let mut data = vec![0u8; width * height * 4];
+    for y in 0..height.min(ph) {
+        for x in 0..width.min(pw) {
+            let src = y * info.line_size + x * channels;
+            let alpha = if channels == 4 { buf[src + 3] } else { 255 };
+            put(
+                &mut data,
+                width,
+                x,
+                y,
+                [buf[src + 2], buf[src + 1], buf[src], alpha],
+            );
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = SyntheticConfig::parse("text;size=641x480;fps=10;pixfmt=rgba").unwrap();
+        assert_eq!(config.content, Content::Text);
+        assert_eq!((config.width, config.height, config.fps), (640, 480, 10));
+        assert_eq!(config.pixfmt, Pixfmt::RGBA);
+        assert_eq!(
+            SyntheticConfig::parse("png:/tmp/frames").unwrap().content,
+            Content::Png(PathBuf::from("/tmp/frames"))
+        );
+        assert!(SyntheticConfig::parse("noise").is_err());
+        assert!(SyntheticConfig::parse("boxes;size=0x0").is_err());
+        assert!(SyntheticConfig::parse("boxes;size=1x1").is_err());
+    }
+
+    #[test]
+    fn test_deterministic_and_moving() {
+        for content in ["boxes;size=320x240", "text;size=320x240"] {
+            let config = SyntheticConfig::parse(content).unwrap();
+            let mut a = SyntheticCapturer::new(config.clone()).unwrap();
+            let mut b = SyntheticCapturer::new(config).unwrap();
+            a.render();
+            b.render();
+            assert_eq!(a.data(), b.data());
+            let first = a.data().to_vec();
+            a.render();
+            assert_ne!(a.data(), &first[..], "{content}");
+        }
+    }
+}
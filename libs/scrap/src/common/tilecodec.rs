@@ -0,0 +1,325 @@
+// Lossless tile codec for screen content, like code editors and terminals.
+//
+// Only the changed tiles of a frame are sent, so static text stays sharp at any bitrate.
+// A tile with few colors is sent as a palette and indices, other tiles as raw BGRA.
+// The payload of a frame is compressed with zstd.
+//
+// payload:
+//   u32 width | u32 height | u32 tile size | u32 tile count | tile*
+// tile:
+//   u32 col | u32 row | u8 kind | data
+//   KIND_RAW: w * h * 4 bytes BGRA
+//   KIND_PALETTE: u8 (colors - 1) | colors * 4 bytes BGRA | w * h bytes indices
+
+use crate::{dirty::TileMap, ImageFormat, ImageRgb, Pixfmt};
+use hbb_common::{
+    bail,
+    bytes::Bytes,
+    compress::{compress, decompress},
+    message_proto::{EncodedVideoFrame, EncodedVideoFrames},
+    ResultType,
+};
+
+const KIND_RAW: u8 = 0;
+const KIND_PALETTE: u8 = 1;
+const HEADER_SIZE: usize = 16;
+const MAX_PALETTE_COLORS: usize = 256;
+// Tiles with few colors are typical for text and UI.
+const SCREEN_CONTENT_COLORS: usize = 64;
+const SCREEN_CONTENT_TILES: f32 = 0.75;
+
+pub struct TileEncoder {
+    width: usize,
+    height: usize,
+}
+
+impl TileEncoder {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self { width, height }
+    }
+
+    // Encode the dirty tiles of a BGRA or RGBA frame, all tiles if `key`.
+    pub fn encode(
+        &mut self,
+        data: &[u8],
+        stride: usize,
+        pixfmt: Pixfmt,
+        tiles: &TileMap,
+        key: bool,
+        ms: i64,
+    ) -> ResultType<EncodedVideoFrames> {
+        if pixfmt != Pixfmt::BGRA && pixfmt != Pixfmt::RGBA {
+            bail!("unsupported pixfmt: {:?}", pixfmt);
+        }
+        if tiles.width != self.width || tiles.height != self.height {
+            bail!("tile map size mismatch");
+        }
+        if data.len() < stride * self.height || stride < self.width * 4 {
+            bail!("frame data not enough");
+        }
+        let mut buf = Vec::with_capacity(HEADER_SIZE);
+        let mut count = 0u32;
+        buf.extend((self.width as u32).to_le_bytes());
+        buf.extend((self.height as u32).to_le_bytes());
+        buf.extend((tiles.tile as u32).to_le_bytes());
+        buf.extend(count.to_le_bytes());
+        let mut bgra = Vec::new();
+        for row in 0..tiles.rows {
+            for col in 0..tiles.cols {
+                if !key && !tiles.is_dirty(col, row) {
+                    continue;
+                }
+                let r = tiles.tile_rect(col, row);
+                bgra.clear();
+                for y in r.y..r.y + r.h {
+                    let offset = y * stride + r.x * 4;
+                    bgra.extend_from_slice(&data[offset..offset + r.w * 4]);
+                }
+                if pixfmt == Pixfmt::RGBA {
+                    bgra.chunks_exact_mut(4).for_each(|p| p.swap(0, 2));
+                }
+                buf.extend((col as u32).to_le_bytes());
+                buf.extend((row as u32).to_le_bytes());
+                write_tile(&mut buf, &bgra);
+                count += 1;
+            }
+        }
+        buf[12..HEADER_SIZE].copy_from_slice(&count.to_le_bytes());
+        let frame = EncodedVideoFrame {
+            data: Bytes::from(compress(&buf)),
+            key,
+            pts: ms,
+            ..Default::default()
+        };
+        Ok(EncodedVideoFrames {
+            frames: vec![frame].into(),
+            ..Default::default()
+        })
+    }
+}
+
+fn write_tile(buf: &mut Vec<u8>, bgra: &[u8]) {
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    let mut indices = Vec::with_capacity(bgra.len() / 4);
+    for p in bgra.chunks_exact(4) {
+        let color = [p[0], p[1], p[2], p[3]];
+        let index = match palette.iter().position(|c| *c == color) {
+            Some(index) => index,
+            None if palette.len() < MAX_PALETTE_COLORS => {
+                palette.push(color);
+                palette.len() - 1
+            }
+            None => {
+                buf.push(KIND_RAW);
+                buf.extend_from_slice(bgra);
+                return;
+            }
+        };
+        indices.push(index as u8);
+    }
+    buf.push(KIND_PALETTE);
+    buf.push((palette.len() - 1) as u8);
+    palette.iter().for_each(|c| buf.extend_from_slice(c));
+    buf.extend(indices);
+}
+
+// Whether the frame looks like text or UI rather than video or photos.
+pub fn is_screen_content(data: &[u8], stride: usize, width: usize, height: usize) -> bool {
+    let tiles = TileMap::full(width, height, crate::dirty::TILE_SIZE);
+    let (mut total, mut flat) = (0, 0);
+    let mut colors: Vec<[u8; 4]> = Vec::with_capacity(SCREEN_CONTENT_COLORS + 1);
+    for row in 0..tiles.rows {
+        for col in 0..tiles.cols {
+            let r = tiles.tile_rect(col, row);
+            colors.clear();
+            // Every second pixel of every second line is enough to count colors.
+            'tile: for y in (r.y..r.y + r.h).step_by(2) {
+                for x in (r.x..r.x + r.w).step_by(2) {
+                    let offset = y * stride + x * 4;
+                    let Some(p) = data.get(offset..offset + 4) else {
+                        break 'tile;
+                    };
+                    let color = [p[0], p[1], p[2], p[3]];
+                    if !colors.contains(&color) {
+                        colors.push(color);
+                        if colors.len() > SCREEN_CONTENT_COLORS {
+                            break 'tile;
+                        }
+                    }
+                }
+            }
+            total += 1;
+            if colors.len() <= SCREEN_CONTENT_COLORS {
+                flat += 1;
+            }
+        }
+    }
+    total > 0 && flat as f32 / total as f32 >= SCREEN_CONTENT_TILES
+}
+
+#[derive(Default)]
+pub struct TileDecoder {}
+
+impl TileDecoder {
+    // Apply the tiles to the last decoded image, returns true if the image is updated.
+    pub fn decode(&mut self, frames: &EncodedVideoFrames, rgb: &mut ImageRgb) -> ResultType<bool> {
+        let mut updated = false;
+        for frame in frames.frames.iter() {
+            let buf = decompress(&frame.data);
+            self.decode_one(&buf, frame.key, rgb)?;
+            updated = true;
+        }
+        Ok(updated)
+    }
+
+    fn decode_one(&mut self, buf: &[u8], key: bool, rgb: &mut ImageRgb) -> ResultType<()> {
+        let mut reader = Reader { buf, pos: 0 };
+        let width = reader.u32()? as usize;
+        let height = reader.u32()? as usize;
+        let tile = reader.u32()? as usize;
+        let count = reader.u32()? as usize;
+        let bytes_per_pixel = match rgb.fmt() {
+            ImageFormat::Raw => 3,
+            ImageFormat::ARGB | ImageFormat::ABGR => 4,
+        };
+        let align = rgb.align().max(1);
+        let bytes_per_row = (width * bytes_per_pixel + align - 1) & !(align - 1);
+        if rgb.w != width || rgb.h != height || rgb.raw.len() != bytes_per_row * height {
+            if !key {
+                bail!("tile frame without base image");
+            }
+            rgb.w = width;
+            rgb.h = height;
+            rgb.raw.resize(bytes_per_row * height, 0);
+        }
+        let map = TileMap::new(width, height, tile);
+        for _ in 0..count {
+            let col = reader.u32()? as usize;
+            let row = reader.u32()? as usize;
+            if col >= map.cols || row >= map.rows {
+                bail!("tile out of range");
+            }
+            let r = map.tile_rect(col, row);
+            let pixels = r.w * r.h;
+            let kind = reader.u8()?;
+            let (palette, data) = match kind {
+                KIND_RAW => (None, reader.take(pixels * 4)?),
+                KIND_PALETTE => {
+                    let colors = reader.u8()? as usize + 1;
+                    (Some(reader.take(colors * 4)?), reader.take(pixels)?)
+                }
+                _ => bail!("unknown tile kind: {kind}"),
+            };
+            for y in 0..r.h {
+                let line = (r.y + y) * bytes_per_row + r.x * bytes_per_pixel;
+                for x in 0..r.w {
+                    let i = y * r.w + x;
+                    let p = match palette {
+                        Some(palette) => {
+                            let index = data[i] as usize * 4;
+                            palette
+                                .get(index..index + 4)
+                                .ok_or_else(|| hbb_common::anyhow::anyhow!("bad palette index"))?
+                        }
+                        None => &data[i * 4..i * 4 + 4],
+                    };
+                    let dst = &mut rgb.raw[line + x * bytes_per_pixel..][..bytes_per_pixel];
+                    match rgb.fmt {
+                        // libyuv names, ARGB is B G R A in memory
+                        ImageFormat::ARGB => dst.copy_from_slice(p),
+                        ImageFormat::ABGR => dst.copy_from_slice(&[p[2], p[1], p[0], p[3]]),
+                        ImageFormat::Raw => dst.copy_from_slice(&[p[2], p[1], p[0]]),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> ResultType<&'a [u8]> {
+        if self.pos + n > self.buf.len() {
+            bail!("tile frame truncated");
+        }
+        let v = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(v)
+    }
+
+    fn u8(&mut self) -> ResultType<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> ResultType<u32> {
+        let mut b = [0u8; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dirty::DirtyRect;
+
+    #[test]
+    fn test_tile_roundtrip() {
+        let (w, h) = (100, 70);
+        // left half text-like with two colors, right half with many colors
+        let mut data = vec![0u8; w * h * 4];
+        for y in 0..h {
+            for x in 0..w {
+                let p = &mut data[(y * w + x) * 4..][..4];
+                if x < w / 2 {
+                    let v = if (x + y) % 3 == 0 { 255 } else { 0 };
+                    p.copy_from_slice(&[v, v, v, 255]);
+                } else {
+                    p.copy_from_slice(&[x as u8, y as u8, (x * y) as u8, 255]);
+                }
+            }
+        }
+        let mut encoder = TileEncoder::new(w, h);
+        let mut decoder = TileDecoder::default();
+        let mut rgb = ImageRgb::new(ImageFormat::ARGB, 64);
+        let tiles = TileMap::full(w, h, 64);
+        let frames = encoder
+            .encode(&data, w * 4, Pixfmt::BGRA, &tiles, true, 0)
+            .unwrap();
+        assert!(decoder.decode(&frames, &mut rgb).unwrap());
+        let bytes_per_row = (w * 4 + 63) & !63;
+        for y in 0..h {
+            assert_eq!(
+                &rgb.raw[y * bytes_per_row..y * bytes_per_row + w * 4],
+                &data[y * w * 4..(y + 1) * w * 4]
+            );
+        }
+
+        // only the changed tile is applied
+        data[(65 * w + 80) * 4] = 1;
+        let mut tiles = TileMap::new(w, h, 64);
+        tiles.mark(&DirtyRect::new(80, 65, 1, 1));
+        let frames = encoder
+            .encode(&data, w * 4, Pixfmt::BGRA, &tiles, false, 1)
+            .unwrap();
+        assert!(decoder.decode(&frames, &mut rgb).unwrap());
+        assert_eq!(rgb.raw[65 * bytes_per_row + 80 * 4], 1);
+    }
+
+    #[test]
+    fn test_tile_without_base() {
+        let mut encoder = TileEncoder::new(8, 8);
+        let mut tiles = TileMap::new(8, 8, 64);
+        tiles.set_dirty(0, 0);
+        let frames = encoder
+            .encode(&[0u8; 256], 32, Pixfmt::BGRA, &tiles, false, 0)
+            .unwrap();
+        let mut rgb = ImageRgb::new(ImageFormat::ABGR, 1);
+        assert!(TileDecoder::default().decode(&frames, &mut rgb).is_err());
+    }
+}
@@ -0,0 +1,344 @@
+// Write decoded frames to a v4l2loopback device, so that local applications can use the
+// remote camera as a local one.
+//
+// Only the output ioctls needed by v4l2loopback are implemented, frames are written with
+// write(2) in YUYV, which is accepted by most consumers.
+//
+// The format is set on the first frame and on resolution changes. v4l2loopback refuses to change
+// the format while a consumer is reading (EBUSY), then frames are scaled to the current format.
+// YUYV has 2 pixels per macro pixel, an odd width is rounded up, the last column repeated.
+
+use std::{
+    ffi::CString,
+    mem::size_of,
+    os::raw::{c_int, c_ulong},
+};
+
+use hbb_common::{bail, libc, log, ResultType};
+
+use crate::{generate_call_macro, ABGRToARGB, ARGBToYUY2, ImageFormat, ImageRgb, RAWToARGB};
+
+generate_call_macro!(call_yuv, false);
+
+const V4L2_BUF_TYPE_VIDEO_OUTPUT: u32 = 2;
+const V4L2_FIELD_NONE: u32 = 1;
+const V4L2_COLORSPACE_SRGB: u32 = 8;
+const V4L2_CAP_VIDEO_OUTPUT: u32 = 0x0000_0002;
+const V4L2_CAP_DEVICE_CAPS: u32 = 0x8000_0000;
+const V4L2_PIX_FMT_YUYV: u32 = fourcc(b"YUYV");
+
+const VIDIOC_QUERYCAP: c_ulong = ior(b'V', 0, size_of::<V4l2Capability>());
+const VIDIOC_G_FMT: c_ulong = iowr(b'V', 4, size_of::<V4l2Format>());
+const VIDIOC_S_FMT: c_ulong = iowr(b'V', 5, size_of::<V4l2Format>());
+
+const fn fourcc(c: &[u8; 4]) -> u32 {
+    (c[0] as u32) | (c[1] as u32) << 8 | (c[2] as u32) << 16 | (c[3] as u32) << 24
+}
+
+// _IOC of asm-generic/ioctl.h
+const fn ioc(dir: c_ulong, ty: u8, nr: u8, size: usize) -> c_ulong {
+    dir << 30 | (size as c_ulong) << 16 | (ty as c_ulong) << 8 | nr as c_ulong
+}
+
+const fn ior(ty: u8, nr: u8, size: usize) -> c_ulong {
+    ioc(2, ty, nr, size)
+}
+
+const fn iowr(ty: u8, nr: u8, size: usize) -> c_ulong {
+    ioc(3, ty, nr, size)
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct V4l2Capability {
+    driver: [u8; 16],
+    card: [u8; 32],
+    bus_info: [u8; 32],
+    version: u32,
+    capabilities: u32,
+    device_caps: u32,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+struct V4l2PixFormat {
+    width: u32,
+    height: u32,
+    pixelformat: u32,
+    field: u32,
+    bytesperline: u32,
+    sizeimage: u32,
+    colorspace: u32,
+    priv_: u32,
+    flags: u32,
+    ycbcr_enc: u32,
+    quantization: u32,
+    xfer_func: u32,
+}
+
+// The union of v4l2_format has 200 bytes and contains pointers.
+#[repr(C)]
+union V4l2FormatUnion {
+    pix: V4l2PixFormat,
+    raw_data: [usize; 200 / size_of::<usize>()],
+}
+
+#[repr(C)]
+struct V4l2Format {
+    type_: u32,
+    fmt: V4l2FormatUnion,
+}
+
+impl V4l2Format {
+    fn new(pix: V4l2PixFormat) -> Self {
+        let mut f = Self {
+            type_: V4L2_BUF_TYPE_VIDEO_OUTPUT,
+            fmt: V4l2FormatUnion {
+                raw_data: [0; 200 / size_of::<usize>()],
+            },
+        };
+        f.fmt.pix = pix;
+        f
+    }
+}
+
+pub struct V4l2Loopback {
+    fd: c_int,
+    path: String,
+    // Current format of the device, (width, height).
+    size: Option<(usize, usize)>,
+    argb: Vec<u8>,
+    yuyv: Vec<u8>,
+    scaled: Vec<u8>,
+}
+
+impl V4l2Loopback {
+    pub fn open(path: &str) -> ResultType<Self> {
+        let cpath = CString::new(path)?;
+        let fd = unsafe { libc::open(cpath.as_ptr(), libc::O_RDWR | libc::O_NONBLOCK) };
+        if fd < 0 {
+            bail!(
+                "Failed to open {}, {}",
+                path,
+                std::io::Error::last_os_error()
+            );
+        }
+        let output = Self {
+            fd,
+            path: path.to_owned(),
+            size: None,
+            argb: Vec::new(),
+            yuyv: Vec::new(),
+            scaled: Vec::new(),
+        };
+        let mut cap = V4l2Capability::default();
+        output.ioctl(VIDIOC_QUERYCAP, &mut cap as *mut _ as _)?;
+        let caps = if cap.capabilities & V4L2_CAP_DEVICE_CAPS != 0 {
+            cap.device_caps
+        } else {
+            cap.capabilities
+        };
+        if caps & V4L2_CAP_VIDEO_OUTPUT == 0 {
+            bail!("{} is not a video output device", path);
+        }
+        log::info!(
+            "Opened virtual camera {}, driver: {}",
+            path,
+            String::from_utf8_lossy(&cap.driver).trim_end_matches('\0')
+        );
+        Ok(output)
+    }
+
+    fn ioctl(&self, request: c_ulong, arg: *mut libc::c_void) -> ResultType<()> {
+        if unsafe { libc::ioctl(self.fd, request as _, arg) } < 0 {
+            bail!(
+                "ioctl {:#x} on {}, {}",
+                request,
+                self.path,
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+
+    // Set the format to the frame size, or keep the current one if the device is busy.
+    fn ensure_format(&mut self, width: usize, height: usize) -> ResultType<(usize, usize)> {
+        if self.size == Some((width, height)) {
+            return Ok((width, height));
+        }
+        let mut f = V4l2Format::new(V4l2PixFormat {
+            width: width as _,
+            height: height as _,
+            pixelformat: V4L2_PIX_FMT_YUYV,
+            field: V4L2_FIELD_NONE,
+            bytesperline: (width * 2) as _,
+            sizeimage: (width * height * 2) as _,
+            colorspace: V4L2_COLORSPACE_SRGB,
+            ..Default::default()
+        });
+        match self.ioctl(VIDIOC_S_FMT, &mut f as *mut _ as _) {
+            Ok(()) => {
+                let pix = unsafe { f.fmt.pix };
+                log::info!("Virtual camera format: {}x{}", pix.width, pix.height);
+                self.size = Some((pix.width as _, pix.height as _));
+            }
+            Err(e) => {
+                let mut f = V4l2Format::new(Default::default());
+                self.ioctl(VIDIOC_G_FMT, &mut f as *mut _ as _)?;
+                let pix = unsafe { f.fmt.pix };
+                if pix.pixelformat != V4L2_PIX_FMT_YUYV || pix.width == 0 || pix.height == 0 {
+                    bail!("Failed to set virtual camera format, {}", e);
+                }
+                log::info!(
+                    "Virtual camera is busy, scale {}x{} to {}x{}",
+                    width,
+                    height,
+                    pix.width,
+                    pix.height
+                );
+                self.size = Some((pix.width as _, pix.height as _));
+            }
+        }
+        Ok(self.size.unwrap_or((width, height)))
+    }
+
+    pub fn write(&mut self, rgb: &ImageRgb) -> ResultType<()> {
+        let (w, h) = (rgb.w, rgb.h);
+        if w == 0 || h == 0 {
+            return Ok(());
+        }
+        let ew = even_width(w);
+        let (dw, dh) = self.ensure_format(ew, h)?;
+        to_yuyv(rgb, &mut self.argb, &mut self.yuyv)?;
+        let data = if (dw, dh) == (ew, h) {
+            &self.yuyv
+        } else {
+            scale_yuyv(&self.yuyv, ew, h, &mut self.scaled, dw, dh);
+            &self.scaled
+        };
+        let n = unsafe { libc::write(self.fd, data.as_ptr() as _, data.len()) };
+        if n < 0 {
+            let err = std::io::Error::last_os_error();
+            // No consumer or the buffers are full, drop the frame.
+            if err.kind() != std::io::ErrorKind::WouldBlock {
+                bail!("Failed to write {}, {}", self.path, err);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for V4l2Loopback {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[inline]
+fn even_width(w: usize) -> usize {
+    (w + 1) & !1
+}
+
+// ARGBToYUY2 writes a whole macro pixel for the last pixel of an odd width.
+fn to_yuyv(rgb: &ImageRgb, argb: &mut Vec<u8>, yuyv: &mut Vec<u8>) -> ResultType<()> {
+    let (w, h) = (rgb.w, rgb.h);
+    let bpp = match rgb.fmt() {
+        ImageFormat::Raw => 3,
+        ImageFormat::ARGB | ImageFormat::ABGR => 4,
+    };
+    let align = rgb.align().max(1);
+    let stride = (w * bpp + align - 1) & !(align - 1);
+    if rgb.raw.len() < stride * h {
+        bail!("image data not enough");
+    }
+    let ew = even_width(w);
+    yuyv.resize(ew * h * 2, 0);
+    let (src, src_stride) = match rgb.fmt() {
+        ImageFormat::ARGB => (rgb.raw.as_ptr(), stride),
+        ImageFormat::ABGR => {
+            argb.resize(w * h * 4, 0);
+            call_yuv!(ABGRToARGB(
+                rgb.raw.as_ptr(),
+                stride as _,
+                argb.as_mut_ptr(),
+                (w * 4) as _,
+                w as _,
+                h as _,
+            ));
+            (argb.as_ptr(), w * 4)
+        }
+        ImageFormat::Raw => {
+            argb.resize(w * h * 4, 0);
+            call_yuv!(RAWToARGB(
+                rgb.raw.as_ptr(),
+                stride as _,
+                argb.as_mut_ptr(),
+                (w * 4) as _,
+                w as _,
+                h as _,
+            ));
+            (argb.as_ptr(), w * 4)
+        }
+    };
+    call_yuv!(ARGBToYUY2(
+        src,
+        src_stride as _,
+        yuyv.as_mut_ptr(),
+        (ew * 2) as _,
+        w as _,
+        h as _,
+    ));
+    Ok(())
+}
+
+// Nearest neighbor scaling of YUYV by macro pixels (2 pixels sharing U and V).
+fn scale_yuyv(src: &[u8], sw: usize, sh: usize, dst: &mut Vec<u8>, dw: usize, dh: usize) {
+    dst.resize(dw * dh * 2, 0);
+    let (smw, dmw) = (sw / 2, dw / 2);
+    if smw == 0 || dmw == 0 || sh == 0 {
+        return;
+    }
+    for y in 0..dh {
+        let sy = y * sh / dh;
+        for mx in 0..dmw {
+            let smx = mx * smw / dmw;
+            let s = sy * sw * 2 + smx * 4;
+            let d = y * dw * 2 + mx * 4;
+            dst[d..d + 4].copy_from_slice(&src[s..s + 4]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ioctl_numbers() {
+        assert_eq!(V4L2_PIX_FMT_YUYV, 0x5659_5559);
+        assert_eq!(VIDIOC_QUERYCAP, 0x8068_5600);
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(VIDIOC_S_FMT, 0xc0d0_5605);
+        #[cfg(target_pointer_width = "32")]
+        assert_eq!(VIDIOC_S_FMT, 0xc0cc_5605);
+    }
+
+    #[test]
+    fn test_scale_yuyv() {
+        // 4x2, macro pixels 0..4
+        let src: Vec<u8> = (0..4u8).flat_map(|i| [i; 4]).collect();
+        let mut dst = Vec::new();
+        scale_yuyv(&src, 4, 2, &mut dst, 2, 1);
+        assert_eq!(dst, vec![0; 4]);
+        scale_yuyv(&src, 4, 2, &mut dst, 8, 2);
+        assert_eq!(dst.len(), 8 * 2 * 2);
+        assert_eq!(
+            &dst[..16],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1]
+        );
+        assert_eq!(&dst[16..20], &[2, 2, 2, 2]);
+    }
+}
@@ -39,6 +39,8 @@ pub struct VpxEncoder {
     id: VpxVideoCodecId,
     i444: bool,
     yuvfmt: EncodeYuvFormat,
+    roi_enabled: bool,
+    keyframe: bool,
 }
 
 pub struct VpxDecoder {
@@ -165,6 +167,8 @@ impl EncoderApi for VpxEncoder {
                     id: config.codec,
                     i444,
                     yuvfmt: Self::get_yuvfmt(config.width, config.height, i444),
+                    roi_enabled: false,
+                    keyframe: false,
                 })
             }
             _ => Err(anyhow!("encoder type mismatch")),
@@ -228,8 +232,50 @@ impl EncoderApi for VpxEncoder {
     }
 
     fn disable(&self) {}
+
+    fn set_dirty_tiles(&mut self, tiles: &crate::dirty::TileMap) {
+        // Segment 1 is the changed area, no contrast if all or nothing changed.
+        let enable = !tiles.is_full() && !tiles.is_empty();
+        if !enable && !self.roi_enabled {
+            return;
+        }
+        // https://chromium.googlesource.com/webm/libvpx/+/refs/heads/main/vpx/vp8cx.h#829
+        // VP8 map is per 16x16 macroblock, VP9 map is per 8x8 block.
+        let (block, delta_q, control) = match self.id {
+            VpxVideoCodecId::VP8 => (16, ROI_DELTA_Q_VP8, VP8E_SET_ROI_MAP),
+            VpxVideoCodecId::VP9 => (8, ROI_DELTA_Q_VP9, VP9E_SET_ROI_MAP),
+        };
+        let (mut map, cols, rows) = tiles.block_map(block);
+        let mut roi: vpx_roi_map_t = unsafe { std::mem::MaybeUninit::zeroed().assume_init() };
+        roi.roi_map = map.as_mut_ptr();
+        roi.rows = rows as _;
+        roi.cols = cols as _;
+        // Zero deltas disable the segmentation.
+        if enable {
+            roi.delta_q[1] = delta_q;
+        }
+        // -1: do not force a reference frame
+        roi.ref_frame = [-1; 8];
+        let ret = unsafe {
+            vpx_codec_control_(&mut self.ctx, control as _, &mut roi as *mut vpx_roi_map_t)
+        };
+        if ret != VPX_CODEC_OK {
+            log::warn!("failed to set roi map: {ret:?}");
+            return;
+        }
+        self.roi_enabled = enable;
+    }
+
+    fn request_keyframe(&mut self) -> bool {
+        self.keyframe = true;
+        true
+    }
 }
 
+// Quantizer index deltas of the changed area, VP9 q index range is twice of VP8.
+const ROI_DELTA_Q_VP8: c_int = -15;
+const ROI_DELTA_Q_VP9: c_int = -30;
+
 impl VpxEncoder {
     pub fn encode(&mut self, pts: i64, data: &[u8], stride_align: usize) -> Result<EncodeFrames> {
         let bpp = if self.i444 { 24 } else { 12 };
@@ -252,12 +298,17 @@ impl VpxEncoder {
             data.as_ptr() as _,
         ));
 
+        let flags = if std::mem::take(&mut self.keyframe) {
+            VPX_EFLAG_FORCE_KF
+        } else {
+            0
+        };
         call_vpx!(vpx_codec_encode(
             &mut self.ctx,
             &image,
             pts as _,
             1, // Duration
-            0, // Flags
+            flags as _,
             VPX_DL_REALTIME as _,
         ));
 
@@ -100,7 +100,8 @@ impl Display {
         false
     }
 
+    // Stable across sessions, so the peer can tell the displays apart after reconnecting.
     pub fn name(&self) -> String {
-        "".to_owned()
+        self.0.id.clone()
     }
 }
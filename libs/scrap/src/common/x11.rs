@@ -1,4 +1,4 @@
-use crate::{common::TraitCapturer, x11, Frame, Pixfmt, TraitPixelBuffer};
+use crate::{common::TraitCapturer, dirty::TileMap, x11, Frame, Pixfmt, TraitPixelBuffer};
 use std::{io, time::Duration};
 
 pub struct Capturer(x11::Capturer);
@@ -24,12 +24,10 @@ impl TraitCapturer for Capturer {
         let width = self.width();
         let height = self.height();
         let pixfmt = self.0.display().pixfmt();
-        Ok(Frame::PixelBuffer(PixelBuffer::new(
-            self.0.frame()?,
-            pixfmt,
-            width,
-            height,
-        )))
+        let (data, tiles) = self.0.frame()?;
+        Ok(Frame::PixelBuffer(
+            PixelBuffer::new(data, pixfmt, width, height).with_dirty_tiles(tiles),
+        ))
     }
 }
 
@@ -39,6 +37,7 @@ pub struct PixelBuffer<'a> {
     width: usize,
     height: usize,
     stride: Vec<usize>,
+    dirty_tiles: Option<&'a TileMap>,
 }
 
 impl<'a> PixelBuffer<'a> {
@@ -52,8 +51,14 @@ impl<'a> PixelBuffer<'a> {
             width,
             height,
             stride,
+            dirty_tiles: None,
         }
     }
+
+    pub fn with_dirty_tiles(mut self, dirty_tiles: Option<&'a TileMap>) -> Self {
+        self.dirty_tiles = dirty_tiles;
+        self
+    }
 }
 
 impl<'a> TraitPixelBuffer for PixelBuffer<'a> {
@@ -76,6 +81,10 @@ impl<'a> TraitPixelBuffer for PixelBuffer<'a> {
     fn pixfmt(&self) -> crate::Pixfmt {
         self.pixfmt
     }
+
+    fn dirty_tiles(&self) -> Option<&TileMap> {
+        self.dirty_tiles
+    }
 }
 
 pub struct Display(x11::Display);
@@ -108,6 +117,26 @@ impl Display {
         Ok(x11::Server::displays(server).map(Display).collect())
     }
 
+    pub fn all_windows() -> io::Result<Vec<Display>> {
+        let server = match x11::Server::default() {
+            Ok(server) => server,
+            Err(_) => return Err(io::ErrorKind::ConnectionRefused.into()),
+        };
+
+        Ok(x11::Server::windows(server)
+            .into_iter()
+            .map(Display)
+            .collect())
+    }
+
+    pub fn is_window(&self) -> bool {
+        self.0.window().is_some()
+    }
+
+    pub fn crop(&mut self, x: i32, y: i32, w: usize, h: usize) -> bool {
+        self.0.crop(x, y, w, h)
+    }
+
     pub fn width(&self) -> usize {
         self.0.rect().w as usize
     }
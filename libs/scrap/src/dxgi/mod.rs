@@ -22,7 +22,10 @@ use winapi::{
 
 use crate::RotationMode::*;
 
-use crate::{AdapterDevice, Frame, PixelBuffer};
+use crate::{
+    dirty::{self, DirtyRect, TileMap},
+    AdapterDevice, Frame, PixelBuffer,
+};
 use std::ffi::c_void;
 
 pub struct ComPtr<T>(*mut T);
@@ -58,6 +61,7 @@ pub struct Capturer {
     output_texture: bool,
     adapter_desc1: DXGI_ADAPTER_DESC1,
     rotate: Rotate,
+    dirty_tiles: Option<TileMap>,
 }
 
 impl Capturer {
@@ -174,6 +178,7 @@ impl Capturer {
             output_texture: false,
             adapter_desc1,
             rotate,
+            dirty_tiles: None,
         })
     }
 
@@ -340,6 +345,11 @@ impl Capturer {
         if *info.LastPresentTime.QuadPart() == 0 {
             return Err(std::io::ErrorKind::WouldBlock.into());
         }
+        self.dirty_tiles = self.frame_dirty_rects(&info).map(|rects| {
+            let mut map = TileMap::new(self.width, self.height, dirty::TILE_SIZE);
+            rects.iter().for_each(|r| map.mark(r));
+            map
+        });
 
         #[allow(invalid_value)]
         let mut rect = mem::MaybeUninit::uninit().assume_init();
@@ -352,6 +362,41 @@ impl Capturer {
         Ok((rect.pBits, rect.Pitch))
     }
 
+    // Moved and dirty rects of the acquired frame, in the unrotated desktop image.
+    // https://learn.microsoft.com/en-us/windows/win32/direct3ddxgi/desktop-dup-api
+    unsafe fn frame_dirty_rects(&self, info: &DXGI_OUTDUPL_FRAME_INFO) -> Option<Vec<DirtyRect>> {
+        let size = info.TotalMetadataBufferSize;
+        if size == 0 {
+            return None;
+        }
+        let to_dirty_rect = |r: &RECT| {
+            DirtyRect::new(
+                r.left.max(0) as _,
+                r.top.max(0) as _,
+                (r.right - r.left).max(0) as _,
+                (r.bottom - r.top).max(0) as _,
+            )
+        };
+        let mut rects = vec![];
+        let mut required: UINT = 0;
+        let mut moves: Vec<DXGI_OUTDUPL_MOVE_RECT> =
+            Vec::with_capacity(size as usize / mem::size_of::<DXGI_OUTDUPL_MOVE_RECT>() + 1);
+        if (*self.duplication.0).GetFrameMoveRects(size, moves.as_mut_ptr(), &mut required) != S_OK
+        {
+            return None;
+        }
+        moves.set_len(required as usize / mem::size_of::<DXGI_OUTDUPL_MOVE_RECT>());
+        rects.extend(moves.iter().map(|m| to_dirty_rect(&m.DestinationRect)));
+        let mut dirty: Vec<RECT> = Vec::with_capacity(size as usize / mem::size_of::<RECT>() + 1);
+        if (*self.duplication.0).GetFrameDirtyRects(size, dirty.as_mut_ptr(), &mut required) != S_OK
+        {
+            return None;
+        }
+        dirty.set_len(required as usize / mem::size_of::<RECT>());
+        rects.extend(dirty.iter().map(to_dirty_rect));
+        Some(rects)
+    }
+
     // copy from GPU memory to system memory
     unsafe fn ohgodwhat(&mut self, frame: *mut IDXGIResource) -> io::Result<*mut IDXGISurface> {
         let mut texture: *mut ID3D11Texture2D = ptr::null_mut();
@@ -396,15 +441,17 @@ impl Capturer {
         } else {
             let width = self.width;
             let height = self.height;
-            Ok(Frame::PixelBuffer(PixelBuffer::with_BGRA(
-                self.get_pixelbuffer(timeout)?,
-                width,
-                height,
-            )))
+            let (data, dirty_tiles) = self.get_pixelbuffer(timeout)?;
+            Ok(Frame::PixelBuffer(
+                PixelBuffer::with_BGRA(data, width, height).with_dirty_tiles(dirty_tiles),
+            ))
         }
     }
 
-    fn get_pixelbuffer<'a>(&'a mut self, timeout: UINT) -> io::Result<&'a [u8]> {
+    fn get_pixelbuffer<'a>(
+        &'a mut self,
+        timeout: UINT,
+    ) -> io::Result<(&'a [u8], Option<&'a TileMap>)> {
         unsafe {
             // Release last frame.
             // No error checking needed because we don't care.
@@ -413,10 +460,14 @@ impl Capturer {
                 if let Some(gdi_capturer) = &self.gdi_capturer {
                     match gdi_capturer.frame(&mut self.gdi_buffer) {
                         Ok(_) => {
-                            crate::would_block_if_equal(
+                            self.dirty_tiles = Some(dirty::would_block_if_equal_tiles(
                                 &mut self.saved_raw_data,
                                 &self.gdi_buffer,
-                            )?;
+                                self.width * 4,
+                                self.width,
+                                self.height,
+                                4,
+                            )?);
                             &self.gdi_buffer
                         }
                         Err(err) => {
@@ -441,6 +492,7 @@ impl Capturer {
                     if rotate == kRotate0 {
                         slice::from_raw_parts(r.0, r.1 as usize * self.height)
                     } else {
+                        self.dirty_tiles = None;
                         self.rotated.resize(self.width * self.height * 4, 0);
                         crate::common::ARGBRotate(
                             r.0,
@@ -463,7 +515,7 @@ impl Capturer {
                     }
                 }
             };
-            Ok(result)
+            Ok((result, self.dirty_tiles.as_ref()))
         }
     }
 
@@ -38,6 +38,11 @@ impl Frame {
         self.bgra_stride
     }
 
+    // The stride of `inner`, `stride` is only set by `surface_to_bgra`.
+    pub fn inner_stride(&self) -> usize {
+        unsafe { IOSurfaceGetBytesPerRowOfPlane(self.surface, 0) }
+    }
+
     pub fn surface_to_bgra<'a>(&'a mut self, h: usize) {
         unsafe {
             let plane0 = IOSurfaceGetBaseAddressOfPlane(self.surface, 0);
@@ -1,5 +1,8 @@
 pub mod capturable;
 pub mod pipewire;
+pub mod restore_token;
+#[cfg(test)]
+mod mock_portal;
 mod screencast_portal;
 mod request_portal;
 pub mod remote_desktop_portal;
@@ -0,0 +1,221 @@
+// A mock of the screencast portal on a private session bus, to test the restore tokens without a
+// desktop. The "user" selects all monitors when the portal shows its dialog.
+//
+// Requires `dbus-daemon`, run with `cargo test --features wayland -- --ignored mock_portal`.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader},
+    os::unix::io::IntoRawFd,
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use dbus::{
+    arg::{OwnedFd, PropMap, RefArg, Variant},
+    blocking::{Connection, SyncConnection},
+    Message, Path,
+};
+use dbus_crossroads::{Context, Crossroads, IfaceBuilder, MethodErr};
+
+use super::pipewire::request_screencast_session;
+
+const PORTAL_NAME: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+
+#[derive(Default)]
+struct MockState {
+    // (x, y, width, height)
+    monitors: Vec<(i32, i32, i32, i32)>,
+    // token -> monitors
+    tokens: HashMap<String, Vec<(i32, i32, i32, i32)>>,
+    // session -> selected monitors
+    selected: HashMap<String, Vec<(i32, i32, i32, i32)>>,
+    dialogs: usize,
+    next_token: usize,
+}
+
+type State = Arc<Mutex<MockState>>;
+
+struct MockPortal {
+    daemon: Child,
+    state: State,
+}
+
+impl Drop for MockPortal {
+    fn drop(&mut self) {
+        self.daemon.kill().ok();
+    }
+}
+
+fn request_path(ctx: &Context, options: &PropMap) -> Path<'static> {
+    let sender = ctx
+        .message()
+        .sender()
+        .map(|s| s.trim_start_matches(':').replace('.', "_"))
+        .unwrap_or_default();
+    let token = options
+        .get("handle_token")
+        .and_then(|v| v.as_str())
+        .unwrap_or("t");
+    Path::from(format!("{}/request/{}/{}", PORTAL_PATH, sender, token))
+}
+
+// Portal responses are unicast signals on the request path.
+fn push_response(ctx: &mut Context, path: &Path<'static>, results: PropMap) {
+    let mut msg = Message::signal(
+        path,
+        &"org.freedesktop.portal.Request".into(),
+        &"Response".into(),
+    )
+    .append2(0u32, results);
+    msg.set_destination(ctx.message().sender().map(|s| s.into_static()));
+    ctx.push_msg(msg);
+}
+
+fn start() -> MockPortal {
+    let mut daemon = Command::new("dbus-daemon")
+        .args(["--session", "--nofork", "--print-address=1"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("dbus-daemon is required");
+    let mut address = String::new();
+    BufReader::new(daemon.stdout.take().unwrap())
+        .read_line(&mut address)
+        .unwrap();
+    std::env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+
+    let state: State = Default::default();
+    let state_serve = state.clone();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let conn = Connection::new_session().unwrap();
+        conn.request_name(PORTAL_NAME, false, true, false).unwrap();
+        let mut cr = Crossroads::new();
+        let token = cr.register(
+            "org.freedesktop.portal.ScreenCast",
+            |b: &mut IfaceBuilder<State>| {
+                b.property("version").get(|_, _| Ok(4u32));
+                b.property("AvailableSourceTypes").get(|_, _| Ok(1u32));
+                b.property("AvailableCursorModes").get(|_, _| Ok(1u32));
+                b.method(
+                    "CreateSession",
+                    ("options",),
+                    ("handle",),
+                    |ctx, _: &mut State, (options,): (PropMap,)| {
+                        let path = request_path(ctx, &options);
+                        let session = options
+                            .get("session_handle_token")
+                            .and_then(|v| v.as_str())
+                            .unwrap_or("s");
+                        let session = format!("{}/session/{}", PORTAL_PATH, session);
+                        let mut results = PropMap::new();
+                        results.insert("session_handle".into(), Variant(Box::new(session)));
+                        push_response(ctx, &path, results);
+                        Ok((path,))
+                    },
+                );
+                b.method(
+                    "SelectSources",
+                    ("session_handle", "options"),
+                    ("handle",),
+                    |ctx, state: &mut State, (session, options): (Path<'static>, PropMap)| {
+                        let path = request_path(ctx, &options);
+                        let mut state = state.lock().unwrap();
+                        let restored = options
+                            .get("restore_token")
+                            .and_then(|v| v.as_str())
+                            .and_then(|t| state.tokens.remove(t))
+                            .filter(|m| m.iter().all(|m| state.monitors.contains(m)));
+                        let selected = match restored {
+                            Some(monitors) => monitors,
+                            None => {
+                                state.dialogs += 1;
+                                state.monitors.clone()
+                            }
+                        };
+                        state.selected.insert(session.to_string(), selected);
+                        push_response(ctx, &path, PropMap::new());
+                        Ok((path,))
+                    },
+                );
+                b.method(
+                    "Start",
+                    ("session_handle", "parent_window", "options"),
+                    ("handle",),
+                    |ctx,
+                     state: &mut State,
+                     (session, _, options): (Path<'static>, String, PropMap)| {
+                        let path = request_path(ctx, &options);
+                        let mut state = state.lock().unwrap();
+                        let selected = state
+                            .selected
+                            .get(&session.to_string())
+                            .cloned()
+                            .ok_or_else(|| MethodErr::failed("No sources selected"))?;
+                        let streams: Vec<(u32, PropMap)> = selected
+                            .iter()
+                            .enumerate()
+                            .map(|(i, (x, y, w, h))| {
+                                let mut props = PropMap::new();
+                                props.insert("position".into(), Variant(Box::new((*x, *y))));
+                                props.insert("size".into(), Variant(Box::new((*w, *h))));
+                                props.insert("source_type".into(), Variant(Box::new(1u32)));
+                                (40 + i as u32, props)
+                            })
+                            .collect();
+                        state.next_token += 1;
+                        let token = format!("token-{}", state.next_token);
+                        state.tokens.insert(token.clone(), selected);
+                        let mut results = PropMap::new();
+                        results.insert("streams".into(), Variant(Box::new(streams)));
+                        results.insert("restore_token".into(), Variant(Box::new(token)));
+                        push_response(ctx, &path, results);
+                        Ok((path,))
+                    },
+                );
+                b.method(
+                    "OpenPipeWireRemote",
+                    ("session_handle", "options"),
+                    ("fd",),
+                    |_, _, (_, _): (Path<'static>, PropMap)| {
+                        let file =
+                            std::fs::File::open("/dev/null").map_err(|e| MethodErr::failed(&e))?;
+                        Ok((unsafe { OwnedFd::new(file.into_raw_fd()) },))
+                    },
+                );
+            },
+        );
+        cr.insert(PORTAL_PATH, &[token], state_serve);
+        tx.send(()).ok();
+        cr.serve(&conn).ok();
+    });
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    MockPortal { daemon, state }
+}
+
+#[test]
+#[ignore]
+fn test_mock_portal_restore_token() {
+    let portal = start();
+    portal.state.lock().unwrap().monitors = vec![(0, 0, 1920, 1080), (1920, 0, 2560, 1440)];
+    let conn = SyncConnection::new_session().unwrap();
+
+    let (_, _, streams, token) = request_screencast_session(&conn, "").unwrap();
+    assert_eq!(streams.len(), 2);
+    assert_eq!(portal.state.lock().unwrap().dialogs, 1);
+
+    // Restored without the dialog, the token is single-use.
+    let (_, _, restored, token) = request_screencast_session(&conn, &token).unwrap();
+    assert_eq!(restored.len(), 2);
+    assert_eq!(restored[1].get_size(), (2560, 1440));
+    assert_eq!(restored[1].position(), (1920, 0));
+    assert_eq!(portal.state.lock().unwrap().dialogs, 1);
+
+    // The layout changes, the token is stale.
+    portal.state.lock().unwrap().monitors = vec![(0, 0, 1920, 1080)];
+    let (_, _, streams, _) = request_screencast_session(&conn, &token).unwrap();
+    assert_eq!(streams.len(), 1);
+    assert_eq!(portal.state.lock().unwrap().dialogs, 2);
+}
@@ -2,9 +2,12 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::os::unix::io::AsRawFd;
 use std::process::Command;
-use std::sync::{atomic::AtomicBool, Arc, Mutex};
-use std::time::Duration;
-use tracing::{debug, trace, warn};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, trace, warn};
 
 use dbus::{
     arg::{OwnedFd, PropMap, RefArg, Variant},
@@ -23,6 +26,7 @@ use super::capturable::PixelProvider;
 use super::capturable::{Capturable, Recorder};
 use super::remote_desktop_portal::OrgFreedesktopPortalRemoteDesktop as remote_desktop_portal;
 use super::request_portal::OrgFreedesktopPortalRequestResponse;
+use super::restore_token::{self, StreamIdentity, RESTORE_TOKEN_CONF_KEY};
 use super::screencast_portal::OrgFreedesktopPortalScreenCast as screencast_portal;
 use lazy_static::lazy_static;
 
@@ -30,6 +34,9 @@ lazy_static! {
     pub static ref RDP_SESSION_INFO: Mutex<Option<RdpSessionInfo>> = Mutex::new(None);
 }
 
+// The clipboard of the remote desktop session, see `request_clipboard()`.
+static CLIPBOARD_ENABLED: AtomicBool = AtomicBool::new(false);
+
 #[inline]
 pub fn close_session() {
     let _ = RDP_SESSION_INFO.lock().unwrap().take();
@@ -40,6 +47,12 @@ pub fn is_rdp_session_hold() -> bool {
     RDP_SESSION_INFO.lock().unwrap().is_some()
 }
 
+/// Whether the portal Clipboard interface can be used with the current session.
+#[inline]
+pub fn is_clipboard_enabled() -> bool {
+    CLIPBOARD_ENABLED.load(Ordering::SeqCst) && is_rdp_session_hold()
+}
+
 pub fn try_close_session() {
     let mut rdp_info = RDP_SESSION_INFO.lock().unwrap();
     let mut close = false;
@@ -61,6 +74,8 @@ pub struct RdpSessionInfo {
     pub session: dbus::Path<'static>,
     pub is_support_restore_token: bool,
     pub resolution: Arc<Mutex<Option<(usize, usize)>>>,
+    // Screencast sessions of the streams the restore token failed to restore, (fd, session, streams).
+    pub extra_sessions: Vec<(OwnedFd, dbus::Path<'static>, Vec<PwStreamInfo>)>,
 }
 #[derive(Debug, Clone, Copy)]
 pub struct PwStreamInfo {
@@ -74,6 +89,14 @@ impl PwStreamInfo {
     pub fn get_size(&self) -> (usize, usize) {
         self.size
     }
+
+    pub fn source_type(&self) -> u64 {
+        self.source_type
+    }
+
+    pub fn position(&self) -> (i32, i32) {
+        self.position
+    }
 }
 
 #[derive(Debug)]
@@ -109,6 +132,8 @@ pub struct PipeWireCapturable {
     source_type: u64,
     pub position: (i32, i32),
     pub size: (usize, usize),
+    // Stable across sessions, see `StreamIdentity`.
+    pub id: String,
 }
 
 impl PipeWireCapturable {
@@ -120,6 +145,7 @@ impl PipeWireCapturable {
     ) -> Self {
         // alternative to get screen resolution as stream.size is not always correct ex: on fractional scaling
         // https://github.com/rustdesk/rustdesk/issues/6116#issuecomment-1817724244
+        let id = StreamIdentity::from(&stream).id();
         let size = get_res(Self {
             dbus_conn: conn.clone(),
             fd: fd.clone(),
@@ -127,6 +153,7 @@ impl PipeWireCapturable {
             source_type: stream.source_type,
             position: stream.position,
             size: stream.size,
+            id: id.clone(),
         })
         .unwrap_or(stream.size);
         *resolution.lock().unwrap() = Some(size);
@@ -137,6 +164,7 @@ impl PipeWireCapturable {
             source_type: stream.source_type,
             position: stream.position,
             size,
+            id,
         }
     }
 }
@@ -500,7 +528,20 @@ fn streams_from_response(response: OrgFreedesktopPortalRequestResponse) -> Vec<P
 
 static mut INIT: bool = false;
 const RESTORE_TOKEN: &str = "restore_token";
-const RESTORE_TOKEN_CONF_KEY: &str = "wayland-restore-token";
+// https://flatpak.github.io/xdg-desktop-portal/docs/doc-org.freedesktop.portal.ScreenCast.html#org-freedesktop-portal-screencast-availablesourcetypes
+const SOURCE_TYPE_MONITOR: u32 = 1;
+const SOURCE_TYPE_WINDOW: u32 = 2;
+
+// Let the user pick a window in the portal dialog too, if window capture is enabled.
+fn source_types(portal: &Proxy<&SyncConnection>) -> u32 {
+    if config::Config::get_option(crate::OPTION_WINDOW_CAPTURE) != "Y" {
+        return SOURCE_TYPE_MONITOR;
+    }
+    match screencast_portal::available_source_types(portal) {
+        Ok(types) if types & SOURCE_TYPE_WINDOW != 0 => SOURCE_TYPE_MONITOR | SOURCE_TYPE_WINDOW,
+        _ => SOURCE_TYPE_MONITOR,
+    }
+}
 
 pub fn get_available_cursor_modes() -> Result<u32, dbus::Error> {
     let conn = SyncConnection::new_session()?;
@@ -509,7 +550,9 @@ pub fn get_available_cursor_modes() -> Result<u32, dbus::Error> {
 }
 
 // mostly inspired by https://gitlab.gnome.org/-/snippets/39
-pub fn request_remote_desktop() -> Result<
+pub fn request_remote_desktop(
+    restore_token: &str,
+) -> Result<
     (
         SyncConnection,
         OwnedFd,
@@ -573,6 +616,7 @@ pub fn request_remote_desktop() -> Result<
             session.clone(),
             failure.clone(),
             is_support_restore_token,
+            restore_token.to_owned(),
         ),
         failure_res.clone(),
     )?;
@@ -617,6 +661,7 @@ fn on_create_session_response(
     session: Arc<Mutex<Option<dbus::Path<'static>>>>,
     failure: Arc<AtomicBool>,
     is_support_restore_token: bool,
+    restore_token: String,
 ) -> impl Fn(
     OrgFreedesktopPortalRequestResponse,
     &SyncConnection,
@@ -644,13 +689,16 @@ fn on_create_session_response(
         session.replace(ses.clone());
 
         let portal = get_portal(c);
+        request_clipboard(&portal, &ses);
         let mut args: PropMap = HashMap::new();
         // See `is_server_running()` to understand the following code.
         if is_server_running() {
             if is_support_restore_token {
-                let restore_token = config::LocalConfig::get_option(RESTORE_TOKEN_CONF_KEY);
                 if !restore_token.is_empty() {
-                    args.insert(RESTORE_TOKEN.to_string(), Variant(Box::new(restore_token)));
+                    args.insert(
+                        RESTORE_TOKEN.to_string(),
+                        Variant(Box::new(restore_token.clone())),
+                    );
                 }
                 // persist_mode may be configured by the user.
                 args.insert("persist_mode".to_string(), Variant(Box::new(2u32)));
@@ -661,7 +709,7 @@ fn on_create_session_response(
             );
             // https://flatpak.github.io/xdg-desktop-portal/docs/doc-org.freedesktop.portal.ScreenCast.html
             // args.insert("multiple".into(), Variant(Box::new(true)));
-            args.insert("types".into(), Variant(Box::new(1u32))); //| 2u32)));
+            args.insert("types".into(), Variant(Box::new(source_types(&portal))));
 
             let path = portal.select_sources(ses.clone(), args)?;
             handle_response(
@@ -705,6 +753,26 @@ fn on_create_session_response(
     }
 }
 
+// The portal Clipboard interface, the clipboard must be requested before the session is started.
+// https://flatpak.github.io/xdg-desktop-portal/docs/doc-org.freedesktop.portal.Clipboard.html
+fn request_clipboard(portal: &Proxy<&SyncConnection>, session: &dbus::Path<'static>) {
+    let res: Result<(), dbus::Error> = portal.method_call(
+        "org.freedesktop.portal.Clipboard",
+        "RequestClipboard",
+        (session.clone(), PropMap::new()),
+    );
+    match res {
+        Ok(()) => {
+            info!("Requested the clipboard of the remote desktop session");
+            CLIPBOARD_ENABLED.store(true, Ordering::SeqCst);
+        }
+        Err(e) => {
+            debug!("Failed to request the clipboard, {}", e);
+            CLIPBOARD_ENABLED.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
 fn on_select_devices_response(
     fd: Arc<Mutex<Option<OwnedFd>>>,
     streams: Arc<Mutex<Vec<PwStreamInfo>>>,
@@ -725,7 +793,7 @@ fn on_select_devices_response(
         );
         // https://flatpak.github.io/xdg-desktop-portal/docs/doc-org.freedesktop.portal.ScreenCast.html
         // args.insert("multiple".into(), Variant(Box::new(true)));
-        args.insert("types".into(), Variant(Box::new(1u32))); //| 2u32)));
+        args.insert("types".into(), Variant(Box::new(source_types(&portal))));
 
         let session = session.clone();
         let path = portal.select_sources(session.clone(), args)?;
@@ -812,6 +880,17 @@ fn on_start_response(
             }
         }
 
+        let clipboard_enabled = r
+            .results
+            .get("clipboard_enabled")
+            .and_then(|v| v.as_u64())
+            .unwrap_or_default()
+            != 0;
+        if CLIPBOARD_ENABLED.load(Ordering::SeqCst) && !clipboard_enabled {
+            warn!("Clipboard is not enabled by the remote desktop portal");
+            CLIPBOARD_ENABLED.store(false, Ordering::SeqCst);
+        }
+
         streams
             .clone()
             .lock()
@@ -826,6 +905,178 @@ fn on_start_response(
     }
 }
 
+// Wait for the `Response` signal of a portal request.
+fn wait_response(
+    conn: &SyncConnection,
+    path: dbus::Path<'static>,
+    timeout: Duration,
+) -> Result<OrgFreedesktopPortalRequestResponse, Box<dyn Error>> {
+    let response = Arc::new(Mutex::new(None));
+    let response_res = response.clone();
+    let failure = Arc::new(AtomicBool::new(false));
+    let token = handle_response(
+        conn,
+        path,
+        move |r, _, _| {
+            response_res.lock().unwrap().replace(r);
+            Ok(())
+        },
+        failure.clone(),
+    )?;
+    let start = Instant::now();
+    let res: Result<_, Box<dyn Error>> = loop {
+        conn.process(Duration::from_millis(100))?;
+        if let Some(r) = response.lock().unwrap().take() {
+            break Ok(r);
+        }
+        if failure.load(std::sync::atomic::Ordering::Relaxed) || start.elapsed() > timeout {
+            break Err(Box::new(DBusError("No response from the portal.".into())));
+        }
+    };
+    conn.remove_match(token).ok();
+    res
+}
+
+// An additional screencast session on the same connection, the user can select multiple sources.
+// Returns the pipewire fd, the session, the streams and the new restore token.
+pub(super) fn request_screencast_session(
+    conn: &SyncConnection,
+    restore_token: &str,
+) -> Result<(OwnedFd, dbus::Path<'static>, Vec<PwStreamInfo>, String), Box<dyn Error>> {
+    let portal = get_portal(conn);
+    let mut args: PropMap = HashMap::new();
+    args.insert(
+        "session_handle_token".to_string(),
+        Variant(Box::new("x1".to_string())),
+    );
+    args.insert(
+        "handle_token".to_string(),
+        Variant(Box::new("x1".to_string())),
+    );
+    let path = screencast_portal::create_session(&portal, args)?;
+    let r = wait_response(conn, path, Duration::from_secs(10))?;
+    let session: dbus::Path<'static> = r
+        .results
+        .get("session_handle")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| DBusError("Failed to obtain session_handle.".into()))?
+        .to_string()
+        .into();
+
+    let mut args: PropMap = HashMap::new();
+    args.insert(
+        "handle_token".to_string(),
+        Variant(Box::new("x2".to_string())),
+    );
+    args.insert("types".into(), Variant(Box::new(source_types(&portal))));
+    args.insert("multiple".into(), Variant(Box::new(true)));
+    args.insert("persist_mode".to_string(), Variant(Box::new(2u32)));
+    if !restore_token.is_empty() {
+        args.insert(
+            RESTORE_TOKEN.to_string(),
+            Variant(Box::new(restore_token.to_owned())),
+        );
+    }
+    let path = portal.select_sources(session.clone(), args)?;
+    // wait 3 minutes for user interaction
+    wait_response(conn, path, Duration::from_secs(180))?;
+
+    let mut args: PropMap = HashMap::new();
+    args.insert(
+        "handle_token".to_string(),
+        Variant(Box::new("x3".to_string())),
+    );
+    let path = screencast_portal::start(&portal, session.clone(), "", args)?;
+    let r = wait_response(conn, path, Duration::from_secs(180))?;
+    let token = r
+        .results
+        .get(RESTORE_TOKEN)
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_owned();
+    let streams = streams_from_response(r);
+    let fd = portal.open_pipe_wire_remote(session.clone(), HashMap::new())?;
+    Ok((fd, session, streams, token))
+}
+
+// Request the remote desktop with the restore token profile of the current monitor layout.
+//
+// If the main token fails to restore all streams of the profile, the token is stale, we request
+// only the missing streams in an additional session, instead of selecting all of them again.
+fn request_with_profile() -> Result<RdpSessionInfo, Box<dyn Error>> {
+    let server_running = is_server_running();
+    let profile = if server_running {
+        restore_token::select()
+    } else {
+        Default::default()
+    };
+    let (conn, fd, streams, session, is_support_restore_token) =
+        request_remote_desktop(&profile.token)?;
+    let mut extra_sessions = vec![];
+    if server_running && is_support_restore_token {
+        let mut restored: Vec<StreamIdentity> = streams.iter().map(|s| s.into()).collect();
+        let missing = restore_token::missing(&profile, &restored);
+        let mut extra_token = String::new();
+        if !missing.is_empty() || !profile.extra_token.is_empty() {
+            if !missing.is_empty() {
+                warn!(
+                    "Restore token is stale, missing streams: {:?}",
+                    missing.iter().map(|s| s.id()).collect::<Vec<_>>()
+                );
+            }
+            match request_screencast_session(&conn, &profile.extra_token) {
+                Ok((fd, session, streams, token)) => {
+                    let streams: Vec<_> = streams
+                        .into_iter()
+                        .filter(|s| !restored.contains(&s.into()))
+                        .collect();
+                    extra_token = token;
+                    if !streams.is_empty() {
+                        restored.extend(streams.iter().map(|s| s.into()));
+                        extra_sessions.push((fd, session, streams));
+                    }
+                }
+                Err(e) => warn!("Failed to request the missing streams: {}", e),
+            }
+        }
+        // `on_start_response()` saves the new main token.
+        let token = config::LocalConfig::get_option(RESTORE_TOKEN_CONF_KEY);
+        info!(
+            "Update restore token profile: {}",
+            restore_token::layout_key(&restored)
+        );
+        restore_token::update(&token, &extra_token, restored);
+    }
+    Ok(RdpSessionInfo {
+        conn: Arc::new(conn),
+        streams,
+        fd,
+        session,
+        is_support_restore_token,
+        resolution: Arc::new(Mutex::new(None)),
+        extra_sessions,
+    })
+}
+
+/// The stable identities of the streams of the current session.
+pub fn stream_identities() -> Vec<StreamIdentity> {
+    let rdp_info = RDP_SESSION_INFO.lock().unwrap();
+    let Some(rdp_info) = rdp_info.as_ref() else {
+        return vec![];
+    };
+    rdp_info
+        .streams
+        .iter()
+        .chain(
+            rdp_info
+                .extra_sessions
+                .iter()
+                .flat_map(|(_, _, s)| s.iter()),
+        )
+        .map(|s| s.into())
+        .collect()
+}
+
 pub fn get_capturables() -> Result<Vec<PipeWireCapturable>, Box<dyn Error>> {
     let mut rdp_connection = match RDP_SESSION_INFO.lock() {
         Ok(conn) => conn,
@@ -833,18 +1084,7 @@ pub fn get_capturables() -> Result<Vec<PipeWireCapturable>, Box<dyn Error>> {
     };
 
     if rdp_connection.is_none() {
-        let (conn, fd, streams, session, is_support_restore_token) = request_remote_desktop()?;
-        let conn = Arc::new(conn);
-
-        let rdp_info = RdpSessionInfo {
-            conn,
-            streams,
-            fd,
-            session,
-            is_support_restore_token,
-            resolution: Arc::new(Mutex::new(None)),
-        };
-        *rdp_connection = Some(rdp_info);
+        *rdp_connection = Some(request_with_profile()?);
     }
 
     let rdp_info = match rdp_connection.as_ref() {
@@ -856,14 +1096,20 @@ pub fn get_capturables() -> Result<Vec<PipeWireCapturable>, Box<dyn Error>> {
 
     Ok(rdp_info
         .streams
-        .clone()
-        .into_iter()
-        .map(|s| {
+        .iter()
+        .map(|s| (&rdp_info.fd, s))
+        .chain(
+            rdp_info
+                .extra_sessions
+                .iter()
+                .flat_map(|(fd, _, streams)| streams.iter().map(move |s| (fd, s))),
+        )
+        .map(|(fd, s)| {
             PipeWireCapturable::new(
                 rdp_info.conn.clone(),
-                rdp_info.fd.clone(),
+                fd.clone(),
                 rdp_info.resolution.clone(),
-                s,
+                *s,
             )
         })
         .collect())
@@ -0,0 +1,218 @@
+// Restore tokens of the screencast portal, saved per monitor layout.
+//
+// A restore token only restores the streams selected when it was issued. If the monitor layout
+// changes, the portal shows its dialog again, and the new token replaces the old one. So we keep
+// one profile per layout, and switch back to the matching profile when the layout is restored.
+//
+// The tokens are single-use, the profile is updated after every successful request.
+
+use std::collections::HashMap;
+
+use hbb_common::config::LocalConfig;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::pipewire::PwStreamInfo;
+
+// The token of the last request, kept for `WaylandScreencastRestoreToken` IPC.
+pub const RESTORE_TOKEN_CONF_KEY: &str = "wayland-restore-token";
+const PROFILES_CONF_KEY: &str = "wayland-restore-token-profiles";
+const LAST_PROFILE_CONF_KEY: &str = "wayland-restore-token-profile";
+
+/// A stable identity of a stream. The pipewire node ids change on every session, but the
+/// geometry of a monitor doesn't change until the layout changes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct StreamIdentity {
+    pub source_type: u64,
+    pub position: (i32, i32),
+    pub size: (usize, usize),
+}
+
+impl StreamIdentity {
+    pub fn id(&self) -> String {
+        let kind = match self.source_type {
+            2 => "window",
+            _ => "monitor",
+        };
+        format!(
+            "{}-{}x{}+{}+{}",
+            kind, self.size.0, self.size.1, self.position.0, self.position.1
+        )
+    }
+}
+
+impl From<&PwStreamInfo> for StreamIdentity {
+    fn from(s: &PwStreamInfo) -> Self {
+        Self {
+            source_type: s.source_type(),
+            position: s.position(),
+            size: s.get_size(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Profile {
+    pub token: String,
+    // Token of the session requested for the streams the main token failed to restore.
+    #[serde(default)]
+    pub extra_token: String,
+    #[serde(default)]
+    pub streams: Vec<StreamIdentity>,
+}
+
+pub fn layout_key(streams: &[StreamIdentity]) -> String {
+    let mut ids: Vec<_> = streams.iter().map(|s| s.id()).collect();
+    ids.sort();
+    ids.join(",")
+}
+
+fn load() -> HashMap<String, Profile> {
+    serde_json::from_str(&LocalConfig::get_option(PROFILES_CONF_KEY)).unwrap_or_default()
+}
+
+fn store(profiles: &HashMap<String, Profile>) {
+    LocalConfig::set_option(
+        PROFILES_CONF_KEY.to_owned(),
+        serde_json::to_string(profiles).unwrap_or_default(),
+    );
+}
+
+// The monitor layout reported by Xwayland, the portal can't tell it before a session is started.
+pub fn current_layout() -> Option<String> {
+    let output = std::process::Command::new("xrandr")
+        .arg("--listmonitors")
+        .output()
+        .ok()?;
+    let monitors = parse_monitors(&String::from_utf8_lossy(&output.stdout));
+    if monitors.is_empty() {
+        None
+    } else {
+        Some(layout_key(&monitors))
+    }
+}
+
+// Lines like " 0: +*XWAYLAND0 1920/509x1080/286+0+0  XWAYLAND0".
+fn parse_monitors(s: &str) -> Vec<StreamIdentity> {
+    s.lines()
+        .filter_map(|line| {
+            let geometry = line.split_whitespace().nth(2)?;
+            let (w, rest) = geometry.split_once('x')?;
+            let mut parts = rest.split('+');
+            let h = parts.next()?;
+            let x = parts.next()?.parse().ok()?;
+            let y = parts.next()?.parse().ok()?;
+            let w = w.split('/').next()?.parse().ok()?;
+            let h = h.split('/').next()?.parse().ok()?;
+            Some(StreamIdentity {
+                source_type: 1,
+                position: (x, y),
+                size: (w, h),
+            })
+        })
+        .collect()
+}
+
+/// Select the profile of the current layout, or the last used one.
+pub fn select() -> Profile {
+    let mut profiles = load();
+    if let Some(layout) = current_layout() {
+        if let Some(profile) = profiles.remove(&layout) {
+            info!("Use the restore token profile of layout {}", layout);
+            return profile;
+        }
+    }
+    let last = LocalConfig::get_option(LAST_PROFILE_CONF_KEY);
+    if let Some(profile) = profiles.remove(&last) {
+        return profile;
+    }
+    Profile {
+        token: LocalConfig::get_option(RESTORE_TOKEN_CONF_KEY),
+        ..Default::default()
+    }
+}
+
+/// Save the tokens issued for the streams, the profile is named by the layout of the streams.
+pub fn update(token: &str, extra_token: &str, streams: Vec<StreamIdentity>) {
+    if streams.is_empty() {
+        return;
+    }
+    let layout = layout_key(&streams);
+    let mut profiles = load();
+    profiles.insert(
+        layout.clone(),
+        Profile {
+            token: token.to_owned(),
+            extra_token: extra_token.to_owned(),
+            streams,
+        },
+    );
+    store(&profiles);
+    LocalConfig::set_option(LAST_PROFILE_CONF_KEY.to_owned(), layout);
+    LocalConfig::set_option(RESTORE_TOKEN_CONF_KEY.to_owned(), token.to_owned());
+}
+
+/// The streams of the profile not restored. The token is stale if any.
+pub fn missing(profile: &Profile, restored: &[StreamIdentity]) -> Vec<StreamIdentity> {
+    profile
+        .streams
+        .iter()
+        .filter(|s| !restored.contains(s))
+        .cloned()
+        .collect()
+}
+
+pub fn clear() {
+    for key in [
+        PROFILES_CONF_KEY,
+        LAST_PROFILE_CONF_KEY,
+        RESTORE_TOKEN_CONF_KEY,
+    ] {
+        LocalConfig::set_option(key.to_owned(), "".to_owned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(x: i32, y: i32, w: usize, h: usize) -> StreamIdentity {
+        StreamIdentity {
+            source_type: 1,
+            position: (x, y),
+            size: (w, h),
+        }
+    }
+
+    #[test]
+    fn test_parse_monitors() {
+        let s = "Monitors: 2\n 0: +*XWAYLAND0 1920/509x1080/286+0+0  XWAYLAND0\n 1: +XWAYLAND1 2560/597x1440/336+1920+0  XWAYLAND1\n";
+        let monitors = parse_monitors(s);
+        assert_eq!(
+            monitors,
+            vec![monitor(0, 0, 1920, 1080), monitor(1920, 0, 2560, 1440)]
+        );
+        assert_eq!(
+            layout_key(&monitors),
+            "monitor-1920x1080+0+0,monitor-2560x1440+1920+0"
+        );
+        // The order of the streams doesn't matter.
+        assert_eq!(
+            layout_key(&[monitors[1].clone(), monitors[0].clone()]),
+            layout_key(&monitors)
+        );
+    }
+
+    #[test]
+    fn test_missing() {
+        let profile = Profile {
+            streams: vec![monitor(0, 0, 1920, 1080), monitor(1920, 0, 1920, 1080)],
+            ..Default::default()
+        };
+        assert!(missing(&profile, &profile.streams).is_empty());
+        assert_eq!(
+            missing(&profile, &[monitor(0, 0, 1920, 1080)]),
+            vec![monitor(1920, 0, 1920, 1080)]
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use super::ffi::*;
 use super::Display;
+use crate::dirty::{self, TileMap};
 use hbb_common::libc;
 use std::{io, ptr, slice};
 
@@ -8,9 +9,12 @@ pub struct Capturer {
     shmid: i32,
     xcbid: u32,
     buffer: *const u8,
+    // The composite pixmap of a window display.
+    pixmap: Option<xcb_pixmap_t>,
 
     size: usize,
     saved_raw_data: Vec<u8>, // for faster compare and copy
+    tiles: Option<TileMap>,
 }
 
 impl Capturer {
@@ -57,13 +61,25 @@ impl Capturer {
             );
         }
 
+        // Windows are read from their offscreen composite pixmap, so overlapping windows do not
+        // show up. The stacking order is left to the user and the window manager.
+        let pixmap = display.window().map(|window| unsafe {
+            let pixmap = xcb_generate_id(server);
+            xcb_composite_redirect_window(server, window, XCB_COMPOSITE_REDIRECT_AUTOMATIC);
+            xcb_composite_name_window_pixmap(server, window, pixmap);
+            xcb_flush(server);
+            pixmap
+        });
+
         let c = Capturer {
             display,
             shmid,
             xcbid,
             buffer,
+            pixmap,
             size,
             saved_raw_data: Vec::new(),
+            tiles: None,
         };
         Ok(c)
     }
@@ -72,14 +88,71 @@ impl Capturer {
         &self.display
     }
 
+    // A moved window only changes the origin of the display. A resized, unmapped or destroyed
+    // window can not be captured with the current pixmap.
+    fn refresh_window(&mut self) -> io::Result<()> {
+        let Some(window) = self.display.window() else {
+            return Ok(());
+        };
+        let rect = self.display.rect();
+        unsafe {
+            let conn = self.display.server().raw();
+            let attrs = xcb_get_window_attributes_reply(
+                conn,
+                xcb_get_window_attributes(conn, window),
+                ptr::null_mut(),
+            );
+            if attrs.is_null() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "window is closed"));
+            }
+            let viewable = (*attrs).map_state == XCB_MAP_STATE_VIEWABLE;
+            libc::free(attrs as _);
+            if !viewable {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "window is not viewable",
+                ));
+            }
+            let geo = xcb_get_geometry_reply(
+                conn,
+                xcb_get_geometry_unchecked(conn, window),
+                ptr::null_mut(),
+            );
+            if geo.is_null() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "window is closed"));
+            }
+            let (w, h) = ((*geo).width & !1, (*geo).height & !1);
+            libc::free(geo as _);
+            if w != rect.w || h != rect.h {
+                return Err(io::Error::new(io::ErrorKind::Other, "window is resized"));
+            }
+            let pos = xcb_translate_coordinates_reply(
+                conn,
+                xcb_translate_coordinates(conn, window, self.display.root(), 0, 0),
+                ptr::null_mut(),
+            );
+            if pos.is_null() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "window is closed"));
+            }
+            let (x, y) = ((*pos).dst_x, (*pos).dst_y);
+            libc::free(pos as _);
+            self.display.set_origin(x, y);
+        }
+        Ok(())
+    }
+
     fn get_image(&self) {
         let rect = self.display.rect();
+        let (drawable, x, y) = match self.pixmap {
+            Some(pixmap) => (pixmap, 0, 0),
+            None => (self.display.root(), rect.x, rect.y),
+        };
         unsafe {
             let request = xcb_shm_get_image_unchecked(
                 self.display.server().raw(),
-                self.display.root(),
-                rect.x,
-                rect.y,
+                drawable,
+                x,
+                y,
                 rect.w,
                 rect.h,
                 !0,
@@ -93,17 +166,33 @@ impl Capturer {
         }
     }
 
-    pub fn frame<'b>(&'b mut self) -> std::io::Result<&'b [u8]> {
+    /// The image with its changed tiles.
+    pub fn frame<'b>(&'b mut self) -> std::io::Result<(&'b [u8], Option<&'b TileMap>)> {
+        self.refresh_window()?;
         self.get_image();
         let result = unsafe { slice::from_raw_parts(self.buffer, self.size) };
-        crate::would_block_if_equal(&mut self.saved_raw_data, result)?;
-        Ok(result)
+        let rect = self.display.rect();
+        let (w, h) = (rect.w as usize, rect.h as usize);
+        self.tiles = Some(dirty::would_block_if_equal_tiles(
+            &mut self.saved_raw_data,
+            result,
+            self.size / h.max(1),
+            w,
+            h,
+            self.display.pixfmt().bytes_per_pixel(),
+        )?);
+        Ok((result, self.tiles.as_ref()))
     }
 }
 
 impl Drop for Capturer {
     fn drop(&mut self) {
         unsafe {
+            if let (Some(window), Some(pixmap)) = (self.display.window(), self.pixmap) {
+                let conn = self.display.server().raw();
+                xcb_free_pixmap(conn, pixmap);
+                xcb_composite_unredirect_window(conn, window, XCB_COMPOSITE_REDIRECT_AUTOMATIC);
+            }
             // Detach segment from XCB.
             xcb_shm_detach(self.display.server().raw(), self.xcbid);
             // Detach segment from our space.
@@ -12,6 +12,8 @@ pub struct Display {
     root: xcb_window_t,
     name: String,
     pixfmt: Pixfmt,
+    // A top level window instead of a monitor, `rect` is its position on the root window.
+    window: Option<xcb_window_t>,
 }
 
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
@@ -38,6 +40,26 @@ impl Display {
             root,
             name,
             pixfmt,
+            window: None,
+        }
+    }
+
+    pub unsafe fn new_window(
+        server: Rc<Server>,
+        rect: Rect,
+        root: xcb_window_t,
+        window: xcb_window_t,
+        name: String,
+        pixfmt: Pixfmt,
+    ) -> Display {
+        Display {
+            server,
+            default: false,
+            rect,
+            root,
+            name,
+            pixfmt,
+            window: Some(window),
         }
     }
 
@@ -67,4 +89,44 @@ impl Display {
     pub fn pixfmt(&self) -> Pixfmt {
         self.pixfmt
     }
+
+    pub fn window(&self) -> Option<xcb_window_t> {
+        self.window
+    }
+
+    // A window display follows the window when it is moved.
+    pub(super) fn set_origin(&mut self, x: i16, y: i16) {
+        self.rect.x = x;
+        self.rect.y = y;
+    }
+
+    // Capture only a region of the display, `x` and `y` are relative to the display.
+    // The region is clipped to the display, returns false if nothing is left.
+    pub fn crop(&mut self, x: i32, y: i32, w: usize, h: usize) -> bool {
+        if self.window.is_some() {
+            return false;
+        }
+        let (dw, dh) = (self.rect.w as i32, self.rect.h as i32);
+        let (x0, y0) = (x.clamp(0, dw), y.clamp(0, dh));
+        let x1 = (x as i64 + w as i64).clamp(0, dw as i64) as i32;
+        let y1 = (y as i64 + h as i64).clamp(0, dh as i64) as i32;
+        // Encoders require even sizes.
+        let (w, h) = ((x1 - x0) & !1, (y1 - y0) & !1);
+        if w <= 0 || h <= 0 {
+            return false;
+        }
+        let (Ok(x), Ok(y)) = (
+            i16::try_from(self.rect.x as i32 + x0),
+            i16::try_from(self.rect.y as i32 + y0),
+        ) else {
+            return false;
+        };
+        self.rect = Rect {
+            x,
+            y,
+            w: w as u16,
+            h: h as u16,
+        };
+        true
+    }
 }
@@ -5,6 +5,7 @@ use hbb_common::libc::c_void;
 #[link(name = "xcb")]
 #[link(name = "xcb-shm")]
 #[link(name = "xcb-randr")]
+#[link(name = "xcb-composite")]
 extern "C" {
     pub fn xcb_connect(displayname: *const i8, screenp: *mut i32) -> *mut xcb_connection_t;
 
@@ -100,9 +101,93 @@ extern "C" {
         e: *mut *mut xcb_generic_error_t,
     ) -> *mut xcb_get_geometry_reply_t;
 
+    pub fn xcb_flush(c: *mut xcb_connection_t) -> i32;
+
+    pub fn xcb_intern_atom(
+        c: *mut xcb_connection_t,
+        only_if_exists: u8,
+        name_len: u16,
+        name: *const i8,
+    ) -> xcb_intern_atom_cookie_t;
+
+    pub fn xcb_intern_atom_reply(
+        c: *mut xcb_connection_t,
+        cookie: xcb_intern_atom_cookie_t,
+        e: *mut *mut xcb_generic_error_t,
+    ) -> *mut xcb_intern_atom_reply_t;
+
+    pub fn xcb_get_property(
+        c: *mut xcb_connection_t,
+        delete: u8,
+        window: xcb_window_t,
+        property: xcb_atom_t,
+        type_: xcb_atom_t,
+        long_offset: u32,
+        long_length: u32,
+    ) -> xcb_get_property_cookie_t;
+
+    pub fn xcb_get_property_reply(
+        c: *mut xcb_connection_t,
+        cookie: xcb_get_property_cookie_t,
+        e: *mut *mut xcb_generic_error_t,
+    ) -> *mut xcb_get_property_reply_t;
+
+    pub fn xcb_get_property_value(r: *const xcb_get_property_reply_t) -> *mut c_void;
+
+    pub fn xcb_get_property_value_length(r: *const xcb_get_property_reply_t) -> i32;
+
+    pub fn xcb_get_window_attributes(
+        c: *mut xcb_connection_t,
+        window: xcb_window_t,
+    ) -> xcb_get_window_attributes_cookie_t;
+
+    pub fn xcb_get_window_attributes_reply(
+        c: *mut xcb_connection_t,
+        cookie: xcb_get_window_attributes_cookie_t,
+        e: *mut *mut xcb_generic_error_t,
+    ) -> *mut xcb_get_window_attributes_reply_t;
+
+    pub fn xcb_translate_coordinates(
+        c: *mut xcb_connection_t,
+        src_window: xcb_window_t,
+        dst_window: xcb_window_t,
+        src_x: i16,
+        src_y: i16,
+    ) -> xcb_translate_coordinates_cookie_t;
+
+    pub fn xcb_translate_coordinates_reply(
+        c: *mut xcb_connection_t,
+        cookie: xcb_translate_coordinates_cookie_t,
+        e: *mut *mut xcb_generic_error_t,
+    ) -> *mut xcb_translate_coordinates_reply_t;
+
+    pub fn xcb_free_pixmap(c: *mut xcb_connection_t, pixmap: xcb_pixmap_t) -> xcb_void_cookie_t;
+
+    pub fn xcb_composite_redirect_window(
+        c: *mut xcb_connection_t,
+        window: xcb_window_t,
+        update: u8,
+    ) -> xcb_void_cookie_t;
+
+    pub fn xcb_composite_unredirect_window(
+        c: *mut xcb_connection_t,
+        window: xcb_window_t,
+        update: u8,
+    ) -> xcb_void_cookie_t;
+
+    pub fn xcb_composite_name_window_pixmap(
+        c: *mut xcb_connection_t,
+        window: xcb_window_t,
+        pixmap: xcb_pixmap_t,
+    ) -> xcb_void_cookie_t;
 }
 
 pub const XCB_IMAGE_FORMAT_Z_PIXMAP: u8 = 2;
+pub const XCB_ATOM_STRING: xcb_atom_t = 31;
+pub const XCB_ATOM_WINDOW: xcb_atom_t = 33;
+pub const XCB_ATOM_WM_NAME: xcb_atom_t = 39;
+pub const XCB_MAP_STATE_VIEWABLE: u8 = 2;
+pub const XCB_COMPOSITE_REDIRECT_AUTOMATIC: u8 = 0;
 
 pub type xcb_atom_t = u32;
 pub type xcb_connection_t = c_void;
@@ -113,6 +198,7 @@ pub type xcb_timestamp_t = u32;
 pub type xcb_colormap_t = u32;
 pub type xcb_shm_seg_t = u32;
 pub type xcb_drawable_t = u32;
+pub type xcb_pixmap_t = u32;
 pub type xcb_get_atom_name_cookie_t = u32;
 pub type xcb_get_atom_name_reply_t = u32;
 pub type xcb_get_atom_name_request_t = xcb_get_atom_name_reply_t;
@@ -281,3 +367,82 @@ pub struct xcb_get_geometry_reply_t {
     pub border_width: u16,
     pub pad0: [u8; 2],
 }
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct xcb_intern_atom_cookie_t {
+    pub sequence: u32,
+}
+
+#[repr(C)]
+pub struct xcb_intern_atom_reply_t {
+    pub response_type: u8,
+    pub pad0: u8,
+    pub sequence: u16,
+    pub length: u32,
+    pub atom: xcb_atom_t,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct xcb_get_property_cookie_t {
+    pub sequence: u32,
+}
+
+#[repr(C)]
+pub struct xcb_get_property_reply_t {
+    pub response_type: u8,
+    pub format: u8,
+    pub sequence: u16,
+    pub length: u32,
+    pub type_: xcb_atom_t,
+    pub bytes_after: u32,
+    pub value_len: u32,
+    pub pad0: [u8; 12],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct xcb_get_window_attributes_cookie_t {
+    pub sequence: u32,
+}
+
+#[repr(C)]
+pub struct xcb_get_window_attributes_reply_t {
+    pub response_type: u8,
+    pub backing_store: u8,
+    pub sequence: u16,
+    pub length: u32,
+    pub visual: xcb_visualid_t,
+    pub class: u16,
+    pub bit_gravity: u8,
+    pub win_gravity: u8,
+    pub backing_planes: u32,
+    pub backing_pixel: u32,
+    pub save_under: u8,
+    pub map_is_installed: u8,
+    pub map_state: u8,
+    pub override_redirect: u8,
+    pub colormap: xcb_colormap_t,
+    pub all_event_masks: u32,
+    pub your_event_mask: u32,
+    pub do_not_propagate_mask: u16,
+    pub pad0: [u8; 2],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct xcb_translate_coordinates_cookie_t {
+    pub sequence: u32,
+}
+
+#[repr(C)]
+pub struct xcb_translate_coordinates_reply_t {
+    pub response_type: u8,
+    pub same_screen: u8,
+    pub sequence: u16,
+    pub length: u32,
+    pub child: xcb_window_t,
+    pub dst_x: i16,
+    pub dst_y: i16,
+}
@@ -8,3 +8,4 @@ mod display;
 mod ffi;
 mod iter;
 mod server;
+mod window;
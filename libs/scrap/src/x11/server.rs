@@ -3,7 +3,7 @@ use std::ptr;
 use std::rc::Rc;
 
 use super::ffi::*;
-use super::DisplayIter;
+use super::{Display, DisplayIter};
 
 #[derive(Debug)]
 pub struct Server {
@@ -24,6 +24,10 @@ impl Server {
         unsafe { DisplayIter::new(slf) }
     }
 
+    pub fn windows(slf: Rc<Server>) -> Vec<Display> {
+        super::window::windows(slf)
+    }
+
     pub fn default() -> Result<Rc<Server>, Error> {
         Ok(Rc::new(Server::connect(ptr::null())?))
         /*
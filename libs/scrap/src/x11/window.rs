@@ -0,0 +1,152 @@
+use std::ffi::CString;
+use std::ptr;
+use std::rc::Rc;
+
+use crate::Pixfmt;
+use hbb_common::libc;
+
+use super::ffi::*;
+use super::{Display, Rect, Server};
+
+// The top level windows managed by the window manager, as capturable displays.
+//
+// Only viewable windows of the first screen are listed, in the stacking order of `_NET_CLIENT_LIST`.
+pub(super) fn windows(server: Rc<Server>) -> Vec<Display> {
+    let conn = server.raw();
+    unsafe {
+        let iter = xcb_setup_roots_iterator(server.setup());
+        if iter.rem == 0 {
+            return vec![];
+        }
+        let root = (*iter.data).root;
+        let client_list = intern_atom(conn, "_NET_CLIENT_LIST");
+        if client_list == 0 {
+            return vec![];
+        }
+        let ids = get_property(conn, root, client_list, XCB_ATOM_WINDOW)
+            .map(|v| {
+                v.chunks_exact(4)
+                    .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        ids.into_iter()
+            .filter_map(|id| window_display(server.clone(), root, id))
+            .collect()
+    }
+}
+
+unsafe fn window_display(
+    server: Rc<Server>,
+    root: xcb_window_t,
+    id: xcb_window_t,
+) -> Option<Display> {
+    let conn = server.raw();
+    let attrs =
+        xcb_get_window_attributes_reply(conn, xcb_get_window_attributes(conn, id), ptr::null_mut());
+    if attrs.is_null() {
+        return None;
+    }
+    let viewable = (*attrs).map_state == XCB_MAP_STATE_VIEWABLE;
+    libc::free(attrs as _);
+    if !viewable {
+        return None;
+    }
+
+    let geo = xcb_get_geometry_reply(conn, xcb_get_geometry_unchecked(conn, id), ptr::null_mut());
+    if geo.is_null() {
+        return None;
+    }
+    let (w, h, depth) = ((*geo).width, (*geo).height, (*geo).depth);
+    libc::free(geo as _);
+    let pixfmt = match depth {
+        16 => Pixfmt::RGB565LE,
+        24 | 32 => Pixfmt::BGRA,
+        _ => return None,
+    };
+    // Encoders require even sizes, the odd last column or row is not captured.
+    let (w, h) = (w & !1, h & !1);
+    if w == 0 || h == 0 {
+        return None;
+    }
+
+    let pos = xcb_translate_coordinates_reply(
+        conn,
+        xcb_translate_coordinates(conn, id, root, 0, 0),
+        ptr::null_mut(),
+    );
+    if pos.is_null() {
+        return None;
+    }
+    let (x, y) = ((*pos).dst_x, (*pos).dst_y);
+    libc::free(pos as _);
+
+    Some(Display::new_window(
+        server,
+        Rect { x, y, w, h },
+        root,
+        id,
+        window_name(conn, id),
+        pixfmt,
+    ))
+}
+
+// `_NET_WM_NAME` is UTF-8, `WM_NAME` is the fallback for old applications.
+unsafe fn window_name(conn: *mut xcb_connection_t, id: xcb_window_t) -> String {
+    let net_wm_name = intern_atom(conn, "_NET_WM_NAME");
+    let utf8_string = intern_atom(conn, "UTF8_STRING");
+    if net_wm_name != 0 && utf8_string != 0 {
+        if let Some(v) = get_property(conn, id, net_wm_name, utf8_string) {
+            if !v.is_empty() {
+                return String::from_utf8_lossy(&v).to_string();
+            }
+        }
+    }
+    get_property(conn, id, XCB_ATOM_WM_NAME, XCB_ATOM_STRING)
+        .map(|v| String::from_utf8_lossy(&v).to_string())
+        .unwrap_or_default()
+}
+
+unsafe fn intern_atom(conn: *mut xcb_connection_t, name: &str) -> xcb_atom_t {
+    let Ok(cname) = CString::new(name) else {
+        return 0;
+    };
+    let reply = xcb_intern_atom_reply(
+        conn,
+        xcb_intern_atom(conn, 1, name.len() as _, cname.as_ptr()),
+        ptr::null_mut(),
+    );
+    if reply.is_null() {
+        return 0;
+    }
+    let atom = (*reply).atom;
+    libc::free(reply as _);
+    atom
+}
+
+unsafe fn get_property(
+    conn: *mut xcb_connection_t,
+    window: xcb_window_t,
+    property: xcb_atom_t,
+    type_: xcb_atom_t,
+) -> Option<Vec<u8>> {
+    // The length is in 32 bit units.
+    const MAX_LENGTH: u32 = 4096;
+    let reply = xcb_get_property_reply(
+        conn,
+        xcb_get_property(conn, 0, window, property, type_, 0, MAX_LENGTH),
+        ptr::null_mut(),
+    );
+    if reply.is_null() {
+        return None;
+    }
+    let len = xcb_get_property_value_length(reply);
+    let value = if (*reply).type_ == type_ && len > 0 {
+        let data = xcb_get_property_value(reply) as *const u8;
+        Some(std::slice::from_raw_parts(data, len as _).to_vec())
+    } else {
+        None
+    };
+    libc::free(reply as _);
+    value
+}
@@ -76,6 +76,7 @@ pub use helper::*;
 use scrap::{
     codec::Decoder,
     record::{Recorder, RecorderContext},
+    tilecodec::TileDecoder,
     CodecFormat, ImageFormat, ImageRgb, ImageTexture,
 };
 
@@ -89,10 +90,12 @@ use crate::ui_session_interface::SessionPermissionConfig;
 
 pub use super::lang::*;
 
+pub mod file_sync;
 pub mod file_trait;
 pub mod helper;
 pub mod io_loop;
 pub mod screenshot;
+pub mod transfer_queue;
 
 pub const MILLI1: Duration = Duration::from_millis(1);
 pub const SEC30: Duration = Duration::from_secs(30);
@@ -1421,6 +1424,7 @@ impl AudioHandler {
 /// Video handler for the [`Client`].
 pub struct VideoHandler {
     decoder: Decoder,
+    tile_decoder: TileDecoder,
     pub rgb: ImageRgb,
     pub texture: ImageTexture,
     recorder: Arc<Mutex<Option<Recorder>>>,
@@ -1453,6 +1457,7 @@ impl VideoHandler {
             };
         VideoHandler {
             decoder: Decoder::new(format, luid),
+            tile_decoder: Default::default(),
             rgb: ImageRgb::new(rgba_format, crate::get_dst_align_rgba()),
             texture: Default::default(),
             recorder: Default::default(),
@@ -1463,6 +1468,14 @@ impl VideoHandler {
         }
     }
 
+    /// Handle lossless tiles, they update the last decoded image.
+    ///
+    /// The decoder is kept for the next encoded frame, the peer encoder doesn't see the tile
+    /// frames either.
+    pub fn handle_tiles(&mut self, frames: &EncodedVideoFrames) -> ResultType<bool> {
+        self.tile_decoder.decode(frames, &mut self.rgb)
+    }
+
     /// Handle a new video frame.
     #[inline]
     pub fn handle_frame(
@@ -1533,23 +1546,30 @@ impl VideoHandler {
     }
 
     /// Start or stop screen record.
-    pub fn record_screen(&mut self, start: bool, id: String, display_idx: usize, camera: bool) {
+    pub fn record_screen(
+        &mut self,
+        start: bool,
+        id: String,
+        display_idx: usize,
+        camera: bool,
+    ) -> ResultType<()> {
         self.record = false;
+        self.recorder = Default::default();
         if start {
-            self.recorder = Recorder::new(RecorderContext {
+            let recorder = Recorder::new(RecorderContext {
                 server: false,
                 id,
                 dir: crate::ui_interface::video_save_directory(false),
                 display_idx,
                 camera,
                 tx: None,
-            })
-            .map_or(Default::default(), |r| Arc::new(Mutex::new(Some(r))));
-        } else {
-            self.recorder = Default::default();
+                encrypt_key: crate::ui_interface::video_encrypt_key(),
+            })?;
+            self.recorder = Arc::new(Mutex::new(Some(recorder)));
         }
 
         self.record = start;
+        Ok(())
     }
 }
 
@@ -2274,6 +2294,36 @@ impl LoginConfigHandler {
         msg_out
     }
 
+    /// Get the file transfer rate limit of the session, in bytes per second, and whether
+    /// transfers yield to the video.
+    pub fn get_file_transfer_limit(&self) -> (Option<u64>, bool) {
+        (
+            crate::file_throttle::parse_speed(
+                &self.get_option(crate::file_throttle::OPTION_MAX_SPEED),
+            ),
+            self.get_option(crate::file_throttle::OPTION_PRIORITY) == "Y",
+        )
+    }
+
+    /// Save the file transfer limit.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_speed` - Max speed in KB/s, 0 for no limit.
+    /// * `priority` - Slow down transfers while the video has a high delay.
+    pub fn set_file_transfer_limit(&mut self, max_speed: i32, priority: bool) {
+        let mut config = self.load_config();
+        config.options.insert(
+            crate::file_throttle::OPTION_MAX_SPEED.to_owned(),
+            max_speed.max(0).to_string(),
+        );
+        config.options.insert(
+            crate::file_throttle::OPTION_PRIORITY.to_owned(),
+            (if priority { "Y" } else { "" }).to_owned(),
+        );
+        self.save_config(config);
+    }
+
     pub fn get_option(&self, k: &str) -> String {
         if let Some(v) = self.config.options.get(k) {
             v.clone()
@@ -2576,6 +2626,7 @@ impl LoginConfigHandler {
 pub enum MediaData {
     VideoQueue,
     VideoFrame(Box<VideoFrame>),
+    Tiles(Box<EncodedVideoFrames>),
     AudioFrame(Box<AudioFrame>),
     AudioFormat(AudioFormat),
     Reset,
@@ -2614,6 +2665,8 @@ pub fn start_video_thread<F, T>(
         let mut count = 0;
         let mut duration = std::time::Duration::ZERO;
         let mut skip_beginning = 0;
+        #[cfg(target_os = "linux")]
+        let mut virtual_camera = VirtualCamera::default();
         loop {
             if let Ok(data) = video_receiver.recv() {
                 match data {
@@ -2647,7 +2700,8 @@ pub fn start_video_thread<F, T>(
                             let record_permission = session.lc.read().unwrap().record_permission;
                             let id = session.lc.read().unwrap().id.clone();
                             if record_state && record_permission {
-                                handler.record_screen(true, id, display, is_view_camera);
+                                let res = handler.record_screen(true, id, display, is_view_camera);
+                                on_record_screen_error(&session, res);
                             }
                             video_handler = Some(handler);
                         }
@@ -2657,6 +2711,11 @@ pub fn start_video_thread<F, T>(
                             let format_changed = handler.decoder.format() != format;
                             match handler.handle_frame(vf, &mut pixelbuffer, &mut tmp_chroma) {
                                 Ok(true) => {
+                                    // Before the callback, which may take the rgb data.
+                                    #[cfg(target_os = "linux")]
+                                    if is_view_camera && pixelbuffer {
+                                        virtual_camera.write(display, &handler.rgb);
+                                    }
                                     video_callback(
                                         display,
                                         &mut handler.rgb,
@@ -2720,6 +2779,23 @@ pub fn start_video_thread<F, T>(
                             ));
                         }
                     }
+                    MediaData::Tiles(frames) => {
+                        if let Some(handler) = video_handler.as_mut() {
+                            match handler.handle_tiles(&frames) {
+                                Ok(true) => video_callback(
+                                    display,
+                                    &mut handler.rgb,
+                                    handler.texture.texture,
+                                    true,
+                                ),
+                                Err(e) => {
+                                    log::error!("handle tile frame error, {}", e);
+                                    session.refresh_video(display as _);
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
                     MediaData::Reset => {
                         if let Some(handler) = video_handler.as_mut() {
                             handler.reset(None);
@@ -2728,7 +2804,8 @@ pub fn start_video_thread<F, T>(
                     MediaData::RecordScreen(start) => {
                         let id = session.lc.read().unwrap().id.clone();
                         if let Some(handler) = video_handler.as_mut() {
-                            handler.record_screen(start, id, display, is_view_camera);
+                            let res = handler.record_screen(start, id, display, is_view_camera);
+                            on_record_screen_error(&session, res);
                         }
                     }
                     _ => {}
@@ -2741,6 +2818,74 @@ pub fn start_video_thread<F, T>(
     });
 }
 
+// A recording which cannot be started is turned off for the whole session, not silently skipped.
+fn on_record_screen_error<T: InvokeUiSession>(session: &Session<T>, res: ResultType<()>) {
+    if let Err(e) = res {
+        log::error!("Failed to start recording: {}", e);
+        session.msgbox("error", "Record", &e.to_string(), "");
+        session.record_screen(false);
+    }
+}
+
+/// Local option, the v4l2loopback devices to publish the camera of the peer to,
+/// separated by ',' and in the order of the camera displays, e.g. "/dev/video10".
+#[cfg(target_os = "linux")]
+pub const OPTION_VIRTUAL_CAMERA_DEVICE: &str = "virtual-camera-device";
+
+// The device may be set, loaded or freed later, it is opened again after a failure.
+#[cfg(target_os = "linux")]
+const VIRTUAL_CAMERA_RETRY: Duration = Duration::from_secs(5);
+
+/// Publish the decoded camera of the peer as a local camera.
+#[cfg(target_os = "linux")]
+#[derive(Default)]
+struct VirtualCamera {
+    output: Option<scrap::v4l2loopback::V4l2Loopback>,
+    failed: Option<Instant>,
+}
+
+#[cfg(target_os = "linux")]
+impl VirtualCamera {
+    fn write(&mut self, display: usize, rgb: &scrap::ImageRgb) {
+        if self
+            .failed
+            .map_or(false, |t| t.elapsed() < VIRTUAL_CAMERA_RETRY)
+        {
+            return;
+        }
+        if self.output.is_none() {
+            let devices = LocalConfig::get_option(OPTION_VIRTUAL_CAMERA_DEVICE);
+            let Some(path) = devices
+                .split(',')
+                .map(|d| d.trim())
+                .nth(display)
+                .filter(|d| !d.is_empty())
+            else {
+                self.failed = Some(Instant::now());
+                return;
+            };
+            match scrap::v4l2loopback::V4l2Loopback::open(path) {
+                Ok(output) => self.output = Some(output),
+                Err(e) => {
+                    log::error!("Failed to open virtual camera: {}", e);
+                    self.failed = Some(Instant::now());
+                    return;
+                }
+            }
+        }
+        if let Some(output) = self.output.as_mut() {
+            match output.write(rgb) {
+                Ok(()) => self.failed = None,
+                Err(e) => {
+                    log::error!("Failed to write virtual camera: {}", e);
+                    self.output = None;
+                    self.failed = Some(Instant::now());
+                }
+            }
+        }
+    }
+}
+
 /// Start an audio thread
 /// Return a audio [`MediaSender`]
 pub fn start_audio_thread() -> MediaSender {
@@ -3487,6 +3632,19 @@ pub enum Data {
     ResetDecoder(Option<usize>),
     RenameFile((i32, String, String, bool)),
     TakeScreenshot((i32, String)),
+    // Block size, size of the local copy and its signatures, none to transfer in full.
+    FileDeltaSignatures((i32, i32, Option<(u32, u64, Vec<u8>)>)),
+    SyncDir((i32, String, String, file_sync::SyncOptions)),
+    StartSync(i32),
+    // Bytes per second and priority mode.
+    FileTransferLimit((Option<u64>, bool)),
+    // Job id, file number and the file numbers which failed the verification.
+    FileVerified((i32, i32, Vec<i32>)),
+    // Job id and the error of the unpacking.
+    ArchiveWritten((i32, Option<String>)),
+    SearchFiles((i32, String, crate::file_search::SearchOptions)),
+    // Path, max size and whether hidden files are allowed.
+    PreviewFile((i32, String, i32, bool)),
 }
 
 /// Keycode for key events.
@@ -0,0 +1,597 @@
+// Directory sync jobs, a one-way mirror or a two-way sync of a local and a remote tree.
+//
+// Both trees are listed with `fs::get_recursive_files`, the plan is computed from size and
+// mtime, then every action runs as a normal transfer or remove job under its own id, while
+// the sync job reports the overall progress under the id it was started with.
+
+use super::Data;
+use hbb_common::{
+    config::Config,
+    fs, log,
+    message_proto::{FileEntry, FileType},
+    sha2::{Digest, Sha256},
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
+// FAT keeps mtime in 2 seconds.
+const MTIME_TOLERANCE: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncMode {
+    /// Mirror the local tree to the remote one.
+    Upload,
+    /// Mirror the remote tree to the local one.
+    Download,
+    TwoWay,
+}
+
+/// Which side wins when a file changed on both sides.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictRule {
+    Newer,
+    Local,
+    Remote,
+    Skip,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyncOptions {
+    pub mode: SyncMode,
+    pub conflict: ConflictRule,
+    /// Transfer files with the same size and mtime too, large ones only cost their block
+    /// signatures with the delta transfer.
+    pub checksum: bool,
+    /// Remove files missing on the source side when mirroring.
+    pub delete: bool,
+    pub include_hidden: bool,
+    /// Only show the plan.
+    pub dry_run: bool,
+    /// Wait for `StartSync` after the plan is shown.
+    pub confirm: bool,
+    #[serde(skip)]
+    pub exit_when_done: bool,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            mode: SyncMode::Upload,
+            conflict: ConflictRule::Newer,
+            checksum: false,
+            delete: false,
+            include_hidden: false,
+            dry_run: false,
+            confirm: false,
+            exit_when_done: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncAction {
+    Upload,
+    Download,
+    DeleteLocal,
+    DeleteRemote,
+    /// Changed on both sides and left alone.
+    Conflict,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncItem {
+    pub name: String,
+    pub action: SyncAction,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FileState {
+    pub size: u64,
+    pub modified_time: u64,
+}
+
+impl FileState {
+    fn same(&self, other: &FileState) -> bool {
+        self.size == other.size
+            && self.modified_time.abs_diff(other.modified_time) <= MTIME_TOLERANCE
+    }
+}
+
+type Tree = BTreeMap<String, FileState>;
+
+// Files only, keyed by their relative path with `/`.
+fn to_tree(entries: &[FileEntry]) -> Tree {
+    entries
+        .iter()
+        .filter(|e| {
+            let t = e.entry_type.value();
+            t == FileType::File as i32 || t == FileType::FileLink as i32
+        })
+        .map(|e| {
+            (
+                e.name.replace('\\', "/"),
+                FileState {
+                    size: e.size,
+                    modified_time: e.modified_time,
+                },
+            )
+        })
+        .collect()
+}
+
+/// The trees of the last successful two-way sync, to tell deletions from creations.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    files: Tree,
+}
+
+impl SyncState {
+    fn path(peer_id: &str, local: &str, remote: &str) -> PathBuf {
+        let hash = Sha256::digest(format!("{peer_id}\n{local}\n{remote}").as_bytes());
+        let name: String = hash[..16].iter().map(|b| format!("{:02x}", b)).collect();
+        Config::path("sync").join(format!("{name}.json"))
+    }
+
+    fn load(peer_id: &str, local: &str, remote: &str) -> Option<Self> {
+        let data = std::fs::read(Self::path(peer_id, local, remote)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    fn store(&self, peer_id: &str, local: &str, remote: &str) {
+        let path = Self::path(peer_id, local, remote);
+        let res = path
+            .parent()
+            .map(std::fs::create_dir_all)
+            .transpose()
+            .and_then(|_| std::fs::write(&path, serde_json::to_vec(self).unwrap_or_default()));
+        if let Err(err) = res {
+            log::error!("Failed to store sync state {}: {}", path.display(), err);
+        }
+    }
+}
+
+fn resolve(conflict: ConflictRule, local: &FileState, remote: &FileState) -> SyncAction {
+    match conflict {
+        ConflictRule::Local => SyncAction::Upload,
+        ConflictRule::Remote => SyncAction::Download,
+        ConflictRule::Newer if local.modified_time > remote.modified_time + MTIME_TOLERANCE => {
+            SyncAction::Upload
+        }
+        ConflictRule::Newer if remote.modified_time > local.modified_time + MTIME_TOLERANCE => {
+            SyncAction::Download
+        }
+        _ => SyncAction::Conflict,
+    }
+}
+
+pub fn plan(local: &Tree, remote: &Tree, base: Option<&Tree>, opts: &SyncOptions) -> Vec<SyncItem> {
+    let names: BTreeSet<&String> = local.keys().chain(remote.keys()).collect();
+    let mut items = vec![];
+    for name in names {
+        let l = local.get(name);
+        let r = remote.get(name);
+        let b = base.and_then(|b| b.get(name));
+        let action = match (opts.mode, l, r) {
+            (_, None, None) => None,
+            (SyncMode::Upload, Some(l), Some(r)) => {
+                (opts.checksum || !l.same(r)).then_some(SyncAction::Upload)
+            }
+            (SyncMode::Upload, Some(_), None) => Some(SyncAction::Upload),
+            (SyncMode::Upload, None, Some(_)) => opts.delete.then_some(SyncAction::DeleteRemote),
+            (SyncMode::Download, Some(l), Some(r)) => {
+                (opts.checksum || !l.same(r)).then_some(SyncAction::Download)
+            }
+            (SyncMode::Download, None, Some(_)) => Some(SyncAction::Download),
+            (SyncMode::Download, Some(_), None) => opts.delete.then_some(SyncAction::DeleteLocal),
+            (SyncMode::TwoWay, Some(l), Some(r)) => {
+                if l.same(r) {
+                    None
+                } else {
+                    match b {
+                        Some(b) if b.same(r) => Some(SyncAction::Upload),
+                        Some(b) if b.same(l) => Some(SyncAction::Download),
+                        _ => Some(resolve(opts.conflict, l, r)),
+                    }
+                }
+            }
+            // A modification wins over a deletion.
+            (SyncMode::TwoWay, Some(l), None) => match b {
+                Some(b) if b.same(l) => Some(SyncAction::DeleteLocal),
+                _ => Some(SyncAction::Upload),
+            },
+            (SyncMode::TwoWay, None, Some(r)) => match b {
+                Some(b) if b.same(r) => Some(SyncAction::DeleteRemote),
+                _ => Some(SyncAction::Download),
+            },
+        };
+        if let Some(action) = action {
+            let size = match action {
+                SyncAction::Upload => l.map(|l| l.size),
+                SyncAction::Download => r.map(|r| r.size),
+                _ => None,
+            };
+            items.push(SyncItem {
+                name: name.clone(),
+                action,
+                size: size.unwrap_or_default(),
+            });
+        }
+    }
+    items
+}
+
+pub struct SyncJob {
+    pub id: i32,
+    peer_id: String,
+    local: String,
+    remote: String,
+    pub opts: SyncOptions,
+    local_tree: Option<Tree>,
+    remote_tree: Option<Tree>,
+    plan: Vec<SyncItem>,
+    started: bool,
+    next: usize,
+    // Sub job id and index in the plan.
+    current: Option<(i32, usize)>,
+    failed: Vec<usize>,
+    finished_size: u64,
+}
+
+impl SyncJob {
+    pub fn new(id: i32, peer_id: String, local: String, remote: String, opts: SyncOptions) -> Self {
+        Self {
+            id,
+            peer_id,
+            local,
+            remote,
+            opts,
+            local_tree: None,
+            remote_tree: None,
+            plan: vec![],
+            started: false,
+            next: 0,
+            current: None,
+            failed: vec![],
+            finished_size: 0,
+        }
+    }
+
+    pub fn local(&self) -> &str {
+        &self.local
+    }
+
+    pub fn remote(&self) -> &str {
+        &self.remote
+    }
+
+    pub fn set_local_files(&mut self, entries: &[FileEntry]) {
+        self.local_tree = Some(to_tree(entries));
+    }
+
+    pub fn set_remote_files(&mut self, entries: &[FileEntry]) {
+        self.remote_tree = Some(to_tree(entries));
+    }
+
+    /// Computes the plan once both trees are listed, returns it as json.
+    pub fn make_plan(&mut self) -> Option<String> {
+        let (Some(local), Some(remote)) = (&self.local_tree, &self.remote_tree) else {
+            return None;
+        };
+        let base = if self.opts.mode == SyncMode::TwoWay {
+            SyncState::load(&self.peer_id, &self.local, &self.remote).map(|s| s.files)
+        } else {
+            None
+        };
+        self.plan = plan(local, remote, base.as_ref(), &self.opts);
+        log::info!(
+            "sync job {}, {} -> {}, {} actions",
+            self.id,
+            self.local,
+            self.remote,
+            self.plan.len()
+        );
+        Some(serde_json::to_string(&self.plan).unwrap_or_default())
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    pub fn is_sub_job(&self, id: i32) -> bool {
+        self.current.map(|(sub, _)| sub) == Some(id)
+    }
+
+    pub fn current_sub_job(&self) -> Option<i32> {
+        self.current.map(|(sub, _)| sub)
+    }
+
+    pub fn finished(&self) -> (usize, u64) {
+        (self.next, self.finished_size)
+    }
+
+    /// The data of the next action, none when the plan is done.
+    pub fn next(&mut self, local_sep: &str, remote_sep: &str) -> Option<Data> {
+        self.started = true;
+        while self.next < self.plan.len() {
+            let index = self.next;
+            self.next += 1;
+            let item = &self.plan[index];
+            let local = format!(
+                "{}{}{}",
+                self.local,
+                local_sep,
+                item.name.replace('/', local_sep)
+            );
+            let remote = format!(
+                "{}{}{}",
+                self.remote,
+                remote_sep,
+                item.name.replace('/', remote_sep)
+            );
+            let id = fs::get_next_job_id();
+            let hidden = self.opts.include_hidden;
+            let data = match item.action {
+                SyncAction::Upload => {
+                    Data::SendFiles((id, fs::JobType::Generic, local, remote, 0, hidden, false))
+                }
+                SyncAction::Download => {
+                    Data::SendFiles((id, fs::JobType::Generic, remote, local, 0, hidden, true))
+                }
+                SyncAction::DeleteLocal => Data::RemoveFile((id, local, 0, false)),
+                SyncAction::DeleteRemote => Data::RemoveFile((id, remote, 0, true)),
+                SyncAction::Conflict => continue,
+            };
+            self.current = Some((id, index));
+            return Some(data);
+        }
+        self.current = None;
+        None
+    }
+
+    pub fn on_sub_job_done(&mut self, err: Option<String>) {
+        if let Some((id, index)) = self.current.take() {
+            let item = &self.plan[index];
+            if let Some(err) = err {
+                log::error!(
+                    "sync job {}, {:?} {} failed: {}",
+                    id,
+                    item.action,
+                    item.name,
+                    err
+                );
+                self.failed.push(index);
+            } else {
+                self.finished_size += item.size;
+            }
+        }
+    }
+
+    /// Stores the two-way state and returns the error of the job if any.
+    pub fn finish(&self) -> Option<String> {
+        let conflicts = self
+            .plan
+            .iter()
+            .filter(|i| i.action == SyncAction::Conflict)
+            .count();
+        if self.opts.mode == SyncMode::TwoWay && !self.opts.dry_run {
+            if let (Some(local), Some(remote)) = (&self.local_tree, &self.remote_tree) {
+                self.state(local, remote)
+                    .store(&self.peer_id, &self.local, &self.remote);
+            }
+        }
+        let summary = format!(
+            "{} actions, {} failed, {} conflicts",
+            self.plan.len() - conflicts,
+            self.failed.len(),
+            conflicts
+        );
+        log::info!("sync job {} done, {}", self.id, summary);
+        if self.opts.exit_when_done {
+            println!("{}", summary);
+        }
+        if self.failed.is_empty() {
+            None
+        } else {
+            Some(summary)
+        }
+    }
+
+    // Files which are the same on both sides after the plan, failed and conflicting ones
+    // are left out to be compared again next time.
+    fn state(&self, local: &Tree, remote: &Tree) -> SyncState {
+        let mut files = local.clone();
+        files.retain(|name, l| remote.get(name).map(|r| l.same(r)).unwrap_or(false));
+        for (index, item) in self.plan.iter().enumerate() {
+            if self.failed.contains(&index) {
+                continue;
+            }
+            match item.action {
+                SyncAction::Upload => {
+                    if let Some(l) = local.get(&item.name) {
+                        files.insert(item.name.clone(), *l);
+                    }
+                }
+                SyncAction::Download => {
+                    if let Some(r) = remote.get(&item.name) {
+                        files.insert(item.name.clone(), *r);
+                    }
+                }
+                SyncAction::DeleteLocal | SyncAction::DeleteRemote | SyncAction::Conflict => {}
+            }
+        }
+        SyncState { files }
+    }
+}
+
+/// `--sync <remote-id> <local-dir> <remote-dir> [options]` for scheduled runs.
+#[cfg(not(any(target_os = "android", target_os = "ios", feature = "cli")))]
+pub fn run_cli(args: &[String]) {
+    const USAGE: &str = "Usage: --sync <remote-id> <local-dir> <remote-dir> \
+        [--mode upload|download|two-way] [--conflict newer|local|remote|skip] \
+        [--checksum] [--delete] [--hidden] [--dry-run] [--password <password>]";
+    if args.len() < 3 {
+        println!("{}", USAGE);
+        return;
+    }
+    let mut opts = SyncOptions {
+        exit_when_done: true,
+        ..Default::default()
+    };
+    let mut password = None;
+    let mut i = 3;
+    while i < args.len() {
+        let value = args.get(i + 1).map(|v| format!("\"{}\"", v));
+        match args[i].as_str() {
+            "--mode" => match value.and_then(|v| serde_json::from_str(&v).ok()) {
+                Some(mode) => opts.mode = mode,
+                None => return println!("{}", USAGE),
+            },
+            "--conflict" => match value.and_then(|v| serde_json::from_str(&v).ok()) {
+                Some(conflict) => opts.conflict = conflict,
+                None => return println!("{}", USAGE),
+            },
+            "--password" => password = args.get(i + 1).cloned(),
+            "--checksum" => opts.checksum = true,
+            "--delete" => opts.delete = true,
+            "--hidden" => opts.include_hidden = true,
+            "--dry-run" => opts.dry_run = true,
+            _ => return println!("{}", USAGE),
+        }
+        i += if ["--mode", "--conflict", "--password"].contains(&args[i].as_str()) {
+            2
+        } else {
+            1
+        };
+    }
+    start_cli_session(
+        args[0].clone(),
+        args[1].clone(),
+        args[2].clone(),
+        opts,
+        password,
+    );
+}
+
+#[cfg(not(any(target_os = "android", target_os = "ios", feature = "cli")))]
+#[tokio::main(flavor = "current_thread")]
+async fn start_cli_session(
+    id: String,
+    local: String,
+    remote: String,
+    opts: SyncOptions,
+    password: Option<String>,
+) {
+    use crate::ui_session_interface::{io_loop, Session};
+    use hbb_common::{rendezvous_proto::ConnType, tokio};
+    #[cfg(feature = "flutter")]
+    type Handler = crate::flutter::FlutterHandler;
+    #[cfg(not(feature = "flutter"))]
+    type Handler = crate::ui::remote::SciterHandler;
+
+    let session: Session<Handler> = Session {
+        args: vec![
+            "--sync".to_owned(),
+            local,
+            remote,
+            serde_json::to_string(&opts).unwrap_or_default(),
+        ],
+        ..Default::default()
+    };
+    session.lc.write().unwrap().initialize(
+        id,
+        ConnType::FILE_TRANSFER,
+        None,
+        false,
+        None,
+        password,
+        None,
+    );
+    io_loop(session, 0).await;
+}
+
+/// The sync job of a session started by `run_cli`.
+pub fn from_args(args: &[String]) -> Option<(String, String, SyncOptions)> {
+    if args.len() != 4 || args[0] != "--sync" {
+        return None;
+    }
+    let mut opts: SyncOptions = serde_json::from_str(&args[3]).ok()?;
+    opts.exit_when_done = true;
+    Some((args[1].clone(), args[2].clone(), opts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(files: &[(&str, u64, u64)]) -> Tree {
+        files
+            .iter()
+            .map(|(name, size, modified_time)| {
+                (
+                    name.to_string(),
+                    FileState {
+                        size: *size,
+                        modified_time: *modified_time,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn actions(items: Vec<SyncItem>) -> Vec<(String, SyncAction)> {
+        items.into_iter().map(|i| (i.name, i.action)).collect()
+    }
+
+    #[test]
+    fn test_plan() {
+        let local = tree(&[("a", 1, 100), ("b", 2, 100), ("c", 3, 100), ("d/e", 4, 101)]);
+        let remote = tree(&[("a", 1, 100), ("b", 2, 200), ("f", 5, 100), ("d/e", 4, 100)]);
+        let mut opts = SyncOptions::default();
+        assert_eq!(
+            actions(plan(&local, &remote, None, &opts)),
+            vec![
+                ("b".to_owned(), SyncAction::Upload),
+                ("c".to_owned(), SyncAction::Upload),
+            ]
+        );
+        opts.mode = SyncMode::Download;
+        opts.delete = true;
+        assert_eq!(
+            actions(plan(&local, &remote, None, &opts)),
+            vec![
+                ("b".to_owned(), SyncAction::Download),
+                ("c".to_owned(), SyncAction::DeleteLocal),
+                ("f".to_owned(), SyncAction::Download),
+            ]
+        );
+
+        // "c" was deleted remotely, "f" was created remotely, "b" changed on both sides.
+        opts.mode = SyncMode::TwoWay;
+        let base = tree(&[("a", 1, 100), ("b", 2, 50), ("c", 3, 100)]);
+        assert_eq!(
+            actions(plan(&local, &remote, Some(&base), &opts)),
+            vec![
+                ("b".to_owned(), SyncAction::Download),
+                ("c".to_owned(), SyncAction::DeleteLocal),
+                ("f".to_owned(), SyncAction::Download),
+            ]
+        );
+        opts.conflict = ConflictRule::Skip;
+        assert_eq!(
+            actions(plan(&local, &remote, None, &opts)),
+            vec![
+                ("b".to_owned(), SyncAction::Conflict),
+                ("c".to_owned(), SyncAction::Upload),
+                ("f".to_owned(), SyncAction::Download),
+            ]
+        );
+    }
+}
@@ -174,4 +174,26 @@ pub trait FileManager: Interface {
     fn rename_file(&self, act_id: i32, path: String, new_name: String, is_remote: bool) {
         self.send(Data::RenameFile((act_id, path, new_name, is_remote)));
     }
+
+    fn sync_dir(&self, id: i32, local: String, remote: String, options: String) {
+        match serde_json::from_str(&options) {
+            Ok(options) => self.send(Data::SyncDir((id, local, remote, options))),
+            Err(err) => log::error!("Invalid sync options {}: {}", options, err),
+        }
+    }
+
+    fn start_sync(&self, id: i32) {
+        self.send(Data::StartSync(id));
+    }
+
+    fn search_remote(&self, id: i32, path: String, options: String) {
+        match serde_json::from_str(&options) {
+            Ok(options) => self.send(Data::SearchFiles((id, path, options))),
+            Err(err) => log::error!("Invalid search options {}: {}", options, err),
+        }
+    }
+
+    fn preview_remote(&self, id: i32, path: String, max_size: i32, include_hidden: bool) {
+        self.send(Data::PreviewFile((id, path, max_size, include_hidden)));
+    }
 }
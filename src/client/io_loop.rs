@@ -4,10 +4,14 @@ use crate::clipboard::{update_clipboard, ClipboardSide};
 use crate::{audio_service, clipboard::CLIPBOARD_INTERVAL, ConnInner, CLIENT_SERVER};
 use crate::{
     client::{
-        self, new_voice_call_request, Client, Data, Interface, MediaData, MediaSender,
+        self,
+        file_sync::{self, SyncMode},
+        new_voice_call_request, transfer_queue, Client, Data, Interface, MediaData, MediaSender,
         QualityStatus, MILLI1, SEC30,
     },
     common::get_default_sound_input,
+    ext_msg::{self, Ext},
+    file_archive, file_delta, file_meta, file_verify,
     ui_session_interface::{InvokeUiSession, Session},
 };
 #[cfg(feature = "unix-file-copy-paste")]
@@ -22,7 +26,8 @@ use crossbeam_queue::ArrayQueue;
 use hbb_common::tokio::sync::mpsc::error::TryRecvError;
 use hbb_common::{
     allow_err,
-    config::{self, LocalConfig, PeerConfig, TransferSerde},
+    anyhow::anyhow,
+    config::{self, LocalConfig, PeerConfig},
     fs::{
         self, can_enable_overwrite_detection, get_job, get_string, new_send_confirm,
         DigestCheckResult, RemoveJobMeta,
@@ -43,7 +48,7 @@ use hbb_common::{
 use hbb_common::{tokio::sync::Mutex as TokioMutex, ResultType};
 use scrap::CodecFormat;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ffi::c_void,
     num::NonZeroI64,
     path::PathBuf,
@@ -63,9 +68,19 @@ pub struct Remote<T: InvokeUiSession> {
     voice_call_request_timestamp: Option<NonZeroI64>,
     read_jobs: Vec<fs::TransferJob>,
     write_jobs: Vec<fs::TransferJob>,
+    file_archives: file_archive::ArchiveReads,
+    archive_writes: HashMap<i32, file_archive::ArchiveWrite>,
     remove_jobs: HashMap<i32, RemoveJob>,
+    file_deltas: HashMap<(i32, i32), FileDelta>,
+    file_syncs: HashMap<i32, file_sync::SyncJob>,
+    file_compression: crate::file_compress::ReadJobsCompression,
+    file_throttle: crate::file_throttle::FileThrottle,
+    write_digests: file_verify::WriteDigests,
+    write_metas: file_meta::WriteMetas,
+    file_verify_jobs: HashMap<i32, VerifyJob>,
     timer: crate::RustDeskInterval,
     last_update_jobs_status: (Instant, HashMap<i32, u64>),
+    last_save_jobs: Instant,
     is_connected: bool,
     first_frame: bool,
     #[cfg(any(target_os = "windows", feature = "unix-file-copy-paste"))]
@@ -79,12 +94,33 @@ pub struct Remote<T: InvokeUiSession> {
     last_record_state: bool,
 }
 
+// A download rebuilt from the local copy and the delta ops of the peer.
+struct FileDelta {
+    // The local copy, the file itself or the partial copy of an interrupted download.
+    basis: PathBuf,
+    path: PathBuf,
+    file_size: u64,
+    modified_time: u64,
+    applier: Option<file_delta::DeltaApplier>,
+}
+
+// The arguments of a transfer, to send the files which failed the verification again.
+struct VerifyJob {
+    r#type: fs::JobType,
+    path: String,
+    to: String,
+    include_hidden: bool,
+    is_remote: bool,
+    retries: usize,
+}
+
 #[derive(Default)]
 struct ParsedPeerInfo {
     platform: String,
     is_installed: bool,
     idd_impl: String,
     support_view_camera: bool,
+    ext_caps: HashSet<String>,
 }
 
 impl ParsedPeerInfo {
@@ -101,6 +137,9 @@ impl<T: InvokeUiSession> Remote<T> {
         receiver: mpsc::UnboundedReceiver<Data>,
         sender: mpsc::UnboundedSender<Data>,
     ) -> Self {
+        let mut file_throttle = crate::file_throttle::FileThrottle::default();
+        let (max_speed, priority) = handler.lc.read().unwrap().get_file_transfer_limit();
+        file_throttle.set_peer_limit(max_speed, priority);
         Self {
             handler,
             audio_sender: crate::client::start_audio_thread(),
@@ -108,9 +147,19 @@ impl<T: InvokeUiSession> Remote<T> {
             sender,
             read_jobs: Vec::new(),
             write_jobs: Vec::new(),
+            file_archives: Default::default(),
+            archive_writes: Default::default(),
             remove_jobs: Default::default(),
+            file_deltas: Default::default(),
+            file_syncs: Default::default(),
+            file_compression: Default::default(),
+            file_throttle,
+            write_digests: Default::default(),
+            write_metas: Default::default(),
+            file_verify_jobs: Default::default(),
             timer: crate::rustdesk_interval(time::interval(SEC30)),
             last_update_jobs_status: (Instant::now(), Default::default()),
+            last_save_jobs: Instant::now(),
             is_connected: false,
             first_frame: false,
             #[cfg(any(target_os = "windows", feature = "unix-file-copy-paste"))]
@@ -269,8 +318,21 @@ impl<T: InvokeUiSession> Remote<T> {
                                 self.handler.msgbox("error", "Connection Error", "Timeout", "");
                                 break;
                             }
-                            if !self.read_jobs.is_empty() {
-                                if let Err(err) = fs::handle_read_jobs(&mut self.read_jobs, &mut peer).await {
+                            if !self.read_jobs.is_empty() || !self.file_archives.is_empty() {
+                                if !self.file_throttle.ready() {
+                                    continue;
+                                }
+                                if !self.read_jobs.is_empty() {
+                                    let res = self.file_compression.handle_read_jobs(&mut self.read_jobs, &mut peer).await;
+                                    self.file_throttle.consume(self.file_compression.take_sent());
+                                    if let Err(err) = res {
+                                        self.handler.msgbox("error", "Connection Error", &err.to_string(), "");
+                                        break;
+                                    }
+                                }
+                                let res = self.file_archives.handle(&mut peer).await;
+                                self.file_throttle.consume(self.file_archives.take_sent());
+                                if let Err(err) = res {
                                     self.handler.msgbox("error", "Connection Error", &err.to_string(), "");
                                     break;
                                 }
@@ -402,6 +464,10 @@ impl<T: InvokeUiSession> Remote<T> {
     }
 
     fn handle_job_status(&mut self, id: i32, file_num: i32, err: Option<String>) {
+        self.file_verify_jobs.remove(&id);
+        if self.on_sync_job_status(id, &err) {
+            return;
+        }
         if let Some(job) = self.remove_jobs.get_mut(&id) {
             if job.no_confirm {
                 let file_num = (file_num + 1) as usize;
@@ -552,7 +618,27 @@ impl<T: InvokeUiSession> Remote<T> {
             }
             Data::SendFiles((id, r#type, path, to, file_num, include_hidden, is_remote)) => {
                 log::info!("send files, is remote {}", is_remote);
+                if r#type == fs::JobType::Generic {
+                    self.file_verify_jobs.entry(id).or_insert(VerifyJob {
+                        r#type,
+                        path: path.clone(),
+                        to: to.clone(),
+                        include_hidden,
+                        is_remote,
+                        retries: 0,
+                    });
+                }
                 let od = can_enable_overwrite_detection(self.handler.lc.read().unwrap().version);
+                // Resumed or retried jobs go on file by file.
+                let archive = if r#type == fs::JobType::Generic
+                    && file_num == 0
+                    && self.peer_info.ext_caps.contains(ext_msg::CAP_FILE_ARCHIVE)
+                {
+                    let lc = self.handler.lc.read().unwrap();
+                    file_archive::Format::from_option(&lc.get_option(file_archive::OPTION_ARCHIVE))
+                } else {
+                    None
+                };
                 if is_remote {
                     log::debug!("New job {}, write to {} from remote {}", id, to, path);
                     let to = match r#type {
@@ -572,10 +658,19 @@ impl<T: InvokeUiSession> Remote<T> {
                         Vec::new(),
                         od,
                     ));
+                    if let Some(format) = archive {
+                        // The peer replies with the archive mode if the path is a directory.
+                        allow_err!(peer.send(&file_archive::new_request(id, format)).await);
+                    }
                     allow_err!(
                         peer.send(&fs::new_send(id, r#type, path, file_num, include_hidden))
                             .await
                     );
+                } else if let Some(format) =
+                    archive.filter(|_| std::path::Path::new(&path).is_dir())
+                {
+                    self.start_archive_read(id, path, to, format, include_hidden, peer)
+                        .await;
                 } else {
                     match fs::TransferJob::new_read(
                         id,
@@ -591,6 +686,17 @@ impl<T: InvokeUiSession> Remote<T> {
                             self.handle_job_status(id, -1, Some(err.to_string()));
                         }
                         Ok(job) => {
+                            self.file_compression.set_verify(
+                                r#type == fs::JobType::Generic
+                                    && self.peer_info.ext_caps.contains(ext_msg::CAP_FILE_VERIFY)
+                                    && self
+                                        .handler
+                                        .lc
+                                        .read()
+                                        .unwrap()
+                                        .get_option(file_verify::OPTION_VERIFY)
+                                        == "Y",
+                            );
                             log::debug!(
                                 "New job {}, read {} to remote {}, {} files",
                                 id,
@@ -598,6 +704,13 @@ impl<T: InvokeUiSession> Remote<T> {
                                 to,
                                 job.files().len()
                             );
+                            let meta = if r#type == fs::JobType::Generic
+                                && self.peer_info.ext_caps.contains(ext_msg::CAP_FILE_META)
+                            {
+                                file_meta::new_meta(id, &path, include_hidden)
+                            } else {
+                                None
+                            };
                             self.handler.update_folder_files(
                                 job.id(),
                                 job.files(),
@@ -621,9 +734,24 @@ impl<T: InvokeUiSession> Remote<T> {
                                 peer.send(&fs::new_receive(id, to, file_num, files, total_size))
                                     .await
                             );
+                            // Before the blocks, so it is there when the job is done.
+                            if let Some(meta) = meta {
+                                allow_err!(peer.send(&meta).await);
+                            }
                         }
                     }
                 }
+                if self.file_syncs.values().any(|job| job.is_sub_job(id)) {
+                    // Sync jobs replace changed files without asking.
+                    for job in self
+                        .write_jobs
+                        .iter_mut()
+                        .chain(self.read_jobs.iter_mut())
+                        .filter(|job| job.id() == id)
+                    {
+                        job.set_overwrite_strategy(Some(true));
+                    }
+                }
             }
             Data::AddJob((id, r#type, path, to, file_num, include_hidden, is_remote)) => {
                 let od = can_enable_overwrite_detection(self.handler.lc.read().unwrap().version);
@@ -647,6 +775,12 @@ impl<T: InvokeUiSession> Remote<T> {
                     );
                     job.is_last_job = true;
                     self.write_jobs.push(job);
+                    // The files received before the session dropped are verified too.
+                    let digests =
+                        transfer_queue::saved_digests(&self.handler.load_config(), &path, &to);
+                    if !digests.is_empty() {
+                        self.write_digests.set(id, digests);
+                    }
                 } else {
                     match fs::TransferJob::new_read(
                         id,
@@ -761,6 +895,30 @@ impl<T: InvokeUiSession> Remote<T> {
                         if remember {
                             job.set_overwrite_strategy(Some(need_override));
                         }
+                        if need_override {
+                            if let (fs::DataSource::FilePath(p), Some(file)) =
+                                (&job.data_source, job.files().get(file_num as usize))
+                            {
+                                let digest = FileTransferDigest {
+                                    id,
+                                    file_num,
+                                    last_modified: file.modified_time,
+                                    file_size: file.size,
+                                    ..Default::default()
+                                };
+                                let path = get_string(&fs::TransferJob::join(p, &file.name));
+                                if Self::start_file_delta(
+                                    &mut self.file_deltas,
+                                    &self.sender,
+                                    self.peer_info.ext_caps.contains(ext_msg::CAP_FILE_DELTA),
+                                    &digest,
+                                    &path,
+                                    &path,
+                                ) {
+                                    return true;
+                                }
+                            }
+                        }
                         let mut msg = Message::new();
                         let mut file_action = FileAction::new();
                         let req = FileTransferSendConfirmRequest {
@@ -827,7 +985,20 @@ impl<T: InvokeUiSession> Remote<T> {
                     job.remove_download_file();
                 }
                 let _ = fs::remove_job(id, &mut self.read_jobs);
+                self.file_archives.remove(id);
+                self.archive_writes.remove(&id);
                 self.remove_jobs.remove(&id);
+                self.abort_file_deltas(id);
+                self.write_digests.remove(id);
+                self.write_metas.remove(id);
+                self.file_verify_jobs.remove(&id);
+                if let Some(sub_id) = self
+                    .file_syncs
+                    .remove(&id)
+                    .and_then(|job| job.current_sub_job())
+                {
+                    self.sender.send(Data::CancelJob(sub_id)).ok();
+                }
             }
             Data::RemoveDir((id, path)) => {
                 let mut msg_out = Message::new();
@@ -886,6 +1057,80 @@ impl<T: InvokeUiSession> Remote<T> {
                     }
                 }
             }
+            Data::SyncDir((id, local, remote, options)) => {
+                self.start_sync_job(id, local, remote, options, peer).await;
+            }
+            Data::StartSync(id) => {
+                self.next_sync_action(id);
+            }
+            Data::FileTransferLimit((max_speed, priority)) => {
+                self.file_throttle.set_peer_limit(max_speed, priority);
+                self.send_file_transfer_limit(max_speed, priority, peer)
+                    .await;
+            }
+            Data::FileVerified((id, file_num, failed)) => {
+                let ext = Ext::FileVerifyResult {
+                    id,
+                    failed: failed.clone(),
+                };
+                allow_err!(peer.send(&ext_msg::new_message(&ext, &[])).await);
+                self.on_file_verified(id, file_num, failed);
+            }
+            Data::ArchiveWritten((id, err)) => {
+                if err.is_some() {
+                    // Stops the peer if the unpacking failed before the end.
+                    let mut msg_out = Message::new();
+                    let mut file_action = FileAction::new();
+                    file_action.set_cancel(FileTransferCancel {
+                        id,
+                        ..Default::default()
+                    });
+                    msg_out.set_file_action(file_action);
+                    allow_err!(peer.send(&msg_out).await);
+                }
+                self.handle_job_status(id, 0, err);
+            }
+            Data::SearchFiles((id, path, options)) => {
+                if !self.peer_info.ext_caps.contains(ext_msg::CAP_FILE_SEARCH) {
+                    log::warn!("The peer does not support file search");
+                    let fd = FileDirectory {
+                        id,
+                        path,
+                        ..Default::default()
+                    };
+                    self.handler.update_search_results(
+                        fd,
+                        true,
+                        crate::file_search::ERR_NOT_SUPPORTED.to_owned(),
+                    );
+                    return true;
+                }
+                let ext = Ext::FileSearch { id, path, options };
+                allow_err!(peer.send(&ext_msg::new_message(&ext, &[])).await);
+            }
+            Data::PreviewFile((id, path, max_size, include_hidden)) => {
+                if !self.peer_info.ext_caps.contains(ext_msg::CAP_FILE_SEARCH) {
+                    log::warn!("The peer does not support file preview");
+                    self.handler.update_file_preview(
+                        id,
+                        path,
+                        Default::default(),
+                        crate::file_search::ERR_NOT_SUPPORTED.to_owned(),
+                    );
+                    return true;
+                }
+                let ext = Ext::FilePreview {
+                    id,
+                    path,
+                    max_size,
+                    include_hidden,
+                };
+                allow_err!(peer.send(&ext_msg::new_message(&ext, &[])).await);
+            }
+            Data::FileDeltaSignatures((id, file_num, res)) => {
+                self.send_file_delta_signatures(id, file_num, res, peer)
+                    .await;
+            }
             Data::RenameFile((id, path, new_name, is_remote)) => {
                 if is_remote {
                     let mut msg_out = Message::new();
@@ -1000,6 +1245,448 @@ impl<T: InvokeUiSession> Remote<T> {
         handler.job_progress(job.id(), file_num, speed, job.finished_size() as f64);
     }
 
+    // Asks the peer for the changed blocks only if the local copy is large enough.
+    fn start_file_delta(
+        file_deltas: &mut HashMap<(i32, i32), FileDelta>,
+        sender: &mpsc::UnboundedSender<Data>,
+        peer_support: bool,
+        digest: &FileTransferDigest,
+        basis: &str,
+        path: &str,
+    ) -> bool {
+        if !peer_support || !file_delta::is_delta_candidate(digest.file_size) {
+            return false;
+        }
+        let basis = PathBuf::from(basis);
+        match std::fs::metadata(&basis) {
+            Ok(m) if m.is_file() && file_delta::is_delta_candidate(m.len()) => {}
+            _ => return false,
+        }
+        let (id, file_num) = (digest.id, digest.file_num);
+        file_deltas.insert(
+            (id, file_num),
+            FileDelta {
+                basis: basis.clone(),
+                path: PathBuf::from(path),
+                file_size: digest.file_size,
+                modified_time: digest.last_modified,
+                applier: None,
+            },
+        );
+        let sender = sender.clone();
+        std::thread::spawn(move || {
+            let res = std::fs::metadata(&basis)
+                .map_err(|e| e.into())
+                .and_then(|m| {
+                    let block_size = file_delta::block_size(m.len());
+                    file_delta::signatures(&basis, block_size)
+                        .map(|signatures| (block_size, m.len(), signatures))
+                });
+            let res = match res {
+                Ok(res) => Some(res),
+                Err(err) => {
+                    log::error!(
+                        "Failed to get block signatures of {}: {}",
+                        basis.display(),
+                        err
+                    );
+                    None
+                }
+            };
+            sender
+                .send(Data::FileDeltaSignatures((id, file_num, res)))
+                .ok();
+        });
+        true
+    }
+
+    // Resumes an interrupted download from the partial copy it has written.
+    fn resume_file_delta(
+        file_deltas: &mut HashMap<(i32, i32), FileDelta>,
+        sender: &mpsc::UnboundedSender<Data>,
+        peer_support: bool,
+        digest: &FileTransferDigest,
+        path: &str,
+    ) -> bool {
+        transfer_queue::partial_file(path).map_or(false, |partial| {
+            Self::start_file_delta(file_deltas, sender, peer_support, digest, &partial, path)
+        })
+    }
+
+    async fn send_file_delta_signatures(
+        &mut self,
+        id: i32,
+        file_num: i32,
+        res: Option<(u32, u64, Vec<u8>)>,
+        peer: &mut Stream,
+    ) {
+        let Some(delta) = self.file_deltas.get_mut(&(id, file_num)) else {
+            return;
+        };
+        if let Some((block_size, basis_size, signatures)) = res {
+            match file_delta::DeltaApplier::new(&delta.basis, &delta.path, block_size) {
+                Ok(applier) => {
+                    delta.applier = Some(applier);
+                    let ext = Ext::FileBlockSignatures {
+                        id,
+                        file_num,
+                        block_size,
+                        basis_size,
+                    };
+                    allow_err!(peer.send(&ext_msg::new_message(&ext, &signatures)).await);
+                    return;
+                }
+                Err(err) => {
+                    log::error!("Failed to start delta of {}: {}", delta.path.display(), err);
+                }
+            }
+        }
+        self.file_delta_fallback(id, file_num, peer).await;
+    }
+
+    async fn handle_file_delta(
+        &mut self,
+        id: i32,
+        file_num: i32,
+        ops: &[u8],
+        done: bool,
+        error: String,
+        peer: &mut Stream,
+    ) {
+        let key = (id, file_num);
+        let Some(applier) = self
+            .file_deltas
+            .get_mut(&key)
+            .and_then(|delta| delta.applier.as_mut())
+        else {
+            return;
+        };
+        let res = if error.is_empty() {
+            applier.apply(ops)
+        } else {
+            Err(anyhow!(error))
+        };
+        if res.is_ok() && !done {
+            return;
+        }
+        let Some(delta) = self.file_deltas.remove(&key) else {
+            return;
+        };
+        let res = match (res, delta.applier) {
+            (Ok(()), Some(applier)) => applier.finish(delta.file_size, delta.modified_time),
+            (res, applier) => {
+                if let Some(applier) = applier {
+                    applier.abort();
+                }
+                res
+            }
+        };
+        match res {
+            Ok(()) => {
+                log::info!("Applied delta to {}", delta.path.display());
+                self.confirm_write_file(
+                    id,
+                    file_num,
+                    file_transfer_send_confirm_request::Union::Skip(true),
+                    peer,
+                )
+                .await;
+                self.update_jobs_status();
+            }
+            Err(err) => {
+                log::error!("Failed to apply delta to {}: {}", delta.path.display(), err);
+                self.file_delta_fallback(id, file_num, peer).await;
+            }
+        }
+    }
+
+    // Transfers the whole file instead.
+    async fn file_delta_fallback(&mut self, id: i32, file_num: i32, peer: &mut Stream) {
+        if let Some(applier) = self
+            .file_deltas
+            .remove(&(id, file_num))
+            .and_then(|delta| delta.applier)
+        {
+            applier.abort();
+        }
+        self.confirm_write_file(
+            id,
+            file_num,
+            file_transfer_send_confirm_request::Union::OffsetBlk(0),
+            peer,
+        )
+        .await;
+    }
+
+    async fn confirm_write_file(
+        &mut self,
+        id: i32,
+        file_num: i32,
+        union: file_transfer_send_confirm_request::Union,
+        peer: &mut Stream,
+    ) {
+        if let Some(job) = fs::get_job(id, &mut self.write_jobs) {
+            let req = FileTransferSendConfirmRequest {
+                id,
+                file_num,
+                union: Some(union),
+                ..Default::default()
+            };
+            job.confirm(&req);
+            allow_err!(peer.send(&new_send_confirm(req)).await);
+        }
+    }
+
+    fn abort_file_deltas(&mut self, id: i32) {
+        let keys: Vec<_> = self
+            .file_deltas
+            .keys()
+            .filter(|(job_id, _)| *job_id == id)
+            .cloned()
+            .collect();
+        for key in keys {
+            if let Some(applier) = self.file_deltas.remove(&key).and_then(|d| d.applier) {
+                applier.abort();
+            }
+        }
+    }
+
+    async fn start_sync_job(
+        &mut self,
+        id: i32,
+        local: String,
+        remote: String,
+        options: file_sync::SyncOptions,
+        peer: &mut Stream,
+    ) {
+        let include_hidden = options.include_hidden;
+        let mut job =
+            file_sync::SyncJob::new(id, self.handler.get_id(), local, remote.clone(), options);
+        match fs::get_recursive_files(job.local(), include_hidden) {
+            Ok(entries) => job.set_local_files(&entries),
+            // The local tree is created by the sync.
+            Err(_)
+                if job.opts.mode != SyncMode::Upload
+                    && !std::path::Path::new(job.local()).exists() =>
+            {
+                job.set_local_files(&[])
+            }
+            Err(err) => {
+                self.handle_job_status(id, -1, Some(err.to_string()));
+                return;
+            }
+        }
+        self.file_syncs.insert(id, job);
+        let mut msg_out = Message::new();
+        let mut file_action = FileAction::new();
+        file_action.set_all_files(ReadAllFiles {
+            id,
+            path: remote,
+            include_hidden,
+            ..Default::default()
+        });
+        msg_out.set_file_action(file_action);
+        allow_err!(peer.send(&msg_out).await);
+    }
+
+    fn on_sync_listed(&mut self, id: i32) {
+        let Some(job) = self.file_syncs.get_mut(&id) else {
+            return;
+        };
+        let Some(plan) = job.make_plan() else {
+            return;
+        };
+        let (dry_run, confirm) = (job.opts.dry_run, job.opts.confirm);
+        self.handler.update_sync_plan(id, plan);
+        if dry_run {
+            self.finish_sync_job(id, None);
+        } else if !confirm {
+            self.next_sync_action(id);
+        }
+    }
+
+    fn next_sync_action(&mut self, id: i32) {
+        let local_sep = self.handler.get_path_sep(false);
+        let remote_sep = self.handler.get_path_sep(true);
+        let Some(job) = self.file_syncs.get_mut(&id) else {
+            return;
+        };
+        if job.current_sub_job().is_some() {
+            return;
+        }
+        match job.next(local_sep, remote_sep) {
+            Some(data) => {
+                self.sender.send(data).ok();
+            }
+            None => self.finish_sync_job(id, None),
+        }
+    }
+
+    // Returns true if the status is of a sync job itself, which is reported by the sync job.
+    fn on_sync_job_status(&mut self, id: i32, err: &Option<String>) -> bool {
+        if let Some(job) = self.file_syncs.get_mut(&id) {
+            if !job.is_started() && err.is_some() {
+                // Failed to list the remote tree, which the upload creates.
+                if job.opts.mode == SyncMode::Upload {
+                    job.set_remote_files(&[]);
+                    self.on_sync_listed(id);
+                } else {
+                    self.finish_sync_job(id, err.clone());
+                }
+            }
+            return true;
+        }
+        let Some(job) = self.file_syncs.values_mut().find(|job| job.is_sub_job(id)) else {
+            return false;
+        };
+        job.on_sub_job_done(err.clone());
+        let sync_id = job.id;
+        let (finished, finished_size) = job.finished();
+        self.handler
+            .job_progress(sync_id, finished as _, 0., finished_size as _);
+        self.next_sync_action(sync_id);
+        false
+    }
+
+    fn finish_sync_job(&mut self, id: i32, err: Option<String>) {
+        let Some(job) = self.file_syncs.remove(&id) else {
+            return;
+        };
+        match err.or_else(|| job.finish()) {
+            Some(err) => self.handler.job_error(id, err, -1),
+            None => self.handler.job_done(id, -1),
+        }
+        if job.opts.exit_when_done {
+            self.sender.send(Data::Close).ok();
+        }
+    }
+
+    async fn start_archive_read(
+        &mut self,
+        id: i32,
+        path: String,
+        to: String,
+        format: file_archive::Format,
+        include_hidden: bool,
+        peer: &mut Stream,
+    ) {
+        match file_archive::ArchiveRead::new(id, &path, format, include_hidden) {
+            Err(err) => {
+                self.handle_job_status(id, -1, Some(err.to_string()));
+            }
+            Ok(job) => {
+                log::debug!(
+                    "New archive job {}, read {} to remote {}, {} files",
+                    id,
+                    path,
+                    to,
+                    job.files().len()
+                );
+                self.handler
+                    .update_folder_files(id, &job.entries(), path, true, true);
+                let msg_out = fs::new_receive(id, to, 0, vec![], job.total_size());
+                self.file_archives.push(job);
+                self.timer = crate::rustdesk_interval(time::interval(MILLI1));
+                allow_err!(peer.send(&file_archive::new_request(id, format)).await);
+                allow_err!(peer.send(&msg_out).await);
+            }
+        }
+    }
+
+    // The peer sends the directory as an archive, unpacked instead of the write job.
+    fn start_archive_write(&mut self, fd: FileDirectory, format: file_archive::Format) {
+        let Some(job) = fs::remove_job(fd.id, &mut self.write_jobs) else {
+            return;
+        };
+        if let fs::DataSource::FilePath(p) = &job.data_source {
+            let apply_ownership = self
+                .handler
+                .lc
+                .read()
+                .unwrap()
+                .get_option(file_meta::OPTION_APPLY_OWNERSHIP)
+                == "Y";
+            let write = file_archive::ArchiveWrite::new(p, format, apply_ownership);
+            self.archive_writes.insert(fd.id, write);
+            self.handler
+                .update_folder_files(fd.id, &fd.entries, fd.path, false, true);
+        }
+    }
+
+    fn write_archive_block(&mut self, block: FileTransferBlock) {
+        if let Some(write) = self.archive_writes.get(&block.id) {
+            if !write.write(block.data.into()) {
+                self.finish_archive_write(block.id);
+            }
+        }
+        self.update_jobs_status();
+    }
+
+    // Waits for the unpacking off the loop, the job ends with `Data::ArchiveWritten`.
+    fn finish_archive_write(&mut self, id: i32) {
+        if let Some(write) = self.archive_writes.remove(&id) {
+            let sender = self.sender.clone();
+            std::thread::spawn(move || {
+                let err = write.finish().err().map(|err| err.to_string());
+                sender.send(Data::ArchiveWritten((id, err))).ok();
+            });
+        }
+    }
+
+    // Hashes the written files off the io loop, the job is done once they are checked.
+    fn verify_written_files(&self, id: i32, file_num: i32, files: Vec<(i32, PathBuf, Vec<u8>)>) {
+        let sender = self.sender.clone();
+        std::thread::spawn(move || {
+            let failed = file_verify::verify(files);
+            sender.send(Data::FileVerified((id, file_num, failed))).ok();
+        });
+    }
+
+    // Off the io loop too, it is a syscall or more per entry of the job.
+    fn apply_file_metas(&self, path: PathBuf, metas: Vec<file_meta::Meta>) {
+        let apply_ownership = self
+            .handler
+            .lc
+            .read()
+            .unwrap()
+            .get_option(file_meta::OPTION_APPLY_OWNERSHIP)
+            == "Y";
+        std::thread::spawn(move || file_meta::apply(&path, metas, apply_ownership));
+    }
+
+    fn on_file_verified(&mut self, id: i32, file_num: i32, failed: Vec<i32>) {
+        let Some(first) = failed.first().cloned() else {
+            self.handler.job_verified(id, true);
+            self.handle_job_status(id, file_num, None);
+            return;
+        };
+        if let Some(job) = self.file_verify_jobs.get_mut(&id) {
+            if job.retries < file_verify::MAX_RETRIES {
+                job.retries += 1;
+                log::warn!(
+                    "Job {} failed the verification of files {:?}, retry {}",
+                    id,
+                    failed,
+                    job.retries
+                );
+                self.sender
+                    .send(Data::SendFiles((
+                        id,
+                        job.r#type,
+                        job.path.clone(),
+                        job.to.clone(),
+                        first,
+                        job.include_hidden,
+                        job.is_remote,
+                    )))
+                    .ok();
+                return;
+            }
+        }
+        self.handler.job_verified(id, false);
+        self.handle_job_status(id, first, Some(file_verify::ERR_VERIFY_FAILED.to_owned()));
+    }
+
     fn update_jobs_status(&mut self) {
         let elapsed = self.last_update_jobs_status.0.elapsed().as_millis() as i32;
         if elapsed >= 1000 {
@@ -1019,27 +1706,45 @@ impl<T: InvokeUiSession> Remote<T> {
                     &mut self.handler,
                 );
             }
+            let mut archives = self.file_archives.progress();
+            archives.extend(
+                self.archive_writes
+                    .iter()
+                    .map(|(id, w)| (*id, w.finished_size())),
+            );
+            for (id, finished_size) in archives {
+                let last = self.last_update_jobs_status.1.insert(id, finished_size);
+                let transferred = finished_size.saturating_sub(last.unwrap_or_default());
+                let speed = transferred as f64 / (elapsed as f64 / 1000.);
+                self.handler
+                    .job_progress(id, 0, speed, finished_size as f64);
+            }
             self.last_update_jobs_status.0 = Instant::now();
         }
+        // Saved while the jobs run too, a dropped link may not end the session cleanly.
+        if self.last_save_jobs.elapsed() >= transfer_queue::SAVE_INTERVAL {
+            self.save_jobs();
+        }
     }
 
-    pub async fn sync_jobs_status_to_local(&mut self) -> bool {
-        log::info!("sync transfer job status");
+    fn save_jobs(&mut self) {
+        self.last_save_jobs = Instant::now();
         let mut config: PeerConfig = self.handler.load_config();
-        let mut transfer_metas = TransferSerde::default();
-        for job in self.read_jobs.iter() {
-            let json_str = serde_json::to_string(&job.gen_meta()).unwrap_or_default();
-            transfer_metas.read_jobs.push(json_str);
-        }
-        for job in self.write_jobs.iter() {
-            let json_str = serde_json::to_string(&job.gen_meta()).unwrap_or_default();
-            transfer_metas.write_jobs.push(json_str);
-        }
-        log::info!("meta: {:?}", transfer_metas);
+        let transfer_metas = transfer_queue::to_transfer_serde(
+            &self.read_jobs,
+            &self.write_jobs,
+            &self.write_digests,
+        );
         if config.transfer != transfer_metas {
+            log::debug!("meta: {:?}", transfer_metas);
             config.transfer = transfer_metas;
             self.handler.save_config(config);
         }
+    }
+
+    pub async fn sync_jobs_status_to_local(&mut self) -> bool {
+        log::info!("sync transfer job status");
+        self.save_jobs();
         true
     }
 
@@ -1284,6 +1989,32 @@ impl<T: InvokeUiSession> Remote<T> {
                         let peer_version = pi.version.clone();
                         let peer_platform = pi.platform.clone();
                         self.set_peer_info(&pi);
+                        if !self.peer_info.ext_caps.is_empty() {
+                            let caps = ext_msg::capabilities()
+                                .into_iter()
+                                .map(|c| c.to_owned())
+                                .collect();
+                            let msg = ext_msg::new_message(&Ext::Caps { caps }, &[]);
+                            allow_err!(peer.send(&msg).await);
+                            let (max_speed, priority) =
+                                self.handler.lc.read().unwrap().get_file_transfer_limit();
+                            if max_speed.is_some() || priority {
+                                self.send_file_transfer_limit(max_speed, priority, peer)
+                                    .await;
+                            }
+                            if self.peer_info.ext_caps.contains(ext_msg::CAP_FILE_VERIFY)
+                                && self
+                                    .handler
+                                    .lc
+                                    .read()
+                                    .unwrap()
+                                    .get_option(file_verify::OPTION_VERIFY)
+                                    == "Y"
+                            {
+                                let ext = Ext::FileVerifyOption { verify: true };
+                                allow_err!(peer.send(&ext_msg::new_message(&ext, &[])).await);
+                            }
+                        }
                         if self.handler.is_view_camera() {
                             if !self.check_view_camera_support(&peer_version, &peer_platform) {
                                 self.handler.lc.write().unwrap().handle_peer_info(&pi);
@@ -1355,6 +2086,18 @@ impl<T: InvokeUiSession> Remote<T> {
 
                         if self.handler.is_file_transfer() {
                             self.handler.load_last_jobs();
+                            if let Some((local, remote, options)) =
+                                file_sync::from_args(&self.handler.args)
+                            {
+                                self.sender
+                                    .send(Data::SyncDir((
+                                        fs::get_next_job_id(),
+                                        local,
+                                        remote,
+                                        options,
+                                    )))
+                                    .ok();
+                            }
                         }
 
                         self.is_connected = true;
@@ -1424,6 +2167,9 @@ impl<T: InvokeUiSession> Remote<T> {
                                 job.set_files(entries);
                             } else if let Some(job) = self.remove_jobs.get_mut(&fd.id) {
                                 job.files = entries;
+                            } else if let Some(job) = self.file_syncs.get_mut(&fd.id) {
+                                job.set_remote_files(&entries);
+                                self.on_sync_listed(fd.id);
                             }
                         }
                         Some(file_response::Union::Digest(digest)) => {
@@ -1488,7 +2234,21 @@ impl<T: InvokeUiSession> Remote<T> {
                                                         allow_err!(peer.send(&msg).await);
                                                     }
                                                     DigestCheckResult::NeedConfirm(digest) => {
-                                                        if let Some(overwrite) = overwrite_strategy
+                                                        if overwrite_strategy == Some(true)
+                                                            && Self::start_file_delta(
+                                                                &mut self.file_deltas,
+                                                                &self.sender,
+                                                                self.peer_info.ext_caps.contains(
+                                                                    ext_msg::CAP_FILE_DELTA,
+                                                                ),
+                                                                &digest,
+                                                                &write_path,
+                                                                &write_path,
+                                                            )
+                                                        {
+                                                            // Confirmed after the delta is applied.
+                                                        } else if let Some(overwrite) =
+                                                            overwrite_strategy
                                                         {
                                                             let req =
                                                                 FileTransferSendConfirmRequest {
@@ -1515,15 +2275,26 @@ impl<T: InvokeUiSession> Remote<T> {
                                                         }
                                                     }
                                                     DigestCheckResult::NoSuchFile => {
-                                                        let req = FileTransferSendConfirmRequest {
-                                                        id: digest.id,
-                                                        file_num: digest.file_num,
-                                                        union: Some(file_transfer_send_confirm_request::Union::OffsetBlk(0)),
-                                                        ..Default::default()
-                                                    };
-                                                        job.confirm(&req);
-                                                        let msg = new_send_confirm(req);
-                                                        allow_err!(peer.send(&msg).await);
+                                                        let resumed = Self::resume_file_delta(
+                                                            &mut self.file_deltas,
+                                                            &self.sender,
+                                                            self.peer_info
+                                                                .ext_caps
+                                                                .contains(ext_msg::CAP_FILE_DELTA),
+                                                            &digest,
+                                                            &write_path,
+                                                        );
+                                                        if !resumed {
+                                                            let req = FileTransferSendConfirmRequest {
+                                                            id: digest.id,
+                                                            file_num: digest.file_num,
+                                                            union: Some(file_transfer_send_confirm_request::Union::OffsetBlk(0)),
+                                                            ..Default::default()
+                                                        };
+                                                            job.confirm(&req);
+                                                            let msg = new_send_confirm(req);
+                                                            allow_err!(peer.send(&msg).await);
+                                                        }
                                                     }
                                                 },
                                                 Err(err) => {
@@ -1535,6 +2306,11 @@ impl<T: InvokeUiSession> Remote<T> {
                                 }
                             }
                         }
+                        Some(file_response::Union::Block(block))
+                            if self.archive_writes.contains_key(&block.id) =>
+                        {
+                            self.write_archive_block(block);
+                        }
                         Some(file_response::Union::Block(block)) => {
                             if let Some(job) = fs::get_job(block.id, &mut self.write_jobs) {
                                 if let Err(_err) = job.write(block).await {
@@ -1545,14 +2321,26 @@ impl<T: InvokeUiSession> Remote<T> {
                                 }
                             }
                         }
+                        Some(file_response::Union::Done(d))
+                            if self.archive_writes.contains_key(&d.id) =>
+                        {
+                            self.finish_archive_write(d.id);
+                        }
                         Some(file_response::Union::Done(d)) => {
                             let mut err: Option<String> = None;
                             let mut job_type = fs::JobType::Generic;
                             let mut printer_data = None;
+                            let mut verify_files = vec![];
                             if let Some(job) = fs::remove_job(d.id, &mut self.write_jobs) {
                                 job.modify_time();
                                 err = job.job_error();
                                 job_type = job.r#type;
+                                verify_files = self.write_digests.take(&job);
+                                if let Some((path, metas)) = self.write_metas.take(&job) {
+                                    if err.is_none() {
+                                        self.apply_file_metas(path, metas);
+                                    }
+                                }
                                 printer_data = match job.get_buf_data().await {
                                     Ok(d) => d,
                                     Err(e) => {
@@ -1562,6 +2350,11 @@ impl<T: InvokeUiSession> Remote<T> {
                                 };
                             }
                             match job_type {
+                                fs::JobType::Generic
+                                    if err.is_none() && !verify_files.is_empty() =>
+                                {
+                                    self.verify_written_files(d.id, d.file_num, verify_files);
+                                }
                                 fs::JobType::Generic => {
                                     self.handle_job_status(d.id, d.file_num, err);
                                 }
@@ -1599,10 +2392,19 @@ impl<T: InvokeUiSession> Remote<T> {
                             }
                         }
                         Some(file_response::Union::Error(e)) => {
+                            self.file_archives.remove(e.id);
+                            self.archive_writes.remove(&e.id);
+                            self.write_metas.remove(e.id);
                             let job_type = fs::remove_job(e.id, &mut self.write_jobs)
                                 .map(|j| j.r#type)
                                 .unwrap_or(fs::JobType::Generic);
                             match job_type {
+                                fs::JobType::Generic
+                                    if e.error == file_verify::ERR_VERIFY_FAILED =>
+                                {
+                                    // Failed on the peer, which removed the file.
+                                    self.on_file_verified(e.id, e.file_num, vec![e.file_num]);
+                                }
                                 fs::JobType::Generic => {
                                     self.handle_job_status(e.id, e.file_num, Some(e.error));
                                 }
@@ -1793,6 +2595,9 @@ impl<T: InvokeUiSession> Remote<T> {
                         #[cfg(feature = "flutter")]
                         self.handler.switch_back(&self.handler.get_id());
                     }
+                    Some(misc::Union::PluginRequest(p)) if ext_msg::is_ext(&p) => {
+                        self.handle_ext_msg(p, peer).await;
+                    }
                     #[cfg(all(feature = "flutter", feature = "plugin_framework"))]
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
                     Some(misc::Union::PluginRequest(p)) => {
@@ -1823,6 +2628,10 @@ impl<T: InvokeUiSession> Remote<T> {
                     _ => {}
                 },
                 Some(message::Union::TestDelay(t)) => {
+                    if !t.from_client {
+                        self.file_throttle
+                            .set_high_delay(t.last_delay >= crate::file_throttle::HIGH_DELAY);
+                    }
                     self.handler.handle_test_delay(t, peer).await;
                 }
                 Some(message::Union::AudioFrame(frame)) => {
@@ -1950,6 +2759,112 @@ impl<T: InvokeUiSession> Remote<T> {
                 .map(|v| v.as_bool())
                 .flatten()
                 .unwrap_or(false);
+            self.peer_info.ext_caps = ext_msg::peer_capabilities(&platform_additions);
+        }
+    }
+
+    async fn send_file_transfer_limit(
+        &self,
+        max_speed: Option<u64>,
+        priority: bool,
+        peer: &mut Stream,
+    ) {
+        if !self
+            .peer_info
+            .ext_caps
+            .contains(ext_msg::CAP_FILE_TRANSFER_LIMIT)
+        {
+            return;
+        }
+        let ext = Ext::FileTransferLimit {
+            max_speed,
+            priority,
+        };
+        allow_err!(peer.send(&ext_msg::new_message(&ext, &[])).await);
+    }
+
+    async fn handle_ext_msg(&mut self, p: PluginRequest, peer: &mut Stream) {
+        let Some((ext, tail)) = ext_msg::parse(&p) else {
+            return;
+        };
+        match ext {
+            Ext::Tiles { display } => {
+                let frames = match EncodedVideoFrames::parse_from_bytes(&tail) {
+                    Ok(frames) => frames,
+                    Err(e) => {
+                        log::error!("Failed to parse tile frames: {}", e);
+                        return;
+                    }
+                };
+                // The video thread is started by the first frame of the codec.
+                if let Some(thread) = self.video_threads.get(&(display as usize)) {
+                    thread
+                        .video_sender
+                        .send(MediaData::Tiles(Box::new(frames)))
+                        .ok();
+                }
+            }
+            Ext::CameraCapabilities { caps } => {
+                self.handler.update_camera_capabilities(caps);
+            }
+            Ext::FileSearchResults { done, error } => {
+                let fd = match FileDirectory::parse_from_bytes(&tail) {
+                    Ok(fd) => fd,
+                    Err(e) => {
+                        log::error!("Failed to parse search results: {}", e);
+                        return;
+                    }
+                };
+                #[cfg(not(windows))]
+                let mut fd = fd;
+                #[cfg(not(windows))]
+                {
+                    if self.handler.peer_platform() == "Windows" {
+                        fs::transform_windows_path(&mut fd.entries);
+                    }
+                }
+                self.handler.update_search_results(fd, done, error);
+            }
+            Ext::FilePreviewResult {
+                id,
+                path,
+                is_image,
+                truncated,
+                error,
+            } => {
+                let preview = crate::file_search::Preview {
+                    is_image,
+                    data: tail.to_vec(),
+                    truncated,
+                };
+                self.handler.update_file_preview(id, path, preview, error);
+            }
+            Ext::FileArchiveDir { format } => match FileDirectory::parse_from_bytes(&tail) {
+                Ok(fd) => self.start_archive_write(fd, format),
+                Err(e) => log::error!("Failed to parse the archive directory: {}", e),
+            },
+            Ext::FileDigest { id, file_num } => {
+                self.write_digests.add(id, file_num, tail.to_vec());
+            }
+            Ext::FileMeta { id, files } => {
+                self.write_metas.add(id, files);
+            }
+            Ext::FileVerifyResult { id, failed } => {
+                // Uploads checked by the peer, failures come as the job error.
+                if failed.is_empty() {
+                    self.handler.job_verified(id, true);
+                }
+            }
+            Ext::FileDeltaOps {
+                id,
+                file_num,
+                done,
+                error,
+            } => {
+                self.handle_file_delta(id, file_num, &tail, done, error, peer)
+                    .await;
+            }
+            _ => {}
         }
     }
 
@@ -0,0 +1,97 @@
+// The transfer queue of a peer, kept in its config so the jobs outlive the session.
+//
+// Every job is saved with the file it is at, a download with the digests of the files it
+// has received, every few seconds while the jobs run and when the session ends. On the next
+// connection to the peer the jobs are loaded paused and the ui offers to resume them. A job
+// resumes at the file it was at, and a large partial copy of a download is the basis of a
+// block delta, so only the part which is missing is sent again.
+
+use crate::file_verify::WriteDigests;
+use hbb_common::{
+    config::{PeerConfig, TransferSerde},
+    fs::{self, TransferJobMeta},
+    log,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::time::Duration;
+
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueuedJob {
+    #[serde(flatten)]
+    pub meta: TransferJobMeta,
+    /// The digests of the files received so far, by file number.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub digests: Vec<(i32, Vec<u8>)>,
+}
+
+fn to_json(job: &fs::TransferJob, digests: &WriteDigests) -> String {
+    let job = QueuedJob {
+        meta: job.gen_meta(),
+        digests: digests.get(job.id()).to_vec(),
+    };
+    serde_json::to_string(&job).unwrap_or_default()
+}
+
+pub fn to_transfer_serde(
+    read_jobs: &[fs::TransferJob],
+    write_jobs: &[fs::TransferJob],
+    digests: &WriteDigests,
+) -> TransferSerde {
+    let mut transfer = TransferSerde::default();
+    transfer
+        .read_jobs
+        .extend(read_jobs.iter().map(|job| to_json(job, digests)));
+    transfer
+        .write_jobs
+        .extend(write_jobs.iter().map(|job| to_json(job, digests)));
+    transfer
+}
+
+/// The digests saved with the download of `remote` to `to`.
+pub fn saved_digests(config: &PeerConfig, remote: &str, to: &str) -> Vec<(i32, Vec<u8>)> {
+    config
+        .transfer
+        .write_jobs
+        .iter()
+        .filter_map(|s| serde_json::from_str::<QueuedJob>(s).ok())
+        .find(|job| job.meta.is_remote && job.meta.remote == remote && job.meta.to == to)
+        .map(|job| job.digests)
+        .unwrap_or_default()
+}
+
+/// The partial copy left by an interrupted download of `path`.
+pub fn partial_file(path: &str) -> Option<String> {
+    let partial = format!("{}.download", path);
+    match std::fs::metadata(&partial) {
+        Ok(m) if m.is_file() => {
+            log::info!("Found {} bytes of {}", m.len(), path);
+            Some(partial)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_saved_digests() {
+        let mut config = PeerConfig::default();
+        config.transfer.write_jobs = vec![
+            // Saved before the digests were.
+            r#"{"id":1,"remote":"/a","to":"/b","show_hidden":false,"file_num":2,"is_remote":true}"#
+                .to_owned(),
+            r#"{"id":2,"remote":"/c","to":"/d","show_hidden":false,"file_num":3,"is_remote":true,"digests":[[0,[1,2]],[1,[3]]]}"#
+                .to_owned(),
+        ];
+        assert!(saved_digests(&config, "/a", "/b").is_empty());
+        assert_eq!(
+            saved_digests(&config, "/c", "/d"),
+            vec![(0, vec![1, 2]), (1, vec![3])]
+        );
+        assert!(saved_digests(&config, "/d", "/c").is_empty());
+    }
+}
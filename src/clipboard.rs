@@ -6,6 +6,9 @@ use std::{
     time::Duration,
 };
 
+#[cfg(target_os = "linux")]
+mod wayland;
+
 pub const CLIPBOARD_NAME: &'static str = "clipboard";
 #[cfg(feature = "unix-file-copy-paste")]
 pub const FILE_CLIPBOARD_NAME: &'static str = "file-clipboard";
@@ -237,9 +240,42 @@ pub fn update_clipboard(multi_clipboards: Vec<Clipboard>, side: ClipboardSide) {
     });
 }
 
+#[cfg(not(target_os = "android"))]
+enum Backend {
+    Arboard(arboard::Clipboard),
+    #[cfg(target_os = "linux")]
+    Wayland(wayland::WaylandClipboard),
+}
+
+#[cfg(not(target_os = "android"))]
+impl Backend {
+    fn get_formats(
+        &mut self,
+        formats: &[ClipboardFormat],
+    ) -> Result<Vec<ClipboardData>, arboard::Error> {
+        match self {
+            Backend::Arboard(b) => b.get_formats(formats),
+            #[cfg(target_os = "linux")]
+            Backend::Wayland(b) => b.get_formats(formats).map_err(|e| arboard::Error::Unknown {
+                description: e.to_string(),
+            }),
+        }
+    }
+
+    fn set_formats(&mut self, data: &[ClipboardData]) -> Result<(), arboard::Error> {
+        match self {
+            Backend::Arboard(b) => b.set_formats(data),
+            #[cfg(target_os = "linux")]
+            Backend::Wayland(b) => b.set_formats(data).map_err(|e| arboard::Error::Unknown {
+                description: e.to_string(),
+            }),
+        }
+    }
+}
+
 #[cfg(not(target_os = "android"))]
 pub struct ClipboardContext {
-    inner: arboard::Clipboard,
+    inner: Backend,
 }
 
 #[cfg(not(target_os = "android"))]
@@ -252,6 +288,17 @@ impl ClipboardContext {
             board = arboard::Clipboard::new()?;
         }
         #[cfg(target_os = "linux")]
+        if wayland::is_wayland() {
+            match wayland::WaylandClipboard::new() {
+                Ok(b) => {
+                    return Ok(ClipboardContext {
+                        inner: Backend::Wayland(b),
+                    })
+                }
+                Err(e) => log::warn!("Failed to create Wayland clipboard, {}", e),
+            }
+        }
+        #[cfg(target_os = "linux")]
         {
             let mut i = 1;
             loop {
@@ -275,7 +322,23 @@ impl ClipboardContext {
             }
         }
 
-        Ok(ClipboardContext { inner: board })
+        Ok(ClipboardContext {
+            inner: Backend::Arboard(board),
+        })
+    }
+
+    // The portal clipboard is only available after the remote desktop session is started,
+    // which may be later than the clipboard context is created.
+    #[cfg(target_os = "linux")]
+    fn try_switch_to_portal(&mut self) {
+        if matches!(self.inner, Backend::Arboard(_))
+            && scrap::wayland::pipewire::is_clipboard_enabled()
+        {
+            match wayland::WaylandClipboard::new() {
+                Ok(b) => self.inner = Backend::Wayland(b),
+                Err(e) => log::warn!("Failed to create Wayland clipboard, {}", e),
+            }
+        }
     }
 
     fn get_formats(&mut self, formats: &[ClipboardFormat]) -> ResultType<Vec<ClipboardData>> {
@@ -331,6 +394,8 @@ impl ClipboardContext {
         force: bool,
     ) -> ResultType<Vec<ClipboardData>> {
         let _lock = ARBOARD_MTX.lock().unwrap();
+        #[cfg(target_os = "linux")]
+        self.try_switch_to_portal();
         let data = self.get_formats(formats)?;
         if data.is_empty() {
             return Ok(data);
@@ -377,6 +442,8 @@ impl ClipboardContext {
 
     fn set(&mut self, data: &[ClipboardData]) -> ResultType<()> {
         let _lock = ARBOARD_MTX.lock().unwrap();
+        #[cfg(target_os = "linux")]
+        self.try_switch_to_portal();
         self.inner.set_formats(data)?;
         Ok(())
     }
@@ -804,6 +871,22 @@ pub mod clipboard_listener {
     pub struct ClipboardListener {
         subscribers: Arc<Mutex<HashMap<String, Sender<CallbackResult>>>>,
         handle: Option<(Shutdown, JoinHandle<()>)>,
+        // The Wayland clipboard notifies the selection changes itself.
+        #[cfg(target_os = "linux")]
+        wayland: Option<super::wayland::WaylandClipboard>,
+    }
+
+    #[cfg(target_os = "linux")]
+    fn start_wayland_listener(
+        subscribers: Arc<Mutex<HashMap<String, Sender<CallbackResult>>>>,
+    ) -> ResultType<super::wayland::WaylandClipboard> {
+        let clipboard = super::wayland::WaylandClipboard::new()?;
+        clipboard.set_on_change(Some(Arc::new(move || {
+            for tx in subscribers.lock().unwrap().values() {
+                tx.send(CallbackResult::Next).ok();
+            }
+        })));
+        Ok(clipboard)
     }
 
     pub fn subscribe(name: String, tx: Sender<CallbackResult>) -> ResultType<()> {
@@ -815,7 +898,22 @@ pub mod clipboard_listener {
             .unwrap()
             .insert(name.clone(), tx);
 
-        if listener_lock.handle.is_none() {
+        #[cfg(target_os = "linux")]
+        if listener_lock.wayland.is_none() && super::wayland::is_wayland() {
+            match start_wayland_listener(listener_lock.subscribers.clone()) {
+                Ok(clipboard) => {
+                    log::info!("Wayland clipboard listener started");
+                    listener_lock.wayland = Some(clipboard);
+                }
+                Err(e) => log::warn!("Failed to start Wayland clipboard listener, {}", e),
+            }
+        }
+        #[cfg(target_os = "linux")]
+        let is_wayland_listening = listener_lock.wayland.is_some();
+        #[cfg(not(target_os = "linux"))]
+        let is_wayland_listening = false;
+
+        if listener_lock.handle.is_none() && !is_wayland_listening {
             log::info!("Start clipboard listener thread");
             let handler = Handler {
                 subscribers: listener_lock.subscribers.clone(),
@@ -851,6 +949,10 @@ pub mod clipboard_listener {
             sub_lock.is_empty()
         };
         if is_empty {
+            #[cfg(target_os = "linux")]
+            if listener_lock.wayland.take().is_some() {
+                log::info!("Wayland clipboard listener stopped");
+            }
             if let Some((shutdown, h)) = listener_lock.handle.take() {
                 log::info!("Stop clipboard listener thread");
                 shutdown.signal();
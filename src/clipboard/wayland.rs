@@ -0,0 +1,808 @@
+// Clipboard on Wayland.
+//
+// Wayland clients can only access the clipboard when they have the keyboard focus, which RustDesk
+// never has. wlroots based compositors and KDE support the `wlr-data-control` protocol, which is
+// being replaced by `ext-data-control`, both allow privileged clients to manage the selection.
+// GNOME supports neither, but the remote desktop portal shares the clipboard with the session.
+//
+// The data is exchanged by mime types, `ClipboardData` is mapped to the mime types below.
+
+use arboard::{ClipboardData, ClipboardFormat};
+use dbus::{
+    arg::{PropMap, RefArg, Variant},
+    blocking::SyncConnection,
+    channel::Token,
+    message::MatchRule,
+    Path,
+};
+use hbb_common::{bail, log, ResultType};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::File,
+    io::{Read, Write},
+    os::unix::io::{AsFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::channel,
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use wayland_client::{
+    backend::ObjectId,
+    event_created_child,
+    globals::{registry_queue_init, GlobalListContents},
+    protocol::{wl_callback::WlCallback, wl_registry::WlRegistry, wl_seat::WlSeat},
+    Connection, Dispatch, Proxy, QueueHandle,
+};
+use wayland_protocols_wlr::data_control::v1::client::{
+    zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1},
+    zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+    zwlr_data_control_offer_v1::{self, ZwlrDataControlOfferV1},
+    zwlr_data_control_source_v1::{self, ZwlrDataControlSourceV1},
+};
+
+use ext_data_control::{
+    ext_data_control_device_v1::{self, ExtDataControlDeviceV1},
+    ext_data_control_manager_v1::ExtDataControlManagerV1,
+    ext_data_control_offer_v1::{self, ExtDataControlOfferV1},
+    ext_data_control_source_v1::{self, ExtDataControlSourceV1},
+};
+
+// The wayland-protocols crate in Cargo.lock doesn't have ext-data-control yet.
+#[allow(non_upper_case_globals, non_camel_case_types, unused)]
+mod ext_data_control {
+    use wayland_client;
+    use wayland_client::protocol::*;
+
+    pub mod __interfaces {
+        use wayland_client::protocol::__interfaces::*;
+        wayland_scanner::generate_interfaces!("src/clipboard/ext-data-control-v1.xml");
+    }
+    use self::__interfaces::*;
+
+    wayland_scanner::generate_client_code!("src/clipboard/ext-data-control-v1.xml");
+}
+
+const READ_TIMEOUT: Duration = Duration::from_millis(1000);
+
+const MIME_TEXT: &[&str] = &[
+    "text/plain;charset=utf-8",
+    "UTF8_STRING",
+    "text/plain",
+    "TEXT",
+    "STRING",
+];
+const MIME_HTML: &str = "text/html";
+const MIME_RTF: &[&str] = &["text/rtf", "application/rtf"];
+const MIME_PNG: &str = "image/png";
+const MIME_SVG: &str = "image/svg+xml";
+
+type MimeData = Vec<(String, Arc<Vec<u8>>)>;
+type OnChange = Arc<dyn Fn() + Send + Sync>;
+
+/// Use the Wayland backends instead of arboard.
+///
+/// arboard falls back to X11 (Xwayland) if the compositor doesn't support `wlr-data-control`,
+/// which only syncs the clipboard of X11 applications.
+pub fn is_wayland() -> bool {
+    std::env::var("WAYLAND_DISPLAY")
+        .map(|s| !s.is_empty())
+        .unwrap_or(false)
+        || scrap::wayland::pipewire::is_clipboard_enabled()
+}
+
+pub enum WaylandClipboard {
+    DataControl(DataControl),
+    Portal(PortalClipboard),
+}
+
+impl WaylandClipboard {
+    pub fn new() -> ResultType<Self> {
+        let data_control_err = match DataControl::new() {
+            Ok(c) => return Ok(Self::DataControl(c)),
+            Err(e) => e,
+        };
+        if scrap::wayland::pipewire::is_clipboard_enabled() {
+            return Ok(Self::Portal(PortalClipboard::new()?));
+        }
+        bail!(
+            "No Wayland clipboard is available, data control: {}",
+            data_control_err
+        );
+    }
+
+    #[inline]
+    pub fn is_portal(&self) -> bool {
+        matches!(self, Self::Portal(_))
+    }
+
+    pub fn get_formats(&mut self, formats: &[ClipboardFormat]) -> ResultType<Vec<ClipboardData>> {
+        match self {
+            Self::DataControl(c) => {
+                let (offer, mimes) = match c.selection() {
+                    Some(x) => x,
+                    None => return Ok(vec![]),
+                };
+                Ok(from_mime_data(formats, &mimes, |mime| c.read(&offer, mime)))
+            }
+            Self::Portal(c) => {
+                let mimes = c.mime_types();
+                Ok(from_mime_data(formats, &mimes, |mime| c.read(mime)))
+            }
+        }
+    }
+
+    pub fn set_formats(&mut self, data: &[ClipboardData]) -> ResultType<()> {
+        let data = to_mime_data(data)?;
+        match self {
+            Self::DataControl(c) => c.set(data),
+            Self::Portal(c) => c.set(data),
+        }
+    }
+
+    /// The callback is called when the selection is changed, including by ourselves.
+    pub fn set_on_change(&self, on_change: Option<OnChange>) {
+        match self {
+            Self::DataControl(c) => c.shared.lock().unwrap().on_change = on_change,
+            Self::Portal(c) => c.shared.lock().unwrap().on_change = on_change,
+        }
+    }
+}
+
+// Read the data written to the fd, until EOF. The source may never write or close the fd.
+fn read_fd(fd: OwnedFd) -> ResultType<Vec<u8>> {
+    let (tx, rx) = channel();
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        let res = File::from(fd).read_to_end(&mut buf).map(|_| buf);
+        tx.send(res).ok();
+    });
+    match rx.recv_timeout(READ_TIMEOUT) {
+        Ok(res) => Ok(res?),
+        Err(_) => bail!("Timeout reading the clipboard"),
+    }
+}
+
+fn write_fd(fd: OwnedFd, data: Arc<Vec<u8>>) {
+    std::thread::spawn(move || {
+        if let Err(e) = File::from(fd).write_all(&data) {
+            log::debug!("Failed to write the clipboard, {}", e);
+        }
+    });
+}
+
+fn find_mime_data<'a>(data: &'a MimeData, mime: &str) -> Option<&'a Arc<Vec<u8>>> {
+    data.iter().find(|(m, _)| m == mime).map(|(_, d)| d)
+}
+
+fn to_mime_data(data: &[ClipboardData]) -> ResultType<MimeData> {
+    let mut res = MimeData::new();
+    for d in data {
+        match d {
+            ClipboardData::Text(s) => {
+                let bytes = Arc::new(s.as_bytes().to_vec());
+                res.extend(MIME_TEXT.iter().map(|m| (m.to_string(), bytes.clone())));
+            }
+            ClipboardData::Html(s) => {
+                res.push((MIME_HTML.to_owned(), Arc::new(s.as_bytes().to_vec())));
+            }
+            ClipboardData::Rtf(s) => {
+                let bytes = Arc::new(s.as_bytes().to_vec());
+                res.extend(MIME_RTF.iter().map(|m| (m.to_string(), bytes.clone())));
+            }
+            ClipboardData::Image(img) => match img {
+                arboard::ImageData::Rgba(rgba) => {
+                    let Some(buf) = image::RgbaImage::from_raw(
+                        rgba.width as _,
+                        rgba.height as _,
+                        img.bytes().to_vec(),
+                    ) else {
+                        log::warn!("Invalid clipboard image {}x{}", rgba.width, rgba.height);
+                        continue;
+                    };
+                    let mut png = std::io::Cursor::new(Vec::new());
+                    image::DynamicImage::ImageRgba8(buf)
+                        .write_to(&mut png, image::ImageOutputFormat::Png)?;
+                    res.push((MIME_PNG.to_owned(), Arc::new(png.into_inner())));
+                }
+                arboard::ImageData::Png(png) => {
+                    res.push((MIME_PNG.to_owned(), Arc::new(png.to_vec())));
+                }
+                arboard::ImageData::Svg(_) => {
+                    res.push((MIME_SVG.to_owned(), Arc::new(img.bytes().to_vec())));
+                }
+            },
+            ClipboardData::Special((name, d)) => {
+                res.push((name.clone(), Arc::new(d.clone())));
+            }
+            _ => {}
+        }
+    }
+    Ok(res)
+}
+
+fn from_mime_data(
+    formats: &[ClipboardFormat],
+    mimes: &[String],
+    mut read: impl FnMut(&str) -> ResultType<Vec<u8>>,
+) -> Vec<ClipboardData> {
+    let mut read_first = |candidates: &[&str]| -> Option<Vec<u8>> {
+        let mime = candidates.iter().find(|c| mimes.iter().any(|m| m == *c))?;
+        match read(mime) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                log::debug!("Failed to read clipboard {}, {}", mime, e);
+                None
+            }
+        }
+    };
+    let has_png = formats
+        .iter()
+        .any(|f| matches!(f, ClipboardFormat::ImagePng));
+    let mut res = Vec::new();
+    for f in formats {
+        let data = match f {
+            ClipboardFormat::Text => read_first(MIME_TEXT)
+                .map(|d| ClipboardData::Text(String::from_utf8_lossy(&d).into_owned())),
+            ClipboardFormat::Html => read_first(&[MIME_HTML])
+                .map(|d| ClipboardData::Html(String::from_utf8_lossy(&d).into_owned())),
+            ClipboardFormat::Rtf => read_first(MIME_RTF)
+                .map(|d| ClipboardData::Rtf(String::from_utf8_lossy(&d).into_owned())),
+            ClipboardFormat::ImagePng => read_first(&[MIME_PNG])
+                .map(|d| ClipboardData::Image(arboard::ImageData::png(d.into()))),
+            // The png is sent as it is if requested, no need to decode it.
+            ClipboardFormat::ImageRgba if !has_png => read_first(&[MIME_PNG])
+                .and_then(|d| image::load_from_memory_with_format(&d, image::ImageFormat::Png).ok())
+                .map(|img| {
+                    let rgba = img.to_rgba8();
+                    ClipboardData::Image(arboard::ImageData::rgba(
+                        rgba.width() as _,
+                        rgba.height() as _,
+                        Cow::Owned(rgba.into_raw()),
+                    ))
+                }),
+            ClipboardFormat::ImageSvg => read_first(&[MIME_SVG]).map(|d| {
+                ClipboardData::Image(arboard::ImageData::svg(&String::from_utf8_lossy(&d)))
+            }),
+            ClipboardFormat::Special(name) => {
+                read_first(&[*name]).map(|d| ClipboardData::Special((name.to_string(), d)))
+            }
+            _ => None,
+        };
+        if let Some(data) = data {
+            res.push(data);
+        }
+    }
+    res
+}
+
+#[derive(Clone)]
+enum Offer {
+    Wlr(ZwlrDataControlOfferV1),
+    Ext(ExtDataControlOfferV1),
+}
+
+impl Offer {
+    fn receive(&self, mime: String, fd: BorrowedFd) {
+        match self {
+            Self::Wlr(o) => o.receive(mime, fd),
+            Self::Ext(o) => o.receive(mime, fd),
+        }
+    }
+
+    fn destroy(&self) {
+        match self {
+            Self::Wlr(o) => o.destroy(),
+            Self::Ext(o) => o.destroy(),
+        }
+    }
+}
+
+enum Manager {
+    Wlr(ZwlrDataControlManagerV1, ZwlrDataControlDeviceV1),
+    Ext(ExtDataControlManagerV1, ExtDataControlDeviceV1),
+}
+
+#[derive(Default)]
+struct DataControlShared {
+    // Offers introduced by `data_offer`, with their mime types.
+    offers: HashMap<ObjectId, (Offer, Vec<String>)>,
+    selection: Option<(Offer, Vec<String>)>,
+    // Our source of the selection.
+    source: Option<(ObjectId, MimeData)>,
+    on_change: Option<OnChange>,
+}
+
+struct State {
+    shared: Arc<Mutex<DataControlShared>>,
+}
+
+impl State {
+    fn on_data_offer(&self, offer: Offer, id: ObjectId) {
+        self.shared
+            .lock()
+            .unwrap()
+            .offers
+            .insert(id, (offer, vec![]));
+    }
+
+    fn on_offer(&self, id: ObjectId, mime: String) {
+        if let Some((_, mimes)) = self.shared.lock().unwrap().offers.get_mut(&id) {
+            mimes.push(mime);
+        }
+    }
+
+    fn on_selection(&self, id: Option<ObjectId>) {
+        let on_change = {
+            let mut shared = self.shared.lock().unwrap();
+            let selection = id.and_then(|id| shared.offers.remove(&id));
+            if let Some((offer, _)) = std::mem::replace(&mut shared.selection, selection) {
+                offer.destroy();
+            }
+            shared.on_change.clone()
+        };
+        if let Some(on_change) = on_change {
+            on_change();
+        }
+    }
+
+    fn on_primary_selection(&self, id: Option<ObjectId>) {
+        if let Some(id) = id {
+            if let Some((offer, _)) = self.shared.lock().unwrap().offers.remove(&id) {
+                offer.destroy();
+            }
+        }
+    }
+
+    fn on_send(&self, source: ObjectId, mime: &str, fd: OwnedFd) {
+        let shared = self.shared.lock().unwrap();
+        if let Some((id, data)) = &shared.source {
+            if *id == source {
+                if let Some(d) = find_mime_data(data, mime) {
+                    write_fd(fd, d.clone());
+                }
+            }
+        }
+    }
+
+    fn on_cancelled(&self, source: ObjectId) {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.source.as_ref().map(|(id, _)| *id == source) == Some(true) {
+            shared.source = None;
+        }
+    }
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for State {
+    fn event(
+        _: &mut Self,
+        _: &WlRegistry,
+        _: <WlRegistry as Proxy>::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+wayland_client::delegate_noop!(State: ignore WlSeat);
+wayland_client::delegate_noop!(State: ignore WlCallback);
+wayland_client::delegate_noop!(State: ZwlrDataControlManagerV1);
+wayland_client::delegate_noop!(State: ExtDataControlManagerV1);
+
+// The two protocols are identical except for the names.
+macro_rules! impl_data_control_dispatch {
+    ($offer_variant:ident, $device:ty, $device_mod:ident, $offer:ty, $offer_mod:ident, $source:ty, $source_mod:ident) => {
+        impl Dispatch<$device, ()> for State {
+            fn event(
+                state: &mut Self,
+                _: &$device,
+                event: $device_mod::Event,
+                _: &(),
+                _: &Connection,
+                _: &QueueHandle<Self>,
+            ) {
+                match event {
+                    $device_mod::Event::DataOffer { id } => {
+                        let oid = id.id();
+                        state.on_data_offer(Offer::$offer_variant(id), oid);
+                    }
+                    $device_mod::Event::Selection { id } => {
+                        state.on_selection(id.map(|o| o.id()));
+                    }
+                    $device_mod::Event::PrimarySelection { id } => {
+                        state.on_primary_selection(id.map(|o| o.id()));
+                    }
+                    $device_mod::Event::Finished => {
+                        log::warn!("Wayland data control device is finished");
+                    }
+                    _ => {}
+                }
+            }
+
+            event_created_child!(State, $device, [
+                $device_mod::EVT_DATA_OFFER_OPCODE => ($offer, ()),
+            ]);
+        }
+
+        impl Dispatch<$offer, ()> for State {
+            fn event(
+                state: &mut Self,
+                offer: &$offer,
+                event: $offer_mod::Event,
+                _: &(),
+                _: &Connection,
+                _: &QueueHandle<Self>,
+            ) {
+                if let $offer_mod::Event::Offer { mime_type } = event {
+                    state.on_offer(offer.id(), mime_type);
+                }
+            }
+        }
+
+        impl Dispatch<$source, ()> for State {
+            fn event(
+                state: &mut Self,
+                source: &$source,
+                event: $source_mod::Event,
+                _: &(),
+                _: &Connection,
+                _: &QueueHandle<Self>,
+            ) {
+                match event {
+                    $source_mod::Event::Send { mime_type, fd } => {
+                        state.on_send(source.id(), &mime_type, fd);
+                    }
+                    $source_mod::Event::Cancelled => {
+                        state.on_cancelled(source.id());
+                        source.destroy();
+                    }
+                    _ => {}
+                }
+            }
+        }
+    };
+}
+
+impl_data_control_dispatch!(
+    Wlr,
+    ZwlrDataControlDeviceV1,
+    zwlr_data_control_device_v1,
+    ZwlrDataControlOfferV1,
+    zwlr_data_control_offer_v1,
+    ZwlrDataControlSourceV1,
+    zwlr_data_control_source_v1
+);
+impl_data_control_dispatch!(
+    Ext,
+    ExtDataControlDeviceV1,
+    ext_data_control_device_v1,
+    ExtDataControlOfferV1,
+    ext_data_control_offer_v1,
+    ExtDataControlSourceV1,
+    ext_data_control_source_v1
+);
+
+/// The `ext-data-control` and `wlr-data-control` protocols.
+pub struct DataControl {
+    conn: Connection,
+    qh: QueueHandle<State>,
+    manager: Manager,
+    shared: Arc<Mutex<DataControlShared>>,
+    exit: Arc<AtomicBool>,
+}
+
+impl DataControl {
+    fn new() -> ResultType<Self> {
+        let conn = Connection::connect_to_env()?;
+        let (globals, mut queue) = registry_queue_init::<State>(&conn)?;
+        let qh = queue.handle();
+        let seat: WlSeat = globals.bind(&qh, 1..=1, ())?;
+        let manager = match globals.bind::<ExtDataControlManagerV1, _, _>(&qh, 1..=1, ()) {
+            Ok(m) => {
+                let device = m.get_data_device(&seat, &qh, ());
+                log::info!("Use the ext-data-control clipboard");
+                Manager::Ext(m, device)
+            }
+            Err(_) => {
+                let m = globals.bind::<ZwlrDataControlManagerV1, _, _>(&qh, 1..=2, ())?;
+                let device = m.get_data_device(&seat, &qh, ());
+                log::info!("Use the wlr-data-control clipboard");
+                Manager::Wlr(m, device)
+            }
+        };
+        let shared: Arc<Mutex<DataControlShared>> = Default::default();
+        let mut state = State {
+            shared: shared.clone(),
+        };
+        // Receive the current selection.
+        queue.roundtrip(&mut state)?;
+        let exit = Arc::new(AtomicBool::new(false));
+        let exit_dispatch = exit.clone();
+        std::thread::spawn(move || {
+            while !exit_dispatch.load(Ordering::SeqCst) {
+                if let Err(e) = queue.blocking_dispatch(&mut state) {
+                    log::error!("Failed to dispatch Wayland clipboard events, {}", e);
+                    break;
+                }
+            }
+            log::debug!("Wayland clipboard event loop exited");
+        });
+        Ok(Self {
+            conn,
+            qh,
+            manager,
+            shared,
+            exit,
+        })
+    }
+
+    fn selection(&self) -> Option<(Offer, Vec<String>)> {
+        self.shared.lock().unwrap().selection.clone()
+    }
+
+    fn read(&self, offer: &Offer, mime: &str) -> ResultType<Vec<u8>> {
+        let (reader, writer) = nix::unistd::pipe()?;
+        offer.receive(mime.to_owned(), writer.as_fd());
+        self.conn.flush()?;
+        // The source holds its own copy of the fd, we must close ours to get EOF.
+        drop(writer);
+        read_fd(reader)
+    }
+
+    fn set(&self, data: MimeData) -> ResultType<()> {
+        match &self.manager {
+            Manager::Wlr(m, device) => {
+                let source = m.create_data_source(&self.qh, ());
+                for (mime, _) in &data {
+                    source.offer(mime.clone());
+                }
+                self.shared.lock().unwrap().source = Some((source.id(), data));
+                device.set_selection(Some(&source));
+            }
+            Manager::Ext(m, device) => {
+                let source = m.create_data_source(&self.qh, ());
+                for (mime, _) in &data {
+                    source.offer(mime.clone());
+                }
+                self.shared.lock().unwrap().source = Some((source.id(), data));
+                device.set_selection(Some(&source));
+            }
+        }
+        self.conn.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for DataControl {
+    fn drop(&mut self) {
+        self.exit.store(true, Ordering::SeqCst);
+        match &self.manager {
+            Manager::Wlr(m, device) => {
+                device.destroy();
+                m.destroy();
+            }
+            Manager::Ext(m, device) => {
+                device.destroy();
+                m.destroy();
+            }
+        }
+        // Wake up the event loop to exit.
+        self.conn.display().sync(&self.qh, ());
+        self.conn.flush().ok();
+    }
+}
+
+#[derive(Default)]
+struct PortalShared {
+    // Mime types of the session clipboard.
+    mime_types: Vec<String>,
+    // Our data of the selection.
+    data: MimeData,
+    on_change: Option<OnChange>,
+}
+
+/// The Clipboard interface of the remote desktop portal, used on GNOME.
+/// The session of `scrap::wayland::pipewire::RDP_SESSION_INFO` must be started.
+pub struct PortalClipboard {
+    conn: Arc<SyncConnection>,
+    session: Path<'static>,
+    shared: Arc<Mutex<PortalShared>>,
+    tokens: Vec<Token>,
+    exit: Arc<AtomicBool>,
+}
+
+const PORTAL_CLIPBOARD: &str = "org.freedesktop.portal.Clipboard";
+
+impl PortalClipboard {
+    fn new() -> ResultType<Self> {
+        let (conn, session) = match scrap::wayland::pipewire::RDP_SESSION_INFO
+            .lock()
+            .unwrap()
+            .as_ref()
+        {
+            Some(info) => (info.conn.clone(), info.session.clone()),
+            None => bail!("No remote desktop session"),
+        };
+        let shared: Arc<Mutex<PortalShared>> = Default::default();
+
+        let shared_owner = shared.clone();
+        let session_owner = session.clone();
+        let token_owner = conn.add_match(
+            MatchRule::new_signal(PORTAL_CLIPBOARD, "SelectionOwnerChanged"),
+            move |(s, options): (Path<'static>, PropMap), _, _| {
+                if s == session_owner {
+                    let mime_types = options
+                        .get("mime_types")
+                        .and_then(|v| v.0.as_iter())
+                        .map(|iter| {
+                            iter.filter_map(|m| m.as_str().map(|m| m.to_owned()))
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    let on_change = {
+                        let mut shared = shared_owner.lock().unwrap();
+                        shared.mime_types = mime_types;
+                        shared.on_change.clone()
+                    };
+                    if let Some(on_change) = on_change {
+                        on_change();
+                    }
+                }
+                true
+            },
+        )?;
+
+        let shared_transfer = shared.clone();
+        let session_transfer = session.clone();
+        let conn_transfer = conn.clone();
+        let token_transfer = conn.add_match(
+            MatchRule::new_signal(PORTAL_CLIPBOARD, "SelectionTransfer"),
+            move |(s, mime, serial): (Path<'static>, String, u32), _, _| {
+                if s == session_transfer {
+                    let data =
+                        find_mime_data(&shared_transfer.lock().unwrap().data, &mime).cloned();
+                    let conn = conn_transfer.clone();
+                    let session = session_transfer.clone();
+                    // Don't call methods while processing the signal.
+                    std::thread::spawn(move || {
+                        Self::transfer(&conn, session, serial, data);
+                    });
+                }
+                true
+            },
+        )?;
+
+        let exit = Arc::new(AtomicBool::new(false));
+        let exit_process = exit.clone();
+        let conn_process = conn.clone();
+        std::thread::spawn(move || {
+            while !exit_process.load(Ordering::SeqCst) {
+                if let Err(e) = conn_process.process(Duration::from_millis(300)) {
+                    log::error!("Failed to process the portal clipboard signals, {}", e);
+                    break;
+                }
+            }
+        });
+        log::info!("Use the portal clipboard");
+        Ok(Self {
+            conn,
+            session,
+            shared,
+            tokens: vec![token_owner, token_transfer],
+            exit,
+        })
+    }
+
+    fn transfer(
+        conn: &SyncConnection,
+        session: Path<'static>,
+        serial: u32,
+        data: Option<Arc<Vec<u8>>>,
+    ) {
+        let portal = scrap::wayland::pipewire::get_portal(conn);
+        let mut success = false;
+        if let Some(data) = data {
+            let res: Result<(dbus::arg::OwnedFd,), dbus::Error> = portal.method_call(
+                PORTAL_CLIPBOARD,
+                "SelectionWrite",
+                (session.clone(), serial),
+            );
+            match res {
+                Ok((fd,)) => {
+                    let mut file = unsafe { File::from_raw_fd(fd.into_raw_fd()) };
+                    success = file.write_all(&data).is_ok();
+                }
+                Err(e) => log::debug!("Failed to write the portal clipboard, {}", e),
+            }
+        }
+        let res: Result<(), dbus::Error> = portal.method_call(
+            PORTAL_CLIPBOARD,
+            "SelectionWriteDone",
+            (session, serial, success),
+        );
+        if let Err(e) = res {
+            log::debug!("Failed to finish writing the portal clipboard, {}", e);
+        }
+    }
+
+    fn mime_types(&self) -> Vec<String> {
+        self.shared.lock().unwrap().mime_types.clone()
+    }
+
+    fn read(&self, mime: &str) -> ResultType<Vec<u8>> {
+        let portal = scrap::wayland::pipewire::get_portal(&self.conn);
+        let (fd,): (dbus::arg::OwnedFd,) = portal.method_call(
+            PORTAL_CLIPBOARD,
+            "SelectionRead",
+            (self.session.clone(), mime),
+        )?;
+        read_fd(unsafe { OwnedFd::from_raw_fd(fd.into_raw_fd()) })
+    }
+
+    fn set(&self, data: MimeData) -> ResultType<()> {
+        let mime_types: Vec<String> = data.iter().map(|(m, _)| m.clone()).collect();
+        self.shared.lock().unwrap().data = data;
+        let mut options = PropMap::new();
+        options.insert("mime_types".to_owned(), Variant(Box::new(mime_types)));
+        let portal = scrap::wayland::pipewire::get_portal(&self.conn);
+        let _: () = portal.method_call(
+            PORTAL_CLIPBOARD,
+            "SetSelection",
+            (self.session.clone(), options),
+        )?;
+        Ok(())
+    }
+}
+
+impl Drop for PortalClipboard {
+    fn drop(&mut self) {
+        self.exit.store(true, Ordering::SeqCst);
+        for token in self.tokens.drain(..) {
+            self.conn.remove_match(token).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mime_data() {
+        let data = to_mime_data(&[
+            ClipboardData::Text("hello".to_owned()),
+            ClipboardData::Html("<b>hello</b>".to_owned()),
+            ClipboardData::Special(("dyn.com.rustdesk.owner".to_owned(), vec![1])),
+        ])
+        .unwrap();
+        let mimes: Vec<String> = data.iter().map(|(m, _)| m.clone()).collect();
+        assert!(mimes.contains(&"UTF8_STRING".to_owned()));
+
+        let formats = [
+            ClipboardFormat::Text,
+            ClipboardFormat::Html,
+            ClipboardFormat::ImagePng,
+            ClipboardFormat::Special("dyn.com.rustdesk.owner"),
+        ];
+        let read = |mime: &str| -> ResultType<Vec<u8>> {
+            match find_mime_data(&data, mime) {
+                Some(d) => Ok(d.to_vec()),
+                None => bail!("no data of {}", mime),
+            }
+        };
+        let res = from_mime_data(&formats, &mimes, read);
+        assert_eq!(res.len(), 3);
+        assert!(matches!(&res[0], ClipboardData::Text(s) if s == "hello"));
+        assert!(matches!(&res[1], ClipboardData::Html(s) if s == "<b>hello</b>"));
+        assert!(
+            matches!(&res[2], ClipboardData::Special((s, d)) if s == "dyn.com.rustdesk.owner" && d == &vec![1])
+        );
+
+        // Only the text offered by other applications.
+        let res = from_mime_data(&formats, &["text/plain".to_owned()], |_| Ok(b"a".to_vec()));
+        assert!(matches!(&res[..], [ClipboardData::Text(s)] if s == "a"));
+    }
+}
@@ -559,6 +559,53 @@ pub fn core_main() -> Option<Vec<String>> {
                 println!("Installation and administrative privileges required!");
             }
             return None;
+        } else if args[0] == "--gen-record-keypair" {
+            let (pk, sk) = scrap::record_crypt::gen_keypair();
+            println!("public key: {}", pk);
+            println!("secret key: {}", sk);
+            return None;
+        } else if args[0] == "--decrypt-record" {
+            // --decrypt-record <file.enc> <secret key file> [output] [--allow-truncated]
+            // The key is read from a file, or from stdin with "-", never from the command line.
+            // A recording which was cut off is only written with --allow-truncated.
+            let allow_truncated = args.iter().any(|a| a == "--allow-truncated");
+            let args: Vec<_> = args
+                .iter()
+                .filter(|a| *a != "--allow-truncated")
+                .cloned()
+                .collect();
+            if args.len() == 3 || args.len() == 4 {
+                let out = if args.len() == 4 {
+                    args[3].clone()
+                } else {
+                    args[1]
+                        .strip_suffix(&format!(".{}", scrap::record_crypt::ENCRYPTED_EXT))
+                        .map(|s| s.to_owned())
+                        .unwrap_or(format!("{}.dec", args[1]))
+                };
+                let key = if args[2] == "-" {
+                    std::io::read_to_string(std::io::stdin())
+                } else {
+                    std::fs::read_to_string(&args[2])
+                };
+                match key
+                    .map_err(Into::into)
+                    .and_then(|key| scrap::record_crypt::decode_secret_key(&key))
+                    .and_then(|sk| {
+                        scrap::record_crypt::decrypt_file(&args[1], &out, &sk, allow_truncated)
+                    }) {
+                    Ok(()) => println!("Done! {}", out),
+                    Err(err) => println!("{err}"),
+                }
+            } else {
+                println!(
+                    "Usage: --decrypt-record <file.enc> <secret key file|-> [output] [--allow-truncated]"
+                );
+            }
+            return None;
+        } else if args[0] == "--sync" {
+            crate::client::file_sync::run_cli(&args[1..]);
+            return None;
         } else if args[0] == "--check-hwcodec-config" {
             #[cfg(feature = "hwcodec")]
             crate::ipc::hwcodec_process();
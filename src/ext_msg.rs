@@ -0,0 +1,283 @@
+// Messages of the features which are not in message.proto, carried in `Misc.plugin_request`.
+//
+// The content of the request is a u32 little endian length, the json header of that length
+// and a binary tail, protobuf encoded data or raw bytes depending on the header. Peers
+// announce the messages they handle as capabilities: the controlled side in the platform
+// additions of its peer info, the controlling side with `Ext::Caps` after the login. A
+// message is only sent if the peer announced its capability.
+
+use crate::{file_archive::Format, file_meta::Meta, file_search::SearchOptions};
+use hbb_common::{
+    bytes::Bytes,
+    log,
+    message_proto::{Message, Misc, PeerInfo, PluginRequest},
+};
+use scrap::camera::{CameraCapabilities, CameraSettings};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+const ID: &str = "rustdesk-ext";
+// Sent by the video service, in order with the video frames.
+const VIDEO_ID: &str = "rustdesk-ext/video";
+// Key of the capabilities in the platform additions of the peer info.
+const PLATFORM_ADDITION: &str = "ext";
+
+/// Lossless tile frames of the screen content mode.
+pub const CAP_TILES: &str = "tiles";
+/// Capture regions of displays, X11 only.
+pub const CAP_CAPTURE_REGION: &str = "capture_region";
+/// Formats and controls of cameras in the view camera session.
+pub const CAP_CAMERA_SETTINGS: &str = "camera_settings";
+/// Block delta of downloads which overwrite a large local copy.
+pub const CAP_FILE_DELTA: &str = "file_delta";
+/// The file transfer limit of the controlling side.
+pub const CAP_FILE_TRANSFER_LIMIT: &str = "file_transfer_limit";
+/// Whole-file sha256 verification of file transfers.
+pub const CAP_FILE_VERIFY: &str = "file_verify";
+/// Recursive search and preview of remote files.
+pub const CAP_FILE_SEARCH: &str = "file_search";
+/// Directory transfers as a single tar stream.
+pub const CAP_FILE_ARCHIVE: &str = "file_archive";
+/// Unix metadata and symlinks of file transfers, applied on Linux and macOS only.
+pub const CAP_FILE_META: &str = "file_meta";
+
+/// The messages this side handles.
+pub fn capabilities() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut caps = vec![
+        CAP_TILES,
+        CAP_FILE_DELTA,
+        CAP_FILE_TRANSFER_LIMIT,
+        CAP_FILE_VERIFY,
+        CAP_FILE_SEARCH,
+        CAP_FILE_ARCHIVE,
+    ];
+    #[cfg(target_os = "linux")]
+    caps.push(CAP_CAPTURE_REGION);
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    caps.push(CAP_FILE_META);
+    #[cfg(any(target_os = "windows", target_os = "linux"))]
+    caps.push(CAP_CAMERA_SETTINGS);
+    caps
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Ext {
+    /// The capabilities of the controlling side.
+    Caps {
+        caps: Vec<String>,
+    },
+    /// Tail: `EncodedVideoFrames` of the tile codec.
+    Tiles {
+        display: i32,
+    },
+    /// The region of a display to capture, relative to the display. An empty region restores
+    /// the whole display.
+    CaptureRegion {
+        display: i32,
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+    },
+    CameraCapabilitiesQuery {
+        display: i32,
+    },
+    /// The reply to a query or to settings, after the capturer has applied them.
+    CameraCapabilities {
+        caps: CameraCapabilities,
+    },
+    CameraSettings {
+        settings: CameraSettings,
+    },
+    /// Tail: the block signatures of the local copy, see `file_delta`.
+    FileBlockSignatures {
+        id: i32,
+        file_num: i32,
+        block_size: u32,
+        basis_size: u64,
+    },
+    /// Tail: encoded delta ops. The last message is `done`, with the error if any.
+    FileDeltaOps {
+        id: i32,
+        file_num: i32,
+        done: bool,
+        error: String,
+    },
+    /// Max speed in bytes per second, none for no limit. Priority slows down transfers while
+    /// the video has a high delay.
+    FileTransferLimit {
+        max_speed: Option<u64>,
+        priority: bool,
+    },
+    /// Whether the controlled side sends the digests of the files the controlling side
+    /// downloads.
+    FileVerifyOption {
+        verify: bool,
+    },
+    /// Tail: the sha256 of the file, sent after its last block.
+    FileDigest {
+        id: i32,
+        file_num: i32,
+    },
+    /// The files of the job which failed the verification, empty if all passed.
+    FileVerifyResult {
+        id: i32,
+        failed: Vec<i32>,
+    },
+    /// Cancelled with the job cancel of the id.
+    FileSearch {
+        id: i32,
+        path: String,
+        options: SearchOptions,
+    },
+    /// Tail: a `FileDirectory` of the search id and path with a batch of matches.
+    FileSearchResults {
+        done: bool,
+        error: String,
+    },
+    FilePreview {
+        id: i32,
+        path: String,
+        max_size: i32,
+        include_hidden: bool,
+    },
+    /// Tail: the png thumbnail or the start of the text.
+    FilePreviewResult {
+        id: i32,
+        path: String,
+        is_image: bool,
+        truncated: bool,
+        error: String,
+    },
+    /// The next send or receive request of the job is for an archive. A send request of a
+    /// path which is not a directory is answered as usual.
+    FileArchiveRequest {
+        id: i32,
+        format: Format,
+    },
+    /// Tail: the `FileDirectory` reply to an archive send request, the blocks are unpacked.
+    FileArchiveDir {
+        format: Format,
+    },
+    /// The metadata of the entries of the job, sent before its blocks.
+    FileMeta {
+        id: i32,
+        files: Vec<Meta>,
+    },
+}
+
+fn content(ext: &Ext, tail: &[u8]) -> Bytes {
+    let header = serde_json::to_vec(ext).unwrap_or_default();
+    let mut buf = Vec::with_capacity(4 + header.len() + tail.len());
+    buf.extend((header.len() as u32).to_le_bytes());
+    buf.extend(header);
+    buf.extend_from_slice(tail);
+    buf.into()
+}
+
+fn new_request(id: &str, ext: &Ext, tail: &[u8]) -> Message {
+    let mut misc = Misc::new();
+    misc.set_plugin_request(PluginRequest {
+        id: id.to_owned(),
+        content: content(ext, tail),
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_misc(misc);
+    msg_out
+}
+
+pub fn new_message(ext: &Ext, tail: &[u8]) -> Message {
+    new_request(ID, ext, tail)
+}
+
+pub fn new_video_message(ext: &Ext, tail: &[u8]) -> Message {
+    new_request(VIDEO_ID, ext, tail)
+}
+
+#[inline]
+pub fn is_ext(p: &PluginRequest) -> bool {
+    p.id == ID || p.id == VIDEO_ID
+}
+
+#[inline]
+pub fn is_video(p: &PluginRequest) -> bool {
+    p.id == VIDEO_ID
+}
+
+/// The message and its tail, none if it is not an extension message or unknown.
+pub fn parse(p: &PluginRequest) -> Option<(Ext, Bytes)> {
+    if !is_ext(p) {
+        return None;
+    }
+    let content = &p.content;
+    let len = content
+        .get(..4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)?;
+    // The length comes from the peer, it may overflow on 32-bit targets.
+    let end = 4usize.checked_add(len)?;
+    let header = content.get(4..end)?;
+    match serde_json::from_slice(header) {
+        Ok(ext) => Some((ext, content.slice(end..))),
+        Err(e) => {
+            // A newer peer, or a peer which doesn't check the capabilities.
+            log::debug!("Unknown extension message: {}", e);
+            None
+        }
+    }
+}
+
+/// Adds the capabilities to the platform additions of the peer info.
+pub fn add_platform_addition(platform_additions: &mut serde_json::Map<String, serde_json::Value>) {
+    platform_additions.insert(PLATFORM_ADDITION.into(), capabilities().into());
+}
+
+/// Whether the peer info announces the capability.
+pub fn peer_info_has_capability(pi: &PeerInfo, cap: &str) -> bool {
+    serde_json::from_str::<HashMap<String, serde_json::Value>>(&pi.platform_additions)
+        .map_or(false, |v| peer_capabilities(&v).contains(cap))
+}
+
+/// The capabilities in the platform additions of the peer info.
+pub fn peer_capabilities(
+    platform_additions: &HashMap<String, serde_json::Value>,
+) -> HashSet<String> {
+    platform_additions
+        .get(PLATFORM_ADDITION)
+        .and_then(|v| v.as_array())
+        .map(|caps| {
+            caps.iter()
+                .filter_map(|c| c.as_str().map(|c| c.to_owned()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ext_msg() {
+        let ext = Ext::Tiles { display: 1 };
+        let msg = new_video_message(&ext, &[1, 2, 3]);
+        let p = msg.misc().plugin_request();
+        assert!(is_video(p));
+        assert_eq!(parse(p), Some((ext, Bytes::from_static(&[1, 2, 3]))));
+
+        let msg = new_message(&Ext::Caps { caps: vec![] }, &[]);
+        let mut p = msg.misc().plugin_request().clone();
+        assert!(!is_video(&p));
+        assert!(matches!(parse(&p), Some((Ext::Caps { .. }, tail)) if tail.is_empty()));
+        p.content = content(&Ext::Caps { caps: vec![] }, &[]).slice(..6);
+        assert!(parse(&p).is_none());
+        let mut oversized = u32::MAX.to_le_bytes().to_vec();
+        oversized.extend(b"{}");
+        p.content = oversized.into();
+        assert!(parse(&p).is_none());
+        p.id = "plugin".to_owned();
+        assert!(parse(&p).is_none());
+    }
+}
@@ -0,0 +1,479 @@
+// Directory transfers as a single tar stream, optionally zstd compressed.
+//
+// Sending a directory file by file costs a few messages per file, which dominates with many
+// small files. In archive mode the sender packs the directory in a thread while the transfer
+// loop takes the blocks, and the receiver unpacks them in a thread as they come.
+// Permissions, symlinks and modification times are kept. The progress is in bytes of the
+// uncompressed archive, against a total estimated from the listing. The archive mode is
+// negotiated with `Ext::FileArchiveRequest` and `Ext::FileArchiveDir`.
+
+use crate::ext_msg::{self, Ext};
+use hbb_common::{
+    bail, fs,
+    message_proto::{FileDirectory, FileEntry, FileTransferBlock, FileType, Message},
+    protobuf::Message as _,
+    ResultType, Stream,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender, TryRecvError},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+// "tar" or "tar-zstd", empty to transfer the files one by one.
+pub const OPTION_ARCHIVE: &str = "file-transfer-archive";
+const BLOCK_SIZE: usize = 128 * 1024;
+// Blocks packed ahead of the transfer loop.
+const READ_AHEAD: usize = 8;
+const TAR_BLOCK: u64 = 512;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Format {
+    Tar,
+    TarZstd,
+}
+
+impl Format {
+    pub fn from_option(v: &str) -> Option<Self> {
+        match v {
+            "tar" => Some(Self::Tar),
+            "tar-zstd" => Some(Self::TarZstd),
+            _ => None,
+        }
+    }
+}
+
+/// Asks the peer for the archive mode, sent before the send or receive request.
+pub fn new_request(id: i32, format: Format) -> Message {
+    ext_msg::new_message(&Ext::FileArchiveRequest { id, format }, &[])
+}
+
+// The entries below the root, relative to it, the directories before their content.
+fn scan(root: &Path, include_hidden: bool) -> ResultType<Vec<(PathBuf, FileEntry)>> {
+    let mut entries = vec![];
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&root.join(&dir), include_hidden)?.entries {
+            let path = dir.join(&entry.name);
+            if is_dir(&entry) {
+                dirs.push(path.clone());
+            }
+            entries.push((path, entry));
+        }
+    }
+    Ok(entries)
+}
+
+fn is_dir(entry: &FileEntry) -> bool {
+    entry.entry_type.value() == FileType::Dir as i32
+}
+
+fn is_file(entry: &FileEntry) -> bool {
+    entry.entry_type.value() == FileType::File as i32
+}
+
+// The size of the uncompressed archive, exact unless the links have long targets.
+fn estimate(entries: &[(PathBuf, FileEntry)]) -> u64 {
+    let pad = |n: u64| n.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+    let size: u64 = entries
+        .iter()
+        .map(|(path, entry)| {
+            // GNU long name entry.
+            let name_len = path.to_string_lossy().len() as u64 + is_dir(entry) as u64;
+            let long_name = if name_len > 100 {
+                TAR_BLOCK + pad(name_len + 1)
+            } else {
+                0
+            };
+            let data = if is_file(entry) { pad(entry.size) } else { 0 };
+            TAR_BLOCK + long_name + data
+        })
+        .sum();
+    size + 2 * TAR_BLOCK
+}
+
+// Counts the uncompressed bytes of the archive for the progress.
+struct Counter<T> {
+    inner: T,
+    count: Arc<AtomicU64>,
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count.fetch_add(n as _, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Counter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as _, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+// Cuts the packed stream into blocks, blocks when the transfer loop is behind.
+struct BlockWriter {
+    tx: SyncSender<io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+}
+
+impl Write for BlockWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= BLOCK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            let buf = std::mem::replace(&mut self.buf, Vec::with_capacity(BLOCK_SIZE));
+            self.tx
+                .send(Ok(buf))
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Job cancelled"))?;
+        }
+        Ok(())
+    }
+}
+
+fn pack<W: Write>(w: W, root: &Path, entries: &[(PathBuf, FileEntry)]) -> io::Result<W> {
+    let mut builder = tar::Builder::new(w);
+    builder.follow_symlinks(false);
+    for (path, _) in entries {
+        builder.append_path_with_name(root.join(path), path)?;
+    }
+    builder.into_inner()
+}
+
+/// A directory packed for the transfer.
+pub struct ArchiveRead {
+    id: i32,
+    format: Format,
+    total_size: u64,
+    files: Vec<FileEntry>,
+    finished_size: Arc<AtomicU64>,
+    rx: Receiver<io::Result<Vec<u8>>>,
+    blk_id: u32,
+}
+
+impl ArchiveRead {
+    pub fn new(id: i32, path: &str, format: Format, include_hidden: bool) -> ResultType<Self> {
+        let root = PathBuf::from(path);
+        if !root.is_dir() {
+            bail!("Not a directory");
+        }
+        let entries = scan(&root, include_hidden)?;
+        let total_size = estimate(&entries);
+        let files = entries
+            .iter()
+            .filter(|(_, entry)| is_file(entry))
+            .map(|(path, entry)| FileEntry {
+                name: path.to_string_lossy().to_string(),
+                size: entry.size,
+                ..Default::default()
+            })
+            .collect();
+        let finished_size = Arc::new(AtomicU64::new(0));
+        let count = finished_size.clone();
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        std::thread::spawn(move || {
+            let inner = BlockWriter {
+                tx: tx.clone(),
+                buf: Vec::with_capacity(BLOCK_SIZE),
+            };
+            let res = match format {
+                Format::Tar => pack(Counter { inner, count }, &root, &entries)
+                    .and_then(|mut w| w.inner.flush()),
+                Format::TarZstd => zstd::Encoder::new(inner, ZSTD_LEVEL)
+                    .and_then(|inner| pack(Counter { inner, count }, &root, &entries))
+                    .and_then(|w| w.inner.finish())
+                    .and_then(|mut w| w.flush()),
+            };
+            match res {
+                Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+                Err(err) => {
+                    log::error!("Failed to pack {}: {}", root.display(), err);
+                    tx.send(Err(err)).ok();
+                }
+                Ok(()) => {}
+            }
+        });
+        Ok(Self {
+            id,
+            format,
+            total_size,
+            files,
+            finished_size,
+            rx,
+            blk_id: 0,
+        })
+    }
+
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    /// The files of the directory, for the audit.
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    /// The archive as the only entry of a listing, which gives the size of the job.
+    pub fn entries(&self) -> Vec<FileEntry> {
+        vec![FileEntry {
+            entry_type: FileType::File.into(),
+            size: self.total_size,
+            ..Default::default()
+        }]
+    }
+
+    /// The reply to a send request, which tells the peer to unpack the blocks.
+    pub fn new_dir(&self, path: String) -> Message {
+        let fd = FileDirectory {
+            id: self.id,
+            path,
+            entries: self.entries(),
+            ..Default::default()
+        };
+        let ext = Ext::FileArchiveDir {
+            format: self.format,
+        };
+        ext_msg::new_message(&ext, &fd.write_to_bytes().unwrap_or_default())
+    }
+}
+
+/// Drives the archive read jobs of a connection like `fs::handle_read_jobs`.
+#[derive(Default)]
+pub struct ArchiveReads {
+    jobs: Vec<ArchiveRead>,
+    // Wire bytes since the last take_sent.
+    sent: usize,
+}
+
+impl ArchiveReads {
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    pub fn push(&mut self, job: ArchiveRead) {
+        self.jobs.push(job);
+    }
+
+    /// Stops packing, the thread ends with the next block.
+    pub fn remove(&mut self, id: i32) {
+        self.jobs.retain(|job| job.id != id);
+    }
+
+    pub fn take_sent(&mut self) -> usize {
+        std::mem::take(&mut self.sent)
+    }
+
+    /// Job ids with their uncompressed bytes sent.
+    pub fn progress(&self) -> Vec<(i32, u64)> {
+        self.jobs
+            .iter()
+            .map(|job| (job.id, job.finished_size.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Sends the next block of the first job if it is packed, or its end.
+    pub async fn handle(&mut self, stream: &mut Stream) -> ResultType<()> {
+        let Some(job) = self.jobs.first_mut() else {
+            return Ok(());
+        };
+        let id = job.id;
+        match job.rx.try_recv() {
+            Ok(Ok(data)) => {
+                let block = FileTransferBlock {
+                    id,
+                    file_num: 0,
+                    data: data.into(),
+                    blk_id: job.blk_id,
+                    ..Default::default()
+                };
+                job.blk_id += 1;
+                self.sent += block.data.len();
+                stream.send(&fs::new_block(block)).await?;
+            }
+            Ok(Err(err)) => {
+                self.jobs.remove(0);
+                stream.send(&fs::new_error(id, err, 0)).await?;
+            }
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => {
+                self.jobs.remove(0);
+                stream.send(&fs::new_done(id, 0)).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Feeds the received blocks to the unpacking, the end of the blocks is the end of the stream.
+struct BlockReader {
+    rx: Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for BlockReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.buf.len() {
+            match self.rx.recv() {
+                Ok(data) => {
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// Without `apply_ownership` the modes are masked to 0o777, no setuid, setgid or sticky bits
+// from the peer.
+fn unpack<R: Read>(r: R, dir: &Path, apply_ownership: bool) -> io::Result<()> {
+    let mut archive = tar::Archive::new(r);
+    archive.set_preserve_permissions(apply_ownership);
+    archive.set_preserve_mtime(true);
+    archive.set_overwrite(true);
+    // Entries escaping the directory are skipped.
+    archive.unpack(dir)
+}
+
+/// A directory unpacked as the blocks are received.
+pub struct ArchiveWrite {
+    tx: Sender<Vec<u8>>,
+    handle: JoinHandle<io::Result<()>>,
+    finished_size: Arc<AtomicU64>,
+}
+
+impl ArchiveWrite {
+    pub fn new(path: &Path, format: Format, apply_ownership: bool) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let finished_size = Arc::new(AtomicU64::new(0));
+        let count = finished_size.clone();
+        let dir = path.to_owned();
+        let handle = std::thread::spawn(move || {
+            std::fs::create_dir_all(&dir)?;
+            let inner = BlockReader {
+                rx,
+                buf: vec![],
+                pos: 0,
+            };
+            let res = match format {
+                Format::Tar => unpack(Counter { inner, count }, &dir, apply_ownership),
+                Format::TarZstd => zstd::Decoder::new(inner)
+                    .and_then(|inner| unpack(Counter { inner, count }, &dir, apply_ownership)),
+            };
+            if let Err(err) = &res {
+                log::error!("Failed to unpack to {}: {}", dir.display(), err);
+            }
+            res
+        });
+        Self {
+            tx,
+            handle,
+            finished_size,
+        }
+    }
+
+    /// False if the unpacking stopped, `finish` tells why.
+    pub fn write(&self, data: Vec<u8>) -> bool {
+        self.tx.send(data).is_ok()
+    }
+
+    pub fn finished_size(&self) -> u64 {
+        self.finished_size.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the received blocks to be unpacked.
+    pub fn finish(self) -> ResultType<()> {
+        drop(self.tx);
+        match self.handle.join() {
+            Ok(res) => Ok(res?),
+            Err(_) => bail!("Failed to unpack"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive() {
+        let dir = std::env::temp_dir().join("rustdesk_test_file_archive");
+        std::fs::remove_dir_all(&dir).ok();
+        let src = dir.join("src");
+        std::fs::create_dir_all(src.join("a").join("b")).unwrap();
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(src.join("a").join("b").join("data"), &data).unwrap();
+        std::fs::write(src.join("x.txt"), "x").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::os::unix::fs::symlink("x.txt", src.join("link")).unwrap();
+            std::fs::set_permissions(src.join("x.txt"), std::fs::Permissions::from_mode(0o4751))
+                .unwrap();
+        }
+        for format in [Format::Tar, Format::TarZstd] {
+            let job = ArchiveRead::new(1, &src.to_string_lossy(), format, false).unwrap();
+            assert_eq!(job.files().len(), 2);
+            let dst = dir.join(format!("{:?}", format));
+            let write = ArchiveWrite::new(&dst, format, false);
+            while let Ok(data) = job.rx.recv() {
+                assert!(write.write(data.unwrap()));
+            }
+            if format == Format::Tar {
+                assert_eq!(job.finished_size.load(Ordering::Relaxed), job.total_size());
+            }
+            write.finish().unwrap();
+            let copy = dst.join("a").join("b").join("data");
+            assert_eq!(std::fs::read(&copy).unwrap(), data);
+            let modified = |p: &Path| {
+                let t = std::fs::metadata(p).unwrap().modified().unwrap();
+                t.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+            };
+            assert_eq!(
+                modified(&copy),
+                modified(&src.join("a").join("b").join("data"))
+            );
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                assert_eq!(
+                    std::fs::read_link(dst.join("link")).unwrap(),
+                    PathBuf::from("x.txt")
+                );
+                // No setuid from the peer.
+                let mode = std::fs::metadata(dst.join("x.txt")).unwrap().permissions();
+                assert_eq!(mode.mode() & 0o7777, 0o751);
+            }
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
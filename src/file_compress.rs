@@ -0,0 +1,360 @@
+// Per-file compression of file transfer blocks.
+//
+// Already compressed files, detected by extension, magic bytes or the entropy of a few
+// samples, are sent as is. The zstd level of the others follows the link: slow links get
+// higher levels, fast links the cheapest one so the CPU is not the bottleneck.
+//
+// `TransferJob::read` compresses with the default level unless the extension is a known
+// compressed one, the blocks are recompressed here if the level differs.
+
+use hbb_common::{compress::decompress, fs, message_proto::FileTransferBlock, ResultType, Stream};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    time::Duration,
+};
+
+const SAMPLE_SIZE: usize = 16 * 1024;
+// Bits per byte, deflate or zstd output is close to 8.
+const MAX_ENTROPY: f64 = 7.5;
+const MEASURE_WINDOW: Duration = Duration::from_secs(1);
+// The level of `hbb_common::compress::compress`.
+const DEFAULT_LEVEL: i32 = 3;
+
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "cab", "deb", "dmg", "docx", "epub", "flac",
+    "flv", "gif", "gz", "heic", "iso", "jar", "jpeg", "jpg", "lz", "lz4", "lzma", "m4a", "m4v",
+    "mkv", "mov", "mp3", "mp4", "mpeg", "mpg", "msi", "odt", "ogg", "opus", "png", "pptx", "rar",
+    "rpm", "tgz", "txz", "webm", "webp", "whl", "wmv", "xlsx", "xz", "zip", "zst",
+];
+
+const COMPRESSED_MAGICS: &[&[u8]] = &[
+    b"PK\x03\x04",         // zip, jar, docx, apk
+    b"\x1f\x8b",           // gzip
+    b"\x28\xb5\x2f\xfd",   // zstd
+    b"\xfd7zXZ\x00",       // xz
+    b"BZh",                // bzip2
+    b"7z\xbc\xaf\x27\x1c", // 7z
+    b"Rar!\x1a\x07",       // rar
+    b"\x04\x22\x4d\x18",   // lz4
+    b"\x89PNG\r\n\x1a\n",  // png
+    b"\xff\xd8\xff",       // jpeg
+    b"GIF8",               // gif
+    b"fLaC",               // flac
+    b"OggS",               // ogg
+    b"ID3",                // mp3
+    b"\x1a\x45\xdf\xa3",   // mkv, webm
+];
+
+fn is_compressed_magic(head: &[u8]) -> bool {
+    if COMPRESSED_MAGICS.iter().any(|m| head.starts_with(m)) {
+        return true;
+    }
+    // mp4, mov, heic, avif
+    (head.len() >= 8 && &head[4..8] == b"ftyp")
+        || (head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WEBP")
+}
+
+/// Shannon entropy in bits per byte.
+pub fn entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.;
+    }
+    let mut counts = [0u64; 256];
+    for &b in data {
+        counts[b as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+// The start, the middle and the end of the file.
+fn samples(path: &Path) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut head = vec![0u8; SAMPLE_SIZE.min(len as usize)];
+    file.read_exact(&mut head)?;
+    let mut rest = vec![];
+    for offset in [len / 2, len.saturating_sub(SAMPLE_SIZE as u64)] {
+        if offset < SAMPLE_SIZE as u64 {
+            continue;
+        }
+        let mut buf = vec![0u8; SAMPLE_SIZE.min((len - offset) as usize)];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buf)?;
+        rest.extend(buf);
+    }
+    Ok((head, rest))
+}
+
+pub fn is_compressible_data(head: &[u8], rest: &[u8]) -> bool {
+    if is_compressed_magic(head) {
+        return false;
+    }
+    entropy(head) < MAX_ENTROPY && (rest.is_empty() || entropy(rest) < MAX_ENTROPY)
+}
+
+pub fn is_compressible(path: &Path) -> bool {
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if COMPRESSED_EXTENSIONS.contains(&ext.as_str()) {
+        return false;
+    }
+    match samples(path) {
+        Ok((head, rest)) => is_compressible_data(&head, &rest),
+        Err(_) => true,
+    }
+}
+
+/// zstd level for the measured wire throughput in bytes per second, or the rtt before
+/// there is a measurement.
+pub fn level(throughput: Option<f64>, rtt: Option<u32>) -> i32 {
+    const MB: f64 = 1024. * 1024.;
+    match throughput {
+        Some(t) if t < 1. * MB => 9,
+        Some(t) if t < 5. * MB => 6,
+        Some(t) if t < 25. * MB => 3,
+        Some(_) => 1,
+        None if rtt.unwrap_or_default() >= 150 => 6,
+        None => 3,
+    }
+}
+
+#[derive(Default)]
+struct Throughput {
+    bytes: u64,
+    elapsed: Duration,
+    value: Option<f64>,
+}
+
+impl Throughput {
+    // Only the time blocked in sending counts, a send which returns at once means the link
+    // is not the bottleneck.
+    fn add(&mut self, bytes: usize, elapsed: Duration) {
+        self.bytes += bytes as u64;
+        self.elapsed += elapsed;
+        if self.elapsed >= MEASURE_WINDOW {
+            let t = self.bytes as f64 / self.elapsed.as_secs_f64();
+            self.value = Some(self.value.map(|v| v * 0.7 + t * 0.3).unwrap_or(t));
+            self.bytes = 0;
+            self.elapsed = Duration::ZERO;
+        }
+    }
+}
+
+/// Drives the read jobs of a connection like `fs::handle_read_jobs`, with the compression
+/// decided per file and the file digests for `file_verify`.
+#[derive(Default)]
+pub struct ReadJobsCompression {
+    // Job id to the file and its level.
+    files: HashMap<i32, (i32, Option<i32>)>,
+    // Job id to raw and wire bytes.
+    sizes: HashMap<i32, (u64, u64)>,
+    throughput: Throughput,
+    rtt: Option<u32>,
+    // Wire bytes since the last take_sent.
+    sent: usize,
+    digests: crate::file_verify::ReadDigests,
+}
+
+impl ReadJobsCompression {
+    pub fn set_rtt(&mut self, rtt: u32) {
+        self.rtt = Some(rtt);
+    }
+
+    /// Send the sha256 of every file after its last block.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.digests.set_enabled(verify);
+    }
+
+    pub fn take_sent(&mut self) -> usize {
+        std::mem::take(&mut self.sent)
+    }
+
+    // The level is decided once per file, none if the file is not compressible.
+    fn level(&mut self, job: &fs::TransferJob, file_num: i32) -> Option<i32> {
+        if let Some((num, level)) = self.files.get(&job.id()) {
+            if *num == file_num {
+                return *level;
+            }
+        }
+        let compressible = match (&job.data_source, job.files().get(file_num as usize)) {
+            (fs::DataSource::FilePath(p), Some(file)) => {
+                is_compressible(&fs::TransferJob::join(p, &file.name))
+            }
+            _ => true,
+        };
+        let level = compressible.then(|| level(self.throughput.value, self.rtt));
+        self.files.insert(job.id(), (file_num, level));
+        level
+    }
+
+    pub async fn handle_read_jobs(
+        &mut self,
+        jobs: &mut Vec<fs::TransferJob>,
+        stream: &mut Stream,
+    ) -> ResultType<String> {
+        let mut job_log = Default::default();
+        let mut finished = None;
+        // Handle jobs one by one.
+        if let Some(job) = jobs.iter_mut().find(|job| !job.is_last_job) {
+            let finished_size = job.finished_size();
+            match job.read(stream).await {
+                Err(err) => {
+                    self.digests.discard(job.id());
+                    stream
+                        .send(&fs::new_error(job.id(), err, job.file_num()))
+                        .await?;
+                }
+                Ok(Some(mut block)) => {
+                    if !block.data.is_empty() {
+                        let level = self.level(job, block.file_num);
+                        recompress(&mut block, level);
+                    }
+                    if let Some(msg) = self.digests.on_block(&block) {
+                        stream.send(&msg).await?;
+                    }
+                    let sizes = self.sizes.entry(job.id()).or_default();
+                    sizes.0 += job.finished_size().saturating_sub(finished_size);
+                    sizes.1 += block.data.len() as u64;
+                    let wire = block.data.len();
+                    self.sent += wire;
+                    let start = std::time::Instant::now();
+                    stream.send(&fs::new_block(block)).await?;
+                    self.throughput.add(wire, start.elapsed());
+                }
+                Ok(None) => {
+                    if job.job_completed() {
+                        if let Some(msg) = self.digests.finish(job.id()) {
+                            stream.send(&msg).await?;
+                        }
+                        job_log = self.add_ratio(fs::serialize_transfer_job(job, true, false, ""));
+                        finished = Some(job.id());
+                        match job.job_error() {
+                            Some(err) => {
+                                stream
+                                    .send(&fs::new_error(job.id(), err, job.file_num()))
+                                    .await?
+                            }
+                            None => stream.send(&fs::new_done(job.id(), job.file_num())).await?,
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(id) = finished {
+            let _ = fs::remove_job(id, jobs);
+            self.files.remove(&id);
+            self.sizes.remove(&id);
+        }
+        Ok(job_log)
+    }
+
+    /// Adds `compression_ratio`, raw bytes over sent bytes, to the jobs of a transfer log.
+    pub fn add_ratio(&self, log: String) -> String {
+        let Ok(mut value) = serde_json::from_str::<serde_json::Value>(&log) else {
+            return log;
+        };
+        let mut add = |job: &mut serde_json::Value| {
+            let id = job.get("id").and_then(|id| id.as_i64()).unwrap_or_default() as i32;
+            if let (Some(&(raw, wire)), Some(job)) = (self.sizes.get(&id), job.as_object_mut()) {
+                if wire > 0 {
+                    let ratio = (raw as f64 / wire as f64 * 100.).round() / 100.;
+                    job.insert("compression_ratio".to_owned(), ratio.into());
+                }
+            }
+        };
+        match &mut value {
+            serde_json::Value::Array(jobs) => jobs.iter_mut().for_each(&mut add),
+            job => add(job),
+        }
+        serde_json::to_string(&value).unwrap_or(log)
+    }
+}
+
+// Blocks of incompressible files which `TransferJob::read` could shrink are kept, the CPU
+// is already spent.
+fn recompress(block: &mut FileTransferBlock, level: Option<i32>) {
+    let Some(level) = level else {
+        return;
+    };
+    if level == DEFAULT_LEVEL && block.compressed {
+        return;
+    }
+    let raw = if block.compressed {
+        decompress(&block.data)
+    } else {
+        block.data.to_vec()
+    };
+    match zstd::bulk::compress(&raw, level) {
+        Ok(data) if data.len() < raw.len() => {
+            block.data = data.into();
+            block.compressed = true;
+        }
+        Ok(_) => {
+            block.data = raw.into();
+            block.compressed = false;
+        }
+        Err(e) => hbb_common::log::debug!("Failed to compress the file block: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressible() {
+        let text = "2024-01-01 INFO connection established\n".repeat(1000);
+        assert!(is_compressible_data(text.as_bytes(), &[]));
+        let mut x = 1u32;
+        let random: Vec<u8> = (0..SAMPLE_SIZE)
+            .map(|_| {
+                x = x.wrapping_mul(1664525).wrapping_add(1013904223);
+                (x >> 24) as u8
+            })
+            .collect();
+        assert!(entropy(&random) > MAX_ENTROPY);
+        assert!(!is_compressible_data(&random, &random));
+        // A text header with a compressed body.
+        assert!(!is_compressible_data(text.as_bytes(), &random));
+        let mut zip = b"PK\x03\x04".to_vec();
+        zip.extend(text.as_bytes());
+        assert!(!is_compressible_data(&zip, &[]));
+    }
+
+    #[test]
+    fn test_level() {
+        assert_eq!(level(None, None), 3);
+        assert_eq!(level(None, Some(200)), 6);
+        assert_eq!(level(Some(100. * 1024.), Some(200)), 9);
+        assert_eq!(level(Some(100. * 1024. * 1024.), None), 1);
+    }
+
+    #[test]
+    fn test_recompress() {
+        let text = "2024-01-01 INFO connection established\n".repeat(1000);
+        let mut block = FileTransferBlock {
+            data: text.clone().into_bytes().into(),
+            ..Default::default()
+        };
+        recompress(&mut block, None);
+        assert!(!block.compressed);
+        recompress(&mut block, Some(9));
+        assert!(block.compressed && block.data.len() < text.len());
+        assert_eq!(decompress(&block.data), text.as_bytes());
+        recompress(&mut block, Some(1));
+        assert_eq!(decompress(&block.data), text.as_bytes());
+    }
+}
@@ -0,0 +1,534 @@
+// rsync-style block delta for file transfer.
+//
+// The receiver sends the signatures of its existing copy, one rolling weak checksum and one
+// strong hash per block. The sender scans its file with a rolling window and answers with
+// copy ops for the blocks the receiver already has and literal data for the rest.
+
+use hbb_common::{
+    anyhow::anyhow,
+    bail,
+    sha2::{Digest, Sha256},
+    ResultType,
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// Smaller files are cheaper to resend than to diff.
+pub const DELTA_MIN_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+const MIN_BLOCK_SIZE: u32 = 4 * 1024;
+const MAX_BLOCK_SIZE: u32 = 1024 * 1024;
+const STRONG_LEN: usize = 16;
+const SIGNATURE_LEN: usize = 4 + STRONG_LEN;
+// Literal data is flushed in batches of this size.
+const BATCH_SIZE: usize = 64 * 1024;
+const READ_SIZE: usize = 256 * 1024;
+
+const OP_COPY: u8 = 0;
+const OP_DATA: u8 = 1;
+
+#[inline]
+pub fn is_delta_candidate(file_size: u64) -> bool {
+    file_size >= DELTA_MIN_FILE_SIZE
+}
+
+/// Square root of the file size, like rsync, rounded to 1 KiB.
+pub fn block_size(file_size: u64) -> u32 {
+    let size = ((file_size as f64).sqrt() as u64 + 1023) & !1023;
+    size.clamp(MIN_BLOCK_SIZE as u64, MAX_BLOCK_SIZE as u64) as u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let len = data.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, &x) in data.iter().enumerate() {
+            a = a.wrapping_add(x as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(x as u32));
+        }
+        Self { a, b, len }
+    }
+
+    #[inline]
+    fn roll(&mut self, out: u8, inp: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(inp as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    #[inline]
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(data: &[u8]) -> [u8; STRONG_LEN] {
+    let mut out = [0u8; STRONG_LEN];
+    out.copy_from_slice(&Sha256::digest(data)[..STRONG_LEN]);
+    out
+}
+
+// Fills `buf` as far as possible, returns the number of bytes read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Signatures of every block of `path`, the last block may be short.
+pub fn signatures(path: &Path, block_size: u32) -> ResultType<Vec<u8>> {
+    if block_size == 0 {
+        bail!("invalid block size");
+    }
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut block = vec![0u8; block_size as usize];
+    let mut out = Vec::new();
+    loop {
+        let n = read_full(&mut reader, &mut block)?;
+        if n == 0 {
+            break;
+        }
+        out.extend_from_slice(&Rolling::new(&block[..n]).digest().to_le_bytes());
+        out.extend_from_slice(&strong(&block[..n]));
+        if n < block.len() {
+            break;
+        }
+    }
+    Ok(out)
+}
+
+struct Signatures {
+    weak: HashMap<u32, Vec<u32>>,
+    strong: Vec<[u8; STRONG_LEN]>,
+    // Index and length of the trailing short block.
+    tail: Option<(u32, u32, u32)>,
+}
+
+impl Signatures {
+    fn parse(data: &[u8], block_size: u32, basis_size: u64) -> ResultType<Self> {
+        if data.len() % SIGNATURE_LEN != 0 {
+            bail!("invalid signatures length {}", data.len());
+        }
+        let count = data.len() / SIGNATURE_LEN;
+        let tail_len = (basis_size % block_size as u64) as u32;
+        let mut weak: HashMap<u32, Vec<u32>> = HashMap::new();
+        let mut strongs = Vec::with_capacity(count);
+        let mut tail = None;
+        for (i, sig) in data.chunks_exact(SIGNATURE_LEN).enumerate() {
+            let w = u32::from_le_bytes([sig[0], sig[1], sig[2], sig[3]]);
+            let mut s = [0u8; STRONG_LEN];
+            s.copy_from_slice(&sig[4..]);
+            strongs.push(s);
+            if i + 1 == count && tail_len != 0 {
+                tail = Some((i as u32, tail_len, w));
+            } else {
+                weak.entry(w).or_default().push(i as u32);
+            }
+        }
+        Ok(Self {
+            weak,
+            strong: strongs,
+            tail,
+        })
+    }
+
+    fn find(&self, weak: u32, window: &[u8]) -> Option<u32> {
+        let candidates = self.weak.get(&weak)?;
+        let s = strong(window);
+        candidates
+            .iter()
+            .find(|&&i| self.strong[i as usize] == s)
+            .cloned()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeltaOp {
+    /// `count` blocks of the receiver's copy, starting at block `index`.
+    Copy {
+        index: u32,
+        count: u32,
+    },
+    Data(Vec<u8>),
+}
+
+impl DeltaOp {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            DeltaOp::Copy { index, count } => {
+                out.push(OP_COPY);
+                out.extend_from_slice(&index.to_le_bytes());
+                out.extend_from_slice(&count.to_le_bytes());
+            }
+            DeltaOp::Data(data) => {
+                out.push(OP_DATA);
+                out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                out.extend_from_slice(data);
+            }
+        }
+    }
+
+    pub fn decode(mut data: &[u8]) -> ResultType<Vec<DeltaOp>> {
+        fn take_u32(data: &mut &[u8]) -> ResultType<u32> {
+            if data.len() < 4 {
+                bail!("truncated delta op");
+            }
+            let v = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            *data = &data[4..];
+            Ok(v)
+        }
+        let mut ops = Vec::new();
+        while let Some((&tag, rest)) = data.split_first() {
+            data = rest;
+            match tag {
+                OP_COPY => {
+                    let index = take_u32(&mut data)?;
+                    let count = take_u32(&mut data)?;
+                    ops.push(DeltaOp::Copy { index, count });
+                }
+                OP_DATA => {
+                    let len = take_u32(&mut data)? as usize;
+                    if data.len() < len {
+                        bail!("truncated delta data");
+                    }
+                    ops.push(DeltaOp::Data(data[..len].to_vec()));
+                    data = &data[len..];
+                }
+                _ => bail!("unknown delta op {}", tag),
+            }
+        }
+        Ok(ops)
+    }
+}
+
+// Coalesces ops and hands encoded batches to `emit`.
+struct Encoder<'a> {
+    copy: Option<(u32, u32)>,
+    out: Vec<u8>,
+    emit: &'a mut dyn FnMut(Vec<u8>) -> ResultType<()>,
+}
+
+impl<'a> Encoder<'a> {
+    fn copy(&mut self, index: u32) -> ResultType<()> {
+        match self.copy.as_mut() {
+            Some((start, count)) if *start + *count == index => *count += 1,
+            _ => {
+                self.flush_copy();
+                self.copy = Some((index, 1));
+            }
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, data: &[u8]) -> ResultType<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.flush_copy();
+        DeltaOp::Data(data.to_vec()).encode(&mut self.out);
+        if self.out.len() >= BATCH_SIZE {
+            (self.emit)(std::mem::take(&mut self.out))?;
+        }
+        Ok(())
+    }
+
+    fn flush_copy(&mut self) {
+        if let Some((index, count)) = self.copy.take() {
+            DeltaOp::Copy { index, count }.encode(&mut self.out);
+        }
+    }
+
+    fn finish(mut self) -> ResultType<()> {
+        self.flush_copy();
+        if !self.out.is_empty() {
+            (self.emit)(std::mem::take(&mut self.out))?;
+        }
+        Ok(())
+    }
+}
+
+/// Scans `path` against the receiver's signatures, `emit` gets the encoded ops in order.
+pub fn compute_delta(
+    path: &Path,
+    block_size: u32,
+    basis_size: u64,
+    signatures: &[u8],
+    emit: &mut dyn FnMut(Vec<u8>) -> ResultType<()>,
+) -> ResultType<()> {
+    // The block size comes from the peer, the window buffer is allocated with it.
+    if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+        bail!("invalid block size {}", block_size);
+    }
+    let sigs = Signatures::parse(signatures, block_size, basis_size)?;
+    let bs = block_size as usize;
+    let mut reader = File::open(path)?;
+    let mut encoder = Encoder {
+        copy: None,
+        out: Vec::new(),
+        emit,
+    };
+    // buf[lit..pos] is pending literal data, buf[pos..pos + bs] is the window.
+    let mut buf: Vec<u8> = Vec::new();
+    let mut lit = 0;
+    let mut pos = 0;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+    loop {
+        if pos + bs > buf.len() && !eof {
+            buf.drain(..lit);
+            pos -= lit;
+            lit = 0;
+            let len = buf.len();
+            buf.resize(len + READ_SIZE.max(bs), 0);
+            let n = read_full(&mut reader, &mut buf[len..])?;
+            buf.truncate(len + n);
+            eof = n == 0 || len + n < pos + bs;
+            continue;
+        }
+        if pos + bs > buf.len() {
+            break;
+        }
+        let window = &buf[pos..pos + bs];
+        let r = *rolling.get_or_insert_with(|| Rolling::new(window));
+        if let Some(index) = sigs.find(r.digest(), window) {
+            encoder.data(&buf[lit..pos])?;
+            encoder.copy(index)?;
+            pos += bs;
+            lit = pos;
+            rolling = None;
+            continue;
+        }
+        if pos + bs < buf.len() {
+            if let Some(r) = rolling.as_mut() {
+                r.roll(buf[pos], buf[pos + bs]);
+            }
+        } else {
+            rolling = None;
+        }
+        pos += 1;
+        if pos - lit >= BATCH_SIZE {
+            encoder.data(&buf[lit..pos])?;
+            lit = pos;
+        }
+    }
+    // The remainder is shorter than a block, it can only match the receiver's short tail.
+    let rest = &buf[pos..];
+    if let Some((index, len, weak)) = sigs.tail {
+        if rest.len() == len as usize
+            && Rolling::new(rest).digest() == weak
+            && sigs.strong[index as usize] == strong(rest)
+        {
+            encoder.data(&buf[lit..pos])?;
+            encoder.copy(index)?;
+            return encoder.finish();
+        }
+    }
+    encoder.data(&buf[lit..])?;
+    encoder.finish()
+}
+
+/// Rebuilds a file from the receiver's copy and the sender's ops into a temporary file,
+/// which replaces the copy on `finish`.
+pub struct DeltaApplier {
+    path: PathBuf,
+    basis_path: PathBuf,
+    tmp: PathBuf,
+    block_size: u64,
+    basis: File,
+    out: BufWriter<File>,
+    written: u64,
+}
+
+impl DeltaApplier {
+    /// The copy is `basis`, `path` itself or another file which is removed on `finish`.
+    pub fn new(basis: &Path, path: &Path, block_size: u32) -> ResultType<Self> {
+        let mut name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("invalid path {}", path.display()))?
+            .to_os_string();
+        name.push(".delta");
+        let tmp = path.with_file_name(name);
+        Ok(Self {
+            path: path.to_path_buf(),
+            basis_path: basis.to_path_buf(),
+            basis: File::open(basis)?,
+            out: BufWriter::new(File::create(&tmp)?),
+            tmp,
+            block_size: block_size as u64,
+            written: 0,
+        })
+    }
+
+    pub fn apply(&mut self, ops: &[u8]) -> ResultType<()> {
+        for op in DeltaOp::decode(ops)? {
+            match op {
+                DeltaOp::Copy { index, count } => {
+                    let len = count as u64 * self.block_size;
+                    self.basis
+                        .seek(SeekFrom::Start(index as u64 * self.block_size))?;
+                    let n = std::io::copy(&mut (&self.basis).take(len), &mut self.out)?;
+                    if n == 0 {
+                        bail!("delta block {} is out of range", index);
+                    }
+                    self.written += n;
+                }
+                DeltaOp::Data(data) => {
+                    self.out.write_all(&data)?;
+                    self.written += data.len() as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the rebuilt size, then moves the file in place with the sender's mtime.
+    pub fn finish(self, file_size: u64, modified_time: u64) -> ResultType<()> {
+        let Self {
+            path,
+            basis_path,
+            tmp,
+            out,
+            written,
+            basis,
+            ..
+        } = self;
+        drop(basis);
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        if written != file_size {
+            drop(file);
+            std::fs::remove_file(&tmp).ok();
+            bail!("delta size mismatch, {} != {}", written, file_size);
+        }
+        file.sync_all()?;
+        if modified_time > 0 {
+            file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(modified_time))?;
+        }
+        drop(file);
+        std::fs::rename(&tmp, &path)?;
+        if basis_path != path {
+            std::fs::remove_file(&basis_path).ok();
+        }
+        Ok(())
+    }
+
+    pub fn abort(self) {
+        let tmp = self.tmp.clone();
+        drop(self);
+        std::fs::remove_file(tmp).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(old: &[u8], new: &[u8], block_size: u32) -> usize {
+        let dir = std::env::temp_dir().join(format!(
+            "file_delta_{}_{}_{}",
+            std::process::id(),
+            old.len(),
+            new.len()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let old_path = dir.join("old");
+        let new_path = dir.join("new");
+        std::fs::write(&old_path, old).unwrap();
+        std::fs::write(&new_path, new).unwrap();
+        let sigs = signatures(&old_path, block_size).unwrap();
+        let mut batches = vec![];
+        compute_delta(
+            &new_path,
+            block_size,
+            old.len() as _,
+            &sigs,
+            &mut |ops: Vec<u8>| {
+                batches.push(ops);
+                Ok(())
+            },
+        )
+        .unwrap();
+        let mut applier = DeltaApplier::new(&old_path, &old_path, block_size).unwrap();
+        let mut literal = 0;
+        for ops in batches {
+            for op in DeltaOp::decode(&ops).unwrap() {
+                if let DeltaOp::Data(d) = op {
+                    literal += d.len();
+                }
+            }
+            applier.apply(&ops).unwrap();
+        }
+        applier.finish(new.len() as _, 0).unwrap();
+        assert_eq!(std::fs::read(&old_path).unwrap(), new);
+        std::fs::remove_dir_all(&dir).ok();
+        literal
+    }
+
+    fn random(len: usize, seed: u64) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rolling() {
+        let data = random(300, 1);
+        let mut r = Rolling::new(&data[..100]);
+        for i in 0..200 {
+            r.roll(data[i], data[i + 100]);
+            assert_eq!(r, Rolling::new(&data[i + 1..i + 101]));
+        }
+    }
+
+    #[test]
+    fn test_delta() {
+        let bs = 1024;
+        let old = random(100 * 1024 + 300, 2);
+        assert_eq!(roundtrip(&old, &old, bs), 0);
+
+        // Insert, modify and truncate, only the changed bytes go as literals.
+        let mut new = old.clone();
+        new.splice(5000..5000, random(10, 3));
+        new[60000] ^= 0xff;
+        new.truncate(90 * 1024 + 7);
+        assert!(roundtrip(&old, &new, bs) < 4 * bs as usize);
+
+        assert_eq!(roundtrip(&old, &[], bs), 0);
+        assert_eq!(roundtrip(&[], &old, bs), old.len());
+
+        // The block size comes from the peer.
+        let path = std::env::temp_dir().join(format!("delta-bs-{}", std::process::id()));
+        std::fs::write(&path, &old).unwrap();
+        for bs in [0, MAX_BLOCK_SIZE + 1, u32::MAX] {
+            assert!(compute_delta(&path, bs, 0, &[], &mut |_| Ok(())).is_err());
+        }
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,399 @@
+// Unix metadata of file transfers: permissions, ownership, mtimes, symlinks and xattrs.
+//
+// The sender walks the job path once more before sending and sends the metadata of every
+// entry, symlinks included, which the listing of the job skips. The receiver keeps it until
+// the job is done, then applies it, the deepest entries first so the directories get their
+// mtime and mode last, and creates the symlinks at the end. Nothing is changed through a
+// symlink below the job path. The ownership is applied by name and only if the receiver
+// allows it, the other errors are only logged.
+
+use crate::ext_msg::{self, Ext};
+use hbb_common::{fs, message_proto::Message};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
+
+/// Option of the receiver, a peer option on the client, "Y" to apply the owner, the group
+/// and the setuid, setgid and sticky bits.
+pub const OPTION_APPLY_OWNERSHIP: &str = "file-transfer-apply-ownership";
+// The xattrs of a file are not sent if they are larger.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+const MAX_XATTRS_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Meta {
+    /// Relative to the job path, empty for the job path itself.
+    pub name: String,
+    pub is_dir: bool,
+    pub mode: u32,
+    pub user: String,
+    pub group: String,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    /// Not empty for a symlink.
+    pub link_target: String,
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// The metadata to send with the job, none if there is nothing to preserve on this platform.
+pub fn new_meta(id: i32, path: &str, include_hidden: bool) -> Option<Message> {
+    let files = collect(&fs::get_path(path), include_hidden);
+    if files.is_empty() {
+        return None;
+    }
+    Some(ext_msg::new_message(&Ext::FileMeta { id, files }, &[]))
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+mod unix {
+    use super::*;
+    use std::{
+        collections::HashSet,
+        os::unix::fs::{lchown, symlink, MetadataExt, PermissionsExt},
+    };
+    use users::{Groups, Users, UsersCache};
+
+    fn xattrs(path: &Path) -> Vec<(String, Vec<u8>)> {
+        let Ok(names) = xattr::list(path) else {
+            return vec![];
+        };
+        let mut size = 0;
+        let mut xattrs = vec![];
+        for name in names {
+            let name = name.to_string_lossy().to_string();
+            // Only the user namespace is portable, the others are labels and acls of the
+            // sender's system.
+            #[cfg(target_os = "linux")]
+            if !name.starts_with("user.") {
+                continue;
+            }
+            if let Ok(Some(value)) = xattr::get(path, &name) {
+                size += name.len() + value.len();
+                xattrs.push((name, value));
+            }
+        }
+        if size > MAX_XATTRS_SIZE {
+            log::warn!("Xattrs of {:?} too large, {} bytes", path, size);
+            return vec![];
+        }
+        xattrs
+    }
+
+    fn meta(cache: &UsersCache, path: &Path, name: String) -> Option<Meta> {
+        let md = std::fs::symlink_metadata(path).ok()?;
+        let link_target = if md.file_type().is_symlink() {
+            std::fs::read_link(path).ok()?.to_string_lossy().to_string()
+        } else {
+            "".to_owned()
+        };
+        Some(Meta {
+            name,
+            is_dir: md.is_dir(),
+            mode: md.mode() & 0o7777,
+            user: cache
+                .get_user_by_uid(md.uid())
+                .map(|u| u.name().to_string_lossy().to_string())
+                .unwrap_or_default(),
+            group: cache
+                .get_group_by_gid(md.gid())
+                .map(|g| g.name().to_string_lossy().to_string())
+                .unwrap_or_default(),
+            mtime: md.mtime(),
+            mtime_nsec: md.mtime_nsec() as _,
+            xattrs: if link_target.is_empty() {
+                xattrs(path)
+            } else {
+                vec![]
+            },
+            link_target,
+        })
+    }
+
+    /// The metadata of `root` and of everything below it, the directories before their
+    /// content. Links to directories are not followed.
+    pub fn collect(root: &Path, include_hidden: bool) -> Vec<Meta> {
+        let cache = UsersCache::new();
+        let Some(m) = meta(&cache, root, "".to_owned()) else {
+            return vec![];
+        };
+        let mut dirs = if m.is_dir {
+            vec![root.to_owned()]
+        } else {
+            vec![]
+        };
+        let mut metas = vec![m];
+        while let Some(dir) = dirs.pop() {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                if !include_hidden && file_name.starts_with('.') {
+                    continue;
+                }
+                let path = entry.path();
+                let Ok(name) = path.strip_prefix(root) else {
+                    continue;
+                };
+                let Some(m) = meta(&cache, &path, name.to_string_lossy().to_string()) else {
+                    continue;
+                };
+                if m.is_dir {
+                    dirs.push(path);
+                }
+                metas.push(m);
+            }
+        }
+        metas
+    }
+
+    fn apply_one(path: &Path, m: &Meta, cache: &UsersCache, apply_ownership: bool) {
+        let Ok(md) = std::fs::symlink_metadata(path) else {
+            return;
+        };
+        let is_symlink = md.file_type().is_symlink();
+        // Only a symlink created from the metadata is changed, not the one it points to.
+        let is_link_meta = !m.link_target.is_empty();
+        if is_symlink != is_link_meta {
+            return;
+        }
+        if apply_ownership {
+            let uid = cache.get_user_by_name(&m.user).map(|u| u.uid());
+            let gid = cache.get_group_by_name(&m.group).map(|g| g.gid());
+            if let Err(err) = lchown(path, uid, gid) {
+                log::warn!("Failed to change the owner of {:?}: {}", path, err);
+            }
+        }
+        if !is_symlink {
+            for (name, value) in &m.xattrs {
+                if let Err(err) = xattr::set(path, name, value) {
+                    log::debug!("Failed to set xattr {} of {:?}: {}", name, path, err);
+                }
+            }
+            // After chown, which clears the setuid and setgid bits.
+            let mode = if apply_ownership {
+                m.mode & 0o7777
+            } else {
+                m.mode & 0o777
+            };
+            let perms = std::fs::Permissions::from_mode(mode);
+            if let Err(err) = std::fs::set_permissions(path, perms) {
+                log::warn!("Failed to set the mode of {:?}: {}", path, err);
+            }
+        }
+        set_mtime(path, &md, m);
+    }
+
+    fn set_mtime(path: &Path, md: &std::fs::Metadata, m: &Meta) {
+        let atime = filetime::FileTime::from_last_access_time(md);
+        let mtime = filetime::FileTime::from_unix_time(m.mtime, m.mtime_nsec);
+        if let Err(err) = filetime::set_symlink_file_times(path, atime, mtime) {
+            log::warn!("Failed to set the mtime of {:?}: {}", path, err);
+        }
+    }
+
+    // Whether a directory between the root and the entry is a symlink, which the entry would
+    // be created or changed through.
+    fn below_symlink(root: &Path, name: &str) -> bool {
+        let mut path = root.to_owned();
+        let Some(parent) = Path::new(name).parent() else {
+            return false;
+        };
+        for c in parent.components() {
+            path.push(c);
+            if std::fs::symlink_metadata(&path).map_or(false, |md| md.is_symlink()) {
+                log::warn!("Ignored {:?} below the symlink {:?}", name, path);
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn apply(root: &Path, metas: Vec<Meta>, apply_ownership: bool) {
+        let cache = UsersCache::new();
+        let (links, metas): (Vec<_>, Vec<_>) = metas
+            .into_iter()
+            .filter_map(|m| Some((join(root, &m.name)?, m)))
+            .partition(|(_, m)| !m.link_target.is_empty());
+        for (path, m) in &metas {
+            // Empty directories are not written by the job.
+            if m.is_dir && !path.exists() && !below_symlink(root, &m.name) {
+                std::fs::create_dir_all(path).ok();
+            }
+        }
+        for (path, m) in metas.iter().rev() {
+            if !below_symlink(root, &m.name) {
+                apply_one(path, m, &cache, apply_ownership);
+            }
+        }
+        // Last, so no other entry is written through them. The parents are checked again, a
+        // link created here may be the parent of the next one.
+        let mut parents = HashSet::new();
+        for (path, m) in &links {
+            if below_symlink(root, &m.name) {
+                continue;
+            }
+            if std::fs::symlink_metadata(path).map_or(false, |md| md.is_symlink()) {
+                std::fs::remove_file(path).ok();
+            }
+            if let Err(err) = symlink(&m.link_target, path) {
+                log::warn!("Failed to create the symlink {:?}: {}", path, err);
+                continue;
+            }
+            apply_one(path, m, &cache, apply_ownership);
+            if let Some(parent) = Path::new(&m.name).parent() {
+                parents.insert(parent.to_string_lossy().to_string());
+            }
+        }
+        // Creating the links changed the mtime of their directories.
+        for (path, m) in &metas {
+            if m.is_dir && parents.contains(&m.name) {
+                if let Ok(md) = std::fs::symlink_metadata(path) {
+                    set_mtime(path, &md, m);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub use unix::{apply, collect};
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+pub fn collect(_root: &Path, _include_hidden: bool) -> Vec<Meta> {
+    vec![]
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+pub fn apply(_root: &Path, _metas: Vec<Meta>, _apply_ownership: bool) {}
+
+// The names come from the peer, they must stay below the job path.
+#[cfg_attr(not(any(target_os = "macos", target_os = "linux")), allow(dead_code))]
+fn join(root: &Path, name: &str) -> Option<PathBuf> {
+    let name = Path::new(name);
+    if !name.components().all(|c| matches!(c, Component::Normal(_))) {
+        log::warn!("Invalid file name in metadata: {:?}", name);
+        return None;
+    }
+    Some(root.join(name))
+}
+
+#[derive(Default)]
+pub struct WriteMetas {
+    metas: HashMap<i32, Vec<Meta>>,
+}
+
+impl WriteMetas {
+    pub fn add(&mut self, id: i32, metas: Vec<Meta>) {
+        self.metas.insert(id, metas);
+    }
+
+    /// The job path with the metadata to apply to it.
+    pub fn take(&mut self, job: &fs::TransferJob) -> Option<(PathBuf, Vec<Meta>)> {
+        let metas = self.metas.remove(&job.id())?;
+        let fs::DataSource::FilePath(p) = &job.data_source else {
+            return None;
+        };
+        Some((p.clone(), metas))
+    }
+
+    pub fn remove(&mut self, id: i32) {
+        self.metas.remove(&id);
+    }
+}
+
+#[cfg(test)]
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+
+    #[test]
+    fn test_meta() {
+        let dir = std::env::temp_dir().join("rustdesk_test_file_meta");
+        std::fs::remove_dir_all(&dir).ok();
+        let src = dir.join("src");
+        let dst = dir.join("dst");
+        std::fs::create_dir_all(src.join("bin")).unwrap();
+        std::fs::create_dir_all(src.join("empty")).unwrap();
+        std::fs::write(src.join("bin").join("run"), "#!/bin/sh").unwrap();
+        let perms = std::fs::Permissions::from_mode(0o751);
+        std::fs::set_permissions(src.join("bin").join("run"), perms).unwrap();
+        symlink("bin/run", src.join("link")).unwrap();
+        let metas = collect(&src, false);
+        assert_eq!(metas.len(), 5);
+        assert!(metas
+            .iter()
+            .any(|m| m.name == "link" && m.link_target == "bin/run"));
+
+        // The job writes the regular files only.
+        std::fs::create_dir_all(dst.join("bin")).unwrap();
+        std::fs::write(dst.join("bin").join("run"), "#!/bin/sh").unwrap();
+        let bad = Meta {
+            name: "../escape".to_owned(),
+            is_dir: true,
+            ..Default::default()
+        };
+        apply(&dst, metas.into_iter().chain([bad]).collect(), false);
+        let run = std::fs::metadata(dst.join("bin").join("run")).unwrap();
+        assert_eq!(run.mode() & 0o7777, 0o751);
+        let src_run = std::fs::metadata(src.join("bin").join("run")).unwrap();
+        assert_eq!(run.mtime(), src_run.mtime());
+        assert_eq!(
+            std::fs::read_link(dst.join("link")).unwrap(),
+            Path::new("bin/run")
+        );
+        assert!(dst.join("empty").is_dir() && !dir.join("escape").exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_meta_symlink_escape() {
+        let dir = std::env::temp_dir().join("rustdesk_test_file_meta_escape");
+        std::fs::remove_dir_all(&dir).ok();
+        let dst = dir.join("dst");
+        let outside = dir.join("outside");
+        std::fs::create_dir_all(&dst).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("secret"), "").unwrap();
+        let perms = std::fs::Permissions::from_mode(0o600);
+        std::fs::set_permissions(outside.join("secret"), perms).unwrap();
+        // The destination has a link out of the job path already.
+        symlink(&outside, dst.join("old")).unwrap();
+        // A hostile peer sends a link out of the job path too, and entries below both.
+        let mut metas = vec![Meta {
+            name: "evil".to_owned(),
+            link_target: outside.to_string_lossy().to_string(),
+            ..Default::default()
+        }];
+        for parent in ["evil", "old"] {
+            metas.push(Meta {
+                name: format!("{}/secret", parent),
+                mode: 0o777,
+                ..Default::default()
+            });
+            metas.push(Meta {
+                name: format!("{}/new", parent),
+                is_dir: true,
+                ..Default::default()
+            });
+            metas.push(Meta {
+                name: format!("{}/inner", parent),
+                link_target: "/".to_owned(),
+                ..Default::default()
+            });
+        }
+        apply(&dst, metas, false);
+        let secret = std::fs::metadata(outside.join("secret")).unwrap();
+        assert_eq!(secret.mode() & 0o7777, 0o600);
+        assert!(!outside.join("new").exists());
+        assert!(std::fs::symlink_metadata(outside.join("inner")).is_err());
+        // The directory of the entries was created first, the link is not.
+        assert!(!std::fs::symlink_metadata(dst.join("evil"))
+            .unwrap()
+            .is_symlink());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,324 @@
+// Recursive search and preview of files for file transfer.
+//
+// The search walks the tree with `fs::read_dir`, so hidden files follow the same rules as
+// the listing, and streams the matches in batches, the shallow ones first. Links to
+// directories are not followed.
+
+use hbb_common::{
+    bail,
+    config::Config,
+    fs,
+    message_proto::{FileEntry, FileType},
+    regex::{self, bytes, Regex, RegexBuilder},
+    ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::Read,
+    path::{Path, MAIN_SEPARATOR},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+pub const ERR_NOT_SUPPORTED: &str = "Not supported by the remote";
+pub const ERR_BUSY: &str = "Another request is still running";
+
+const BATCH_SIZE: usize = 100;
+const BATCH_INTERVAL: Duration = Duration::from_millis(500);
+const DEFAULT_MAX_RESULTS: usize = 1000;
+const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024;
+// Larger files are never searched for the content, whatever the request.
+const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_PREVIEW_SIZE: usize = 16 * 1024;
+const MAX_PREVIEW_SIZE: usize = 1024 * 1024;
+#[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
+const THUMBNAIL_SIZE: u32 = 256;
+#[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
+const MAX_IMAGE_SIZE: u64 = 32 * 1024 * 1024;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchOptions {
+    /// A glob on the names, a regex with `regex`. Without wildcards it matches any name
+    /// containing it.
+    pub pattern: String,
+    pub regex: bool,
+    /// Only the files containing it, a regex with `regex`.
+    pub content: String,
+    /// Larger files are not searched for the content, 0 for the default.
+    pub max_file_size: u64,
+    pub include_hidden: bool,
+    /// 0 for the default.
+    pub max_results: usize,
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let glob = if glob.contains(['*', '?', '[']) {
+        glob.to_owned()
+    } else {
+        format!("*{}*", glob)
+    };
+    let mut re = "^".to_owned();
+    let mut chars = glob.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            '[' => {
+                re.push('[');
+                let mut first = true;
+                for c in chars.by_ref() {
+                    match c {
+                        ']' => {
+                            re.push(']');
+                            break;
+                        }
+                        '!' if first => re.push('^'),
+                        '\\' => re.push_str("\\\\"),
+                        c => re.push(c),
+                    }
+                    first = false;
+                }
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
+struct Matcher {
+    name: Regex,
+    content: Option<bytes::Regex>,
+    max_file_size: u64,
+}
+
+impl Matcher {
+    fn new(opts: &SearchOptions) -> ResultType<Self> {
+        let name = if opts.regex {
+            opts.pattern.clone()
+        } else {
+            glob_to_regex(&opts.pattern)
+        };
+        let content = if opts.content.is_empty() {
+            None
+        } else {
+            let content = if opts.regex {
+                opts.content.clone()
+            } else {
+                regex::escape(&opts.content)
+            };
+            Some(
+                bytes::RegexBuilder::new(&content)
+                    .case_insensitive(true)
+                    .build()?,
+            )
+        };
+        let max_file_size = match opts.max_file_size {
+            0 => DEFAULT_MAX_FILE_SIZE,
+            n => n.min(MAX_FILE_SIZE),
+        };
+        Ok(Self {
+            name: RegexBuilder::new(&name).case_insensitive(true).build()?,
+            content,
+            max_file_size,
+        })
+    }
+
+    fn is_match(&self, path: &Path, entry: &FileEntry) -> bool {
+        if !self.name.is_match(&entry.name) {
+            return false;
+        }
+        let Some(content) = &self.content else {
+            return true;
+        };
+        let t = entry.entry_type.value();
+        if (t != FileType::File as i32 && t != FileType::FileLink as i32)
+            || entry.size > self.max_file_size
+        {
+            return false;
+        }
+        let mut data = Vec::new();
+        File::open(path)
+            .and_then(|f| f.take(self.max_file_size).read_to_end(&mut data))
+            .is_ok()
+            && content.is_match(&data)
+    }
+}
+
+/// Searches below `root` and passes the matches to `emit`, named by their path relative to
+/// `root`.
+pub fn search(
+    root: &str,
+    opts: &SearchOptions,
+    cancel: &AtomicBool,
+    emit: &mut dyn FnMut(Vec<FileEntry>),
+) -> ResultType<()> {
+    let matcher = Matcher::new(opts)?;
+    let root = if root.is_empty() {
+        Config::get_home()
+    } else {
+        fs::get_path(root)
+    };
+    let max_results = match opts.max_results {
+        0 => DEFAULT_MAX_RESULTS,
+        n => n,
+    };
+    let mut dirs = VecDeque::from([(root, "".to_owned())]);
+    let mut is_root = true;
+    let mut found = 0;
+    let mut batch = vec![];
+    let mut last_emit = Instant::now();
+    while let Some((dir, prefix)) = dirs.pop_front() {
+        let fd = match fs::read_dir(&dir, opts.include_hidden) {
+            Ok(fd) => fd,
+            // The root must be readable, the errors below it are skipped.
+            Err(err) if is_root => return Err(err),
+            Err(_) => continue,
+        };
+        is_root = false;
+        for mut entry in fd.entries {
+            if cancel.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            let path = dir.join(&entry.name);
+            let name = format!("{}{}", prefix, entry.name);
+            if entry.entry_type.value() == FileType::Dir as i32 {
+                dirs.push_back((path.clone(), format!("{}{}", name, MAIN_SEPARATOR)));
+            }
+            if matcher.is_match(&path, &entry) {
+                entry.name = name;
+                batch.push(entry);
+                found += 1;
+                if found >= max_results {
+                    emit(batch);
+                    return Ok(());
+                }
+            }
+            if batch.len() >= BATCH_SIZE
+                || (!batch.is_empty() && last_emit.elapsed() >= BATCH_INTERVAL)
+            {
+                emit(std::mem::take(&mut batch));
+                last_emit = Instant::now();
+            }
+        }
+    }
+    if !batch.is_empty() {
+        emit(batch);
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct Preview {
+    /// A png thumbnail if true, the start of a text file otherwise.
+    pub is_image: bool,
+    pub data: Vec<u8>,
+    pub truncated: bool,
+}
+
+#[cfg(windows)]
+fn is_hidden(path: &Path) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    std::fs::metadata(path).map_or(false, |m| m.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+#[cfg(not(windows))]
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .map_or(false, |name| name.to_string_lossy().starts_with('.'))
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
+fn thumbnail(path: &Path) -> ResultType<Vec<u8>> {
+    let image = image::open(path)?.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    let mut png = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut png, image::ImageOutputFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+/// The first `max_size` bytes of a text file, 0 for the default, or a thumbnail of an image.
+pub fn preview(path: &str, max_size: usize, include_hidden: bool) -> ResultType<Preview> {
+    let path = fs::get_path(path);
+    if !include_hidden && is_hidden(&path) {
+        bail!("Hidden file");
+    }
+    let len = std::fs::metadata(&path)?.len();
+    if !path.is_file() {
+        bail!("Not a file");
+    }
+    #[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
+    if image::ImageFormat::from_path(&path).is_ok() {
+        if len > MAX_IMAGE_SIZE {
+            bail!("Image too large");
+        }
+        return Ok(Preview {
+            is_image: true,
+            data: thumbnail(&path)?,
+            truncated: false,
+        });
+    }
+    let max_size = match max_size {
+        0 => DEFAULT_PREVIEW_SIZE,
+        n => n.min(MAX_PREVIEW_SIZE),
+    };
+    let mut data = Vec::new();
+    File::open(&path)?
+        .take(max_size as u64)
+        .read_to_end(&mut data)?;
+    // Like git, a NUL byte means binary.
+    if data.contains(&0) {
+        bail!("Not a text file");
+    }
+    Ok(Preview {
+        is_image: false,
+        truncated: len > data.len() as u64,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        assert_eq!(glob_to_regex("*.log"), "^.*\\.log$");
+        assert_eq!(glob_to_regex("err"), "^.*err.*$");
+        assert_eq!(glob_to_regex("[!a]?"), "^[^a].$");
+
+        let root = std::env::temp_dir().join("rustdesk_test_file_search");
+        std::fs::create_dir_all(root.join("a").join("b")).unwrap();
+        std::fs::write(root.join("x.log"), "ok").unwrap();
+        std::fs::write(root.join("a").join("b").join("y.LOG"), "fatal error").unwrap();
+        std::fs::write(root.join("a").join("z.txt"), "fatal error").unwrap();
+        let root_str = root.to_string_lossy().to_string();
+        let run = |opts: SearchOptions| {
+            let mut names = vec![];
+            search(&root_str, &opts, &AtomicBool::new(false), &mut |entries| {
+                names.extend(entries.into_iter().map(|e| e.name))
+            })
+            .unwrap();
+            names.sort();
+            names
+        };
+        let b = format!("a{0}b{0}y.LOG", MAIN_SEPARATOR);
+        let opts = SearchOptions {
+            pattern: "*.log".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(run(opts.clone()), vec![b.clone(), "x.log".to_owned()]);
+        let content = SearchOptions {
+            content: "FATAL".to_owned(),
+            ..opts
+        };
+        assert_eq!(run(content), vec![b]);
+
+        let preview = preview(&root.join("x.log").to_string_lossy(), 1, true).unwrap();
+        assert!(!preview.is_image && preview.truncated && preview.data == b"o");
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
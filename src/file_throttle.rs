@@ -0,0 +1,164 @@
+// Rate limits of file transfer read jobs.
+//
+// Every connection has its own limit, all the connections of the process share the global
+// one. In priority mode a transfer slows down to PRIORITY_SPEED while the video reports a
+// high delay, so a large upload does not make the remote screen unusable.
+
+use hbb_common::config::Config;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Speeds are in KB/s, empty or 0 for no limit.
+pub const OPTION_MAX_SPEED: &str = "file-transfer-max-speed";
+pub const OPTION_GLOBAL_MAX_SPEED: &str = "file-transfer-global-max-speed";
+pub const OPTION_PRIORITY: &str = "file-transfer-priority";
+// Delay in ms from which the video is considered to suffer.
+pub const HIGH_DELAY: u32 = 150;
+const PRIORITY_SPEED: u64 = 256 * 1024;
+const BURST: Duration = Duration::from_millis(200);
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static::lazy_static! {
+    static ref GLOBAL: Mutex<RateLimiter> = Default::default();
+}
+
+/// Bytes per second of a speed option.
+pub fn parse_speed(v: &str) -> Option<u64> {
+    v.trim()
+        .parse::<u64>()
+        .ok()
+        .filter(|v| *v > 0)
+        .map(|v| v * 1024)
+}
+
+fn min_speed(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        _ => a.or(b),
+    }
+}
+
+// Token bucket, a block larger than the balance is allowed and paid back afterwards.
+#[derive(Default)]
+struct RateLimiter {
+    speed: Option<u64>,
+    balance: f64,
+    last: Option<Instant>,
+}
+
+impl RateLimiter {
+    fn set_speed(&mut self, speed: Option<u64>) {
+        if self.speed != speed {
+            self.speed = speed;
+            self.balance = 0.;
+            self.last = None;
+        }
+    }
+
+    fn ready(&mut self, now: Instant) -> bool {
+        let Some(speed) = self.speed else {
+            return true;
+        };
+        if let Some(last) = self.last {
+            let speed = speed as f64;
+            self.balance = (self.balance
+                + speed * now.saturating_duration_since(last).as_secs_f64())
+            .min(speed * BURST.as_secs_f64());
+        }
+        self.last = Some(now);
+        self.balance >= 0.
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        if self.speed.is_some() {
+            self.balance -= bytes as f64;
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct FileThrottle {
+    conn: RateLimiter,
+    local_speed: Option<u64>,
+    local_priority: bool,
+    peer_speed: Option<u64>,
+    peer_priority: bool,
+    high_delay: bool,
+    refreshed: Option<Instant>,
+}
+
+impl FileThrottle {
+    /// Limits set by the peer, or by the session options on the controlling side.
+    pub fn set_peer_limit(&mut self, speed: Option<u64>, priority: bool) {
+        self.peer_speed = speed;
+        self.peer_priority = priority;
+    }
+
+    pub fn set_high_delay(&mut self, high_delay: bool) {
+        self.high_delay = high_delay;
+    }
+
+    fn refresh(&mut self, now: Instant) {
+        if matches!(self.refreshed, Some(t) if now.saturating_duration_since(t) < REFRESH_INTERVAL)
+        {
+            return;
+        }
+        self.refreshed = Some(now);
+        self.local_speed = parse_speed(&Config::get_option(OPTION_MAX_SPEED));
+        self.local_priority = Config::get_option(OPTION_PRIORITY) == "Y";
+        GLOBAL
+            .lock()
+            .unwrap()
+            .set_speed(parse_speed(&Config::get_option(OPTION_GLOBAL_MAX_SPEED)));
+    }
+
+    fn speed(&self) -> Option<u64> {
+        let speed = min_speed(self.local_speed, self.peer_speed);
+        if self.high_delay && (self.local_priority || self.peer_priority) {
+            min_speed(speed, Some(PRIORITY_SPEED))
+        } else {
+            speed
+        }
+    }
+
+    /// Whether the next block can be read.
+    pub fn ready(&mut self) -> bool {
+        let now = Instant::now();
+        self.refresh(now);
+        self.conn.set_speed(self.speed());
+        self.conn.ready(now) && GLOBAL.lock().unwrap().ready(now)
+    }
+
+    pub fn consume(&mut self, bytes: usize) {
+        if bytes > 0 {
+            self.conn.consume(bytes);
+            GLOBAL.lock().unwrap().consume(bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::default();
+        let now = Instant::now();
+        assert!(limiter.ready(now));
+        limiter.set_speed(Some(1000));
+        assert!(limiter.ready(now));
+        limiter.consume(500);
+        assert!(!limiter.ready(now + Duration::from_millis(100)));
+        assert!(limiter.ready(now + Duration::from_millis(500)));
+        // The balance is capped, a long pause does not allow a burst.
+        assert!(limiter.ready(now + Duration::from_secs(100)));
+        limiter.consume(1000);
+        assert!(!limiter.ready(now + Duration::from_millis(100_500)));
+        assert_eq!(parse_speed("2"), Some(2048));
+        assert_eq!(parse_speed("0"), None);
+        assert_eq!(parse_speed(""), None);
+    }
+}
@@ -0,0 +1,192 @@
+// Whole-file sha256 verification of file transfers.
+//
+// The sender hashes the blocks of every file while reading and sends the digest after the
+// last block of the file. The receiver keeps the digests until the job is done, then hashes
+// the written files. The files which do not match are removed and transferred again.
+
+use crate::ext_msg::{self, Ext};
+use hbb_common::{
+    compress::decompress,
+    fs,
+    message_proto::{FileTransferBlock, Message},
+    sha2::{Digest, Sha256},
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+pub const OPTION_VERIFY: &str = "file-transfer-verify";
+pub const ERR_VERIFY_FAILED: &str = "File integrity check failed";
+pub const MAX_RETRIES: usize = 2;
+
+#[derive(Default)]
+pub struct ReadDigests {
+    enabled: bool,
+    // Job id to the file being read and its hasher, none if the file is not read from the start.
+    files: HashMap<i32, (i32, Option<Sha256>)>,
+}
+
+impl ReadDigests {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Hashes the block, returns the digest of the previous file of the job if the block
+    /// starts the next one.
+    pub fn on_block(&mut self, block: &FileTransferBlock) -> Option<Message> {
+        if !self.enabled {
+            return None;
+        }
+        let mut done = None;
+        if self.files.get(&block.id).map(|(file_num, _)| *file_num) != Some(block.file_num) {
+            done = self.finish(block.id);
+            let hasher = (block.blk_id == 0).then(Sha256::new);
+            self.files.insert(block.id, (block.file_num, hasher));
+        }
+        if let Some((_, Some(hasher))) = self.files.get_mut(&block.id) {
+            if block.compressed {
+                hasher.update(decompress(&block.data));
+            } else {
+                hasher.update(&block.data);
+            }
+        }
+        done
+    }
+
+    /// The digest of the last file of the job.
+    pub fn finish(&mut self, id: i32) -> Option<Message> {
+        let (file_num, hasher) = self.files.remove(&id)?;
+        let ext = Ext::FileDigest { id, file_num };
+        Some(ext_msg::new_message(&ext, &hasher?.finalize()))
+    }
+
+    /// The current file of the job failed to be read, there is nothing to verify.
+    pub fn discard(&mut self, id: i32) {
+        if let Some((_, hasher)) = self.files.get_mut(&id) {
+            *hasher = None;
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct WriteDigests {
+    digests: HashMap<i32, Vec<(i32, Vec<u8>)>>,
+}
+
+impl WriteDigests {
+    pub fn add(&mut self, id: i32, file_num: i32, sha256: Vec<u8>) {
+        self.digests.entry(id).or_default().push((file_num, sha256));
+    }
+
+    /// The digests received so far for the job.
+    pub fn get(&self, id: i32) -> &[(i32, Vec<u8>)] {
+        self.digests.get(&id).map_or(&[], |d| d.as_slice())
+    }
+
+    /// Restores the digests of a resumed job.
+    pub fn set(&mut self, id: i32, digests: Vec<(i32, Vec<u8>)>) {
+        self.digests.insert(id, digests);
+    }
+
+    /// The written files of the job with their expected digest.
+    pub fn take(&mut self, job: &fs::TransferJob) -> Vec<(i32, PathBuf, Vec<u8>)> {
+        let digests = self.digests.remove(&job.id()).unwrap_or_default();
+        let fs::DataSource::FilePath(p) = &job.data_source else {
+            return vec![];
+        };
+        digests
+            .into_iter()
+            .filter_map(|(file_num, digest)| {
+                let file = job.files().get(file_num as usize)?;
+                Some((file_num, fs::TransferJob::join(p, &file.name), digest))
+            })
+            .collect()
+    }
+
+    pub fn remove(&mut self, id: i32) {
+        self.digests.remove(&id);
+    }
+}
+
+pub fn sha256_file(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 128 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Hashes the written files and removes the ones which do not match, so they are not
+/// skipped as identical when sent again. Returns their file numbers, in order.
+pub fn verify(files: Vec<(i32, PathBuf, Vec<u8>)>) -> Vec<i32> {
+    let mut failed: Vec<i32> = files
+        .into_iter()
+        .filter(|(_, path, digest)| match sha256_file(path) {
+            Ok(d) if &d == digest => false,
+            res => {
+                log::error!("Failed to verify {:?}: {:?}", path, res.map(|_| "mismatch"));
+                std::fs::remove_file(path).ok();
+                true
+            }
+        })
+        .map(|(file_num, _, _)| file_num)
+        .collect();
+    failed.sort();
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let dir = std::env::temp_dir().join("rustdesk_test_file_verify");
+        std::fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let mut digests = ReadDigests::default();
+        digests.set_enabled(true);
+        for (file_num, chunk) in data.chunks(100_000).enumerate() {
+            let block = FileTransferBlock {
+                id: 1,
+                file_num: file_num as _,
+                data: chunk.to_vec().into(),
+                ..Default::default()
+            };
+            // The digest of file 0 comes with the first block of file 1.
+            assert_eq!(digests.on_block(&block).is_some(), file_num > 0);
+        }
+        let digest = digests
+            .finish(1)
+            .and_then(|msg| ext_msg::parse(msg.misc().plugin_request()));
+        assert_eq!(
+            digest,
+            Some((
+                Ext::FileDigest { id: 1, file_num: 2 },
+                Sha256::digest(&data[200_000..]).to_vec().into()
+            ))
+        );
+
+        let good = dir.join("good");
+        let bad = dir.join("bad");
+        std::fs::write(&good, &data).unwrap();
+        std::fs::write(&bad, &data[1..]).unwrap();
+        let expected = Sha256::digest(&data).to_vec();
+        let failed = verify(vec![
+            (0, good.clone(), expected.clone()),
+            (1, bad.clone(), expected),
+        ]);
+        assert_eq!(failed, vec![1]);
+        assert!(good.exists() && !bad.exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -785,6 +785,63 @@ impl InvokeUiSession for FlutterHandler {
         }
     }
 
+    fn update_sync_plan(&self, id: i32, plan: String) {
+        self.push_event(
+            "sync_plan",
+            &[("id", &id.to_string()), ("value", &plan)],
+            &[],
+        );
+    }
+
+    fn job_verified(&self, id: i32, verified: bool) {
+        self.push_event(
+            "job_verified",
+            &[("id", &id.to_string()), ("verified", &verified.to_string())],
+            &[],
+        );
+    }
+
+    fn update_search_results(&self, fd: FileDirectory, done: bool, error: String) {
+        let mut value = crate::common::_make_fd_to_json(fd.id, fd.path, &fd.entries);
+        value.insert("done".into(), json!(done));
+        value.insert("error".into(), json!(error));
+        self.push_event(
+            "search_results",
+            &[("value", &serde_json::to_string(&value).unwrap_or_default())],
+            &[],
+        );
+    }
+
+    fn update_file_preview(
+        &self,
+        id: i32,
+        path: String,
+        preview: crate::file_search::Preview,
+        error: String,
+    ) {
+        let data = if preview.is_image {
+            crate::encode64(&preview.data)
+        } else {
+            String::from_utf8_lossy(&preview.data).to_string()
+        };
+        self.push_event(
+            "file_preview",
+            &[
+                ("id", &id.to_string()),
+                ("path", &path),
+                ("is_image", &preview.is_image.to_string()),
+                ("data", &data),
+                ("truncated", &preview.truncated.to_string()),
+                ("error", &error),
+            ],
+            &[],
+        );
+    }
+
+    fn offer_resume_jobs(&self, count: i32) {
+        self.push_event("offer_resume_jobs", &[("count", &count.to_string())], &[]);
+    }
+
     fn update_empty_dirs(&self, res: ReadEmptyDirsResponse) {
         self.push_event(
             "empty_dirs",
@@ -799,6 +856,43 @@ impl InvokeUiSession for FlutterHandler {
         );
     }
 
+    fn update_camera_capabilities(&self, caps: scrap::camera::CameraCapabilities) {
+        let format = |f: &scrap::camera::CameraFormat| {
+            json!({
+                "width": f.width,
+                "height": f.height,
+                "frame_rate": f.frame_rate,
+                "fourcc": f.fourcc,
+            })
+        };
+        let controls: Vec<_> = caps
+            .controls
+            .iter()
+            .map(|c| {
+                json!({
+                    "name": c.name,
+                    "value": c.value,
+                    "min": c.min,
+                    "max": c.max,
+                    "step": c.step,
+                    "default": c.default_value,
+                })
+            })
+            .collect();
+        let formats: Vec<_> = caps.formats.iter().map(format).collect();
+        let current = caps.current.as_ref().map(format);
+        self.push_event(
+            "camera_capabilities",
+            &[
+                ("display", json!(caps.display)),
+                ("formats", json!(formats)),
+                ("current", json!(current)),
+                ("controls", json!(controls)),
+            ],
+            &[],
+        );
+    }
+
     // unused in flutter
     fn update_transfer_list(&self) {}
 
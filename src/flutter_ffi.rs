@@ -493,6 +493,12 @@ pub fn session_set_custom_fps(session_id: SessionID, fps: i32) {
     }
 }
 
+pub fn session_set_file_transfer_limit(session_id: SessionID, max_speed: i32, priority: bool) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.set_file_transfer_limit(max_speed, priority);
+    }
+}
+
 pub fn session_get_trackpad_speed(session_id: SessionID) -> Option<i32> {
     if let Some(session) = sessions::get_session_by_session_id(&session_id) {
         Some(session.get_trackpad_speed())
@@ -716,6 +722,42 @@ pub fn session_cancel_job(session_id: SessionID, act_id: i32) {
     }
 }
 
+pub fn session_sync_dir(
+    session_id: SessionID,
+    act_id: i32,
+    local: String,
+    remote: String,
+    options: String,
+) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.sync_dir(act_id, local, remote, options);
+    }
+}
+
+pub fn session_start_sync(session_id: SessionID, act_id: i32) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.start_sync(act_id);
+    }
+}
+
+pub fn session_search_remote(session_id: SessionID, act_id: i32, path: String, options: String) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.search_remote(act_id, path, options);
+    }
+}
+
+pub fn session_preview_remote(
+    session_id: SessionID,
+    act_id: i32,
+    path: String,
+    max_size: i32,
+    include_hidden: bool,
+) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.preview_remote(act_id, path, max_size, include_hidden);
+    }
+}
+
 pub fn session_create_dir(session_id: SessionID, act_id: i32, path: String, is_remote: bool) {
     if let Some(session) = sessions::get_session_by_session_id(&session_id) {
         session.create_dir(act_id, path, is_remote);
@@ -837,6 +879,40 @@ pub fn session_change_resolution(session_id: SessionID, display: i32, width: i32
     }
 }
 
+pub fn session_set_capture_region(
+    session_id: SessionID,
+    display: i32,
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.set_capture_region(display, x, y, width, height);
+    }
+}
+
+pub fn session_query_camera_capabilities(session_id: SessionID, display: i32) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.query_camera_capabilities(display);
+    }
+}
+
+pub fn session_set_camera_settings(
+    session_id: SessionID,
+    display: i32,
+    width: i32,
+    height: i32,
+    frame_rate: u32,
+    fourcc: String,
+    control: String,
+    value: i64,
+) {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.set_camera_settings(display, width, height, frame_rate, fourcc, control, value);
+    }
+}
+
 pub fn session_set_size(session_id: SessionID, display: usize, width: usize, height: usize) {
     super::flutter::session_set_size(session_id, display, width, height)
 }
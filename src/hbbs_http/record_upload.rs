@@ -3,11 +3,13 @@ use bytes::Bytes;
 use hbb_common::{bail, config::Config, lazy_static, log, ResultType};
 use reqwest::blocking::{Body, Client};
 use scrap::record::RecordState;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Map;
+use sha2::{Digest, Sha256};
 use std::{
     fs::File,
     io::{prelude::*, SeekFrom},
+    path::{Path, PathBuf},
     sync::{mpsc::Receiver, Arc, Mutex},
     time::{Duration, Instant},
 };
@@ -15,6 +17,11 @@ use std::{
 const MAX_HEADER_LEN: usize = 1024;
 const SHOULD_SEND_TIME: Duration = Duration::from_secs(1);
 const SHOULD_SEND_SIZE: u64 = 1024 * 1024;
+const MAX_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+const MAX_RETRIES: u32 = 5;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+const STATE_EXT: &str = "upload";
 
 lazy_static::lazy_static! {
     static ref ENABLE: Arc<Mutex<bool>> = Default::default();
@@ -25,18 +32,7 @@ pub fn is_enable() -> bool {
 }
 
 pub fn run(rx: Receiver<RecordState>) {
-    let mut uploader = RecordUploader {
-        client: create_http_client(),
-        api_server: crate::get_api_server(
-            Config::get_option("api-server"),
-            Config::get_option("custom-rendezvous-server"),
-        ),
-        filepath: Default::default(),
-        filename: Default::default(),
-        upload_size: Default::default(),
-        running: Default::default(),
-        last_send: Instant::now(),
-    };
+    let mut uploader = RecordUploader::new(create_http_client(), api_server());
     std::thread::spawn(move || loop {
         if let Err(e) = match rx.recv() {
             Ok(state) => match state {
@@ -68,23 +64,143 @@ pub fn run(rx: Receiver<RecordState>) {
                 break;
             }
         } {
+            // The state file is kept, `resume_unfinished` picks it up on next startup.
             uploader.running = false;
             log::error!("upload stop: {}", e);
         }
     });
 }
 
+/// Scan `dir` for uploads interrupted by a crash or network failure and finish them.
+pub fn resume_unfinished(dir: String) {
+    std::thread::spawn(move || {
+        let mut uploader = RecordUploader::new(create_http_client(), api_server());
+        for state_path in find_states(&dir) {
+            if let Err(e) = uploader.resume(&state_path) {
+                log::error!("failed to resume upload {:?}: {}", state_path, e);
+            }
+        }
+    });
+}
+
+fn api_server() -> String {
+    crate::get_api_server(
+        Config::get_option("api-server"),
+        Config::get_option("custom-rendezvous-server"),
+    )
+}
+
+fn state_path(filepath: &str) -> PathBuf {
+    PathBuf::from(format!("{}.{}", filepath, STATE_EXT))
+}
+
+fn find_states(dir: &str) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().map(|e| e == STATE_EXT).unwrap_or_default())
+        .collect()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn file_sha256_hex(filepath: &str) -> ResultType<String> {
+    let mut file = File::open(filepath)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Progress of one recording upload, persisted next to the recording.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct UploadState {
+    filename: String,
+    filepath: String,
+    /// Bytes confirmed by the server.
+    offset: u64,
+    /// The server has accepted the "new" request.
+    created: bool,
+}
+
+impl UploadState {
+    fn load(path: &Path) -> ResultType<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    fn store(&self) -> ResultType<()> {
+        let path = state_path(&self.filepath);
+        let tmp = path.with_extension(format!("{}.tmp", STATE_EXT));
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    fn remove(&self) {
+        std::fs::remove_file(state_path(&self.filepath)).ok();
+    }
+}
+
 struct RecordUploader {
     client: Client,
     api_server: String,
-    filepath: String,
-    filename: String,
-    upload_size: u64,
+    state: UploadState,
     running: bool,
     last_send: Instant,
+    retry_base_delay: Duration,
 }
 impl RecordUploader {
+    fn new(client: Client, api_server: String) -> Self {
+        Self {
+            client,
+            api_server,
+            state: Default::default(),
+            running: Default::default(),
+            last_send: Instant::now(),
+            retry_base_delay: RETRY_BASE_DELAY,
+        }
+    }
+
     fn send<Q, B>(&self, query: &Q, body: B) -> ResultType<()>
+    where
+        Q: Serialize + ?Sized,
+        B: Into<Body> + Clone,
+    {
+        let mut delay = self.retry_base_delay;
+        let mut retries = 0;
+        loop {
+            match self.send_once(query, body.clone()) {
+                Ok(()) => return Ok(()),
+                Err(e) if retries < MAX_RETRIES => {
+                    retries += 1;
+                    log::warn!(
+                        "upload {} failed: {}, retry {}/{} in {:?}",
+                        self.state.filename,
+                        e,
+                        retries,
+                        MAX_RETRIES,
+                        delay
+                    );
+                    std::thread::sleep(delay);
+                    delay = (delay * 2).min(RETRY_MAX_DELAY);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn send_once<Q, B>(&self, query: &Q, body: B) -> ResultType<()>
     where
         Q: Serialize + ?Sized,
         B: Into<Body>,
@@ -97,11 +213,15 @@ impl RecordUploader {
             .send()
         {
             Ok(resp) => {
+                let status = resp.status();
                 if let Ok(m) = resp.json::<Map<String, serde_json::Value>>() {
                     if let Some(e) = m.get("error") {
                         bail!(e.to_string());
                     }
                 }
+                if !status.is_success() {
+                    bail!("http status {}", status);
+                }
                 Ok(())
             }
             Err(e) => bail!(e.to_string()),
@@ -112,13 +232,16 @@ impl RecordUploader {
         match std::path::PathBuf::from(&filepath).file_name() {
             Some(filename) => match filename.to_owned().into_string() {
                 Ok(filename) => {
-                    self.filename = filename.clone();
-                    self.filepath = filepath.clone();
-                    self.upload_size = 0;
+                    self.state = UploadState {
+                        filename,
+                        filepath,
+                        offset: 0,
+                        created: false,
+                    };
+                    self.state.store()?;
                     self.running = true;
                     self.last_send = Instant::now();
-                    self.send(&[("type", "new"), ("file", &filename)], Bytes::new())?;
-                    Ok(())
+                    self.send_new()
                 }
                 Err(_) => bail!("can't parse filename:{:?}", filename),
             },
@@ -126,80 +249,268 @@ impl RecordUploader {
         }
     }
 
+    fn send_new(&mut self) -> ResultType<()> {
+        self.send(
+            &[("type", "new"), ("file", &self.state.filename)],
+            Bytes::new(),
+        )?;
+        self.state.created = true;
+        self.state.store()
+    }
+
     fn handle_frame(&mut self, flush: bool) -> ResultType<()> {
         if !flush && self.last_send.elapsed() < SHOULD_SEND_TIME {
             return Ok(());
         }
-        match File::open(&self.filepath) {
-            Ok(mut file) => match file.metadata() {
-                Ok(m) => {
-                    let len = m.len();
-                    if len <= self.upload_size {
-                        return Ok(());
-                    }
-                    if !flush && len - self.upload_size < SHOULD_SEND_SIZE {
-                        return Ok(());
-                    }
-                    let mut buf = Vec::new();
-                    match file.seek(SeekFrom::Start(self.upload_size)) {
-                        Ok(_) => match file.read_to_end(&mut buf) {
-                            Ok(length) => {
-                                self.send(
-                                    &[
-                                        ("type", "part"),
-                                        ("file", &self.filename),
-                                        ("offset", &self.upload_size.to_string()),
-                                        ("length", &length.to_string()),
-                                    ],
-                                    buf,
-                                )?;
-                                self.upload_size = len;
-                                self.last_send = Instant::now();
-                                Ok(())
-                            }
-                            Err(e) => bail!(e.to_string()),
-                        },
-                        Err(e) => bail!(e.to_string()),
-                    }
-                }
-                Err(e) => bail!(e.to_string()),
-            },
-            Err(e) => bail!(e.to_string()),
+        let mut file = File::open(&self.state.filepath)?;
+        let len = file.metadata()?.len();
+        if len <= self.state.offset {
+            return Ok(());
         }
+        if !flush && len - self.state.offset < SHOULD_SEND_SIZE {
+            return Ok(());
+        }
+        while self.state.offset < len {
+            let length = (len - self.state.offset).min(MAX_CHUNK_SIZE);
+            let mut buf = vec![0u8; length as usize];
+            file.seek(SeekFrom::Start(self.state.offset))?;
+            file.read_exact(&mut buf)?;
+            let sha256 = sha256_hex(&buf);
+            self.send(
+                &[
+                    ("type", "part"),
+                    ("file", &self.state.filename),
+                    ("offset", &self.state.offset.to_string()),
+                    ("length", &length.to_string()),
+                    ("sha256", &sha256),
+                ],
+                Bytes::from(buf),
+            )?;
+            self.state.offset += length;
+            self.state.store()?;
+        }
+        self.last_send = Instant::now();
+        Ok(())
     }
 
     fn handle_tail(&mut self) -> ResultType<()> {
         self.handle_frame(true)?;
-        match File::open(&self.filepath) {
-            Ok(mut file) => {
-                let mut buf = vec![0u8; MAX_HEADER_LEN];
-                match file.read(&mut buf) {
-                    Ok(length) => {
-                        buf.truncate(length);
-                        self.send(
-                            &[
-                                ("type", "tail"),
-                                ("file", &self.filename),
-                                ("offset", "0"),
-                                ("length", &length.to_string()),
-                            ],
-                            buf,
-                        )?;
-                        log::info!("upload success, file: {}", self.filename);
-                        Ok(())
-                    }
-                    Err(e) => bail!(e.to_string()),
-                }
-            }
-            Err(e) => bail!(e.to_string()),
-        }
+        let mut file = File::open(&self.state.filepath)?;
+        let mut buf = vec![0u8; MAX_HEADER_LEN];
+        let length = file.read(&mut buf)?;
+        buf.truncate(length);
+        let file_sha256 = file_sha256_hex(&self.state.filepath)?;
+        let size = file.metadata()?.len();
+        self.send(
+            &[
+                ("type", "tail"),
+                ("file", &self.state.filename),
+                ("offset", "0"),
+                ("length", &length.to_string()),
+                ("sha256", &sha256_hex(&buf)),
+                ("file_sha256", &file_sha256),
+                ("size", &size.to_string()),
+            ],
+            Bytes::from(buf),
+        )?;
+        self.state.remove();
+        log::info!("upload success, file: {}", self.state.filename);
+        Ok(())
     }
 
     fn handle_remove(&mut self) -> ResultType<()> {
+        self.state.remove();
         self.send(
-            &[("type", "remove"), ("file", &self.filename)],
+            &[("type", "remove"), ("file", &self.state.filename)],
             Bytes::new(),
         )?;
         Ok(())
     }
+
+    fn resume(&mut self, state_path: &Path) -> ResultType<()> {
+        let state = UploadState::load(state_path)?;
+        if !Path::new(&state.filepath).exists() {
+            log::info!("recording {} is gone, drop upload state", state.filepath);
+            std::fs::remove_file(state_path).ok();
+            return Ok(());
+        }
+        log::info!(
+            "resume upload {} from offset {}",
+            state.filename,
+            state.offset
+        );
+        self.state = state;
+        self.running = true;
+        if !self.state.created {
+            self.send_new()?;
+        }
+        // The recording finished before the restart, the header is final now.
+        self.handle_tail()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::HashMap,
+        io::BufRead,
+        net::{TcpListener, TcpStream},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    #[derive(Debug, Clone)]
+    struct Request {
+        query: HashMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    /// A minimal stand-in for the `/api/record` endpoint.
+    struct Server {
+        addr: String,
+        requests: Arc<Mutex<Vec<Request>>>,
+        fail_next: Arc<AtomicUsize>,
+    }
+
+    impl Server {
+        fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = format!("http://{}", listener.local_addr().unwrap());
+            let requests: Arc<Mutex<Vec<Request>>> = Default::default();
+            let fail_next: Arc<AtomicUsize> = Default::default();
+            let requests2 = requests.clone();
+            let fail_next2 = fail_next.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else {
+                        break;
+                    };
+                    Self::serve(stream, &requests2, &fail_next2);
+                }
+            });
+            Self {
+                addr,
+                requests,
+                fail_next,
+            }
+        }
+
+        fn serve(
+            mut stream: TcpStream,
+            requests: &Arc<Mutex<Vec<Request>>>,
+            fail_next: &Arc<AtomicUsize>,
+        ) {
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let target = line.split_whitespace().nth(1).unwrap_or_default().to_owned();
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                let lower = header.to_lowercase();
+                if let Some(v) = lower.strip_prefix("content-length:") {
+                    content_length = v.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0u8; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let failing = fail_next
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            let response = if failing {
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            } else {
+                let query = url::Url::parse(&format!("http://localhost{}", target))
+                    .unwrap()
+                    .query_pairs()
+                    .into_owned()
+                    .collect();
+                requests.lock().unwrap().push(Request { query, body });
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}"
+            };
+            stream.write_all(response.as_bytes()).ok();
+        }
+
+        /// Rebuild the file the server would have stored.
+        fn assemble(&self) -> Vec<u8> {
+            let mut data = vec![];
+            for r in self.requests.lock().unwrap().iter() {
+                let ty = r.query.get("type").map(|s| s.as_str());
+                if ty != Some("part") && ty != Some("tail") {
+                    continue;
+                }
+                assert_eq!(r.query["sha256"], sha256_hex(&r.body));
+                let offset: usize = r.query["offset"].parse().unwrap();
+                if data.len() < offset + r.body.len() {
+                    data.resize(offset + r.body.len(), 0);
+                }
+                data[offset..offset + r.body.len()].copy_from_slice(&r.body);
+            }
+            data
+        }
+    }
+
+    fn uploader(server: &Server) -> RecordUploader {
+        let mut uploader = RecordUploader::new(Client::new(), server.addr.clone());
+        uploader.retry_base_delay = Duration::from_millis(1);
+        uploader
+    }
+
+    fn recording(dir: &Path, name: &str, len: usize) -> (String, Vec<u8>) {
+        let path = dir.join(name).to_string_lossy().to_string();
+        let data: Vec<u8> = (0..len).map(|i| (i * 7 % 256) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        (path, data)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("record_upload_{}_{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_upload_with_retry() {
+        let server = Server::start();
+        let dir = temp_dir("retry");
+        let (path, data) = recording(&dir, "a.webm", (MAX_CHUNK_SIZE + 100) as usize);
+        let mut uploader = uploader(&server);
+        uploader.handle_new_file(path.clone()).unwrap();
+        server.fail_next.store(2, Ordering::SeqCst);
+        uploader.handle_tail().unwrap();
+        assert_eq!(server.assemble(), data);
+        let requests = server.requests.lock().unwrap();
+        let tail = requests.last().unwrap();
+        assert_eq!(tail.query["type"], "tail");
+        assert_eq!(tail.query["file_sha256"], sha256_hex(&data));
+        assert!(!state_path(&path).exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_resume_after_failure() {
+        let server = Server::start();
+        let dir = temp_dir("resume");
+        let (path, data) = recording(&dir, "b.webm", 3 * 1024 * 1024);
+        let mut uploader = uploader(&server);
+        uploader.handle_new_file(path.clone()).unwrap();
+        server
+            .fail_next
+            .store(MAX_RETRIES as usize + 1, Ordering::SeqCst);
+        assert!(uploader.handle_frame(true).is_err());
+        assert_eq!(UploadState::load(&state_path(&path)).unwrap().offset, 0);
+
+        let states = find_states(&dir.to_string_lossy());
+        assert_eq!(states, vec![state_path(&path)]);
+        let mut uploader = self::uploader(&server);
+        uploader.resume(&states[0]).unwrap();
+        assert_eq!(server.assemble(), data);
+        assert!(find_states(&dir.to_string_lossy()).is_empty());
+        std::fs::remove_dir_all(&dir).ok();
+    }
 }
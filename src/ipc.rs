@@ -75,6 +75,11 @@ pub enum FS {
         total_size: u64,
         conn_id: i32,
     },
+    NewArchiveWrite {
+        path: String,
+        id: i32,
+        format: crate::file_archive::Format,
+    },
     CancelWrite {
         id: i32,
     },
@@ -110,6 +115,15 @@ pub enum FS {
         path: String,
         new_name: String,
     },
+    Verify {
+        id: i32,
+        file_num: i32,
+        sha256: Vec<u8>,
+    },
+    WriteMeta {
+        id: i32,
+        files: Vec<crate::file_meta::Meta>,
+    },
 }
 
 #[cfg(target_os = "windows")]
@@ -262,6 +276,8 @@ pub enum Data {
     #[cfg(windows)]
     SyncWinCpuUsage(Option<f64>),
     FileTransferLog((String, String)),
+    // Job id and the file numbers which failed the verification.
+    FileTransferVerified((i32, Vec<i32>)),
     #[cfg(windows)]
     ControlledSessionCount(usize),
     CmErr(String),
@@ -686,7 +702,10 @@ async fn handle(data: Data, stream: &mut Connection) {
             } else if value == "clear" {
                 set_local_option(key.clone(), "".to_owned());
                 #[cfg(target_os = "linux")]
-                scrap::wayland::pipewire::close_session();
+                {
+                    scrap::wayland::restore_token::clear();
+                    scrap::wayland::pipewire::close_session();
+                }
                 Some("".to_owned())
             } else {
                 None
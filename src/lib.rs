@@ -47,6 +47,14 @@ mod clipboard;
 #[cfg(not(any(target_os = "android", target_os = "ios", feature = "cli")))]
 pub mod core_main;
 mod custom_server;
+mod ext_msg;
+mod file_archive;
+mod file_compress;
+mod file_delta;
+mod file_meta;
+mod file_search;
+mod file_throttle;
+mod file_verify;
 mod lang;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 mod port_forward;
@@ -337,6 +337,14 @@ fn stop_subprocess() {
     ));
 }
 
+// The kiosk X servers left by a previous service.
+fn stop_kiosk_subprocess() {
+    let _ = run_cmds(&format!(
+        r##"ps -ef | grep -E '/tmp/.{}-kiosk-[0-9]+.Xauthority' | grep -v grep | awk '{{printf("kill -9 %d\n", $2)}}' | bash"##,
+        crate::get_app_name().to_lowercase(),
+    ));
+}
+
 fn should_start_server(
     try_x11: bool,
     is_display_changed: bool,
@@ -410,6 +418,7 @@ pub fn start_os_service() {
     check_if_stop_service();
     stop_rustdesk_servers();
     stop_subprocess();
+    stop_kiosk_subprocess();
     start_uinput_service();
 
     std::thread::spawn(|| {
@@ -432,8 +441,15 @@ pub fn start_os_service() {
 
     let mut cm0 = false;
     let mut last_restart = Instant::now();
+    let mut kiosk = super::linux_desktop_manager::Kiosk::default();
     while running.load(Ordering::SeqCst) {
         desktop.refresh();
+        // The kiosk session has no logind session, it is used if there is no seat0 session.
+        if let Some(session) = kiosk.check() {
+            if desktop.sid.is_empty() {
+                desktop.set_kiosk(session);
+            }
+        }
 
         // Duplicate logic here with should_start_server
         // Login wayland will try to start a headless --server.
@@ -1025,6 +1041,7 @@ mod desktop {
         pub home: String,
         pub is_rustdesk_subprocess: bool,
         pub wl_display: String,
+        pub is_kiosk: bool,
     }
 
     impl Desktop {
@@ -1040,7 +1057,20 @@ mod desktop {
 
         #[inline]
         pub fn is_headless(&self) -> bool {
-            self.sid.is_empty() || self.is_rustdesk_subprocess
+            (self.sid.is_empty() && !self.is_kiosk) || self.is_rustdesk_subprocess
+        }
+
+        pub fn set_kiosk(
+            &mut self,
+            session: &crate::platform::linux_desktop_manager::KioskSession,
+        ) {
+            self.username = session.username.clone();
+            self.uid = session.uid.clone();
+            self.home = session.home.clone();
+            self.display = session.display.clone();
+            self.xauth = session.xauth.clone();
+            self.protocol = DISPLAY_SERVER_X11.to_owned();
+            self.is_kiosk = true;
         }
 
         fn get_display_xauth_xwayland(&mut self) {
@@ -1108,7 +1138,7 @@ mod desktop {
 
         fn get_xauth_from_xorg(&mut self) {
             if let Ok(output) = run_cmds(&format!(
-                "ps -u {} -f | grep 'Xorg' | grep -v 'grep'",
+                "ps -u {} -f | grep -E 'Xorg|Xvfb|Xephyr' | grep -v 'grep'",
                 &self.uid
             )) {
                 for line in output.lines() {
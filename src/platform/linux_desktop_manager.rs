@@ -4,7 +4,7 @@ use crate::client::{
     LOGIN_MSG_DESKTOP_SESSION_NOT_READY, LOGIN_MSG_DESKTOP_XORG_NOT_FOUND,
     LOGIN_MSG_DESKTOP_XSESSION_FAILED,
 };
-use hbb_common::{allow_err, bail, log, rand::prelude::*, tokio::time};
+use hbb_common::{allow_err, bail, config::Config, log, rand::prelude::*, tokio::time};
 use pam;
 use std::{
     collections::HashMap,
@@ -13,13 +13,26 @@ use std::{
     process::{Child, Command},
     sync::{
         atomic::{AtomicBool, Ordering},
-        mpsc::{sync_channel, SyncSender},
+        mpsc::{sync_channel, Receiver, SyncSender},
         Arc, Mutex,
     },
     time::{Duration, Instant},
 };
 use users::{get_user_by_name, os::unix::UserExt, User};
 
+// Kiosk mode, a virtual X server (Xvfb or Xephyr) with a window manager or a single
+// application, running as a service user. No display manager, login or password is needed.
+// The service keeps the kiosk session running and, if there is no seat0 session, starts the
+// --server on it as the kiosk user like on a desktop session.
+pub const OPTION_LINUX_KIOSK: &str = "linux-kiosk";
+const OPTION_LINUX_KIOSK_USER: &str = "linux-kiosk-user";
+const OPTION_LINUX_KIOSK_COMMAND: &str = "linux-kiosk-command";
+const OPTION_LINUX_KIOSK_SERVER: &str = "linux-kiosk-server";
+const OPTION_LINUX_KIOSK_RESOLUTION: &str = "linux-kiosk-resolution";
+const KIOSK_DEFAULT_COMMAND: &str = "x-window-manager";
+const KIOSK_DEFAULT_RESOLUTION: (u32, u32, u32) = (1920, 1080, 24);
+const KIOSK_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
 lazy_static::lazy_static! {
     static ref DESKTOP_RUNNING: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
     static ref DESKTOP_MANAGER: Arc<Mutex<Option<DesktopManager>>> = Arc::new(Mutex::new(None));
@@ -92,6 +105,10 @@ fn detect_headless() -> Option<&'static str> {
 
 pub fn try_start_desktop(_username: &str, _passsword: &str) -> String {
     debug_assert!(crate::is_server());
+    if is_kiosk() {
+        // The service has started this server on the kiosk session.
+        return "".to_owned();
+    }
     if _username.is_empty() {
         let username = get_username();
         if username.is_empty() {
@@ -161,8 +178,185 @@ fn try_start_x_session(username: &str, password: &str) -> ResultType<(String, bo
     }
 }
 
+#[inline]
+pub fn is_kiosk() -> bool {
+    Config::get_option(OPTION_LINUX_KIOSK) == "Y"
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KioskServer {
+    Xvfb,
+    // Nested in the X server of `DISPLAY`, mostly for debugging the kiosk setup.
+    Xephyr,
+}
+
+/// The kiosk X server, the --server is started on it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KioskSession {
+    pub username: String,
+    pub uid: String,
+    pub home: String,
+    pub display: String,
+    pub xauth: String,
+}
+
+impl KioskSession {
+    // The env of the kiosk user, with the display and xauth of the X server to use if any.
+    fn envs(&self, x: Option<(&str, &str)>) -> HashMap<&'static str, String> {
+        let mut envs = HashMap::from([
+            ("SHELL", "/bin/sh".to_owned()),
+            ("PATH", "/sbin:/bin:/usr/bin:/usr/local/bin".to_owned()),
+            ("USER", self.username.clone()),
+            ("UID", self.uid.clone()),
+            ("HOME", self.home.clone()),
+            ("XDG_RUNTIME_DIR", format!("/run/user/{}", self.uid)),
+        ]);
+        if let Some((display, xauth)) = x {
+            envs.insert("DISPLAY", display.to_owned());
+            envs.insert("XAUTHORITY", xauth.to_owned());
+        }
+        envs
+    }
+}
+
+/// The kiosk session of the service, restarted if the X server or the command exits.
+#[derive(Default)]
+pub struct Kiosk {
+    manager: Option<DesktopManager>,
+    session: Option<KioskSession>,
+    last_start: Option<Instant>,
+}
+
+impl Kiosk {
+    /// Starts the session if kiosk mode is on and it is not running, stops it if the mode is
+    /// off. Returns the running session.
+    pub fn check(&mut self) -> Option<&KioskSession> {
+        if !is_kiosk() {
+            if self.manager.take().is_some() {
+                log::info!("Kiosk mode is off, kiosk session stopped");
+            }
+            self.session = None;
+            return None;
+        }
+        let manager = self.manager.get_or_insert_with(DesktopManager::new);
+        if !manager.is_running() {
+            self.session = None;
+            if self
+                .last_start
+                .map_or(true, |t| t.elapsed() >= KIOSK_RETRY_INTERVAL)
+            {
+                self.last_start = Some(Instant::now());
+                match KioskConfig::get().and_then(|config| manager.try_start_kiosk(&config)) {
+                    Ok(session) => self.session = Some(session),
+                    Err(e) => log::error!("Failed to start kiosk session, {}", e),
+                }
+            }
+        }
+        self.session.as_ref()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct KioskConfig {
+    username: String,
+    command: String,
+    server: KioskServer,
+    width: u32,
+    height: u32,
+    depth: u32,
+}
+
+impl KioskConfig {
+    fn get() -> ResultType<Self> {
+        let username = Config::get_option(OPTION_LINUX_KIOSK_USER);
+        if username.is_empty() {
+            bail!("{} is not set", OPTION_LINUX_KIOSK_USER);
+        }
+        if username == "root" {
+            bail!("kiosk session should not run as root");
+        }
+        let command = Config::get_option(OPTION_LINUX_KIOSK_COMMAND);
+        let command = if command.trim().is_empty() {
+            KIOSK_DEFAULT_COMMAND.to_owned()
+        } else {
+            command
+        };
+        let server = match Config::get_option(OPTION_LINUX_KIOSK_SERVER).as_str() {
+            "" | "xvfb" => KioskServer::Xvfb,
+            "xephyr" => KioskServer::Xephyr,
+            s => bail!("unknown kiosk x server {}", s),
+        };
+        let (width, height, depth) =
+            Self::parse_resolution(&Config::get_option(OPTION_LINUX_KIOSK_RESOLUTION))?;
+        Ok(Self {
+            username,
+            command,
+            server,
+            width,
+            height,
+            depth,
+        })
+    }
+
+    // "<width>x<height>[x<depth>]"
+    fn parse_resolution(s: &str) -> ResultType<(u32, u32, u32)> {
+        if s.is_empty() {
+            return Ok(KIOSK_DEFAULT_RESOLUTION);
+        }
+        let v = s
+            .split('x')
+            .map(|v| v.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()?;
+        match v[..] {
+            [w, h] if w > 0 && h > 0 => Ok((w, h, KIOSK_DEFAULT_RESOLUTION.2)),
+            [w, h, d] if w > 0 && h > 0 && (d == 16 || d == 24) => Ok((w, h, d)),
+            _ => bail!("invalid kiosk resolution {}", s),
+        }
+    }
+
+    // The X server of the session and its arguments.
+    fn x_server_args(&self, session: &KioskSession) -> (&'static str, Vec<String>) {
+        let (program, mut args) = match self.server {
+            KioskServer::Xvfb => (
+                "Xvfb",
+                vec![
+                    "-screen".to_owned(),
+                    "0".to_owned(),
+                    format!("{}x{}x{}", self.width, self.height, self.depth),
+                ],
+            ),
+            KioskServer::Xephyr => (
+                "Xephyr",
+                vec![
+                    "-screen".to_owned(),
+                    format!("{}x{}", self.width, self.height),
+                ],
+            ),
+        };
+        args.extend(
+            [
+                "-noreset",
+                "-nolisten",
+                "tcp",
+                "+extension",
+                "RANDR",
+                "+extension",
+                "XTEST",
+                "-auth",
+                &session.xauth,
+                &session.display,
+            ]
+            .map(|s| s.to_owned()),
+        );
+        (program, args)
+    }
+}
+
 #[inline]
 pub fn is_headless() -> bool {
+    if is_kiosk() {
+        return false;
+    }
     DESKTOP_MANAGER
         .lock()
         .unwrap()
@@ -349,7 +543,10 @@ impl DesktopManager {
             }
         });
 
-        // wait x11
+        Self::wait_x11_started(rx_res)
+    }
+
+    fn wait_x11_started(rx_res: Receiver<String>) -> ResultType<()> {
         match rx_res.recv_timeout(Duration::from_millis(10_000)) {
             Ok(res) => {
                 if res == "" {
@@ -364,6 +561,165 @@ impl DesktopManager {
         }
     }
 
+    fn try_start_kiosk(&mut self, config: &KioskConfig) -> ResultType<KioskSession> {
+        let Some(userinfo) = get_user_by_name(&config.username) else {
+            bail!("failed to get userinfo of {}", &config.username);
+        };
+        self.stop_children();
+
+        let display_num = Self::get_avail_display()?;
+        let app_name = crate::get_app_name().to_lowercase();
+        let session = KioskSession {
+            username: config.username.clone(),
+            uid: userinfo.uid().to_string(),
+            home: userinfo.home_dir().to_string_lossy().to_string(),
+            display: Self::display_from_num(display_num),
+            // The kiosk user usually has no writable home, keep the cookie in /tmp.
+            xauth: format!("/tmp/.{app_name}-kiosk-{display_num}.Xauthority"),
+        };
+        let host = if config.server == KioskServer::Xephyr {
+            let host_display = get_env_var("DISPLAY");
+            if host_display.is_empty() {
+                bail!("Xephyr needs DISPLAY of the host X server");
+            }
+            Some((host_display, get_env_var("XAUTHORITY")))
+        } else {
+            None
+        };
+        self.child_exit.store(false, Ordering::SeqCst);
+        let child_exit = self.child_exit.clone();
+        let is_child_running = self.is_child_running.clone();
+
+        let (tx_res, rx_res) = sync_channel(1);
+        let (uid, gid) = (userinfo.uid(), userinfo.primary_group_id());
+        let config2 = config.clone();
+        let session2 = session.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = Self::start_kiosk_thread(
+                tx_res.clone(),
+                child_exit,
+                is_child_running,
+                uid,
+                gid,
+                display_num,
+                &config2,
+                &session2,
+                host,
+            ) {
+                log::error!("Failed to start kiosk thread");
+                allow_err!(tx_res.send(format!("Failed to start kiosk thread, {}", e)));
+            }
+        });
+
+        Self::wait_x11_started(rx_res)?;
+        log::info!(
+            "Succeeded to start kiosk session of {}, {}",
+            &config.username,
+            &config.command
+        );
+        self.child_username = config.username.clone();
+        Ok(session)
+    }
+
+    fn start_kiosk_thread(
+        tx_res: SyncSender<String>,
+        child_exit: Arc<AtomicBool>,
+        is_child_running: Arc<AtomicBool>,
+        uid: u32,
+        gid: u32,
+        display_num: u32,
+        config: &KioskConfig,
+        session: &KioskSession,
+        host: Option<(String, String)>,
+    ) -> ResultType<()> {
+        Self::add_xauth_cookie(
+            &session.xauth,
+            &session.display,
+            uid,
+            gid,
+            &session.envs(None),
+        )?;
+        // Xephyr is a client of the host X server.
+        let envs = session.envs(host.as_ref().map(|(d, a)| (d.as_str(), a.as_str())));
+        let mut child_xserver = Self::start_kiosk_x_server(config, session, uid, gid, &envs)?;
+        if let Err(e) = Self::wait_x_server_running(child_xserver.id(), display_num, 10) {
+            match Self::wait_xorg_exit(&mut child_xserver) {
+                Ok(msg) => log::info!("{}", msg),
+                Err(e) => {
+                    log::error!("{}", e);
+                    Self::fatal_exit();
+                }
+            }
+            bail!(e)
+        }
+
+        let envs = session.envs(Some((&session.display, &session.xauth)));
+        let child_app = match Command::new("/bin/sh")
+            .args(["-c", &config.command])
+            .envs(&envs)
+            .uid(uid)
+            .gid(gid)
+            .spawn()
+        {
+            Ok(c) => c,
+            Err(e) => {
+                match Self::wait_xorg_exit(&mut child_xserver) {
+                    Ok(msg) => log::info!("{}", msg),
+                    Err(e) => {
+                        log::error!("{}", e);
+                        Self::fatal_exit();
+                    }
+                }
+                bail!("Failed to start kiosk command, {}", e);
+            }
+        };
+        is_child_running.store(true, Ordering::SeqCst);
+
+        log::info!("Start kiosk x server and command done, notify and wait stop");
+        allow_err!(tx_res.send("".to_owned()));
+
+        // Not `wait_stop_x11`, the kiosk is not the desktop manager of the server.
+        let (mut child_xserver, mut child_app) = (child_xserver, child_app);
+        while !child_exit.load(Ordering::SeqCst)
+            && !Self::try_wait_x11_child_exit(&mut child_xserver, &mut child_app)
+        {
+            std::thread::sleep(Duration::from_millis(super::SERVICE_INTERVAL));
+        }
+        Self::wait_x11_children_exit(&mut child_xserver, &mut child_app);
+        is_child_running.store(false, Ordering::SeqCst);
+        child_exit.store(true, Ordering::SeqCst);
+        log::info!("Wait kiosk stop done");
+        Ok(())
+    }
+
+    fn start_kiosk_x_server(
+        config: &KioskConfig,
+        session: &KioskSession,
+        uid: u32,
+        gid: u32,
+        envs: &HashMap<&str, String>,
+    ) -> ResultType<Child> {
+        let (program, args) = config.x_server_args(session);
+        log::info!("Use kiosk x server: {} {:?}", program, &args);
+        match Command::new(program)
+            .envs(envs)
+            .uid(uid)
+            .gid(gid)
+            .args(&args)
+            .spawn()
+        {
+            Ok(c) => Ok(c),
+            Err(e) => {
+                bail!(
+                    "Failed to start {} with display {}, {}",
+                    program,
+                    &session.display,
+                    e
+                );
+            }
+        }
+    }
+
     #[inline]
     fn display_from_num(num: u32) -> String {
         format!(":{num}")
@@ -738,3 +1094,63 @@ fn pam_get_service_name() -> String {
         "gdm".to_owned()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kiosk_command() {
+        let session = KioskSession {
+            username: "kiosk".to_owned(),
+            uid: "1001".to_owned(),
+            home: "/var/lib/kiosk".to_owned(),
+            display: ":3".to_owned(),
+            xauth: "/tmp/.rustdesk-kiosk-3.Xauthority".to_owned(),
+        };
+        let mut config = KioskConfig {
+            username: "kiosk".to_owned(),
+            command: "xterm".to_owned(),
+            server: KioskServer::Xvfb,
+            width: 1280,
+            height: 720,
+            depth: 24,
+        };
+        let (program, args) = config.x_server_args(&session);
+        assert_eq!(program, "Xvfb");
+        assert_eq!(
+            args,
+            [
+                "-screen",
+                "0",
+                "1280x720x24",
+                "-noreset",
+                "-nolisten",
+                "tcp",
+                "+extension",
+                "RANDR",
+                "+extension",
+                "XTEST",
+                "-auth",
+                "/tmp/.rustdesk-kiosk-3.Xauthority",
+                ":3",
+            ]
+        );
+        config.server = KioskServer::Xephyr;
+        let (program, args) = config.x_server_args(&session);
+        assert_eq!(program, "Xephyr");
+        assert_eq!(args[..2], ["-screen", "1280x720"]);
+        assert_eq!(args.last().unwrap(), ":3");
+
+        let envs = session.envs(None);
+        assert!(!envs.contains_key("DISPLAY") && !envs.contains_key("XAUTHORITY"));
+        assert_eq!(envs["XDG_RUNTIME_DIR"], "/run/user/1001");
+        let envs = session.envs(Some((&session.display, &session.xauth)));
+        assert_eq!(envs["DISPLAY"], ":3");
+        assert_eq!(envs["XAUTHORITY"], "/tmp/.rustdesk-kiosk-3.Xauthority");
+        assert_eq!(
+            (envs["USER"].as_str(), envs["HOME"].as_str()),
+            ("kiosk", "/var/lib/kiosk")
+        );
+    }
+}
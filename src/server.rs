@@ -62,6 +62,7 @@ pub mod input_service {
     pub const NAME_WINDOW_FOCUS: &'static str = "";
 }
 
+mod bandwidth_estimator;
 mod connection;
 pub mod display_service;
 #[cfg(windows)]
@@ -394,6 +395,14 @@ impl Server {
             }
             if sub {
                 s.on_subscribe(conn.clone());
+                // Another viewer gets the whole display back.
+                #[cfg(target_os = "linux")]
+                if let Some(display) = name
+                    .strip_prefix(VideoSource::Monitor.service_name_prefix())
+                    .and_then(|d| d.parse().ok())
+                {
+                    display_service::clear_others_capture_region(display, conn.id());
+                }
             } else {
                 s.on_unsubscribe(conn.id());
             }
@@ -427,6 +436,17 @@ impl Server {
         }
     }
 
+    // Whether a connection other than `conn_id` watches the display.
+    #[cfg(target_os = "linux")]
+    pub fn is_display_subed_by_others(&self, display: usize, conn_id: i32) -> bool {
+        let name = video_service::get_service_name(VideoSource::Monitor, display);
+        self.services.get(&name).map_or(false, |s| {
+            self.connections
+                .keys()
+                .any(|id| *id != conn_id && s.is_subed(*id))
+        })
+    }
+
     fn get_subbed_displays_count(&self, conn_id: i32) -> usize {
         self.services
             .keys()
@@ -567,6 +587,15 @@ pub async fn start_server(is_server: bool, no_server: bool) {
         crate::platform::try_kill_broker();
         #[cfg(feature = "hwcodec")]
         scrap::hwcodec::start_check_process();
+        if crate::hbbs_http::record_upload::is_enable() {
+            #[cfg(windows)]
+            let root = crate::platform::is_root();
+            #[cfg(not(windows))]
+            let root = false;
+            crate::hbbs_http::record_upload::resume_unfinished(
+                crate::ui_interface::video_save_directory(root),
+            );
+        }
         crate::RendezvousMediator::start_all().await;
     } else {
         match crate::ipc::connect(1000, "").await {
@@ -0,0 +1,381 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/*
+Sender side bandwidth estimation, per connection.
+
+inputs:
+    bytes sent => throughput of the last THROUGHPUT_WINDOW
+    delay samples (TestDelay) => trend of the queueing delay, a growing delay means the link queue is filling
+    late frames (not fetched by the peer in time) => loss fraction, TCP hides real loss so late frames are used instead
+
+target bitrate, updated every UPDATE_INTERVAL:
+    overuse (delay trend > OVERUSE_SLOPE or loss > HIGH_LOSS) => decrease to BACKOFF * throughput
+    underuse (delay trend < -OVERUSE_SLOPE) => hold, the queue is draining
+    normal => increase by INCREASE_FACTOR, limited to MAX_OVER_THROUGHPUT * throughput
+
+app limited:
+    When the throughput is below APP_LIMITED of the encoder bitrate, e.g. on a static screen, it says
+    nothing about the link. Overuse then decreases to BACKOFF * target, the other states hold.
+*/
+
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(2);
+const UPDATE_INTERVAL: Duration = Duration::from_millis(1000);
+const DELAY_WINDOW: usize = 8;
+const LOSS_WINDOW: usize = 30;
+const OVERUSE_SLOPE: f32 = 0.05; // ms of queueing delay growth per ms
+const BACKOFF: f32 = 0.85;
+const INCREASE_FACTOR: f32 = 1.08;
+const MAX_OVER_THROUGHPUT: f32 = 1.5;
+const HIGH_LOSS: f32 = 0.1;
+const LOW_LOSS: f32 = 0.02;
+const APP_LIMITED: f32 = 0.65;
+pub const MIN_BITRATE: u32 = 100; // kbps
+pub const MAX_BITRATE: u32 = 100_000; // kbps
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Usage {
+    Normal,
+    Overuse,
+    Underuse,
+}
+
+#[derive(Debug, Clone)]
+pub struct BandwidthEstimator {
+    sent: VecDeque<(Instant, usize)>,
+    delays: VecDeque<(Instant, u32)>,
+    frames: VecDeque<bool>, // true if the frame was late
+    target: Option<u32>,
+    last_update: Option<Instant>,
+    usage: Usage,
+}
+
+impl Default for BandwidthEstimator {
+    fn default() -> Self {
+        Self {
+            sent: Default::default(),
+            delays: Default::default(),
+            frames: Default::default(),
+            target: None,
+            last_update: None,
+            usage: Usage::Normal,
+        }
+    }
+}
+
+impl BandwidthEstimator {
+    pub fn on_sent(&mut self, now: Instant, bytes: usize) {
+        self.sent.push_back((now, bytes));
+        self.frames.push_back(false);
+        while self.frames.len() > LOSS_WINDOW {
+            self.frames.pop_front();
+        }
+        while let Some((t, _)) = self.sent.front() {
+            if now.saturating_duration_since(*t) > THROUGHPUT_WINDOW {
+                self.sent.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub fn on_late(&mut self) {
+        if let Some(last) = self.frames.back_mut() {
+            *last = true;
+        }
+    }
+
+    pub fn on_delay(&mut self, now: Instant, delay: u32) {
+        if self.delays.len() >= DELAY_WINDOW {
+            self.delays.pop_front();
+        }
+        self.delays.push_back((now, delay));
+    }
+
+    // kbps sent during the window
+    pub fn throughput(&self, now: Instant) -> Option<u32> {
+        let first = self.sent.front()?.0;
+        let span = now.saturating_duration_since(first).max(UPDATE_INTERVAL);
+        let bytes: usize = self.sent.iter().map(|s| s.1).sum();
+        Some((bytes as f32 * 8.0 / 1000.0 / span.as_secs_f32()) as u32)
+    }
+
+    pub fn loss(&self) -> f32 {
+        if self.frames.is_empty() {
+            return 0.0;
+        }
+        self.frames.iter().filter(|f| **f).count() as f32 / self.frames.len() as f32
+    }
+
+    // Least squares slope of delay over time, ms per ms.
+    fn delay_slope(&self) -> Option<f32> {
+        if self.delays.len() < 3 {
+            return None;
+        }
+        let t0 = self.delays.front()?.0;
+        let points: Vec<(f32, f32)> = self
+            .delays
+            .iter()
+            .map(|(t, d)| {
+                (
+                    t.saturating_duration_since(t0).as_secs_f32() * 1000.0,
+                    *d as f32,
+                )
+            })
+            .collect();
+        let n = points.len() as f32;
+        let mean_x = points.iter().map(|p| p.0).sum::<f32>() / n;
+        let mean_y = points.iter().map(|p| p.1).sum::<f32>() / n;
+        let num: f32 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+        let den: f32 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+        if den <= f32::EPSILON {
+            return None;
+        }
+        Some(num / den)
+    }
+
+    pub fn usage(&self) -> Usage {
+        self.usage
+    }
+
+    pub fn target(&self) -> Option<u32> {
+        self.target
+    }
+
+    // Update the target bitrate, returns the new target if it was updated.
+    // `encoder_bitrate` is the kbps the encoder is configured to, 0 if unknown.
+    pub fn update(&mut self, now: Instant, encoder_bitrate: u32) -> Option<u32> {
+        if let Some(last) = self.last_update {
+            if now.saturating_duration_since(last) < UPDATE_INTERVAL {
+                return None;
+            }
+        }
+        let first = self.sent.front()?.0;
+        if self.target.is_none() && now.saturating_duration_since(first) < UPDATE_INTERVAL {
+            // Not enough history for the first estimate
+            return None;
+        }
+        let throughput = self.throughput(now)?;
+        let app_limited = (throughput as f32) < encoder_bitrate as f32 * APP_LIMITED;
+        if app_limited && self.target.is_none() {
+            return None;
+        }
+        self.last_update = Some(now);
+        let loss = self.loss();
+        self.usage = match self.delay_slope() {
+            Some(slope) if slope > OVERUSE_SLOPE => Usage::Overuse,
+            Some(slope) if slope < -OVERUSE_SLOPE => Usage::Underuse,
+            _ => Usage::Normal,
+        };
+        if loss > HIGH_LOSS {
+            self.usage = Usage::Overuse;
+        }
+        let current = self.target.unwrap_or(throughput.max(MIN_BITRATE));
+        let target = match self.usage {
+            Usage::Overuse if app_limited => (current as f32 * BACKOFF * (1.0 - loss / 2.0)) as u32,
+            _ if app_limited => current,
+            Usage::Overuse => {
+                let backoff = (throughput as f32 * BACKOFF).min(current as f32 * BACKOFF);
+                (backoff * (1.0 - loss / 2.0)) as u32
+            }
+            Usage::Underuse => current,
+            Usage::Normal => {
+                if loss < LOW_LOSS {
+                    let cap = throughput as f32 * MAX_OVER_THROUGHPUT;
+                    (current as f32 * INCREASE_FACTOR).min(cap.max(current as f32)) as u32
+                } else {
+                    current
+                }
+            }
+        };
+        let target = target.clamp(MIN_BITRATE, MAX_BITRATE);
+        self.target = Some(target);
+        Some(target)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod simulation {
+    use super::*;
+
+    /// One segment of a network trace.
+    #[derive(Debug, Clone, Copy)]
+    pub struct TraceSegment {
+        pub duration: Duration,
+        pub capacity: u32, // kbps
+        pub base_delay: u32,
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct Sample {
+        pub at: Duration,
+        pub target: u32,
+        pub queue_delay: u32,
+    }
+
+    /// Replays a trace against an estimator with a single bottleneck queue.
+    /// The sender sends at the target bitrate, the queue drains at the link capacity,
+    /// frames queued longer than `late_after` count as late.
+    pub struct Simulation {
+        pub tick: Duration,
+        pub delay_interval: Duration,
+        pub late_after: Duration,
+        pub initial_bitrate: u32,
+    }
+
+    impl Default for Simulation {
+        fn default() -> Self {
+            Self {
+                tick: Duration::from_millis(33),
+                delay_interval: Duration::from_secs(1),
+                late_after: Duration::from_millis(1000),
+                initial_bitrate: 2000,
+            }
+        }
+    }
+
+    impl Simulation {
+        pub fn run(&self, trace: &[TraceSegment]) -> Vec<Sample> {
+            let start = Instant::now();
+            let mut bwe = BandwidthEstimator::default();
+            let mut samples = vec![];
+            let mut queue_bits = 0f64;
+            let mut bitrate = self.initial_bitrate;
+            let mut elapsed = Duration::ZERO;
+            let mut next_delay = self.delay_interval;
+            for segment in trace {
+                let end = elapsed + segment.duration;
+                while elapsed < end {
+                    let now = start + elapsed;
+                    let bits = bitrate as f64 * 1000.0 * self.tick.as_secs_f64();
+                    queue_bits += bits;
+                    bwe.on_sent(now, (bits / 8.0) as usize);
+                    let drained = segment.capacity as f64 * 1000.0 * self.tick.as_secs_f64();
+                    queue_bits = (queue_bits - drained).max(0.0);
+                    let queue_delay =
+                        (queue_bits / (segment.capacity as f64 * 1000.0) * 1000.0) as u32;
+                    if Duration::from_millis(queue_delay as _) > self.late_after {
+                        bwe.on_late();
+                    }
+                    if elapsed >= next_delay {
+                        next_delay += self.delay_interval;
+                        bwe.on_delay(now, segment.base_delay + queue_delay);
+                    }
+                    if let Some(target) = bwe.update(now, bitrate) {
+                        bitrate = target;
+                        samples.push(Sample {
+                            at: elapsed,
+                            target,
+                            queue_delay,
+                        });
+                    }
+                    elapsed += self.tick;
+                }
+            }
+            samples
+        }
+    }
+
+    pub fn seg(secs: u64, capacity: u32, base_delay: u32) -> TraceSegment {
+        TraceSegment {
+            duration: Duration::from_secs(secs),
+            capacity,
+            base_delay,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::simulation::*;
+    use super::*;
+
+    fn last_secs(samples: &[Sample], from: Duration, to: Duration) -> Vec<Sample> {
+        samples
+            .iter()
+            .filter(|s| s.at >= from && s.at < to)
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn test_ramp_up_on_free_link() {
+        let samples = Simulation::default().run(&[seg(60, 20_000, 30)]);
+        let tail = last_secs(&samples, Duration::from_secs(40), Duration::from_secs(60));
+        assert!(tail.iter().all(|s| s.target > 5_000), "{tail:?}");
+        assert!(tail.iter().all(|s| s.queue_delay < 1000), "{tail:?}");
+    }
+
+    #[test]
+    fn test_back_off_on_capacity_drop() {
+        let samples = Simulation::default().run(&[seg(30, 8_000, 30), seg(30, 1_000, 30)]);
+        let tail = last_secs(&samples, Duration::from_secs(45), Duration::from_secs(60));
+        assert!(!tail.is_empty());
+        assert!(tail.iter().all(|s| s.target < 2_000), "{tail:?}");
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let trace = [seg(20, 4_000, 50), seg(20, 500, 80), seg(20, 4_000, 50)];
+        let a: Vec<u32> = Simulation::default()
+            .run(&trace)
+            .iter()
+            .map(|s| s.target)
+            .collect();
+        let b: Vec<u32> = Simulation::default()
+            .run(&trace)
+            .iter()
+            .map(|s| s.target)
+            .collect();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_late_frames_are_loss() {
+        let mut bwe = BandwidthEstimator::default();
+        let now = Instant::now();
+        for i in 0..10 {
+            bwe.on_sent(now + Duration::from_millis(i * 100), 1000);
+            if i % 2 == 0 {
+                bwe.on_late();
+            }
+        }
+        assert!((bwe.loss() - 0.5).abs() < f32::EPSILON);
+        bwe.update(now + Duration::from_secs(1), 0);
+        assert_eq!(bwe.usage(), Usage::Overuse);
+    }
+
+    #[test]
+    fn test_app_limited() {
+        let mut bwe = BandwidthEstimator::default();
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        // A static screen gives no first estimate.
+        bwe.on_sent(at(0), 1000);
+        bwe.on_sent(at(1000), 1000);
+        assert_eq!(bwe.update(at(1000), 4000), None);
+        // 50 KB every 100ms, 4000 kbps
+        for i in 11..40 {
+            bwe.on_sent(at(i * 100), 50_000);
+        }
+        let target = bwe.update(at(4000), 4000).unwrap();
+        assert!(target > 3000, "{target}");
+        // The screen stops changing, a growing delay backs off from the target, not to the
+        // throughput.
+        for i in 0..4 {
+            bwe.on_delay(at(4000 + i * 1000), 100 + i as u32 * 200);
+            bwe.on_sent(at(4000 + i * 1000), 1000);
+        }
+        let decreased = bwe.update(at(7000), 4000).unwrap();
+        assert_eq!(bwe.usage(), Usage::Overuse);
+        assert_eq!(decreased, (target as f32 * BACKOFF) as u32);
+        // And it holds without overuse.
+        for i in 8..12 {
+            bwe.on_delay(at(i * 1000), 100);
+            bwe.on_sent(at(i * 1000), 1000);
+        }
+        assert_eq!(bwe.update(at(11000), 4000), Some(decreased));
+    }
+}
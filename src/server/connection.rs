@@ -47,12 +47,13 @@ use scrap::android::{call_main_service_key_event, call_main_service_pointer_inpu
 use scrap::camera;
 use serde_derive::Serialize;
 use serde_json::{json, value::Value};
-#[cfg(not(any(target_os = "android", target_os = "ios")))]
-use std::sync::atomic::Ordering;
 use std::{
     num::NonZeroI64,
     path::PathBuf,
-    sync::{atomic::AtomicI64, mpsc as std_mpsc},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        mpsc as std_mpsc,
+    },
 };
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 use system_shutdown;
@@ -178,6 +179,18 @@ pub struct Connection {
     server: super::ServerPtrWeak,
     hash: Hash,
     read_jobs: Vec<fs::TransferJob>,
+    file_archives: crate::file_archive::ArchiveReads,
+    // Job id to the archive format asked for the next send or receive request of the job.
+    file_archive_requests: HashMap<i32, crate::file_archive::Format>,
+    file_compression: crate::file_compress::ReadJobsCompression,
+    file_throttle: crate::file_throttle::FileThrottle,
+    file_verify: bool,
+    // Job id to the audit of the job, posted again with the verification result.
+    file_verify_audits: HashMap<i32, (FileAuditType, String, Vec<(String, i64)>)>,
+    // Search id to the cancellation flag of the running search, set when it is done too.
+    file_searches: HashMap<i32, Arc<AtomicBool>>,
+    // The same for previews.
+    file_previews: HashMap<i32, Arc<AtomicBool>>,
     timer: crate::RustDeskInterval,
     file_timer: crate::RustDeskInterval,
     file_transfer: Option<(String, bool)>,
@@ -247,6 +260,10 @@ pub struct Connection {
     multi_ui_session: bool,
     tx_from_authed: mpsc::UnboundedSender<ipc::Data>,
     printer_data: Vec<(Instant, String, Vec<u8>)>,
+    // Capabilities of the peer, see `ext_msg`.
+    ext_caps: HashSet<String>,
+    // display -> (capabilities serial, time) of camera settings not applied by the capturer yet
+    camera_caps_pending: HashMap<usize, (u64, Instant)>,
 }
 
 impl ConnInner {
@@ -263,11 +280,12 @@ impl Subscriber for ConnInner {
 
     #[inline]
     fn send(&mut self, msg: Arc<Message>) {
-        // Send SwitchDisplay on the same channel as VideoFrame to avoid send order problems.
+        // Send SwitchDisplay and the tile frames on the same channel as VideoFrame to avoid send order problems.
         let tx_by_video = match &msg.union {
             Some(message::Union::VideoFrame(_)) => true,
             Some(message::Union::Misc(misc)) => match &misc.union {
                 Some(misc::Union::SwitchDisplay(_)) => true,
+                Some(misc::Union::PluginRequest(p)) => crate::ext_msg::is_video(p),
                 _ => false,
             },
             _ => false,
@@ -290,6 +308,8 @@ const MILLI1: Duration = Duration::from_millis(1);
 const SEND_TIMEOUT_VIDEO: u64 = 12_000;
 const SEND_TIMEOUT_OTHER: u64 = SEND_TIMEOUT_VIDEO * 10;
 const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+// The capabilities are sent anyway if the capturer has not applied the camera settings.
+const CAMERA_SETTINGS_TIMEOUT: Duration = Duration::from_secs(5);
 
 impl Connection {
     pub async fn start(
@@ -335,6 +355,14 @@ impl Connection {
             server,
             hash,
             read_jobs: Vec::new(),
+            file_archives: Default::default(),
+            file_archive_requests: Default::default(),
+            file_compression: Default::default(),
+            file_throttle: Default::default(),
+            file_verify: false,
+            file_verify_audits: Default::default(),
+            file_searches: Default::default(),
+            file_previews: Default::default(),
             timer: crate::rustdesk_interval(time::interval(SEC30)),
             file_timer: crate::rustdesk_interval(time::interval(SEC30)),
             file_transfer: None,
@@ -401,6 +429,8 @@ impl Connection {
             retina: Retina::default(),
             tx_from_authed,
             printer_data: Vec::new(),
+            ext_caps: Default::default(),
+            camera_caps_pending: Default::default(),
         };
         let addr = hbb_common::try_into_v4(addr);
         if !conn.on_open(addr).await {
@@ -586,6 +616,9 @@ impl Connection {
                         ipc::Data::RawMessage(bytes) => {
                             allow_err!(conn.stream.send_raw(bytes).await);
                         }
+                        ipc::Data::FileTransferVerified((id, failed)) => {
+                            conn.post_file_verify_audit(id, failed);
+                        }
                         #[cfg(target_os = "windows")]
                         ipc::Data::ClipboardFile(clip) => {
                             allow_err!(conn.stream.send(&clip_2_msg(clip)).await);
@@ -669,18 +702,31 @@ impl Connection {
                     }
                 },
                 _ = conn.file_timer.tick() => {
-                    if !conn.read_jobs.is_empty() {
-                        conn.send_to_cm(ipc::Data::FileTransferLog(("transfer".to_string(), fs::serialize_transfer_jobs(&conn.read_jobs))));
-                        match fs::handle_read_jobs(&mut conn.read_jobs, &mut conn.stream).await {
-                            Ok(log) => {
-                                if !log.is_empty() {
-                                    conn.send_to_cm(ipc::Data::FileTransferLog(("transfer".to_string(), log)));
+                    if !conn.read_jobs.is_empty() || !conn.file_archives.is_empty() {
+                        if !conn.file_throttle.ready() {
+                            continue;
+                        }
+                        if !conn.read_jobs.is_empty() {
+                            conn.send_to_cm(ipc::Data::FileTransferLog(("transfer".to_string(), conn.file_compression.add_ratio(fs::serialize_transfer_jobs(&conn.read_jobs)))));
+                            let res = conn.file_compression.handle_read_jobs(&mut conn.read_jobs, &mut conn.stream).await;
+                            conn.file_throttle.consume(conn.file_compression.take_sent());
+                            match res {
+                                Ok(log) => {
+                                    if !log.is_empty() {
+                                        conn.send_to_cm(ipc::Data::FileTransferLog(("transfer".to_string(), log)));
+                                    }
+                                }
+                                Err(err) =>  {
+                                    conn.on_close(&err.to_string(), false).await;
+                                    break;
                                 }
                             }
-                            Err(err) =>  {
-                                conn.on_close(&err.to_string(), false).await;
-                                break;
-                            }
+                        }
+                        let res = conn.file_archives.handle(&mut conn.stream).await;
+                        conn.file_throttle.consume(conn.file_archives.take_sent());
+                        if let Err(err) = res {
+                            conn.on_close(&err.to_string(), false).await;
+                            break;
                         }
                     } else {
                         conn.file_timer = crate::rustdesk_interval(time::interval_at(Instant::now() + SEC30, SEC30));
@@ -789,6 +835,7 @@ impl Connection {
                     conn.file_remove_log_control.on_timer().drain(..).map(|x| conn.send_to_cm(x)).count();
                     #[cfg(feature = "hwcodec")]
                     conn.update_supported_encoding();
+                    conn.check_camera_capabilities().await;
                 }
                 _ = test_delay_timer.tick() => {
                     if last_recv_time.elapsed() >= SEC30 {
@@ -811,6 +858,9 @@ impl Connection {
                             video_service::VIDEO_QOS.lock().unwrap().user_delay_response_elapsed(id, last_test_delay.elapsed().as_millis());
                         }
                     }
+                    if !conn.read_jobs.is_empty() {
+                        conn.file_throttle.set_high_delay(video_service::VIDEO_QOS.lock().unwrap().delay_high());
+                    }
                 }
                 clip_file = rx_clip.recv() => match clip_file {
                     Some(_clip) => {
@@ -1122,6 +1172,180 @@ impl Connection {
         });
     }
 
+    // Scans the file against the client's block signatures off the connection loop.
+    fn send_file_delta(
+        &self,
+        id: i32,
+        file_num: i32,
+        block_size: u32,
+        basis_size: u64,
+        signatures: Bytes,
+        path: PathBuf,
+    ) {
+        let mut inner = self.inner.clone();
+        std::thread::spawn(move || {
+            let new_delta = |ops: Vec<u8>, done: bool, error: String| {
+                let ext = crate::ext_msg::Ext::FileDeltaOps {
+                    id,
+                    file_num,
+                    done,
+                    error,
+                };
+                Arc::new(crate::ext_msg::new_message(&ext, &ops))
+            };
+            let mut sender = inner.clone();
+            let res = crate::file_delta::compute_delta(
+                &path,
+                block_size,
+                basis_size,
+                &signatures,
+                &mut |ops| {
+                    sender.send(new_delta(ops, false, "".to_owned()));
+                    Ok(())
+                },
+            );
+            match res {
+                Ok(()) => inner.send(new_delta(vec![], true, "".to_owned())),
+                Err(err) => {
+                    log::error!("Failed to compute delta of {}: {}", path.display(), err);
+                    inner.send(new_delta(vec![], true, err.to_string()));
+                }
+            }
+        });
+    }
+
+    // Like reading files, searches and previews are refused with the one-way file transfer.
+    // Only one of each runs at a time, a peer can't start a thread per request.
+    fn file_search_error(running: &mut HashMap<i32, Arc<AtomicBool>>) -> Option<&'static str> {
+        if crate::get_builtin_option(keys::OPTION_ONE_WAY_FILE_TRANSFER) == "Y" {
+            return Some("one-way-file-transfer-tip");
+        }
+        running.retain(|_, c| !c.load(Ordering::SeqCst));
+        if !running.is_empty() {
+            return Some(crate::file_search::ERR_BUSY);
+        }
+        None
+    }
+
+    fn new_search_results(
+        id: i32,
+        path: &str,
+        entries: Vec<FileEntry>,
+        done: bool,
+        error: String,
+    ) -> Arc<Message> {
+        let fd = FileDirectory {
+            id,
+            path: path.to_owned(),
+            entries,
+            ..Default::default()
+        };
+        let ext = crate::ext_msg::Ext::FileSearchResults { done, error };
+        Arc::new(crate::ext_msg::new_message(
+            &ext,
+            &fd.write_to_bytes().unwrap_or_default(),
+        ))
+    }
+
+    fn new_preview_result(
+        id: i32,
+        path: String,
+        preview: crate::file_search::Preview,
+        error: String,
+    ) -> Arc<Message> {
+        let ext = crate::ext_msg::Ext::FilePreviewResult {
+            id,
+            path,
+            is_image: preview.is_image,
+            truncated: preview.truncated,
+            error,
+        };
+        Arc::new(crate::ext_msg::new_message(&ext, &preview.data))
+    }
+
+    // Streams the matches of a search off the connection loop, until done or cancelled.
+    fn search_files(&mut self, id: i32, path: String, opts: crate::file_search::SearchOptions) {
+        if let Some(error) = Self::file_search_error(&mut self.file_searches) {
+            let msg = Self::new_search_results(id, &path, vec![], true, error.to_owned());
+            self.inner.send(msg);
+            return;
+        }
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.file_searches.insert(id, cancel.clone());
+        let mut inner = self.inner.clone();
+        std::thread::spawn(move || {
+            let mut sender = inner.clone();
+            let res = crate::file_search::search(&path, &opts, &cancel, &mut |entries| {
+                sender.send(Self::new_search_results(
+                    id,
+                    &path,
+                    entries,
+                    false,
+                    "".to_owned(),
+                ));
+            });
+            let error = match res {
+                Ok(()) => "".to_owned(),
+                Err(err) => {
+                    log::error!("Failed to search {}: {}", path, err);
+                    err.to_string()
+                }
+            };
+            // Marks the search as finished for the clean up.
+            cancel.store(true, Ordering::SeqCst);
+            inner.send(Self::new_search_results(id, &path, vec![], true, error));
+        });
+    }
+
+    fn preview_file(&mut self, id: i32, path: String, max_size: i32, include_hidden: bool) {
+        if let Some(error) = Self::file_search_error(&mut self.file_previews) {
+            let msg = Self::new_preview_result(id, path, Default::default(), error.to_owned());
+            self.inner.send(msg);
+            return;
+        }
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.file_previews.insert(id, cancel.clone());
+        let mut inner = self.inner.clone();
+        std::thread::spawn(move || {
+            let (preview, error) =
+                match crate::file_search::preview(&path, max_size.max(0) as _, include_hidden) {
+                    Ok(preview) => (preview, "".to_owned()),
+                    Err(err) => {
+                        log::error!("Failed to preview {}: {}", path, err);
+                        (Default::default(), err.to_string())
+                    }
+                };
+            // Done, unless it was cancelled, then the result is dropped.
+            if !cancel.swap(true, Ordering::SeqCst) {
+                inner.send(Self::new_preview_result(id, path, preview, error));
+            }
+        });
+    }
+
+    async fn read_archive(
+        &mut self,
+        s: FileTransferSendRequest,
+        format: crate::file_archive::Format,
+    ) {
+        match crate::file_archive::ArchiveRead::new(s.id, &s.path, format, s.include_hidden) {
+            Err(err) => {
+                self.send(fs::new_error(s.id, err, 0)).await;
+            }
+            Ok(job) => {
+                self.send(job.new_dir(s.path.clone())).await;
+                let files = Self::get_files_for_audit(JobType::Generic, job.files().to_vec());
+                self.file_archives.push(job);
+                self.file_timer = crate::rustdesk_interval(time::interval(MILLI1));
+                self.post_file_audit(
+                    FileAuditType::RemoteSend,
+                    &s.path,
+                    files,
+                    json!({ "archive": true }),
+                );
+            }
+        }
+    }
+
     fn get_files_for_audit(job_type: fs::JobType, mut files: Vec<FileEntry>) -> Vec<(String, i64)> {
         files
             .drain(..)
@@ -1173,6 +1397,21 @@ impl Connection {
         });
     }
 
+    fn post_file_verify_audit(&self, id: i32, failed: Vec<i32>) {
+        if let Some((r#type, path, files)) = self.file_verify_audits.get(&id) {
+            let verified = failed.is_empty();
+            let files = if verified {
+                files.clone()
+            } else {
+                failed
+                    .iter()
+                    .filter_map(|file_num| files.get(*file_num as usize).cloned())
+                    .collect()
+            };
+            self.post_file_audit(*r#type, path, files, json!({ "verified": verified }));
+        }
+    }
+
     pub fn post_alarm_audit(typ: AlarmAuditType, info: Value) {
         let url = crate::get_audit_server(
             Config::get_option("api-server"),
@@ -1276,13 +1515,7 @@ impl Connection {
             pi.hostname = DEVICE_NAME.lock().unwrap().clone();
             pi.platform = "Android".into();
         }
-        #[cfg(all(target_os = "macos", not(feature = "unix-file-copy-paste")))]
-        let platform_additions = serde_json::Map::new();
-        #[cfg(any(
-            target_os = "windows",
-            target_os = "linux",
-            all(target_os = "macos", feature = "unix-file-copy-paste")
-        ))]
+        #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
         let mut platform_additions = serde_json::Map::new();
         #[cfg(target_os = "linux")]
         {
@@ -1335,7 +1568,8 @@ impl Connection {
         }
 
         #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
-        if !platform_additions.is_empty() {
+        {
+            crate::ext_msg::add_platform_addition(&mut platform_additions);
             pi.platform_additions = serde_json::to_string(&platform_additions).unwrap_or("".into());
         }
 
@@ -2081,6 +2315,7 @@ impl Connection {
                         .unwrap()
                         .user_network_delay(self.inner.id(), new_delay);
                     self.network_delay = new_delay;
+                    self.file_compression.set_rtt(new_delay);
                 }
             }
         } else if let Some(message::Union::SwitchSidesResponse(_s)) = msg.union {
@@ -2441,6 +2676,16 @@ impl Connection {
                                 ));
                                 let path = s.path.clone();
                                 let r#type = JobType::from_proto(s.file_type);
+                                // Files are sent one by one if it is not a directory.
+                                let archive = self.file_archive_requests.remove(&id).filter(|_| {
+                                    r#type == JobType::Generic
+                                        && std::path::Path::new(&path).is_dir()
+                                });
+                                if let Some(format) = archive {
+                                    self.read_archive(s, format).await;
+                                    self.file_transferred = true;
+                                    return true;
+                                }
                                 let data_source;
                                 match r#type {
                                     JobType::Generic => {
@@ -2477,8 +2722,19 @@ impl Connection {
                                         self.send(fs::new_error(id, err, 0)).await;
                                     }
                                     Ok(mut job) => {
+                                        let meta = if r#type == JobType::Generic
+                                            && self.ext_caps.contains(crate::ext_msg::CAP_FILE_META)
+                                        {
+                                            crate::file_meta::new_meta(id, &path, s.include_hidden)
+                                        } else {
+                                            None
+                                        };
                                         self.send(fs::new_dir(id, path, job.files().to_vec()))
                                             .await;
+                                        // Before the blocks, so it is there when the job is done.
+                                        if let Some(meta) = meta {
+                                            self.send(meta).await;
+                                        }
                                         let files = job.files().to_owned();
                                         job.is_remote = true;
                                         job.conn_id = self.inner.id();
@@ -2486,6 +2742,25 @@ impl Connection {
                                         self.read_jobs.push(job);
                                         self.file_timer =
                                             crate::rustdesk_interval(time::interval(MILLI1));
+                                        let files = Self::get_files_for_audit(job_type, files);
+                                        if job_type == fs::JobType::Generic {
+                                            self.file_compression.set_verify(
+                                                self.ext_caps
+                                                    .contains(crate::ext_msg::CAP_FILE_VERIFY)
+                                                    && (self.file_verify
+                                                        || Config::get_option(
+                                                            crate::file_verify::OPTION_VERIFY,
+                                                        ) == "Y"),
+                                            );
+                                            self.file_verify_audits.insert(
+                                                id,
+                                                (
+                                                    FileAuditType::RemoteSend,
+                                                    s.path.clone(),
+                                                    files.clone(),
+                                                ),
+                                            );
+                                        }
                                         self.post_file_audit(
                                             FileAuditType::RemoteSend,
                                             if job_type == fs::JobType::Printer {
@@ -2493,7 +2768,7 @@ impl Connection {
                                             } else {
                                                 &s.path
                                             },
-                                            Self::get_files_for_audit(job_type, files),
+                                            files,
                                             json!({}),
                                         );
                                     }
@@ -2504,6 +2779,21 @@ impl Connection {
                                 // client to server
                                 // note: 1.1.10 introduced identical file detection, which breaks original logic of send/recv files
                                 // whenever got send/recv request, check peer version to ensure old version of rustdesk
+                                if let Some(format) = self.file_archive_requests.remove(&r.id) {
+                                    self.send_fs(ipc::FS::NewArchiveWrite {
+                                        path: r.path.clone(),
+                                        id: r.id,
+                                        format,
+                                    });
+                                    self.post_file_audit(
+                                        FileAuditType::RemoteReceive,
+                                        &r.path,
+                                        vec![],
+                                        json!({ "archive": true }),
+                                    );
+                                    self.file_transferred = true;
+                                    return true;
+                                }
                                 let od = can_enable_overwrite_detection(get_version_number(
                                     &self.lr.version,
                                 ));
@@ -2521,10 +2811,16 @@ impl Connection {
                                     total_size: r.total_size,
                                     conn_id: self.inner.id(),
                                 });
+                                let files =
+                                    Self::get_files_for_audit(fs::JobType::Generic, r.files);
+                                self.file_verify_audits.insert(
+                                    r.id,
+                                    (FileAuditType::RemoteReceive, r.path.clone(), files.clone()),
+                                );
                                 self.post_file_audit(
                                     FileAuditType::RemoteReceive,
                                     &r.path,
-                                    Self::get_files_for_audit(fs::JobType::Generic, r.files),
+                                    files,
                                     json!({}),
                                 );
                                 self.file_transferred = true;
@@ -2562,7 +2858,16 @@ impl Connection {
                                 )));
                             }
                             Some(file_action::Union::Cancel(c)) => {
+                                if let Some(cancel) = self
+                                    .file_searches
+                                    .remove(&c.id)
+                                    .or_else(|| self.file_previews.remove(&c.id))
+                                {
+                                    cancel.store(true, Ordering::SeqCst);
+                                    return true;
+                                }
                                 self.send_fs(ipc::FS::CancelWrite { id: c.id });
+                                self.file_archives.remove(c.id);
                                 if let Some(job) = fs::remove_job(c.id, &mut self.read_jobs) {
                                     self.send_to_cm(ipc::Data::FileTransferLog((
                                         "transfer".to_string(),
@@ -2738,6 +3043,9 @@ impl Connection {
                     Some(misc::Union::ChangeDisplayResolution(dr)) => {
                         self.change_resolution(Some(dr.display as _), &dr.resolution)
                     }
+                    Some(misc::Union::PluginRequest(p)) if crate::ext_msg::is_ext(&p) => {
+                        self.handle_ext_msg(p).await;
+                    }
                     #[cfg(all(feature = "flutter", feature = "plugin_framework"))]
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
                     Some(misc::Union::PluginRequest(p)) => {
@@ -2892,6 +3200,104 @@ impl Connection {
         ((failure, time), res)
     }
 
+    async fn handle_ext_msg(&mut self, p: PluginRequest) {
+        use crate::ext_msg::{self, Ext};
+        let Some((ext, tail)) = ext_msg::parse(&p) else {
+            return;
+        };
+        match ext {
+            Ext::Caps { caps } => {
+                log::info!("peer capabilities: {:?}", caps);
+                self.ext_caps = caps.into_iter().collect();
+                video_service::VIDEO_QOS.lock().unwrap().user_support_tiles(
+                    self.inner.id(),
+                    self.ext_caps.contains(ext_msg::CAP_TILES),
+                );
+            }
+            #[cfg(target_os = "linux")]
+            Ext::CaptureRegion {
+                display,
+                x,
+                y,
+                width,
+                height,
+            } => self.set_capture_region(display, x, y, width, height),
+            Ext::CameraCapabilitiesQuery { display } => {
+                if self.is_authed_view_camera_conn() {
+                    self.send_camera_capabilities(display as _).await;
+                }
+            }
+            Ext::CameraSettings { settings } => {
+                if self.is_authed_view_camera_conn() {
+                    self.apply_camera_settings(&settings).await;
+                }
+            }
+            Ext::FileVerifyOption { verify } => {
+                self.file_verify = verify;
+            }
+            Ext::FileDigest { id, file_num } => {
+                self.send_fs(ipc::FS::Verify {
+                    id,
+                    file_num,
+                    sha256: tail.to_vec(),
+                });
+            }
+            Ext::FileVerifyResult { id, failed } => {
+                self.post_file_verify_audit(id, failed);
+            }
+            // Like the file actions, only with the file transfer permission.
+            Ext::FileArchiveRequest { id, format } => {
+                if self.file_transfer.is_some() {
+                    self.file_archive_requests.insert(id, format);
+                }
+            }
+            Ext::FileSearch { id, path, options } => {
+                if self.file_transfer.is_some() {
+                    self.search_files(id, path, options);
+                }
+            }
+            Ext::FilePreview {
+                id,
+                path,
+                max_size,
+                include_hidden,
+            } => {
+                if self.file_transfer.is_some() {
+                    self.preview_file(id, path, max_size, include_hidden);
+                }
+            }
+            Ext::FileMeta { id, files } => {
+                if self.file_transfer.is_some() {
+                    self.send_fs(ipc::FS::WriteMeta { id, files });
+                }
+            }
+            Ext::FileTransferLimit {
+                max_speed,
+                priority,
+            } => {
+                self.file_throttle.set_peer_limit(max_speed, priority);
+            }
+            Ext::FileBlockSignatures {
+                id,
+                file_num,
+                block_size,
+                basis_size,
+            } => {
+                // The job waits for the confirmation of this file, which the client sends
+                // after applying the delta.
+                if let Some(job) = fs::get_job(id, &mut self.read_jobs) {
+                    if let (fs::DataSource::FilePath(p), Some(file)) =
+                        (&job.data_source, job.files().get(file_num as usize))
+                    {
+                        let path = fs::TransferJob::join(p, &file.name);
+                        self.send_file_delta(id, file_num, block_size, basis_size, tail, path);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
     fn refresh_video_display(&self, display: Option<usize>) {
         video_service::refresh();
         self.server.upgrade().map(|s| {
@@ -3124,6 +3530,93 @@ impl Connection {
         }
     }
 
+    // An empty region restores the whole display.
+    #[cfg(target_os = "linux")]
+    fn set_capture_region(&mut self, display: i32, x: i32, y: i32, width: i32, height: i32) {
+        if !self.keyboard || display < 0 {
+            return;
+        }
+        let region = if width > 0 && height > 0 {
+            Some(display_service::DisplayRegion {
+                x,
+                y,
+                width: width as _,
+                height: height as _,
+            })
+        } else {
+            None
+        };
+        // The capturer of a display is shared, cropping it would crop the other viewers too.
+        if region.is_some()
+            && self.server.upgrade().map_or(false, |s| {
+                s.read()
+                    .unwrap()
+                    .is_display_subed_by_others(display as _, self.inner.id())
+            })
+        {
+            log::warn!(
+                "#{} capture region of display {} refused, other viewers are on it",
+                self.inner.id(),
+                display
+            );
+            return;
+        }
+        log::info!(
+            "#{} set capture region of display {}: {:?}",
+            self.inner.id(),
+            display,
+            region
+        );
+        display_service::set_capture_region(self.inner.id(), display as _, region);
+    }
+
+    async fn send_camera_capabilities(&mut self, display: usize) {
+        match camera::Cameras::capabilities(display) {
+            Ok(caps) => {
+                let ext = crate::ext_msg::Ext::CameraCapabilities { caps };
+                self.send(crate::ext_msg::new_message(&ext, &[])).await;
+            }
+            Err(e) => log::error!("Failed to get capabilities of camera {}: {}", display, e),
+        }
+    }
+
+    // The capabilities are sent after the capturer has applied the settings, a new format
+    // recreates it.
+    async fn apply_camera_settings(&mut self, settings: &camera::CameraSettings) {
+        let display = settings.display as usize;
+        let serial = camera::Cameras::capabilities_serial(display);
+        match camera::Cameras::apply_settings(settings) {
+            Ok(true) => {
+                self.camera_caps_pending
+                    .insert(display, (serial, Instant::now()));
+            }
+            Ok(false) => self.send_camera_capabilities(display).await,
+            Err(e) => {
+                log::error!("Failed to apply camera settings: {}", e);
+                self.send_camera_capabilities(display).await;
+            }
+        }
+    }
+
+    async fn check_camera_capabilities(&mut self) {
+        if self.camera_caps_pending.is_empty() {
+            return;
+        }
+        let ready: Vec<usize> = self
+            .camera_caps_pending
+            .iter()
+            .filter(|(display, (serial, time))| {
+                camera::Cameras::capabilities_serial(**display) > *serial
+                    || time.elapsed() >= CAMERA_SETTINGS_TIMEOUT
+            })
+            .map(|(display, _)| *display)
+            .collect();
+        for display in ready {
+            self.camera_caps_pending.remove(&display);
+            self.send_camera_capabilities(display).await;
+        }
+    }
+
     pub async fn handle_voice_call(&mut self, accepted: bool) {
         if let Some(ts) = self.voice_call_request_timestamp.take() {
             let msg = new_voice_call_response(ts.get(), accepted);
@@ -3491,6 +3984,15 @@ impl Connection {
         // But it's not necessary now and we have to consider two audio services(client, server).
         crate::audio_service::set_voice_call_input_device(None, true);
         log::info!("#{} Connection closed: {}", self.inner.id(), reason);
+        #[cfg(target_os = "linux")]
+        display_service::clear_capture_regions(self.inner.id());
+        for cancel in self
+            .file_searches
+            .values()
+            .chain(self.file_previews.values())
+        {
+            cancel.store(true, Ordering::SeqCst);
+        }
         if lock && self.lock_after_session_end && self.keyboard {
             #[cfg(not(any(target_os = "android", target_os = "ios")))]
             lock_screen().await;
@@ -3934,6 +4436,7 @@ pub enum AlarmAuditType {
     SixAttemptsWithinOneMinute = 2,
 }
 
+#[derive(Clone, Copy)]
 pub enum FileAuditType {
     RemoteSend = 0,
     RemoteReceive = 1,
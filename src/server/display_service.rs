@@ -17,6 +17,25 @@ pub const NAME: &'static str = "display";
 #[cfg(windows)]
 const DUMMY_DISPLAY_SIDE_MAX_SIZE: usize = 1024;
 
+// Regions of displays to capture, "<display>:<x>,<y>,<width>,<height>" separated by ";".
+// x and y are relative to the display. Regions requested by peers take precedence.
+#[cfg(target_os = "linux")]
+pub const OPTION_CAPTURE_REGION: &str = "capture-region";
+
+// A region of a display, relative to the display.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisplayRegion {
+    pub x: i32,
+    pub y: i32,
+    pub width: usize,
+    pub height: usize,
+}
+
+// (full rect, region rect) of the cropped displays, absolute (x, y, w, h).
+#[cfg(target_os = "linux")]
+type CroppedDisplay = ((i32, i32, usize, usize), (i32, i32, usize, usize));
+
 struct ChangedResolution {
     original: (i32, i32),
     changed: (i32, i32),
@@ -29,6 +48,11 @@ lazy_static::lazy_static! {
     // It should not be updated when displays changed.
     pub static ref PRIMARY_DISPLAY_IDX: usize = get_primary();
     static ref SYNC_DISPLAYS: Arc<Mutex<SyncDisplaysInfo>> = Default::default();
+    // display index -> (conn id, region)
+    #[cfg(target_os = "linux")]
+    static ref PEER_CAPTURE_REGIONS: RwLock<HashMap<usize, (i32, DisplayRegion)>> = Default::default();
+    #[cfg(target_os = "linux")]
+    static ref CROPPED_DISPLAYS: RwLock<Vec<CroppedDisplay>> = Default::default();
 }
 
 // https://github.com/rustdesk/rustdesk/pull/8537
@@ -197,7 +221,22 @@ fn displays_to_msg(displays: Vec<DisplayInfo>) -> Message {
     msg_out
 }
 
+// The synthetic display replaces all the real displays, no display server is needed.
+fn check_update_synthetic_displays() -> bool {
+    let Some(config) = scrap::synthetic::config() else {
+        return false;
+    };
+    SYNC_DISPLAYS
+        .lock()
+        .unwrap()
+        .check_changed(vec![scrap::synthetic::display_info(&config)]);
+    true
+}
+
 fn check_get_displays_changed_msg() -> Option<Message> {
+    if check_update_synthetic_displays() {
+        return get_displays_msg();
+    }
     #[cfg(target_os = "linux")]
     {
         if !is_x11() {
@@ -209,6 +248,9 @@ fn check_get_displays_changed_msg() -> Option<Message> {
 }
 
 pub fn check_displays_changed() -> ResultType<()> {
+    if check_update_synthetic_displays() {
+        return Ok(());
+    }
     #[cfg(target_os = "linux")]
     {
         // Currently, wayland need to call wayland::clear() before call Display::all(), otherwise it will cause
@@ -338,6 +380,9 @@ pub(super) fn check_update_displays(all: &Vec<Display>) {
 }
 
 pub fn is_inited_msg() -> Option<Message> {
+    if scrap::synthetic::config().is_some() {
+        return None;
+    }
     #[cfg(target_os = "linux")]
     if !is_x11() {
         return super::wayland::is_inited();
@@ -346,6 +391,9 @@ pub fn is_inited_msg() -> Option<Message> {
 }
 
 pub async fn update_get_sync_displays_on_login() -> ResultType<Vec<DisplayInfo>> {
+    if check_update_synthetic_displays() {
+        return Ok(SYNC_DISPLAYS.lock().unwrap().displays.clone());
+    }
     #[cfg(target_os = "linux")]
     {
         if !is_x11() {
@@ -362,6 +410,9 @@ pub async fn update_get_sync_displays_on_login() -> ResultType<Vec<DisplayInfo>>
 
 #[inline]
 pub fn get_primary() -> usize {
+    if scrap::synthetic::config().is_some() {
+        return 0;
+    }
     #[cfg(target_os = "linux")]
     {
         if !is_x11() {
@@ -408,7 +459,135 @@ fn no_displays(displays: &Vec<Display>) -> bool {
 #[inline]
 #[cfg(not(windows))]
 pub fn try_get_displays() -> ResultType<Vec<Display>> {
-    Ok(Display::all()?)
+    #[allow(unused_mut)]
+    let mut displays = Display::all()?;
+    // Windows follow the monitors, so the indexes of the monitors are not changed.
+    #[cfg(target_os = "linux")]
+    if Config::get_option(scrap::OPTION_WINDOW_CAPTURE) == "Y" {
+        match Display::all_windows() {
+            Ok(windows) => displays.extend(windows),
+            Err(e) => log::error!("Failed to get windows: {}", e),
+        }
+    }
+    #[cfg(target_os = "linux")]
+    crop_displays(&mut displays);
+    Ok(displays)
+}
+
+#[cfg(target_os = "linux")]
+fn parse_capture_regions(s: &str) -> HashMap<usize, DisplayRegion> {
+    s.split(';')
+        .filter(|v| !v.trim().is_empty())
+        .filter_map(|v| {
+            let (idx, rect) = v.split_once(':')?;
+            let rect = rect
+                .split(',')
+                .map(|n| n.trim().parse::<i32>().ok())
+                .collect::<Option<Vec<_>>>()?;
+            let [x, y, width, height] = rect[..] else {
+                return None;
+            };
+            if width <= 0 || height <= 0 {
+                return None;
+            }
+            let region = DisplayRegion {
+                x,
+                y,
+                width: width as _,
+                height: height as _,
+            };
+            Some((idx.trim().parse().ok()?, region))
+        })
+        .collect()
+}
+
+// Display lists, capturers and input all use the cropped rects, so the peer sees the region
+// as a display and its coordinates already point into the region.
+#[cfg(target_os = "linux")]
+fn crop_displays(displays: &mut Vec<Display>) {
+    let mut regions = parse_capture_regions(&Config::get_option(OPTION_CAPTURE_REGION));
+    for (idx, (_, region)) in PEER_CAPTURE_REGIONS.read().unwrap().iter() {
+        regions.insert(*idx, *region);
+    }
+    let mut cropped = Vec::new();
+    for (idx, region) in regions {
+        let Some(d) = displays.get_mut(idx) else {
+            continue;
+        };
+        let full = (d.origin().0, d.origin().1, d.width(), d.height());
+        if d.crop(region.x, region.y, region.width, region.height) {
+            cropped.push((full, (d.origin().0, d.origin().1, d.width(), d.height())));
+        } else {
+            log::warn!("Invalid capture region of display {}: {:?}", idx, region);
+        }
+    }
+    *CROPPED_DISPLAYS.write().unwrap() = cropped;
+}
+
+// Set or clear (`None`) the capture region of a display requested by a peer.
+#[cfg(target_os = "linux")]
+pub fn set_capture_region(conn_id: i32, display: usize, region: Option<DisplayRegion>) {
+    {
+        let mut lock = PEER_CAPTURE_REGIONS.write().unwrap();
+        match region {
+            Some(region) => {
+                lock.insert(display, (conn_id, region));
+            }
+            None => {
+                lock.remove(&display);
+            }
+        }
+    }
+    allow_err!(check_displays_changed());
+}
+
+// The region of `display` set by a connection other than `conn_id` is removed.
+#[cfg(target_os = "linux")]
+pub fn clear_others_capture_region(display: usize, conn_id: i32) {
+    let removed = {
+        let mut lock = PEER_CAPTURE_REGIONS.write().unwrap();
+        match lock.get(&display) {
+            Some((id, _)) if *id != conn_id => lock.remove(&display).is_some(),
+            _ => false,
+        }
+    };
+    if removed {
+        log::info!(
+            "Capture region of display {} removed for another viewer",
+            display
+        );
+        allow_err!(check_displays_changed());
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn clear_capture_regions(conn_id: i32) {
+    let removed = {
+        let mut lock = PEER_CAPTURE_REGIONS.write().unwrap();
+        let len = lock.len();
+        lock.retain(|_, (id, _)| *id != conn_id);
+        len != lock.len()
+    };
+    if removed {
+        allow_err!(check_displays_changed());
+    }
+}
+
+// Keep the mouse in the captured region if the point is on a cropped display.
+#[cfg(target_os = "linux")]
+pub fn clamp_to_capture_region(x: i32, y: i32) -> (i32, i32) {
+    let contains = |(rx, ry, rw, rh): (i32, i32, usize, usize)| {
+        x >= rx && y >= ry && x < rx + rw as i32 && y < ry + rh as i32
+    };
+    for (full, (rx, ry, rw, rh)) in CROPPED_DISPLAYS.read().unwrap().iter() {
+        if contains(*full) {
+            return (
+                x.clamp(*rx, rx + *rw as i32 - 1),
+                y.clamp(*ry, ry + *rh as i32 - 1),
+            );
+        }
+    }
+    (x, y)
 }
 
 #[inline]
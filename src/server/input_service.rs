@@ -1107,7 +1107,27 @@ pub fn handle_mouse_(evt: &MouseEvent, conn: i32) {
     crate::platform::windows::try_change_desktop();
     let buttons = evt.mask >> 3;
     let evt_type = evt.mask & 0x7;
+    // Wheel events carry deltas, only positions are clamped.
+    // Clicks and wheel events act at the cursor, see below.
+    #[cfg(target_os = "linux")]
+    let evt = &{
+        let mut evt = evt.clone();
+        if evt_type == MOUSE_TYPE_MOVE {
+            (evt.x, evt.y) = super::display_service::clamp_to_capture_region(evt.x, evt.y);
+        }
+        evt
+    };
     let mut en = ENIGO.lock().unwrap();
+    // The local user may have moved the cursor out of the captured region.
+    #[cfg(target_os = "linux")]
+    if evt_type != MOUSE_TYPE_MOVE {
+        if let Some((x, y)) = crate::get_cursor_pos() {
+            let (cx, cy) = super::display_service::clamp_to_capture_region(x, y);
+            if (cx, cy) != (x, y) {
+                en.mouse_move_to(cx, cy);
+            }
+        }
+    }
     #[cfg(target_os = "macos")]
     en.set_ignore_flags(enigo_ignore_flags());
     #[cfg(not(target_os = "macos"))]
@@ -1,7 +1,7 @@
-use super::*;
+use super::{bandwidth_estimator::BandwidthEstimator, *};
 use scrap::codec::{Quality, BR_BALANCED, BR_BEST, BR_SPEED};
 use std::{
-    collections::VecDeque,
+    collections::{HashSet, VecDeque},
     time::{Duration, Instant},
 };
 
@@ -18,6 +18,9 @@ a. user set image quality => update to the maximum ratio of the latest quality
 b. 3 seconds timeout => update ratio according to network delay
     When network delay < DELAY_THRESHOLD_150MS, increase ratio, max 150kbps;
     When network delay >= DELAY_THRESHOLD_150MS, decrease ratio;
+c. the ratio is only increased for dynamic screens, which encode at least DYNAMIC_SCREEN_THRESHOLD
+    times per second and change at least DYNAMIC_CHANGED_AREA of the screen per second.
+    The increase step is scaled by the changed area, a blinking cursor no longer counts as a dynamic screen.
 
 adjust betwen FPS and ratio:
     When network delay < DELAY_THRESHOLD_150MS, fps is always higher than the minimum fps, and ratio is increasing;
@@ -25,6 +28,19 @@ adjust betwen FPS and ratio:
 
 delay:
     use delay minus RTT as the actual network delay
+
+bandwidth estimation:
+    Every user has a BandwidthEstimator fed with bytes sent, delay samples and late frames.
+    When enabled and an estimate is available, the ratio follows the minimum target bitrate of the
+    base layer users instead of the delay thresholds below, the 150kbps increase limit still applies.
+
+simulcast:
+    When enabled, every user is put on one of MAX_LAYERS layers according to its own delay.
+    Layer 0 is driven by the users on it, so one slow viewer no longer degrades everyone.
+    Lower layers are encoded with a fraction of the layer 0 ratio and fps.
+    When both the delay and avg delay >= LAYER_DOWN_DELAY for LAYER_DOWN_SAMPLES samples, move the
+    user one layer down, an unanswered delay test counts as such a sample;
+    When avg delay < LAYER_UP_DELAY for LAYER_UP_SAMPLES samples, move the user one layer up.
 */
 
 // Constants
@@ -42,8 +58,19 @@ const MAX_BR_MULTIPLE: f32 = 1.0;
 const HISTORY_DELAY_LEN: usize = 2;
 const ADJUST_RATIO_INTERVAL: usize = 3; // Adjust quality ratio every 3 seconds
 const DYNAMIC_SCREEN_THRESHOLD: usize = 2; // Allow increase quality ratio if encode more than 2 times in one second
+const DYNAMIC_CHANGED_AREA: f32 = 0.05; // Screens changed per second to be a dynamic screen
+const FULL_CHANGED_AREA: f32 = 1.0; // Screens changed per second for the full increase step
 const DELAY_THRESHOLD_150MS: u32 = 150; // 150ms is the threshold for good network condition
 
+// Simulcast constants
+pub const MAX_LAYERS: usize = 3;
+const LAYER_RATIO_SCALE: [f32; MAX_LAYERS] = [1.0, 0.5, 0.25];
+const LAYER_FPS_SCALE: [f32; MAX_LAYERS] = [1.0, 0.67, 0.5];
+const LAYER_DOWN_DELAY: u32 = 300;
+const LAYER_DOWN_SAMPLES: usize = 3;
+const LAYER_UP_DELAY: u32 = 100;
+const LAYER_UP_SAMPLES: usize = 5;
+
 #[derive(Default, Debug, Clone)]
 struct UserDelay {
     response_delayed: bool,
@@ -52,6 +79,8 @@ struct UserDelay {
     rtt_calculator: RttCalculator,
     quick_increase_fps_count: usize,
     increase_fps_count: usize,
+    layer_down_count: usize,
+    layer_up_count: usize,
 }
 
 impl UserDelay {
@@ -93,12 +122,47 @@ struct UserData {
     quality: Option<(i64, Quality)>, // (time, quality)
     delay: UserDelay,
     record: bool,
+    layer: usize, // simulcast layer, 0 is the best
+    support_tiles: bool,
+    bwe: BandwidthEstimator,
+}
+
+impl UserData {
+    // Returns true if the layer is changed
+    fn update_layer(&mut self, delay: u32) -> bool {
+        let avg_delay = self.delay.avg_delay();
+        let d = &mut self.delay;
+        // A single spike raises the average for a while, it only counts once.
+        if delay >= LAYER_DOWN_DELAY && avg_delay >= LAYER_DOWN_DELAY {
+            d.layer_up_count = 0;
+            d.layer_down_count += 1;
+            if d.layer_down_count >= LAYER_DOWN_SAMPLES && self.layer + 1 < MAX_LAYERS {
+                d.layer_down_count = 0;
+                self.layer += 1;
+                return true;
+            }
+        } else if avg_delay < LAYER_UP_DELAY {
+            d.layer_down_count = 0;
+            d.layer_up_count += 1;
+            if d.layer_up_count >= LAYER_UP_SAMPLES && self.layer > 0 {
+                d.layer_up_count = 0;
+                self.layer -= 1;
+                return true;
+            }
+        } else {
+            d.layer_down_count = 0;
+            d.layer_up_count = 0;
+        }
+        false
+    }
 }
 
 #[derive(Default, Debug, Clone)]
 struct DisplayData {
     send_counter: usize, // Number of times encode during period
+    changed_area: f32,   // Sum of the changed area ratios of the encoded frames during period
     support_changing_quality: bool,
+    support_simulcast: bool,
 }
 
 // Main QoS controller structure
@@ -110,6 +174,8 @@ pub struct VideoQoS {
     bitrate_store: u32,
     adjust_ratio_instant: Instant,
     abr_config: bool,
+    simulcast_config: bool,
+    bwe_config: bool,
     new_user_instant: Instant,
 }
 
@@ -123,6 +189,8 @@ impl Default for VideoQoS {
             bitrate_store: 0,
             adjust_ratio_instant: Instant::now(),
             abr_config: true,
+            simulcast_config: false,
+            bwe_config: false,
             new_user_instant: Instant::now(),
         }
     }
@@ -178,6 +246,56 @@ impl VideoQoS {
     pub fn in_vbr_state(&self) -> bool {
         self.abr_config && self.displays.iter().all(|e| e.1.support_changing_quality)
     }
+
+    pub fn set_support_simulcast(&mut self, video_service_name: &str, support: bool) {
+        if let Some(display) = self.displays.get_mut(video_service_name) {
+            display.support_simulcast = support;
+        }
+    }
+
+    // Check if per-user layers are supported and enabled
+    pub fn in_simulcast_state(&self) -> bool {
+        self.simulcast_config
+            && self.in_vbr_state()
+            && self.displays.iter().all(|e| e.1.support_simulcast)
+    }
+
+    // Layer of every user, empty if not in simulcast state
+    pub fn user_layers(&self) -> HashMap<i32, usize> {
+        if !self.in_simulcast_state() {
+            return Default::default();
+        }
+        self.users.iter().map(|(id, u)| (*id, u.layer)).collect()
+    }
+
+    pub fn layer_ratio(&mut self, layer: usize) -> f32 {
+        let scale = LAYER_RATIO_SCALE[layer.min(MAX_LAYERS - 1)];
+        (self.ratio() * scale).max(BR_MIN_HIGH_RESOLUTION)
+    }
+
+    pub fn layer_spf(&self, layer: usize) -> Duration {
+        let layer = layer.min(MAX_LAYERS - 1);
+        let base_fps = self.fps();
+        let fps = self
+            .users
+            .iter()
+            .filter(|u| u.1.layer == layer)
+            .filter_map(|u| u.1.delay.fps)
+            .min()
+            .unwrap_or((base_fps as f32 * LAYER_FPS_SCALE[layer]) as u32)
+            .clamp(MIN_FPS, base_fps);
+        Duration::from_secs_f32(1. / (fps as f32))
+    }
+
+    // Whether all users can decode lossless tile frames
+    pub fn support_tiles(&self) -> bool {
+        !self.users.is_empty() && self.users.iter().all(|u| u.1.support_tiles)
+    }
+
+    #[inline]
+    fn in_base_layer(&self, user: &UserData) -> bool {
+        !self.in_simulcast_state() || user.layer == 0
+    }
 }
 
 // User session management
@@ -186,6 +304,8 @@ impl VideoQoS {
     pub fn on_connection_open(&mut self, id: i32) {
         self.users.insert(id, UserData::default());
         self.abr_config = Config::get_option("enable-abr") != "N";
+        self.simulcast_config = Config::get_option("enable-simulcast") == "Y";
+        self.bwe_config = Config::get_option("enable-bwe") == "Y";
         self.new_user_instant = Instant::now();
     }
 
@@ -237,6 +357,12 @@ impl VideoQoS {
         }
     }
 
+    pub fn user_support_tiles(&mut self, id: i32, v: bool) {
+        if let Some(user) = self.users.get_mut(&id) {
+            user.support_tiles = v;
+        }
+    }
+
     pub fn user_record(&mut self, id: i32, v: bool) {
         if let Some(user) = self.users.get_mut(&id) {
             user.record = v;
@@ -260,13 +386,20 @@ impl VideoQoS {
         let dividend_ms = DELAY_THRESHOLD_150MS * min_fps;
 
         let mut adjust_ratio = false;
+        let simulcast = self.in_simulcast_state();
         if let Some(user) = self.users.get_mut(&id) {
             let delay = delay.max(10);
+            user.bwe.on_delay(Instant::now(), delay);
             let old_avg_delay = user.delay.avg_delay();
             user.delay.add_delay(delay);
             let mut avg_delay = user.delay.avg_delay();
             avg_delay = avg_delay.max(10);
-            let mut fps = self.fps;
+            // Users on different layers adjust their fps independently
+            let mut fps = if simulcast {
+                user.delay.fps.unwrap_or(self.fps)
+            } else {
+                self.fps
+            };
 
             // Adaptive FPS adjustment based on network delay:
             if avg_delay < 50 {
@@ -326,10 +459,29 @@ impl VideoQoS {
             adjust_ratio = user.delay.fps.is_none();
             user.delay.fps = Some(fps);
         }
+        self.update_user_layer(id, delay);
         self.adjust_fps();
         if adjust_ratio && !cfg!(target_os = "linux") {
             //Reduce the possibility of vaapi being created twice
-            self.adjust_ratio(false);
+            self.adjust_ratio(0.0);
+        }
+    }
+
+    pub fn user_frame_sent(&mut self, ids: &HashSet<i32>, bytes: usize) {
+        let now = Instant::now();
+        for id in ids {
+            if let Some(user) = self.users.get_mut(id) {
+                user.bwe.on_sent(now, bytes);
+            }
+        }
+    }
+
+    // Frames not fetched in time, TCP has no loss so these are the loss signal.
+    pub fn user_frame_late(&mut self, ids: &HashSet<i32>) {
+        for id in ids {
+            if let Some(user) = self.users.get_mut(id) {
+                user.bwe.on_late();
+            }
         }
     }
 
@@ -338,14 +490,35 @@ impl VideoQoS {
             user.delay.response_delayed = elapsed > 2000;
             if user.delay.response_delayed {
                 user.delay.add_delay(elapsed as u32);
+                self.update_user_layer(id, elapsed as u32);
                 self.adjust_fps();
             }
         }
     }
+
+    // Whether any user suffers from the network, file transfers yield to the video then.
+    pub fn delay_high(&self) -> bool {
+        self.users.values().any(|u| {
+            u.delay.response_delayed
+                || (!u.delay.delay_history.is_empty()
+                    && u.delay.avg_delay() >= crate::file_throttle::HIGH_DELAY)
+        })
+    }
 }
 
 // Common adjust functions
 impl VideoQoS {
+    fn update_user_layer(&mut self, id: i32, delay: u32) {
+        if !self.simulcast_config {
+            return;
+        }
+        if let Some(user) = self.users.get_mut(&id) {
+            if user.update_layer(delay) {
+                log::info!("user {id} to simulcast layer {}", user.layer);
+            }
+        }
+    }
+
     pub fn new_display(&mut self, video_service_name: String) {
         self.displays
             .insert(video_service_name, DisplayData::default());
@@ -355,22 +528,35 @@ impl VideoQoS {
         self.displays.remove(video_service_name);
     }
 
-    pub fn update_display_data(&mut self, video_service_name: &str, send_counter: usize) {
+    pub fn update_display_data(
+        &mut self,
+        video_service_name: &str,
+        send_counter: usize,
+        changed_area: f32,
+    ) {
         if let Some(display) = self.displays.get_mut(video_service_name) {
             display.send_counter += send_counter;
+            display.changed_area += changed_area;
         }
+        let now = Instant::now();
+        let bitrate = self.bitrate_store;
+        self.users.iter_mut().for_each(|u| {
+            u.1.bwe.update(now, bitrate);
+        });
         self.adjust_fps();
         let abr_enabled = self.in_vbr_state();
         if abr_enabled {
             if self.adjust_ratio_instant.elapsed().as_secs() >= ADJUST_RATIO_INTERVAL as u64 {
-                let dynamic_screen = self
+                let activity = self
                     .displays
                     .iter()
-                    .any(|d| d.1.send_counter >= ADJUST_RATIO_INTERVAL * DYNAMIC_SCREEN_THRESHOLD);
+                    .map(|d| Self::screen_activity(d.1))
+                    .fold(0.0, f32::max);
                 self.displays.iter_mut().for_each(|d| {
                     d.1.send_counter = 0;
+                    d.1.changed_area = 0.0;
                 });
-                self.adjust_ratio(dynamic_screen);
+                self.adjust_ratio(activity);
             }
         } else {
             self.ratio = self.latest_quality().ratio();
@@ -412,13 +598,32 @@ impl VideoQoS {
             .1
     }
 
+    // 0 for a static screen, (0, 1] for a dynamic screen according to the changed area
+    fn screen_activity(display: &DisplayData) -> f32 {
+        let changed_area = display.changed_area / ADJUST_RATIO_INTERVAL as f32;
+        if display.send_counter < ADJUST_RATIO_INTERVAL * DYNAMIC_SCREEN_THRESHOLD
+            || changed_area < DYNAMIC_CHANGED_AREA
+        {
+            return 0.0;
+        }
+        (changed_area / FULL_CHANGED_AREA).min(1.0)
+    }
+
     // Adjust quality ratio based on network delay and screen changes
-    fn adjust_ratio(&mut self, dynamic_screen: bool) {
+    fn adjust_ratio(&mut self, activity: f32) {
+        let dynamic_screen = activity > 0.0;
+        // Half of the increase step for small changes, full step for a fully changing screen
+        let step_scale = 0.5 + 0.5 * activity;
         if !self.in_vbr_state() {
             return;
         }
-        // Get maximum delay from all users
-        let max_delay = self.users.iter().map(|u| u.1.delay.avg_delay()).max();
+        // Get maximum delay from all users on the base layer
+        let max_delay = self
+            .users
+            .iter()
+            .filter(|u| self.in_base_layer(u.1))
+            .map(|u| u.1.delay.avg_delay())
+            .max();
         let Some(max_delay) = max_delay else {
             return;
         };
@@ -470,18 +675,34 @@ impl VideoQoS {
 
         let mut v = current_ratio;
 
-        // Adjust ratio based on network delay thresholds
-        if max_delay < 50 {
+        // Minimum estimated bandwidth of the base layer users
+        let target_bitrate = if self.bwe_config {
+            self.users
+                .iter()
+                .filter(|u| self.in_base_layer(u.1))
+                .filter_map(|u| u.1.bwe.target())
+                .min()
+        } else {
+            None
+        };
+
+        if let (Some(target_bitrate), true) = (target_bitrate, current_bitrate > 0) {
+            // Bitrate is proportional to ratio
+            v = current_ratio * target_bitrate as f32 / current_bitrate as f32;
+            if !dynamic_screen && v > current_ratio {
+                v = current_ratio;
+            }
+        } else if max_delay < 50 {
             if dynamic_screen {
-                v = current_ratio * 1.15;
+                v = current_ratio * (1.0 + 0.15 * step_scale);
             }
         } else if max_delay < 100 {
             if dynamic_screen {
-                v = current_ratio * 1.1;
+                v = current_ratio * (1.0 + 0.1 * step_scale);
             }
         } else if max_delay < DELAY_THRESHOLD_150MS {
             if dynamic_screen {
-                v = current_ratio * 1.05;
+                v = current_ratio * (1.0 + 0.05 * step_scale);
             }
         } else if max_delay < 200 {
             v = current_ratio * 0.95;
@@ -510,15 +731,20 @@ impl VideoQoS {
     // Adjust fps based on network delay and user response time
     fn adjust_fps(&mut self) {
         let highest_fps = self.highest_fps();
-        // Get minimum fps from all users
+        // Get minimum fps from all users on the base layer
         let mut fps = self
             .users
             .iter()
+            .filter(|u| self.in_base_layer(u.1))
             .map(|u| u.1.delay.fps.unwrap_or(INIT_FPS))
             .min()
             .unwrap_or(INIT_FPS);
 
-        if self.users.iter().any(|u| u.1.delay.response_delayed) {
+        if self
+            .users
+            .iter()
+            .any(|u| self.in_base_layer(u.1) && u.1.delay.response_delayed)
+        {
             if fps > MIN_FPS + 1 {
                 fps = MIN_FPS + 1;
             }
@@ -593,3 +819,48 @@ impl RttCalculator {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(user: &mut UserData, delay: u32) -> bool {
+        user.delay.add_delay(delay);
+        user.update_layer(delay)
+    }
+
+    #[test]
+    fn test_layer() {
+        let mut user = UserData::default();
+        for _ in 0..10 {
+            assert!(!sample(&mut user, 20));
+        }
+        // A spike or two do not move the user.
+        sample(&mut user, 1000);
+        for _ in 0..3 {
+            sample(&mut user, 20);
+        }
+        sample(&mut user, 1000);
+        sample(&mut user, 1000);
+        sample(&mut user, 20);
+        assert_eq!(user.layer, 0);
+        for _ in 0..LAYER_DOWN_SAMPLES - 1 {
+            assert!(!sample(&mut user, 1000));
+        }
+        assert!(sample(&mut user, 1000));
+        assert_eq!(user.layer, 1);
+        for _ in 0..LAYER_DOWN_SAMPLES * 2 {
+            sample(&mut user, 1000);
+        }
+        assert_eq!(user.layer, MAX_LAYERS - 1);
+        // Up one layer at a time, once the history is low again.
+        let mut changes = 0;
+        for _ in 0..HISTORY_DELAY_LEN + 1 + LAYER_UP_SAMPLES * 2 {
+            if sample(&mut user, 20) {
+                changes += 1;
+                assert_eq!(user.layer, MAX_LAYERS - 1 - changes);
+            }
+        }
+        assert_eq!(user.layer, 0);
+    }
+}
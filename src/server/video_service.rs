@@ -18,7 +18,12 @@
 // to-do:
 // https://slhck.info/video/2017/03/01/rate-control.html
 
-use super::{display_service::check_display_changed, service::ServiceTmpl, video_qos::VideoQoS, *};
+use super::{
+    display_service::check_display_changed,
+    service::ServiceTmpl,
+    video_qos::{VideoQoS, MAX_LAYERS},
+    *,
+};
 #[cfg(target_os = "linux")]
 use crate::common::SimpleCallOnReturn;
 #[cfg(target_os = "linux")]
@@ -47,7 +52,10 @@ use scrap::Capturer;
 use scrap::{
     aom::AomEncoderConfig,
     codec::{Encoder, EncoderCfg},
+    dirty::TileMap,
     record::{Recorder, RecorderContext},
+    synthetic::{self, SyntheticCapturer},
+    tilecodec::TileEncoder,
     vpxcodec::{VpxEncoderConfig, VpxVideoCodecId},
     CodecFormat, Display, EncodeInput, TraitCapturer, TraitPixelBuffer,
 };
@@ -61,6 +69,11 @@ use std::{
 };
 
 pub const OPTION_REFRESH: &'static str = "refresh";
+// Changed area ratio of a frame to enter or leave the screen content mode
+const SCREEN_CONTENT_LOW_MOTION: f32 = 0.1;
+const SCREEN_CONTENT_HIGH_MOTION: f32 = 0.3;
+const SCREEN_CONTENT_ENTER_FRAMES: usize = 10;
+const SCREEN_CONTENT_EXIT_FRAMES: usize = 2;
 
 lazy_static::lazy_static! {
     static ref FRAME_FETCHED_NOTIFIER: (UnboundedSender<(i32, Option<Instant>)>, Arc<TokioMutex<UnboundedReceiver<(i32, Option<Instant>)>>>) = {
@@ -322,6 +335,10 @@ fn get_capturer_monitor(
     current: usize,
     portable_service_running: bool,
 ) -> ResultType<CapturerInfo> {
+    if let Some(config) = synthetic::config() {
+        return get_capturer_synthetic(current, config);
+    }
+
     #[cfg(target_os = "linux")]
     {
         if !is_x11() {
@@ -329,7 +346,10 @@ fn get_capturer_monitor(
         }
     }
 
+    #[cfg(not(target_os = "linux"))]
     let mut displays = Display::all()?;
+    #[cfg(target_os = "linux")]
+    let mut displays = super::display_service::try_get_displays()?;
     let ndisplay = displays.len();
     if ndisplay <= current {
         bail!(
@@ -452,6 +472,30 @@ fn get_capturer_camera(current: usize) -> ResultType<CapturerInfo> {
         capturer,
     });
 }
+
+fn get_capturer_synthetic(
+    current: usize,
+    config: synthetic::SyntheticConfig,
+) -> ResultType<CapturerInfo> {
+    if current != 0 {
+        bail!("Failed to get display {}, displays len: 1", current);
+    }
+    let capturer = SyntheticCapturer::new(config)?;
+    let (width, height) = (capturer.width(), capturer.height());
+    let privacy_mode_id = get_privacy_mode_conn_id().unwrap_or(INVALID_PRIVACY_MODE_CONN_ID);
+    log::debug!("synthetic display, width={}, height={}", width, height);
+    Ok(CapturerInfo {
+        origin: (0, 0),
+        width,
+        height,
+        ndisplay: 1,
+        current,
+        privacy_mode_id,
+        _capturer_privacy_mode_id: privacy_mode_id,
+        capturer: Box::new(capturer),
+    })
+}
+
 fn get_capturer(
     source: VideoSource,
     current: usize,
@@ -471,7 +515,9 @@ fn run(vs: VideoService) -> ResultType<()> {
     // to-do: wayland ensure_inited should pass current display index.
     // But for now, we do not support multi-screen capture on wayland.
     #[cfg(target_os = "linux")]
-    super::wayland::ensure_inited()?;
+    if synthetic::config().is_none() {
+        super::wayland::ensure_inited()?;
+    }
     #[cfg(target_os = "linux")]
     let _wayland_call_on_ret = SimpleCallOnReturn {
         b: true,
@@ -548,6 +594,11 @@ fn run(vs: VideoService) -> ResultType<()> {
         .lock()
         .unwrap()
         .set_support_changing_quality(&sp.name(), encoder.support_changing_quality());
+    // Extra encoders are only affordable for software codecs.
+    VIDEO_QOS.lock().unwrap().set_support_simulcast(
+        &sp.name(),
+        encoder.support_changing_quality() && !encoder.is_hardware(),
+    );
     log::info!("initial quality: {quality:?}");
 
     if sp.is_option_true(OPTION_REFRESH) {
@@ -575,7 +626,9 @@ fn run(vs: VideoService) -> ResultType<()> {
     let mut first_frame = true;
     let capture_width = c.width;
     let capture_height = c.height;
-    let (mut second_instant, mut send_counter) = (Instant::now(), 0);
+    let (mut second_instant, mut send_counter, mut changed_area) = (Instant::now(), 0, 0.0);
+    let mut simulcast = Simulcast::default();
+    let mut screen_content = ScreenContent::new(c.width, c.height, codec_format.clone());
 
     while sp.ok() {
         #[cfg(windows)]
@@ -586,8 +639,10 @@ fn run(vs: VideoService) -> ResultType<()> {
             &mut spf,
             client_record,
             &mut send_counter,
+            &mut changed_area,
             &mut second_instant,
             &sp.name(),
+            &mut simulcast,
         )?;
         if sp.is_option_true(OPTION_REFRESH) {
             if vs.source.is_monitor() {
@@ -622,6 +677,14 @@ fn run(vs: VideoService) -> ResultType<()> {
         if vs.source.is_monitor() {
             check_privacy_mode_changed(&sp, display_idx, &c)?;
         }
+        if vs.source.is_camera() && camera::Cameras::take_format_changed(display_idx) {
+            log::info!("switch due to camera format changed");
+            if let Some(msg_out) = make_display_changed_msg(display_idx, None, VideoSource::Camera)
+            {
+                sp.send_shared(Arc::new(msg_out));
+            }
+            bail!("SWITCH");
+        }
         #[cfg(windows)]
         {
             if crate::platform::windows::desktop_changed()
@@ -645,6 +708,7 @@ fn run(vs: VideoService) -> ResultType<()> {
         let res = match c.frame(spf) {
             Ok(frame) => {
                 repeat_encode_counter = 0;
+                let (mut tiles, mut unchanged) = (None, false);
                 if frame.valid() {
                     let screenshot = SCREENSHOTS.lock().unwrap().remove(&display_idx);
                     if let Some(mut screenshot) = screenshot {
@@ -689,8 +753,32 @@ fn run(vs: VideoService) -> ResultType<()> {
                         }
                     }
 
+                    // Texture frames are always treated as fully changed.
+                    tiles = match &frame {
+                        scrap::Frame::PixelBuffer(f) => f.dirty_tiles().cloned(),
+                        scrap::Frame::Texture(_) => None,
+                    };
+                    unchanged = !first_frame && tiles.as_ref().map_or(false, |t| t.is_empty());
+                }
+                let tile_frame = match (&frame, &tiles) {
+                    (scrap::Frame::PixelBuffer(f), Some(tiles)) if frame.valid() && !unchanged => {
+                        let recording = client_record || recorder.lock().unwrap().is_some();
+                        screen_content.encode(f, tiles, recording, ms)
+                    }
+                    _ => None,
+                };
+                if let Some(frames) = tile_frame {
+                    changed_area += tiles.as_ref().map_or(1.0, |t| t.changed_ratio());
+                    let send_conn_ids = handle_tile_frame(display_idx, &sp, frames)?;
+                    frame_controller.set_send(now, send_conn_ids);
+                    send_counter += 1;
+                } else if frame.valid() && !unchanged {
+                    if let Some(tiles) = &tiles {
+                        encoder.set_dirty_tiles(tiles);
+                    }
+                    changed_area += tiles.as_ref().map_or(1.0, |t| t.changed_ratio());
                     let frame = frame.to(encoder.yuvfmt(), &mut yuv, &mut mid_data)?;
-                    let send_conn_ids = handle_one_frame(
+                    let mut send_conn_ids = handle_one_frame(
                         display_idx,
                         &sp,
                         frame,
@@ -701,7 +789,16 @@ fn run(vs: VideoService) -> ResultType<()> {
                         &mut first_frame,
                         capture_width,
                         capture_height,
+                        &simulcast.layered_conn_ids(),
                     )?;
+                    send_conn_ids.extend(simulcast.encode(
+                        display_idx,
+                        &sp,
+                        &yuv,
+                        ms,
+                        &encoder_cfg,
+                        use_i444,
+                    )?);
                     frame_controller.set_send(now, send_conn_ids);
                     send_counter += 1;
                 }
@@ -713,7 +810,12 @@ fn run(vs: VideoService) -> ResultType<()> {
                     }
                     try_gdi = 0;
                 }
-                Ok(())
+                if unchanged {
+                    // Nothing changed beyond the last encoded frame.
+                    Err(WouldBlock.into())
+                } else {
+                    Ok(())
+                }
             }
             Err(err) => Err(err),
         };
@@ -749,7 +851,7 @@ fn run(vs: VideoService) -> ResultType<()> {
                     // yun.len() > 0 means the frame is not texture.
                     if repeat_encode_counter < repeat_encode_max {
                         repeat_encode_counter += 1;
-                        let send_conn_ids = handle_one_frame(
+                        let mut send_conn_ids = handle_one_frame(
                             display_idx,
                             &sp,
                             EncodeInput::YUV(&yuv),
@@ -760,7 +862,16 @@ fn run(vs: VideoService) -> ResultType<()> {
                             &mut first_frame,
                             capture_width,
                             capture_height,
+                            &simulcast.layered_conn_ids(),
                         )?;
+                        send_conn_ids.extend(simulcast.encode(
+                            display_idx,
+                            &sp,
+                            &yuv,
+                            ms,
+                            &encoder_cfg,
+                            use_i444,
+                        )?);
                         frame_controller.set_send(now, send_conn_ids);
                         send_counter += 1;
                     }
@@ -802,6 +913,14 @@ fn run(vs: VideoService) -> ResultType<()> {
                 break;
             }
         }
+        let late_conn_ids: HashSet<i32> = frame_controller
+            .send_conn_ids
+            .difference(&fetched_conn_ids)
+            .cloned()
+            .collect();
+        if !late_conn_ids.is_empty() {
+            VIDEO_QOS.lock().unwrap().user_frame_late(&late_conn_ids);
+        }
 
         let elapsed = now.elapsed();
         // may need to enable frame(timeout)
@@ -978,6 +1097,7 @@ fn get_recorder(
             display_idx,
             camera,
             tx,
+            encrypt_key: crate::ui_interface::video_encrypt_key(),
         })
         .map_or(Default::default(), |r| Arc::new(Mutex::new(Some(r))))
     } else {
@@ -1058,6 +1178,7 @@ fn handle_one_frame(
     first_frame: &mut bool,
     width: usize,
     height: usize,
+    layered_conn_ids: &HashSet<i32>,
 ) -> ResultType<HashSet<i32>> {
     sp.snapshot(|sps| {
         // so that new sub and old sub share the same encoder after switch
@@ -1082,7 +1203,16 @@ fn handle_one_frame(
                 .unwrap()
                 .as_mut()
                 .map(|r| r.write_message(&msg, width, height));
-            send_conn_ids = sp.send_video_frame(msg);
+            let size = msg.compute_size() as usize;
+            send_conn_ids = if layered_conn_ids.is_empty() {
+                sp.send_video_frame(msg)
+            } else {
+                sp.send_video_frame_without(msg, layered_conn_ids)
+            };
+            VIDEO_QOS
+                .lock()
+                .unwrap()
+                .user_frame_sent(&send_conn_ids, size);
         }
         Err(e) => {
             *encode_fail_counter += 1;
@@ -1118,6 +1248,263 @@ fn handle_one_frame(
     Ok(send_conn_ids)
 }
 
+struct LayerEncoder {
+    encoder: Encoder,
+    ratio: f32,
+    last_send: Option<Instant>,
+}
+
+// Lower simulcast layers, layer 0 is served by the main encoder of the video service.
+#[derive(Default)]
+struct Simulcast {
+    user_layers: HashMap<i32, usize>,
+    encoders: HashMap<usize, LayerEncoder>,
+}
+
+impl Simulcast {
+    fn update(
+        &mut self,
+        encoder: &mut Encoder,
+        user_layers: HashMap<i32, usize>,
+    ) -> ResultType<()> {
+        let mut keyframe = user_layers.is_empty() && !self.layered_conn_ids().is_empty();
+        for (id, layer) in user_layers.iter() {
+            let old = self.user_layers.get(id).cloned().unwrap_or_default();
+            if *layer == old {
+                continue;
+            }
+            if *layer == 0 {
+                keyframe = true;
+            } else {
+                // The new member of the layer starts with a key frame.
+                if let Some(e) = self.encoders.get_mut(layer) {
+                    if !e.encoder.request_keyframe() {
+                        self.encoders.remove(layer);
+                    }
+                }
+            }
+        }
+        // The users returning to the main encoder need a key frame, the others keep their stream.
+        if keyframe && !encoder.request_keyframe() {
+            log::info!("switch due to connections back to simulcast layer 0");
+            bail!("SWITCH");
+        }
+        self.user_layers = user_layers;
+        let user_layers = &self.user_layers;
+        self.encoders
+            .retain(|layer, _| user_layers.values().any(|l| l == layer));
+        Ok(())
+    }
+
+    // Connections served by lower layers
+    fn layered_conn_ids(&self) -> HashSet<i32> {
+        self.user_layers
+            .iter()
+            .filter(|(_, layer)| **layer > 0)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    fn encode(
+        &mut self,
+        display: usize,
+        sp: &GenericService,
+        yuv: &[u8],
+        ms: i64,
+        encoder_cfg: &EncoderCfg,
+        use_i444: bool,
+    ) -> ResultType<HashSet<i32>> {
+        let mut send_conn_ids = HashSet::new();
+        if yuv.is_empty() {
+            return Ok(send_conn_ids);
+        }
+        for layer in 1..MAX_LAYERS {
+            let conn_ids: HashSet<i32> = self
+                .user_layers
+                .iter()
+                .filter(|(_, l)| **l == layer)
+                .map(|(id, _)| *id)
+                .collect();
+            if conn_ids.is_empty() {
+                continue;
+            }
+            let mut video_qos = VIDEO_QOS.lock().unwrap();
+            let ratio = video_qos.layer_ratio(layer);
+            let spf = video_qos.layer_spf(layer);
+            drop(video_qos);
+            let layer_encoder = match self.encoders.entry(layer) {
+                std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                std::collections::hash_map::Entry::Vacant(e) => {
+                    let mut encoder = Encoder::new(encoder_cfg.clone(), use_i444)?;
+                    allow_err!(encoder.set_quality(ratio));
+                    log::info!("new simulcast layer {layer} encoder, ratio: {ratio}");
+                    e.insert(LayerEncoder {
+                        encoder,
+                        ratio,
+                        last_send: None,
+                    })
+                }
+            };
+            if let Some(last_send) = layer_encoder.last_send {
+                if last_send.elapsed() < spf {
+                    continue;
+                }
+            }
+            if layer_encoder.ratio != ratio {
+                layer_encoder.ratio = ratio;
+                allow_err!(layer_encoder.encoder.set_quality(ratio));
+            }
+            match layer_encoder
+                .encoder
+                .encode_to_message(EncodeInput::YUV(yuv), ms)
+            {
+                Ok(mut vf) => {
+                    layer_encoder.last_send = Some(Instant::now());
+                    vf.display = display as _;
+                    let mut msg = Message::new();
+                    msg.set_video_frame(vf);
+                    let size = msg.compute_size() as usize;
+                    let ids = sp.send_video_frame_to(msg, &conn_ids);
+                    VIDEO_QOS.lock().unwrap().user_frame_sent(&ids, size);
+                    send_conn_ids.extend(ids);
+                }
+                Err(e) => {
+                    log::error!("simulcast layer {layer} encode fail: {e:?}");
+                    self.encoders.remove(&layer);
+                }
+            }
+        }
+        Ok(send_conn_ids)
+    }
+}
+
+// Lossless tiles for low-motion text and UI, the encoder is used again on motion.
+struct ScreenContent {
+    encoder: TileEncoder,
+    option: bool,
+    active: bool,
+    key: bool,
+    low_motion_frames: usize,
+    high_motion_frames: usize,
+}
+
+impl ScreenContent {
+    fn new(width: usize, height: usize, codec_format: CodecFormat) -> Self {
+        // Peers decode tiles into the image of the software decoder.
+        let option = Config::get_option("enable-screen-content") == "Y"
+            && matches!(
+                codec_format,
+                CodecFormat::VP8 | CodecFormat::VP9 | CodecFormat::AV1
+            );
+        Self {
+            encoder: TileEncoder::new(width, height),
+            option,
+            active: false,
+            key: false,
+            low_motion_frames: 0,
+            high_motion_frames: 0,
+        }
+    }
+
+    // Returns the tile frame if in screen content mode.
+    fn encode(
+        &mut self,
+        f: &scrap::PixelBuffer,
+        tiles: &TileMap,
+        recording: bool,
+        ms: i64,
+    ) -> Option<EncodedVideoFrames> {
+        let enabled = self.option
+            && !recording
+            && matches!(f.pixfmt(), scrap::Pixfmt::BGRA | scrap::Pixfmt::RGBA)
+            && VIDEO_QOS.lock().unwrap().support_tiles();
+        if !enabled {
+            self.leave();
+            return None;
+        }
+        let ratio = tiles.changed_ratio();
+        if self.active {
+            if ratio > SCREEN_CONTENT_HIGH_MOTION {
+                self.high_motion_frames += 1;
+                if self.high_motion_frames >= SCREEN_CONTENT_EXIT_FRAMES {
+                    log::info!("leave screen content mode, changed ratio: {ratio}");
+                    self.leave();
+                    return None;
+                }
+            } else {
+                self.high_motion_frames = 0;
+            }
+        } else {
+            if ratio >= SCREEN_CONTENT_LOW_MOTION {
+                self.low_motion_frames = 0;
+                return None;
+            }
+            self.low_motion_frames += 1;
+            if self.low_motion_frames < SCREEN_CONTENT_ENTER_FRAMES {
+                return None;
+            }
+            self.low_motion_frames = 0;
+            let stride = f.stride().first().copied().unwrap_or_default();
+            if !scrap::tilecodec::is_screen_content(f.data(), stride, f.width(), f.height()) {
+                return None;
+            }
+            log::info!("enter screen content mode");
+            self.active = true;
+            self.key = true;
+            self.high_motion_frames = 0;
+        }
+        let stride = f.stride().first().copied().unwrap_or_default();
+        match self
+            .encoder
+            .encode(f.data(), stride, f.pixfmt(), tiles, self.key, ms)
+        {
+            Ok(frames) => {
+                self.key = false;
+                Some(frames)
+            }
+            Err(e) => {
+                log::error!("tile encode fail: {e:?}");
+                self.leave();
+                None
+            }
+        }
+    }
+
+    fn leave(&mut self) {
+        self.active = false;
+        self.low_motion_frames = 0;
+        self.high_motion_frames = 0;
+    }
+}
+
+fn handle_tile_frame(
+    display: usize,
+    sp: &GenericService,
+    frames: EncodedVideoFrames,
+) -> ResultType<HashSet<i32>> {
+    sp.snapshot(|sps| {
+        // the new sub needs a key frame of the encoder
+        if sps.has_subscribes() {
+            log::info!("switch due to new subscriber");
+            bail!("SWITCH");
+        }
+        Ok(())
+    })?;
+    let msg = crate::ext_msg::new_video_message(
+        &crate::ext_msg::Ext::Tiles {
+            display: display as _,
+        },
+        &frames.write_to_bytes()?,
+    );
+    let size = msg.compute_size() as usize;
+    let send_conn_ids = sp.send_video_frame(msg);
+    VIDEO_QOS
+        .lock()
+        .unwrap()
+        .user_frame_sent(&send_conn_ids, size);
+    Ok(send_conn_ids)
+}
+
 #[inline]
 pub fn refresh() {
     #[cfg(target_os = "android")]
@@ -1234,8 +1621,10 @@ fn check_qos(
     spf: &mut Duration,
     client_record: bool,
     send_counter: &mut usize,
+    changed_area: &mut f32,
     second_instant: &mut Instant,
     name: &str,
+    simulcast: &mut Simulcast,
 ) -> ResultType<()> {
     let mut video_qos = VIDEO_QOS.lock().unwrap();
     *spf = video_qos.spf();
@@ -1256,10 +1645,12 @@ fn check_qos(
         log::info!("switch due to record changed");
         bail!("SWITCH");
     }
+    simulcast.update(encoder, video_qos.user_layers())?;
     if second_instant.elapsed() > Duration::from_secs(1) {
         *second_instant = Instant::now();
-        video_qos.update_display_data(&name, *send_counter);
+        video_qos.update_display_data(&name, *send_counter, *changed_area);
         *send_counter = 0;
+        *changed_area = 0.0;
     }
     drop(video_qos);
     Ok(())
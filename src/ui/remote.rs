@@ -512,6 +512,11 @@ impl sciter::EventHandler for SciterSession {
         fn confirm_delete_files(i32, i32);
         fn set_no_confirm(i32);
         fn cancel_job(i32);
+        fn sync_dir(i32, String, String, String);
+        fn start_sync(i32);
+        fn search_remote(i32, String, String);
+        fn preview_remote(i32, String, i32, bool);
+        fn set_file_transfer_limit(i32, bool);
         fn send_files(i32, i32, String, String, i32, bool, bool);
         fn add_job(i32, i32, String, String, i32, bool, bool);
         fn resume_job(i32, bool);
@@ -347,6 +347,9 @@ impl<T: InvokeUiCM> IpcTaskRunner<T> {
 
         // for tmp use, without real conn id
         let mut write_jobs: Vec<fs::TransferJob> = Vec::new();
+        let mut write_digests = crate::file_verify::WriteDigests::default();
+        let mut archive_writes = HashMap::new();
+        let mut write_metas = crate::file_meta::WriteMetas::default();
 
         #[cfg(target_os = "windows")]
         let is_authorized = self.cm.is_authorized(self.conn_id);
@@ -439,10 +442,10 @@ impl<T: InvokeUiCM> IpcTaskRunner<T> {
                                     if let ipc::FS::WriteBlock { id, file_num, data: _, compressed } = fs {
                                         if let Ok(bytes) = self.stream.next_raw().await {
                                             fs = ipc::FS::WriteBlock{id, file_num, data:bytes.into(), compressed};
-                                            handle_fs(fs, &mut write_jobs, &self.tx, Some(&tx_log)).await;
+                                            handle_fs(fs, &mut write_jobs, &mut write_digests, &mut archive_writes, &mut write_metas, &self.tx, Some(&tx_log)).await;
                                         }
                                     } else {
-                                        handle_fs(fs, &mut write_jobs, &self.tx, Some(&tx_log)).await;
+                                        handle_fs(fs, &mut write_jobs, &mut write_digests, &mut archive_writes, &mut write_metas, &self.tx, Some(&tx_log)).await;
                                     }
                                     let log = fs::serialize_transfer_jobs(&write_jobs);
                                     self.cm.ui_handler.file_transfer_log("transfer", &log);
@@ -670,6 +673,9 @@ pub async fn start_listen<T: InvokeUiCM>(
 ) {
     let mut current_id = 0;
     let mut write_jobs: Vec<fs::TransferJob> = Vec::new();
+    let mut write_digests = crate::file_verify::WriteDigests::default();
+    let mut archive_writes = HashMap::new();
+    let mut write_metas = crate::file_meta::WriteMetas::default();
     loop {
         match rx.recv().await {
             Some(Data::Login {
@@ -714,7 +720,16 @@ pub async fn start_listen<T: InvokeUiCM>(
                 cm.new_message(current_id, text);
             }
             Some(Data::FS(fs)) => {
-                handle_fs(fs, &mut write_jobs, &tx, None).await;
+                handle_fs(
+                    fs,
+                    &mut write_jobs,
+                    &mut write_digests,
+                    &mut archive_writes,
+                    &mut write_metas,
+                    &tx,
+                    None,
+                )
+                .await;
             }
             Some(Data::Close) => {
                 break;
@@ -741,6 +756,9 @@ pub async fn start_listen<T: InvokeUiCM>(
 async fn handle_fs(
     fs: ipc::FS,
     write_jobs: &mut Vec<fs::TransferJob>,
+    write_digests: &mut crate::file_verify::WriteDigests,
+    archive_writes: &mut HashMap<i32, crate::file_archive::ArchiveWrite>,
+    write_metas: &mut crate::file_meta::WriteMetas,
     tx: &UnboundedSender<Data>,
     tx_log: Option<&UnboundedSender<String>>,
 ) {
@@ -807,7 +825,20 @@ async fn handle_fs(
             job.conn_id = conn_id;
             write_jobs.push(job);
         }
+        ipc::FS::NewArchiveWrite { path, id, format } => {
+            let apply_ownership =
+                Config::get_option(crate::file_meta::OPTION_APPLY_OWNERSHIP) == "Y";
+            let write = crate::file_archive::ArchiveWrite::new(
+                &PathBuf::from(&path),
+                format,
+                apply_ownership,
+            );
+            archive_writes.insert(id, write);
+        }
         ipc::FS::CancelWrite { id } => {
+            archive_writes.remove(&id);
+            write_digests.remove(id);
+            write_metas.remove(id);
             if let Some(job) = fs::remove_job(id, write_jobs) {
                 job.remove_download_file();
                 tx_log.map(|tx: &UnboundedSender<String>| {
@@ -816,13 +847,45 @@ async fn handle_fs(
             }
         }
         ipc::FS::WriteDone { id, file_num } => {
+            if let Some(write) = archive_writes.remove(&id) {
+                handle_result(spawn_blocking(move || write.finish()).await, id, 0, tx).await;
+                return;
+            }
             if let Some(job) = fs::remove_job(id, write_jobs) {
                 job.modify_time();
-                send_raw(fs::new_done(id, file_num), tx);
-                tx_log.map(|tx| tx.send(serialize_transfer_job(&job, true, false, "")));
+                if let Some((path, metas)) = write_metas.take(&job) {
+                    let apply_ownership =
+                        Config::get_option(crate::file_meta::OPTION_APPLY_OWNERSHIP) == "Y";
+                    spawn_blocking(move || crate::file_meta::apply(&path, metas, apply_ownership))
+                        .await
+                        .ok();
+                }
+                let files = write_digests.take(&job);
+                if files.is_empty() {
+                    send_raw(fs::new_done(id, file_num), tx);
+                    tx_log.map(|tx| tx.send(serialize_transfer_job(&job, true, false, "")));
+                    return;
+                }
+                let failed = spawn_blocking(move || crate::file_verify::verify(files))
+                    .await
+                    .unwrap_or_default();
+                allow_err!(tx.send(Data::FileTransferVerified((id, failed.clone()))));
+                if let Some(file_num) = failed.first() {
+                    let err = crate::file_verify::ERR_VERIFY_FAILED;
+                    tx_log.map(|tx| tx.send(serialize_transfer_job(&job, false, false, err)));
+                    send_raw(fs::new_error(id, err, *file_num), tx);
+                } else {
+                    let ext = crate::ext_msg::Ext::FileVerifyResult { id, failed: vec![] };
+                    send_raw(crate::ext_msg::new_message(&ext, &[]), tx);
+                    send_raw(fs::new_done(id, file_num), tx);
+                    tx_log.map(|tx| tx.send(serialize_transfer_job(&job, true, false, "")));
+                }
             }
         }
         ipc::FS::WriteError { id, file_num, err } => {
+            archive_writes.remove(&id);
+            write_digests.remove(id);
+            write_metas.remove(id);
             if let Some(job) = fs::remove_job(id, write_jobs) {
                 tx_log.map(|tx| tx.send(serialize_transfer_job(&job, false, false, &err)));
                 send_raw(fs::new_error(job.id(), err, file_num), tx);
@@ -834,6 +897,16 @@ async fn handle_fs(
             data,
             compressed,
         } => {
+            if let Some(write) = archive_writes.get(&id) {
+                if !write.write(data.into()) {
+                    // The unpacking failed, tells the peer why.
+                    if let Some(write) = archive_writes.remove(&id) {
+                        handle_result(spawn_blocking(move || write.finish()).await, id, 0, tx)
+                            .await;
+                    }
+                }
+                return;
+            }
             if let Some(job) = fs::get_job(id, write_jobs) {
                 if let Err(err) = job
                     .write(FileTransferBlock {
@@ -907,6 +980,16 @@ async fn handle_fs(
         ipc::FS::Rename { id, path, new_name } => {
             rename_file(path, new_name, id, tx).await;
         }
+        ipc::FS::Verify {
+            id,
+            file_num,
+            sha256,
+        } => {
+            write_digests.add(id, file_num, sha256);
+        }
+        ipc::FS::WriteMeta { id, files } => {
+            write_metas.add(id, files);
+        }
         _ => {}
     }
 }
@@ -944,6 +944,16 @@ pub fn video_save_directory(root: bool) -> String {
     Default::default()
 }
 
+/// Operator public key (base64) used to encrypt recordings at rest, if configured.
+pub fn video_encrypt_key() -> Option<String> {
+    let key = Config::get_option("record-encryption-public-key");
+    if key.is_empty() {
+        None
+    } else {
+        Some(key)
+    }
+}
+
 #[inline]
 pub fn get_api_server() -> String {
     crate::get_api_server(
@@ -347,6 +347,13 @@ impl<T: InvokeUiSession> Session<T> {
         if let Some(msg) = msg {
             self.send(Data::Message(msg));
         }
+        if name == crate::file_verify::OPTION_VERIFY
+            && self.peer_has_ext_capability(crate::ext_msg::CAP_FILE_VERIFY)
+        {
+            let verify = self.lc.read().unwrap().get_option(&name) == "Y";
+            let ext = crate::ext_msg::Ext::FileVerifyOption { verify };
+            self.send(Data::Message(crate::ext_msg::new_message(&ext, &[])));
+        }
     }
 
     pub fn toggle_privacy_mode(&self, impl_key: String, on: bool) {
@@ -461,6 +468,14 @@ impl<T: InvokeUiSession> Session<T> {
         self.send(Data::Message(msg));
     }
 
+    pub fn set_file_transfer_limit(&self, max_speed: i32, priority: bool) {
+        let mut lc = self.lc.write().unwrap();
+        lc.set_file_transfer_limit(max_speed, priority);
+        let limit = lc.get_file_transfer_limit();
+        drop(lc);
+        self.send(Data::FileTransferLimit(limit));
+    }
+
     pub fn get_remember(&self) -> bool {
         self.lc.read().unwrap().remember
     }
@@ -1315,7 +1330,6 @@ impl<T: InvokeUiSession> Session<T> {
             // no last jobs
             return;
         }
-        // TODO: can add a confirm dialog
         let mut cnt = 1;
         for job_str in pc.transfer.read_jobs.iter() {
             if !job_str.is_empty() {
@@ -1332,6 +1346,8 @@ impl<T: InvokeUiSession> Session<T> {
             }
         }
         self.update_transfer_list();
+        // The jobs are loaded paused, the user decides whether to go on.
+        self.offer_resume_jobs(cnt - 1);
     }
 
     pub fn elevate_direct(&self) {
@@ -1451,6 +1467,87 @@ impl<T: InvokeUiSession> Session<T> {
         self.send(Data::Message(msg));
     }
 
+    // Stream only a region of the display, relative to the display. An empty region restores
+    // the whole display.
+    pub fn set_capture_region(&self, display: i32, x: i32, y: i32, width: i32, height: i32) {
+        if !self.peer_has_ext_capability(crate::ext_msg::CAP_CAPTURE_REGION) {
+            log::warn!("The peer does not support capture regions");
+            return;
+        }
+        let ext = crate::ext_msg::Ext::CaptureRegion {
+            display,
+            x,
+            y,
+            width,
+            height,
+        };
+        self.send(Data::Message(crate::ext_msg::new_message(&ext, &[])));
+    }
+
+    // See `ext_msg`.
+    pub fn peer_has_ext_capability(&self, cap: &str) -> bool {
+        self.lc
+            .read()
+            .unwrap()
+            .peer_info
+            .as_ref()
+            .map_or(false, |pi| {
+                crate::ext_msg::peer_info_has_capability(pi, cap)
+            })
+    }
+
+    pub fn query_camera_capabilities(&self, display: i32) {
+        if !self.peer_has_ext_capability(crate::ext_msg::CAP_CAMERA_SETTINGS) {
+            log::warn!("The peer does not support camera settings");
+            return;
+        }
+        let ext = crate::ext_msg::Ext::CameraCapabilitiesQuery { display };
+        self.send(Data::Message(crate::ext_msg::new_message(&ext, &[])));
+    }
+
+    // A zero width or height keeps the current format, and an empty control name sets no control.
+    pub fn set_camera_settings(
+        &self,
+        display: i32,
+        width: i32,
+        height: i32,
+        frame_rate: u32,
+        fourcc: String,
+        control: String,
+        value: i64,
+    ) {
+        if !self.peer_has_ext_capability(crate::ext_msg::CAP_CAMERA_SETTINGS) {
+            log::warn!("The peer does not support camera settings");
+            return;
+        }
+        let format = if width > 0 && height > 0 {
+            Some(scrap::camera::CameraFormat {
+                width,
+                height,
+                frame_rate,
+                fourcc,
+            })
+        } else {
+            None
+        };
+        let controls = if control.is_empty() {
+            vec![]
+        } else {
+            vec![scrap::camera::CameraControl {
+                name: control,
+                value,
+                ..Default::default()
+            }]
+        };
+        let settings = scrap::camera::CameraSettings {
+            display,
+            format,
+            controls,
+        };
+        let ext = crate::ext_msg::Ext::CameraSettings { settings };
+        self.send(Data::Message(crate::ext_msg::new_message(&ext, &[])));
+    }
+
     #[inline]
     pub fn request_voice_call(&self) {
         #[cfg(target_os = "linux")]
@@ -1601,6 +1698,19 @@ pub trait InvokeUiSession: Send + Sync + Clone + 'static + Sized + Default {
     fn is_multi_ui_session(&self) -> bool;
     fn update_record_status(&self, start: bool);
     fn update_empty_dirs(&self, _res: ReadEmptyDirsResponse) {}
+    fn update_camera_capabilities(&self, _caps: scrap::camera::CameraCapabilities) {}
+    fn update_sync_plan(&self, _id: i32, _plan: String) {}
+    fn job_verified(&self, _id: i32, _verified: bool) {}
+    fn update_search_results(&self, _fd: FileDirectory, _done: bool, _error: String) {}
+    fn update_file_preview(
+        &self,
+        _id: i32,
+        _path: String,
+        _preview: crate::file_search::Preview,
+        _error: String,
+    ) {
+    }
+    fn offer_resume_jobs(&self, _count: i32) {}
     fn printer_request(&self, id: i32, path: String);
     fn handle_screenshot_resp(&self, sid: String, msg: String);
 }